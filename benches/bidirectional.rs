@@ -0,0 +1,105 @@
+//! Latency of messages echoed on one stream while both directions are loaded
+//!
+//! ```plain
+//! cargo bench --bench bidirectional
+//! ```
+//!
+//! The client keeps sending timestamped messages at a fixed rate while reading the echoes on the same stream,
+//! and the server echoes them back, so the session is sending and receiving heavily at the same time.
+//! The latency of a message includes the time it waits for the lock of the session in both ends.
+
+use std::time::Duration;
+
+use bytes::{Buf, BufMut};
+use tokio::{
+    io::{self, AsyncReadExt, AsyncWriteExt},
+    time::{self, Instant},
+};
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+const MESSAGE_SIZE: usize = 1024;
+/// Messages sent every millisecond
+const MESSAGES_PER_MS: usize = 8;
+const DURATION: Duration = Duration::from_secs(10);
+/// Messages in the first second are not measured
+const WARMUP: Duration = Duration::from_secs(1);
+
+async fn run() {
+    let config = KcpConfig {
+        wnd_size: (1024, 1024),
+        ..KcpConfig::turbo()
+    };
+
+    let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = io::split(stream);
+                let mut message = [0u8; MESSAGE_SIZE];
+                while reader.read_exact(&mut message).await.is_ok() {
+                    if writer.write_all(&message).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+    let (mut reader, mut writer) = io::split(stream);
+
+    let start = Instant::now();
+    let total = (DURATION.as_millis() as usize) * MESSAGES_PER_MS;
+
+    tokio::spawn(async move {
+        let mut interval = time::interval(Duration::from_millis(1));
+        let mut message = [0u8; MESSAGE_SIZE];
+        let mut sent = 0;
+        while sent < total {
+            interval.tick().await;
+            for _ in 0..MESSAGES_PER_MS {
+                let nanos = start.elapsed().as_nanos() as u64;
+                (&mut message[..]).put_u64_le(nanos);
+                writer.write_all(&message).await.unwrap();
+                sent += 1;
+            }
+        }
+        // Keeps the stream open until all echoes are received
+        time::sleep(Duration::from_secs(60)).await;
+    });
+
+    let mut latencies = Vec::with_capacity(total);
+    let mut message = [0u8; MESSAGE_SIZE];
+    for _ in 0..total {
+        reader.read_exact(&mut message).await.unwrap();
+        let sent_at = Duration::from_nanos((&message[..]).get_u64_le());
+        if sent_at >= WARMUP {
+            latencies.push(start.elapsed() - sent_at);
+        }
+    }
+
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:>10} {:>12} {:>12} {:>12} {:>12}",
+        "messages", "p50 (us)", "p99 (us)", "p99.9 (us)", "max (us)"
+    );
+    println!(
+        "{:>10} {:>12} {:>12} {:>12} {:>12}",
+        latencies.len(),
+        percentile(0.5).as_micros(),
+        percentile(0.99).as_micros(),
+        percentile(0.999).as_micros(),
+        latencies.last().unwrap().as_micros(),
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    runtime.block_on(run());
+}
@@ -0,0 +1,83 @@
+//! Throughput of one stream written by many tasks at once
+//!
+//! ```plain
+//! cargo bench --bench concurrent_senders
+//! ```
+//!
+//! Clones of a client stream are moved into tasks of a multi-threaded runtime, and each of them writes its share of
+//! the data as fast as it can, while the server reads everything. All of them go through the lock of the same
+//! session, together with its update task, so the time they spend waiting for each other shows in the throughput.
+
+use std::time::Duration;
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::{self, Instant},
+};
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+const MESSAGE_SIZE: usize = 1024;
+/// Bytes sent by all tasks together
+const TOTAL_BYTES: usize = 64 * 1024 * 1024;
+
+async fn run(senders: usize) {
+    let config = KcpConfig {
+        wnd_size: (1024, 1024),
+        ..KcpConfig::turbo()
+    };
+
+    let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+
+    let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+    let per_sender = TOTAL_BYTES / senders / MESSAGE_SIZE;
+
+    let start = Instant::now();
+    let mut tasks = Vec::with_capacity(senders);
+    for _ in 0..senders {
+        let mut stream = stream.clone();
+        tasks.push(tokio::spawn(async move {
+            let message = [0x42u8; MESSAGE_SIZE];
+            for _ in 0..per_sender {
+                stream.write_all(&message).await.unwrap();
+            }
+        }));
+    }
+
+    let (mut server, _) = listener.accept().await.unwrap();
+    let expected = per_sender * senders * MESSAGE_SIZE;
+    let mut received = 0;
+    let mut buf = vec![0u8; 64 * 1024];
+    while received < expected {
+        let n = time::timeout(Duration::from_secs(10), server.read(&mut buf))
+            .await
+            .expect("stalled")
+            .unwrap();
+        assert!(n > 0, "closed early");
+        received += n;
+    }
+    let elapsed = start.elapsed();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    println!(
+        "{:>8} {:>12} {:>14.1}",
+        senders,
+        elapsed.as_millis(),
+        received as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(4)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!("{:>8} {:>12} {:>14}", "senders", "total (ms)", "MiB/s");
+    for senders in [1, 4, 16, 64] {
+        runtime.block_on(run(senders));
+    }
+}
@@ -0,0 +1,136 @@
+//! Latency of one session while another session of the same listener is flooded, for each `DispatchMode`
+//!
+//! ```plain
+//! cargo bench --bench dispatch_contention
+//! ```
+//!
+//! A raw UDP socket floods one session with KCP segments as fast as it can, so its session task falls behind and
+//! its input channel stays full. Meanwhile a client measures round trips of small messages echoed by another
+//! session. With `DispatchMode::Shared` the listener task waits for the flooded session, and the echoes wait
+//! with it. With `DispatchMode::PerSession` segments of the flooded session are dropped instead.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::BufMut;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    time::{self, Instant},
+};
+use tokio_kcp::{DispatchMode, KcpConfig, KcpListener, KcpNoDelayConfig, KcpStream};
+
+const FLOOD_CONV: u32 = 0x7f00_0001;
+const FLOOD_PAYLOAD: usize = 1024;
+const ROUND_TRIPS: usize = 2000;
+
+fn push_segment(sn: u32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(24 + FLOOD_PAYLOAD);
+    buf.put_u32_le(FLOOD_CONV);
+    buf.put_u8(81); // PUSH
+    buf.put_u8(0); // frg
+    buf.put_u16_le(1024); // wnd
+    buf.put_u32_le(0); // ts
+    buf.put_u32_le(sn);
+    buf.put_u32_le(0); // una
+    buf.put_u32_le(FLOOD_PAYLOAD as u32);
+    buf.resize(24 + FLOOD_PAYLOAD, b'x');
+    buf
+}
+
+/// Sends segments of `FLOOD_CONV` to `server_addr` until `stop` is set
+async fn flood(server_addr: SocketAddr, stop: Arc<AtomicBool>) -> u64 {
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut sent = 0;
+    while !stop.load(Ordering::Relaxed) {
+        // Segments within the receive window, which are acknowledged but never complete a message
+        let _ = udp.send_to(&push_segment(1 + (sent % 512) as u32), server_addr).await;
+        sent += 1;
+        if sent % 256 == 0 {
+            tokio::task::yield_now().await;
+        }
+    }
+    sent
+}
+
+async fn run(dispatch_mode: DispatchMode) {
+    let config = KcpConfig {
+        nodelay: KcpNoDelayConfig::fastest(),
+        wnd_size: (1024, 1024),
+        dispatch_mode,
+        ..Default::default()
+    };
+
+    let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1024];
+                while let Ok(n) = stream.read(&mut buf).await {
+                    if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    });
+
+    let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+    let mut buf = [0u8; 64];
+    // Opens the session before the flood
+    stream.write_all(&buf).await.unwrap();
+    stream.read_exact(&mut buf).await.unwrap();
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let flooder = tokio::spawn(flood(server_addr, stop.clone()));
+    time::sleep(Duration::from_millis(200)).await;
+
+    let start = Instant::now();
+    let mut latencies = Vec::with_capacity(ROUND_TRIPS);
+    for _ in 0..ROUND_TRIPS {
+        let sent_at = Instant::now();
+        stream.write_all(&buf).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        latencies.push(sent_at.elapsed());
+    }
+    let elapsed = start.elapsed();
+
+    stop.store(true, Ordering::Relaxed);
+    let flooded = flooder.await.unwrap();
+
+    latencies.sort();
+    let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p) as usize];
+    println!(
+        "{:>12} {:>12} {:>12} {:>12} {:>12} {:>14}",
+        format!("{:?}", dispatch_mode),
+        percentile(0.5).as_micros(),
+        percentile(0.99).as_micros(),
+        latencies.last().unwrap().as_micros(),
+        elapsed.as_millis(),
+        flooded
+    );
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!(
+        "{:>12} {:>12} {:>12} {:>12} {:>12} {:>14}",
+        "mode", "p50 (us)", "p99 (us)", "max (us)", "total (ms)", "flood packets"
+    );
+    for dispatch_mode in [DispatchMode::Shared, DispatchMode::PerSession] {
+        runtime.block_on(run(dispatch_mode));
+    }
+}
@@ -0,0 +1,157 @@
+//! Memory and CPU usage of idle sessions, per-session tasks vs. the shared driver
+//!
+//! ```plain
+//! cargo bench --bench idle_sessions
+//! ```
+//!
+//! Sessions are opened by sending one KCP segment for every conv from a single UDP socket, so no client
+//! sessions are running in the same process. Each mode runs in a child process, Linux only.
+
+#[cfg(target_os = "linux")]
+mod bench {
+    use std::{
+        env,
+        process::Command,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bytes::BufMut;
+    use tokio::{net::UdpSocket, time};
+    use tokio_kcp::{KcpConfig, KcpListener};
+
+    const SESSIONS: u32 = 10_000;
+    const IDLE: Duration = Duration::from_secs(10);
+    const MODE_ENV: &str = "IDLE_SESSIONS_MODE";
+
+    /// Resident set size in KiB
+    fn rss_kib() -> u64 {
+        let statm = std::fs::read_to_string("/proc/self/statm").unwrap();
+        let pages: u64 = statm.split_whitespace().nth(1).unwrap().parse().unwrap();
+        pages * (unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64) / 1024
+    }
+
+    /// User + system CPU time of this process
+    fn cpu_time() -> Duration {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) };
+        let to_duration = |tv: libc::timeval| Duration::new(tv.tv_sec as u64, tv.tv_usec as u32 * 1000);
+        to_duration(usage.ru_utime) + to_duration(usage.ru_stime)
+    }
+
+    fn push_segment(conv: u32) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(25);
+        buf.put_u32_le(conv);
+        buf.put_u8(81); // PUSH
+        buf.put_u8(0); // frg
+        buf.put_u16_le(128); // wnd
+        buf.put_u32_le(0); // ts
+        buf.put_u32_le(0); // sn
+        buf.put_u32_le(0); // una
+        buf.put_u32_le(1); // len
+        buf.put_u8(b'x');
+        buf
+    }
+
+    async fn run(shared_driver: bool) {
+        let config = KcpConfig {
+            shared_driver,
+            ..Default::default()
+        };
+
+        let baseline_rss = rss_kib();
+
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let accepted = Arc::new(AtomicUsize::new(0));
+        {
+            let accepted = accepted.clone();
+            tokio::spawn(async move {
+                // Keeps all streams open
+                let mut streams = Vec::with_capacity(SESSIONS as usize);
+                while let Ok((stream, _)) = listener.accept().await {
+                    streams.push(stream);
+                    accepted.store(streams.len(), Ordering::Relaxed);
+                }
+            });
+        }
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        {
+            // Drains ACKs
+            let udp = udp.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 2048];
+                while udp.recv(&mut buffer).await.is_ok() {}
+            });
+        }
+
+        // Datagrams may be dropped if they are sent too fast, sends again until all sessions are opened
+        while accepted.load(Ordering::Relaxed) < SESSIONS as usize {
+            for conv in 1..=SESSIONS {
+                udp.send_to(&push_segment(conv), server_addr).await.unwrap();
+                if conv % 200 == 0 {
+                    time::sleep(Duration::from_millis(5)).await;
+                }
+            }
+            time::sleep(Duration::from_millis(500)).await;
+        }
+
+        // Let the first ACKs go
+        time::sleep(Duration::from_secs(1)).await;
+
+        let start_cpu = cpu_time();
+        time::sleep(IDLE).await;
+        let idle_cpu = cpu_time() - start_cpu;
+
+        let rss = rss_kib().saturating_sub(baseline_rss);
+        println!(
+            "{:<20} {:>12} {:>16.1} {:>20.2}",
+            if shared_driver {
+                "shared driver"
+            } else {
+                "per-session task"
+            },
+            SESSIONS,
+            rss as f64 / 1024.0,
+            idle_cpu.as_secs_f64() * 1000.0 / IDLE.as_secs_f64(),
+        );
+    }
+
+    pub fn main() {
+        if let Ok(mode) = env::var(MODE_ENV) {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(run(mode == "shared"));
+            return;
+        }
+
+        println!(
+            "{:<20} {:>12} {:>16} {:>20}",
+            "driver", "sessions", "memory (MiB)", "idle CPU (ms/s)"
+        );
+        for mode in ["task", "shared"] {
+            let status = Command::new(env::current_exe().unwrap())
+                .env(MODE_ENV, mode)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() {
+    bench::main();
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() {
+    eprintln!("idle_sessions benchmark is only supported on Linux");
+}
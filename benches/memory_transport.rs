@@ -0,0 +1,119 @@
+//! Round trips and throughput of one stream over `MemoryTransport`, compared with loopback UDP
+//!
+//! ```plain
+//! cargo bench --bench memory_transport --features testing
+//! ```
+//!
+//! Datagrams between in-memory ends are passed by channels, the difference with UDP is the cost of the sockets and
+//! of the kernel, what remains is the cost of the crate. Round trips wait for updates of sessions on both
+//! transports, they are bound by the interval.
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream, MemoryTransport};
+
+const ROUND_TRIPS: usize = 10000;
+const MESSAGE_SIZE: usize = 64;
+const BULK_SIZE: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum Transport {
+    Udp,
+    Memory,
+}
+
+async fn connect(transport: Transport, config: &KcpConfig) -> (KcpListener, KcpStream) {
+    match transport {
+        Transport::Udp => {
+            let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            let stream = KcpStream::connect(config, server_addr).await.unwrap();
+            (listener, stream)
+        }
+        Transport::Memory => {
+            let (server, client) = MemoryTransport::pair();
+            let server_addr: SocketAddr = server.local_addr();
+            let listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+            let stream = KcpStream::connect_with_transport(config, Arc::new(client), server_addr)
+                .await
+                .unwrap();
+            (listener, stream)
+        }
+    }
+}
+
+/// Median round trip of small messages
+async fn round_trips(transport: Transport, config: &KcpConfig) -> Duration {
+    let (mut listener, mut stream) = connect(transport, config).await;
+    tokio::spawn(async move {
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; MESSAGE_SIZE];
+        while accepted.read_exact(&mut buf).await.is_ok() {
+            if accepted.write_all(&buf).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut buf = [0u8; MESSAGE_SIZE];
+    let mut latencies = Vec::with_capacity(ROUND_TRIPS);
+    for _ in 0..ROUND_TRIPS {
+        let sent_at = Instant::now();
+        stream.write_all(&buf).await.unwrap();
+        stream.read_exact(&mut buf).await.unwrap();
+        latencies.push(sent_at.elapsed());
+    }
+    latencies.sort();
+    latencies[latencies.len() / 2]
+}
+
+/// Bytes per second of a bulk transfer
+async fn throughput(transport: Transport, config: &KcpConfig) -> f64 {
+    let (mut listener, mut stream) = connect(transport, config).await;
+    let start = Instant::now();
+    let sink = tokio::spawn(async move {
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while received < BULK_SIZE {
+            received += accepted.read(&mut buf).await.unwrap();
+        }
+        start.elapsed()
+    });
+
+    let chunk = vec![0u8; 64 * 1024];
+    for _ in 0..BULK_SIZE / chunk.len() {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    let elapsed = sink.await.unwrap();
+    BULK_SIZE as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let config = KcpConfig {
+        wnd_size: (1024, 1024),
+        ..KcpConfig::turbo()
+    };
+
+    println!("{:>10} {:>16} {:>16}", "transport", "p50 RTT (us)", "MB/s");
+    for transport in [Transport::Udp, Transport::Memory] {
+        let rtt = runtime.block_on(round_trips(transport, &config));
+        let throughput = runtime.block_on(throughput(transport, &config));
+        println!(
+            "{:>10} {:>16} {:>16.1}",
+            format!("{:?}", transport),
+            rtt.as_micros(),
+            throughput / 1_000_000.0
+        );
+    }
+}
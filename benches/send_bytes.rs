@@ -0,0 +1,70 @@
+//! Throughput of `KcpStream::send_bytes` against `KcpStream::send`
+//!
+//! ```plain
+//! cargo bench --bench send_bytes --features bytes
+//! ```
+//!
+//! KCP copies data into its own segments in both cases, so they are expected to be on par.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use tokio::{io::AsyncReadExt, time::Instant};
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+const CHUNK_SIZE: usize = 64 * 1024;
+const TOTAL_SIZE: usize = 256 * 1024 * 1024;
+
+async fn run(use_bytes: bool) -> Duration {
+    let config = KcpConfig {
+        wnd_size: (1024, 1024),
+        ..KcpConfig::turbo()
+    };
+
+    let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut received = 0;
+        while received < TOTAL_SIZE {
+            received += stream.read(&mut buf).await.unwrap();
+        }
+    });
+
+    let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+    let chunk = Bytes::from(vec![0u8; CHUNK_SIZE]);
+
+    let start = Instant::now();
+    for _ in 0..TOTAL_SIZE / CHUNK_SIZE {
+        if use_bytes {
+            stream.send_bytes(chunk.clone()).await.unwrap();
+        } else {
+            let mut buf = &chunk[..];
+            while !buf.is_empty() {
+                let n = stream.send(buf).await.unwrap();
+                buf = &buf[n..];
+            }
+        }
+    }
+    receiver.await.unwrap();
+    start.elapsed()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!("{:<12} {:>16}", "method", "throughput (MiB/s)");
+    for (name, use_bytes) in [("send", false), ("send_bytes", true)] {
+        let elapsed = runtime.block_on(run(use_bytes));
+        println!(
+            "{:<12} {:>16.1}",
+            name,
+            TOTAL_SIZE as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Segments sent for a stream of small writes, with and without `KcpConfig::write_coalesce`
+//!
+//! ```plain
+//! cargo bench --bench write_coalesce
+//! ```
+//!
+//! The client writes a small request every millisecond, like a chatty request/response protocol.
+//! Its datagrams go through a relay, which counts the data segments in them and the bytes including headers.
+//! KCP still flushes on its own every `nodelay.interval` (10ms in turbo mode), which bounds what coalescing can save.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UdpSocket,
+    time::{self, Instant},
+};
+use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+
+const WRITE_SIZE: usize = 32;
+const KCP_HEADER_LEN: usize = 24;
+/// KCP command of pushing data
+const KCP_CMD_PUSH: u8 = 81;
+const WRITES: usize = 2_000;
+
+#[derive(Default)]
+struct RelayStats {
+    segments: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+/// Data segments packed in a datagram
+fn data_segments(mut buf: &[u8]) -> usize {
+    let mut segments = 0;
+    while buf.len() >= KCP_HEADER_LEN {
+        let len = u32::from_le_bytes([buf[20], buf[21], buf[22], buf[23]]) as usize;
+        if buf[4] == KCP_CMD_PUSH {
+            segments += 1;
+        }
+        buf = &buf[(KCP_HEADER_LEN + len).min(buf.len())..];
+    }
+    segments
+}
+
+/// Forwards datagrams between one client and `server_addr`, counts what client sends
+async fn spawn_relay(server_addr: SocketAddr, stats: Arc<RelayStats>) -> SocketAddr {
+    let client_side = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_side = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    server_side.connect(server_addr).await.unwrap();
+    let relay_addr = client_side.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let mut client_addr = None;
+        let mut client_buf = [0u8; 65536];
+        let mut server_buf = [0u8; 65536];
+        loop {
+            tokio::select! {
+                Ok((n, addr)) = client_side.recv_from(&mut client_buf) => {
+                    client_addr = Some(addr);
+                    stats.segments.fetch_add(data_segments(&client_buf[..n]), Ordering::Relaxed);
+                    stats.bytes.fetch_add(n, Ordering::Relaxed);
+                    let _ = server_side.send(&client_buf[..n]).await;
+                }
+                Ok(n) = server_side.recv(&mut server_buf) => {
+                    if let Some(addr) = client_addr {
+                        let _ = client_side.send_to(&server_buf[..n], addr).await;
+                    }
+                }
+            }
+        }
+    });
+
+    relay_addr
+}
+
+async fn run(write_coalesce: Option<Duration>) -> (usize, usize) {
+    let config = KcpConfig {
+        write_coalesce,
+        ..KcpConfig::turbo()
+    };
+
+    let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+
+    let stats = Arc::new(RelayStats::default());
+    let relay_addr = spawn_relay(server_addr, stats.clone()).await;
+
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; WRITE_SIZE * WRITES];
+        stream.read_exact(&mut buf).await.unwrap();
+    });
+
+    let mut stream = KcpStream::connect(&config, relay_addr).await.unwrap();
+    let mut interval = time::interval(Duration::from_millis(1));
+    for _ in 0..WRITES {
+        interval.tick().await;
+        stream.write_all(&[0u8; WRITE_SIZE]).await.unwrap();
+    }
+    receiver.await.unwrap();
+
+    // ACKs of the last segments
+    time::sleep_until(Instant::now() + Duration::from_millis(100)).await;
+    (
+        stats.segments.load(Ordering::Relaxed),
+        stats.bytes.load(Ordering::Relaxed),
+    )
+}
+
+fn main() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+
+    println!(
+        "{:>16} {:>10} {:>12} {:>12}",
+        "write_coalesce", "writes", "segments", "bytes"
+    );
+    for write_coalesce in [None, Some(Duration::from_millis(5)), Some(Duration::from_millis(20))] {
+        let (segments, bytes) = runtime.block_on(run(write_coalesce));
+        println!(
+            "{:>16} {:>10} {:>12} {:>12}",
+            format!("{:?}", write_coalesce),
+            WRITES,
+            segments,
+            bytes
+        );
+    }
+}
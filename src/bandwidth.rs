@@ -0,0 +1,165 @@
+//! Bandwidth estimation, see `KcpStream::bandwidth_estimate`
+//!
+//! A delivery rate estimator like the one of BBR. Every data segment remembers how many bytes had been delivered
+//! when it was sent, and when. Once it is acknowledged, the bytes delivered since then divided by the time passed
+//! is a sample of the rate that the path delivers at. The time is the longer of sending and acknowledging the
+//! bytes, so a burst of ACKs, like the cumulative ACK after a retransmission, doesn't make the rate look higher.
+//!
+//! Samples are lower than the bandwidth while the sender doesn't fill the path, the estimate is the highest one of
+//! the last `WINDOW_ROUNDS` round trips.
+
+use std::collections::VecDeque;
+
+use tokio::time::Instant;
+
+/// Round trips that the maximum sample is taken of
+const WINDOW_ROUNDS: u64 = 10;
+
+/// State of delivery when a segment was sent
+#[derive(Debug, Clone, Copy)]
+pub struct SendState {
+    /// Bytes delivered
+    delivered: u64,
+    /// Time the last of them was acknowledged
+    delivered_time: Instant,
+    /// Time the segment acknowledged last was sent
+    first_sent_time: Instant,
+    sent_time: Instant,
+}
+
+pub struct BandwidthEstimator {
+    /// Bytes acknowledged by peer
+    delivered: u64,
+    delivered_time: Instant,
+    /// Sending time of the segment acknowledged last
+    first_sent_time: Instant,
+    /// Round trips, a round ends when a segment sent after its start is acknowledged
+    round: u64,
+    /// A segment sent with at least this much delivered ends the round
+    next_round_delivered: u64,
+    /// Samples that may be the maximum of the window, as `(round, bytes per second)`, decreasing rates
+    samples: VecDeque<(u64, u64)>,
+}
+
+impl BandwidthEstimator {
+    pub fn new() -> BandwidthEstimator {
+        let now = Instant::now();
+        BandwidthEstimator {
+            delivered: 0,
+            delivered_time: now,
+            first_sent_time: now,
+            round: 0,
+            next_round_delivered: 0,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// A segment is sent, `idle` if nothing else is in flight
+    pub fn on_sent(&mut self, idle: bool, now: Instant) -> SendState {
+        if idle {
+            // Time without data in flight is not delivering at a low rate
+            self.delivered_time = now;
+            self.first_sent_time = now;
+        }
+        SendState {
+            delivered: self.delivered,
+            delivered_time: self.delivered_time,
+            first_sent_time: self.first_sent_time,
+            sent_time: now,
+        }
+    }
+
+    /// A segment of `len` bytes sent in `state` is acknowledged
+    pub fn on_delivered(&mut self, state: SendState, len: usize, now: Instant) {
+        self.delivered += len as u64;
+        self.delivered_time = now;
+        self.first_sent_time = state.sent_time;
+
+        if state.delivered >= self.next_round_delivered {
+            self.round += 1;
+            self.next_round_delivered = self.delivered;
+        }
+
+        let send_elapsed = state.sent_time.saturating_duration_since(state.first_sent_time);
+        let ack_elapsed = now.saturating_duration_since(state.delivered_time);
+        let interval = send_elapsed.max(ack_elapsed);
+        if interval.is_zero() {
+            return;
+        }
+        let rate = ((self.delivered - state.delivered) as f64 / interval.as_secs_f64()) as u64;
+
+        // Older samples that are not higher can never be the maximum again
+        while matches!(self.samples.back(), Some(&(_, r)) if r <= rate) {
+            self.samples.pop_back();
+        }
+        self.samples.push_back((self.round, rate));
+        while matches!(self.samples.front(), Some(&(round, _)) if round + WINDOW_ROUNDS <= self.round) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Bytes per second, `None` until samples of a whole round trip were taken
+    pub fn estimate(&self) -> Option<u64> {
+        if self.round < 2 {
+            return None;
+        }
+        self.samples.front().map(|&(_, rate)| rate)
+    }
+}
+
+impl Default for BandwidthEstimator {
+    fn default() -> BandwidthEstimator {
+        BandwidthEstimator::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::VecDeque, time::Duration};
+
+    use tokio::time::Instant;
+
+    use super::{BandwidthEstimator, WINDOW_ROUNDS};
+
+    /// Segments of 1000 bytes sent at `rate` from `start` on, each acknowledged `rtt` after it's sent
+    fn transfer(estimator: &mut BandwidthEstimator, start: Instant, rate: u64, rtt: Duration, count: u32) -> Instant {
+        let gap = Duration::from_secs_f64(1000.0 / rate as f64);
+        let mut in_flight = VecDeque::new();
+        let mut sent = 0;
+        let mut now = start;
+        for i in 0..count {
+            now = start + gap * i + rtt;
+            while sent < count && start + gap * sent <= now {
+                in_flight.push_back(estimator.on_sent(sent == 0, start + gap * sent));
+                sent += 1;
+            }
+            estimator.on_delivered(in_flight.pop_front().unwrap(), 1000, now);
+        }
+        now
+    }
+
+    #[test]
+    fn delivery_rate() {
+        let mut estimator = BandwidthEstimator::new();
+        let rtt = Duration::from_millis(50);
+        assert_eq!(estimator.estimate(), None);
+
+        // Less than a round trip of samples
+        let mut now = transfer(&mut estimator, Instant::now(), 1_000_000, rtt, 40);
+        assert_eq!(estimator.estimate(), None);
+
+        now = transfer(&mut estimator, now, 1_000_000, rtt, 1000);
+        let estimate = estimator.estimate().unwrap();
+        assert!((950_000..=1_050_000).contains(&estimate), "estimate {}", estimate);
+
+        // Replaced by samples of a slower path after the window
+        now = transfer(&mut estimator, now, 200_000, rtt, 20);
+        assert!(estimator.estimate().unwrap() >= 950_000);
+        let rounds = estimator.round;
+        while estimator.round < rounds + WINDOW_ROUNDS {
+            now = transfer(&mut estimator, now, 200_000, rtt, 20);
+        }
+        let estimate = estimator.estimate().unwrap();
+        assert!((190_000..=210_000).contains(&estimate), "estimate {}", estimate);
+    }
+}
@@ -0,0 +1,112 @@
+//! Sending the same data to many sessions of a listener, see `KcpListener::broadcast`
+
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::{future, task::noop_waker_ref};
+use tokio::time::{self, Instant};
+
+use crate::{error::KcpError, session::KcpSession};
+
+/// What `KcpListener::broadcast` does with a session whose send window is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum BroadcastPolicy {
+    /// Doesn't send to it, it is reported as `BroadcastOutcome::Skipped`. The default, the others get the data
+    /// immediately and a slow peer doesn't hold the broadcast.
+    #[default]
+    Skip,
+    /// Waits for room in the window up to the given time, sessions that still have none are skipped. Sessions are
+    /// waited for all at once, so the broadcast takes as long as the slowest one.
+    Wait(Duration),
+}
+
+/// What happened to the data of `KcpListener::broadcast` in one session
+#[derive(Debug)]
+pub enum BroadcastOutcome {
+    /// Queued for sending, like a `send` that returned
+    Sent,
+    /// Not sent because the send window was full, see `BroadcastPolicy`
+    Skipped,
+    /// Not sent because the send failed, with the error that `send` of its stream would return. Like
+    /// `ConnectionClosed` if peer closed it, or `Kcp(UserBufTooBig)` if data doesn't fit into one send.
+    Failed(KcpError),
+}
+
+impl BroadcastOutcome {
+    /// Data was queued in the session
+    pub fn is_sent(&self) -> bool {
+        matches!(*self, BroadcastOutcome::Sent)
+    }
+}
+
+/// Result of `KcpListener::broadcast` for one session
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct BroadcastResult {
+    pub conv: u32,
+    pub peer_addr: SocketAddr,
+    pub outcome: BroadcastOutcome,
+}
+
+/// Sends `data` to all of `sessions` with their convs, returns the results ordered by conv, then by peer address
+pub async fn broadcast(
+    sessions: Vec<(u32, Arc<KcpSession>)>,
+    data: &[u8],
+    policy: BroadcastPolicy,
+) -> Vec<BroadcastResult> {
+    let mut results = Vec::with_capacity(sessions.len());
+    let mut waiting = Vec::new();
+
+    // Sessions with room are sent to without waiting, one lock each
+    let mut cx = Context::from_waker(noop_waker_ref());
+    for (conv, session) in sessions {
+        match session.poll_send_whole(&mut cx, data) {
+            Poll::Ready(result) => results.push(broadcast_result(conv, &session, result)),
+            Poll::Pending => match policy {
+                BroadcastPolicy::Skip => results.push(skipped(conv, &session)),
+                BroadcastPolicy::Wait(..) => waiting.push((conv, session)),
+            },
+        }
+    }
+
+    if let BroadcastPolicy::Wait(timeout) = policy {
+        if !waiting.is_empty() {
+            let deadline = Instant::now() + timeout;
+            let sends = waiting.into_iter().map(|(conv, session)| async move {
+                let sent = time::timeout_at(deadline, future::poll_fn(|cx| session.poll_send_whole(cx, data))).await;
+                match sent {
+                    Ok(result) => broadcast_result(conv, &session, result),
+                    Err(..) => skipped(conv, &session),
+                }
+            });
+            results.extend(future::join_all(sends).await);
+        }
+    }
+
+    results.sort_unstable_by_key(|result| (result.conv, result.peer_addr));
+    results
+}
+
+fn broadcast_result(conv: u32, session: &KcpSession, result: Result<(), KcpError>) -> BroadcastResult {
+    BroadcastResult {
+        conv,
+        peer_addr: session.peer_addr(),
+        outcome: match result {
+            Ok(()) => BroadcastOutcome::Sent,
+            Err(err) => BroadcastOutcome::Failed(err),
+        },
+    }
+}
+
+fn skipped(conv: u32, session: &KcpSession) -> BroadcastResult {
+    BroadcastResult {
+        conv,
+        peer_addr: session.peer_addr(),
+        outcome: BroadcastOutcome::Skipped,
+    }
+}
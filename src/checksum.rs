@@ -0,0 +1,78 @@
+//! CRC32 trailer of datagrams, see `KcpConfig::checksum`
+//!
+//! CRC-32 (IEEE 802.3, the one of zlib and Ethernet) of the payload is appended in little endian.
+
+use bytes::{Buf, BufMut};
+
+/// Bytes appended to every datagram
+pub const CHECKSUM_LEN: usize = 4;
+
+/// Lookup table of the reflected polynomial
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 of `buf`
+pub fn crc32(buf: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in buf {
+        crc = TABLE[((crc ^ u32::from(b)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Appends the checksum of the datagram in `buf` to it
+pub fn seal(buf: &mut Vec<u8>) {
+    let checksum = crc32(buf);
+    buf.put_u32_le(checksum);
+}
+
+/// Payload of `buf` without the checksum, `None` if the checksum doesn't match
+pub fn verify(buf: &[u8]) -> Option<&[u8]> {
+    let len = buf.len().checked_sub(CHECKSUM_LEN)?;
+    let (payload, mut checksum) = buf.split_at(len);
+    if checksum.get_u32_le() != crc32(payload) {
+        return None;
+    }
+    Some(payload)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{crc32, seal, verify};
+
+    #[test]
+    fn checksum() {
+        // Check value of CRC-32/ISO-HDLC
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+
+        let mut buf = b"HELLO".to_vec();
+        seal(&mut buf);
+        assert_eq!(verify(&buf), Some(&b"HELLO"[..]));
+
+        // Every flipped bit is caught
+        for i in 0..buf.len() * 8 {
+            let mut corrupted = buf.clone();
+            corrupted[i / 8] ^= 1 << (i % 8);
+            assert_eq!(verify(&corrupted), None);
+        }
+        assert_eq!(verify(&buf[..3]), None);
+    }
+}
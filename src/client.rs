@@ -0,0 +1,533 @@
+//! Client of many sessions on one UDP socket
+//!
+//! `KcpStream::connect` binds a UDP socket for every session. `KcpClient` owns a single socket instead, and hands
+//! over the datagrams it receives to sessions by their conv and source address, like the listener does.
+//!
+//! Without `KcpConfig::handshake`, server allocates a conv for the first packet with conv 0 from an address, and
+//! returns the same one until the client sends a packet with it. So sessions to the same server wait for their convs
+//! one at a time, the next one asks for a conv after the previous one has learnt its own.
+
+use std::{
+    collections::HashMap,
+    io, mem,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use byte_string::ByteStr;
+use log::{debug, error, trace};
+use tokio::{
+    net::ToSocketAddrs,
+    sync::{mpsc, oneshot, Mutex},
+    time::{self, Instant, MissedTickBehavior},
+};
+
+use crate::{
+    config::KcpConfig,
+    error::{KcpError, KcpResult},
+    framing::{Framing, OpenError},
+    handshake::{HandshakeClient, HandshakeFrame, SYN_INITIAL_RTO, SYN_MAX_ATTEMPTS},
+    migration::MigrationFrame,
+    overload::{OverloadMonitor, OVERLOAD_SAMPLE_INTERVAL},
+    pool::BufferPool,
+    session::{KcpSession, KcpSessionManager},
+    skcp::{is_fin_segment, KCP_HEADER_LEN},
+    stream::KcpStream,
+    transport::{self, Endpoint, KcpTransport},
+    utils::{bind_on, random_u64},
+};
+
+/// Session waiting for the conv allocated by server, and the receiver of it
+type Allocation = (Arc<KcpSession>, oneshot::Receiver<KcpResult<u32>>);
+
+/// Requests served by the client task, which owns all sessions
+enum ClientCommand {
+    /// Creates a session of conv 0, which learns its conv from the first packet of server
+    Allocate(SocketAddr, oneshot::Sender<KcpResult<Allocation>>),
+    /// Removes the session of conv 0 that failed to get a conv
+    CancelAllocate(SocketAddr),
+    /// Waits for the SYN-ACK of the token
+    Handshake(SocketAddr, u64, oneshot::Sender<u32>),
+    /// Creates a session of conv negotiated by handshake
+    Open(SocketAddr, u32, oneshot::Sender<KcpResult<Arc<KcpSession>>>),
+    SessionCount(oneshot::Sender<usize>),
+    CorruptedPackets(oneshot::Sender<u64>),
+}
+
+/// Session of conv 0 waiting for server to allocate one
+struct Allocating {
+    conv_tx: oneshot::Sender<KcpResult<u32>>,
+    /// Server was reminded that the previous session has learnt its conv
+    reminded: bool,
+}
+
+/// Client of KCP sessions sharing one UDP socket.
+///
+/// Dropping the client stops connecting. Sessions of streams that are still in use keep running until the streams
+/// are dropped, and the socket is closed after the last session is gone.
+///
+/// Session migration is not supported, `KcpConfig::enable_migration` is rejected.
+pub struct KcpClient {
+    endpoint: Endpoint,
+    handshake: bool,
+    /// Seals frames of handshakes
+    framing: Framing,
+    command_tx: mpsc::Sender<ClientCommand>,
+    /// Held by the session waiting for conv allocated by each server
+    allocating: StdMutex<HashMap<SocketAddr, Arc<Mutex<()>>>>,
+}
+
+impl KcpClient {
+    pub async fn bind<A: ToSocketAddrs>(config: KcpConfig, addr: A) -> KcpResult<KcpClient> {
+        KcpClient::validate(&config)?;
+        let udp = bind_on(addr, &config.socket_options()).await?;
+        Ok(KcpClient::from_endpoint(config, Endpoint::Udp(Arc::new(udp))))
+    }
+
+    /// Creates a client that sends and receives datagrams by `transport`, instead of binding a UDP socket.
+    ///
+    /// Socket options of `config` don't apply, and sessions can't be rebound.
+    pub fn with_transport(config: KcpConfig, transport: Arc<dyn KcpTransport>) -> KcpResult<KcpClient> {
+        KcpClient::validate(&config)?;
+        Ok(KcpClient::from_endpoint(config, Endpoint::Custom(transport)))
+    }
+
+    fn validate(config: &KcpConfig) -> KcpResult<()> {
+        config.validate()?;
+        if config.enable_migration {
+            return Err(KcpError::ConfigInvalid(
+                "session migration is not supported by KcpClient".to_owned(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn from_endpoint(config: KcpConfig, endpoint: Endpoint) -> KcpClient {
+        let client_endpoint = endpoint.clone();
+
+        let buffer_pool = BufferPool::new(&config.buffer_pool_config());
+        let handshake = config.handshake;
+        let framing = Framing::new(&config);
+        let task_framing = framing.clone();
+
+        let (command_tx, mut command_rx) = mpsc::channel(16);
+        // Keeps serving sessions after the client is dropped, until all of them are closed
+        tokio::spawn(async move {
+            let (close_tx, mut close_rx) = mpsc::channel(64);
+            let mut dropped = false;
+
+            let mut sessions = KcpSessionManager::new(buffer_pool.clone(), None, None);
+            // At most one session of conv 0 for each server
+            let mut allocating: HashMap<SocketAddr, Allocating> = HashMap::new();
+            let mut handshakes: HashMap<(SocketAddr, u64), oneshot::Sender<u32>> = HashMap::new();
+            let mut framing_buffer = Vec::new();
+            // Datagrams dropped by `KcpConfig::checksum`
+            let mut corrupted = 0;
+            let mut packet = buffer_pool.get();
+            // Drops of the receive buffer, `None` if the transport doesn't count them
+            let mut overload = OverloadMonitor::new(endpoint.transport());
+            let mut overload_timer = time::interval(OVERLOAD_SAMPLE_INTERVAL);
+            overload_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                packet.clear();
+
+                tokio::select! {
+                    _ = overload_timer.tick(), if overload.is_some() => {
+                        let now = Instant::now();
+                        if let Some(event) = overload.as_mut().and_then(|monitor| monitor.poll(endpoint.transport(), now)) {
+                            debug!("client {:?}, notifying {} sessions", event, sessions.len());
+                            for session in sessions.sessions() {
+                                session.set_receive_overload(event);
+                            }
+                        }
+                    }
+
+                    key = close_rx.recv() => {
+                        let (conv, peer_addr) = key.expect("close_tx closed unexpectly");
+                        sessions.close_conv(conv, peer_addr);
+                        if conv == 0 {
+                            allocating.remove(&peer_addr);
+                        }
+                        trace!("session conv: {} removed, peer: {}", conv, peer_addr);
+                    }
+
+                    command = command_rx.recv(), if !dropped => {
+                        match command {
+                            None => {
+                                debug!("client dropped, waiting for {} sessions to close", sessions.len());
+                                dropped = true;
+                            }
+                            Some(command) => match command {
+                                ClientCommand::Allocate(addr, tx) => {
+                                    if allocating.get(&addr).is_some_and(|a| a.conv_tx.is_closed()) {
+                                        // connect() was cancelled while waiting
+                                        allocating.remove(&addr);
+                                        sessions.abort(0, addr);
+                                    }
+                                    let result = sessions.create_client(&config, 0, &endpoint, addr, &close_tx).map(|session| {
+                                        let (conv_tx, conv_rx) = oneshot::channel();
+                                        allocating.insert(addr, Allocating { conv_tx, reminded: false });
+                                        (session, conv_rx)
+                                    });
+                                    let _ = tx.send(result);
+                                }
+                                ClientCommand::CancelAllocate(addr) => {
+                                    allocating.remove(&addr);
+                                    sessions.abort(0, addr);
+                                }
+                                ClientCommand::Handshake(addr, token, tx) => {
+                                    // Handshakes that timed out or were cancelled
+                                    handshakes.retain(|_, tx| !tx.is_closed());
+                                    handshakes.insert((addr, token), tx);
+                                }
+                                ClientCommand::Open(addr, conv, tx) => {
+                                    let _ = tx.send(sessions.create_client(&config, conv, &endpoint, addr, &close_tx));
+                                }
+                                ClientCommand::SessionCount(tx) => {
+                                    let _ = tx.send(sessions.len());
+                                }
+                                ClientCommand::CorruptedPackets(tx) => {
+                                    let _ = tx.send(corrupted);
+                                }
+                            },
+                        }
+                    }
+
+                    recv_res = transport::recv_buf_from(endpoint.transport(), &mut *packet) => {
+                        match recv_res {
+                            Err(err) => {
+                                error!("udp.recv_from failed, error: {}", err);
+                                time::sleep(Duration::from_secs(1)).await;
+                            }
+                            Ok((n, peer_addr)) => {
+                                trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(&packet));
+
+                                let n = if task_framing.is_plain() {
+                                    n
+                                } else {
+                                    match task_framing.open(&mut packet, &mut framing_buffer) {
+                                        Ok(()) => packet.len(),
+                                        Err(OpenError::Corrupted) => {
+                                            corrupted += 1;
+                                            trace!("checksum mismatch, {} bytes dropped, peer: {}", n, peer_addr);
+                                            continue;
+                                        }
+                                        Err(OpenError::Rejected) => {
+                                            trace!("datagram refused by obfuscator, {} bytes, peer: {}", n, peer_addr);
+                                            continue;
+                                        }
+                                    }
+                                };
+
+                                if let Some(frame) = HandshakeFrame::decode(&packet) {
+                                    if let HandshakeFrame::SynAck { token, conv } = frame {
+                                        // Duplicated SYN-ACKs of completed handshakes are ignored
+                                        if let Some(tx) = handshakes.remove(&(peer_addr, token)) {
+                                            let _ = tx.send(conv);
+                                        }
+                                    }
+                                    continue;
+                                }
+
+                                if let Some(frame) = MigrationFrame::decode(&packet) {
+                                    trace!("ignored migration frame {:?}, peer: {}", frame, peer_addr);
+                                    continue;
+                                }
+
+                                if n < KCP_HEADER_LEN {
+                                    trace!("packet too short, {} bytes, peer: {}", n, peer_addr);
+                                    continue;
+                                }
+
+                                let conv = kcp::get_conv(&packet);
+                                let session = match sessions.get(conv, peer_addr) {
+                                    Some(session) => {
+                                        if conv == 0 && is_fin_segment(&packet) {
+                                            // Refused by server
+                                            if let Some(a) = allocating.remove(&peer_addr) {
+                                                let _ = a.conv_tx.send(Err(KcpError::ConnectionClosed));
+                                            }
+                                        } else if let Some(a) = allocating.get_mut(&peer_addr) {
+                                            if conv != 0 && !a.reminded {
+                                                // Server returned the conv of an existing session, it hasn't
+                                                // received the probe that told it this conv was learnt
+                                                a.reminded = true;
+                                                session.lock_socket().probe_liveness();
+                                            }
+                                        }
+                                        session
+                                    }
+                                    None if conv != 0 && allocating.contains_key(&peer_addr) => {
+                                        let a = allocating.remove(&peer_addr).expect("allocating session");
+                                        let session = match sessions.conv_allocated(peer_addr, conv) {
+                                            Some(s) => s,
+                                            None => continue,
+                                        };
+                                        debug!("conv: {} allocated by server: {}", conv, peer_addr);
+
+                                        // Learns the conv before connect() returns
+                                        let mut socket = session.lock_socket();
+                                        if let Err(err) = socket.input(&packet) {
+                                            error!("UDP input {} bytes error: {}, peer: {}", n, err, peer_addr);
+                                        }
+                                        // Tells server that conv is learnt, or it would allocate the same conv
+                                        // to the next session
+                                        socket.probe_liveness();
+                                        drop(socket);
+
+                                        let _ = a.conv_tx.send(Ok(conv));
+                                        continue;
+                                    }
+                                    None => {
+                                        trace!("packet with unknown conv: {}, peer: {}", conv, peer_addr);
+                                        continue;
+                                    }
+                                };
+
+                                session.input(mem::replace(&mut packet, buffer_pool.get())).await;
+                            }
+                        }
+                    }
+                }
+
+                if dropped && sessions.len() == 0 {
+                    trace!("all sessions closed, client stopped");
+                    break;
+                }
+            }
+        });
+
+        KcpClient {
+            endpoint: client_endpoint,
+            handshake,
+            framing,
+            command_tx,
+            allocating: StdMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens a new session to `addr` on the socket of this client.
+    ///
+    /// Fails with `Timeout` if server didn't respond, or `ConnectionClosed` if it refused.
+    pub async fn connect(&self, addr: SocketAddr) -> KcpResult<KcpStream> {
+        let session = if self.handshake {
+            self.handshake(addr).await?
+        } else {
+            self.allocate(addr).await?
+        };
+        Ok(KcpStream::with_session(session))
+    }
+
+    /// Negotiates conv by handshake, other sessions to the same server may handshake at the same time
+    async fn handshake(&self, addr: SocketAddr) -> KcpResult<Arc<KcpSession>> {
+        let token = random_u64();
+        let (tx, mut rx) = oneshot::channel();
+        self.command_tx
+            .send(ClientCommand::Handshake(addr, token, tx))
+            .await
+            .expect("client task stopped");
+
+        let mut client = HandshakeClient::new(token);
+        while let Some((syn, rto)) = client.next_syn() {
+            transport::send_to(self.endpoint.transport(), &self.framing.seal(&syn.encode()), addr).await?;
+            trace!("[HANDSHAKE] sent SYN to {}, waiting {:?}", addr, rto);
+
+            if let Ok(conv) = time::timeout(rto, &mut rx).await {
+                let conv = conv.expect("client task stopped");
+                let (ack, conv) = client
+                    .on_frame(HandshakeFrame::SynAck { token, conv })
+                    .expect("SYN-ACK of token");
+                transport::send_to(self.endpoint.transport(), &self.framing.seal(&ack.encode()), addr).await?;
+                trace!("[HANDSHAKE] established with {}, conv: {}", addr, conv);
+
+                return self.request(|tx| ClientCommand::Open(addr, conv, tx)).await;
+            }
+        }
+
+        debug!("[HANDSHAKE] no response from {}, timed out", addr);
+        Err(KcpError::Timeout)
+    }
+
+    /// Asks server to allocate a conv by probing with conv 0, after other sessions to the same server got theirs
+    async fn allocate(&self, addr: SocketAddr) -> KcpResult<Arc<KcpSession>> {
+        let lock = self.allocating.lock().unwrap().entry(addr).or_default().clone();
+        let _guard = lock.lock().await;
+
+        let (session, mut conv_rx) = self.request(|tx| ClientCommand::Allocate(addr, tx)).await?;
+
+        // Retransmits like SYN of handshake
+        let mut rto = SYN_INITIAL_RTO;
+        for _ in 0..SYN_MAX_ATTEMPTS {
+            // Server replies the probe with the allocated conv
+            session.lock_socket().probe_liveness();
+            trace!("probed {} for conv, waiting {:?}", addr, rto);
+
+            match time::timeout(rto, &mut conv_rx).await {
+                Ok(Ok(Ok(..))) => return Ok(session),
+                Ok(Ok(Err(err))) => {
+                    debug!("conv allocation refused by {}", addr);
+                    self.cancel_allocate(addr).await;
+                    return Err(err);
+                }
+                Ok(Err(..)) => unreachable!("client task stopped"),
+                Err(..) => rto *= 2,
+            }
+        }
+
+        debug!("no conv allocated by {}, timed out", addr);
+        self.cancel_allocate(addr).await;
+        Err(KcpError::Timeout)
+    }
+
+    async fn cancel_allocate(&self, addr: SocketAddr) {
+        self.command_tx
+            .send(ClientCommand::CancelAllocate(addr))
+            .await
+            .expect("client task stopped");
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.transport().local_addr()
+    }
+
+    /// Number of active sessions, including sessions that are still connecting
+    pub async fn session_count(&self) -> usize {
+        self.request(ClientCommand::SessionCount).await
+    }
+
+    /// Number of datagrams dropped because their CRC32 didn't match, see `KcpConfig::checksum`
+    pub async fn corrupted_packets(&self) -> u64 {
+        self.request(ClientCommand::CorruptedPackets).await
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> ClientCommand) -> T {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx.send(command(tx)).await.expect("client task stopped");
+        rx.await.expect("client task stopped")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::future;
+    use tokio::time::{self, Instant};
+
+    use super::KcpClient;
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig},
+        error::KcpError,
+        listener::KcpListener,
+    };
+
+    const SESSIONS: usize = 8;
+
+    async fn multiplexed_echo(config: KcpConfig) {
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = KcpClient::bind(config, "127.0.0.1:0").await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+
+        let connects = future::try_join_all((0..SESSIONS).map(|_| client.connect(server_addr)));
+        let accepts = async {
+            let mut accepted = Vec::new();
+            while accepted.len() < SESSIONS {
+                let (stream, peer_addr) = listener.accept().await.unwrap();
+                assert_eq!(peer_addr, client_addr);
+                accepted.push(stream);
+            }
+            accepted
+        };
+        let (streams, accepted) = future::join(connects, accepts).await;
+        let mut streams = streams.unwrap();
+
+        // Every session got its own conv on the same peer address
+        let peers = listener.peers().await;
+        assert_eq!(peers.len(), SESSIONS);
+        assert!(peers.iter().all(|(conv, addr)| *conv != 0 && *addr == client_addr));
+        assert_eq!(client.session_count().await, SESSIONS);
+
+        for mut stream in accepted {
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 1024];
+                while let Ok(n) = stream.recv(&mut buffer).await {
+                    if n == 0 || stream.send(&buffer[..n]).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        future::join_all(streams.iter_mut().enumerate().map(|(i, stream)| async move {
+            let message = format!("HELLO {}", i);
+            stream.send(message.as_bytes()).await.unwrap();
+
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..n], message.as_bytes());
+        }))
+        .await;
+
+        // Sessions are removed after their streams are dropped
+        drop(streams);
+        time::timeout(Duration::from_secs(5), async {
+            while client.session_count().await > 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn multiplexed() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        multiplexed_echo(config).await;
+    }
+
+    #[tokio::test]
+    async fn multiplexed_handshake() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            handshake: true,
+            ..Default::default()
+        };
+        multiplexed_echo(config).await;
+    }
+
+    #[tokio::test]
+    async fn connect_refused() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            max_sessions: Some(1),
+            ..Default::default()
+        };
+        let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = KcpClient::bind(config, "127.0.0.1:0").await.unwrap();
+        let _stream = client.connect(server_addr).await.unwrap();
+
+        // Refused by server immediately, instead of timing out
+        let start = Instant::now();
+        match client.connect(server_addr).await {
+            Err(KcpError::ConnectionClosed) => {}
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(..) => panic!("connected beyond max_sessions"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(client.session_count().await, 1);
+    }
+}
@@ -1,9 +1,36 @@
-use std::{io::Write, time::Duration};
+use std::{collections::HashSet, fmt, io::Write, net::SocketAddr, ops::RangeInclusive, sync::Arc, time::Duration};
 
 use kcp::Kcp;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[cfg(feature = "testing")]
+use crate::netem::NetEmConfig;
+use crate::{
+    congestion::CongestionMode,
+    error::{KcpError, KcpResult},
+    framing::{Framing, PacketObfuscator},
+    pacing::PacingConfig,
+    pmtu::PROBE_HEADER_LEN,
+    pool::BufferPoolConfig,
+    utils::{random_u64, SocketOptions},
+};
+
+/// Smallest MTU accepted by `Kcp`
+pub const MIN_MTU: usize = 50;
+
+/// Buffers sized for the MTU cover datagrams of peers with the Ethernet MTU, see `BufferPoolConfig::size`
+const BUFFER_MIN_MTU: usize = 1500;
+
+/// Extra bytes of buffers sized for the MTU, for headers of layers wrapping KCP datagrams
+const BUFFER_SIZE_MARGIN: usize = 256;
+
+/// Convs returned by `KcpConfig::conv_allocator` that are tried for a new session
+pub const CONV_ALLOC_MAX_ATTEMPTS: usize = 16;
 
 /// Kcp Delay Config
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
 pub struct KcpNoDelayConfig {
     /// Enable nodelay
     pub nodelay: bool,
@@ -11,7 +38,8 @@ pub struct KcpNoDelayConfig {
     pub interval: i32,
     /// ACK number to enable fast resend
     pub resend: i32,
-    /// Disable congetion control
+    /// Disable congestion control, like `-nc` of kcptun. Sending is only limited by the send window and the receive
+    /// window of peer, for links with a fixed bandwidth, where the congestion window would collapse after every loss.
     pub nc: bool,
 }
 
@@ -56,10 +84,318 @@ impl KcpNoDelayConfig {
             nc: false,
         }
     }
+
+    /// Minimum retransmission timeout set by KCP in milliseconds
+    pub(crate) fn min_rto(&self) -> u32 {
+        if self.nodelay {
+            30
+        } else {
+            100
+        }
+    }
+
+    /// Update interval, clamped by KCP in the same way
+    pub(crate) fn clamped_interval(&self) -> u32 {
+        self.interval.clamp(10, 5000) as u32
+    }
 }
 
-/// Kcp Config
+/// Path MTU probing of client sessions, see `KcpConfig::pmtu`
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct PmtuConfig {
+    /// Smallest MTU, which is used when the current one stops working. Must work on every path.
+    pub min_mtu: usize,
+    /// Largest MTU probed
+    pub max_mtu: usize,
+    /// Interval of searching for a larger MTU after one was found
+    #[cfg_attr(feature = "serde", serde(with = "duration_ms"))]
+    pub probe_interval: Duration,
+    /// Time to wait for the acknowledgement of a probe before it is considered as lost
+    #[cfg_attr(feature = "serde", serde(with = "duration_ms"))]
+    pub probe_timeout: Duration,
+}
+
+impl Default for PmtuConfig {
+    fn default() -> PmtuConfig {
+        PmtuConfig {
+            min_mtu: 576,
+            max_mtu: 1500,
+            probe_interval: Duration::from_secs(600),
+            probe_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// What the task receiving datagrams of a listener or `KcpClient` does when a session doesn't keep up, see
+/// `KcpConfig::dispatch_mode`
+///
+/// In both modes, every session takes its datagrams from a channel of 64 in its own task, which also runs its
+/// updates. The modes only differ in what the receive task does when that channel is full: it waits for room in
+/// `Shared`, or drops the datagram in `PerSession`. The names tell whether a stalled session holds up the receive
+/// task that all sessions share, or only itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum DispatchMode {
+    /// Waits for room in the channel of the session, backpressure on the receive task.
+    ///
+    /// Nothing is dropped by the receive task, but a session that is slow to process its datagrams, or whose lock
+    /// is held by its stream, stalls dispatching for all other sessions. With `KcpConfig::shared_driver`, sessions
+    /// have no task or channel, and the receive task inputs datagrams to KCP itself, under the lock of the session.
+    #[default]
+    Shared,
+    /// Drops a datagram if the channel of its session is full, never waits.
+    ///
+    /// Peer retransmits what was dropped, so a stalled session only delays itself. `KcpConfig::shared_driver`,
+    /// which has no channels, is rejected.
+    PerSession,
+}
+
+/// When buffered data and ACKs are flushed to the socket besides the updates of KCP, see
+/// `KcpConfig::flush_strategy`
+///
+/// Updates flush every `nodelay.interval`. Flushing sooner lowers latency at the cost of more, smaller datagrams.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum FlushStrategy {
+    /// Data is flushed by the session task right after it is written, ACKs wait for the next update.
+    ///
+    /// Writes that arrive while the task is busy are sent together, ACKs are piggybacked on data when there is some.
+    #[default]
+    Interval,
+    /// Each write is flushed before it returns, by the writing task.
+    ///
+    /// A small write is sent in a datagram of its own, see `KcpConfig::write_coalesce` for merging them instead.
+    OnWrite,
+    /// Each datagram received is answered right away with its ACKs, like `ackNoDelay` of kcp-go. Data waiting for
+    /// the next flush is sent with them.
+    ///
+    /// Peer learns about delivery sooner, which lowers its RTT estimate and latency of retransmissions, at the cost
+    /// of a datagram per input instead of per `nodelay.interval`, which matters to a receiver of bulk transfers that
+    /// has nothing to piggyback the ACKs on. Counted by `KcpStream::ack_only_datagrams`.
+    OnAck,
+    /// `OnWrite` and `OnAck`
+    Aggressive,
+}
+
+impl FlushStrategy {
+    fn from_flags(on_write: bool, on_ack: bool) -> FlushStrategy {
+        match (on_write, on_ack) {
+            (false, false) => FlushStrategy::Interval,
+            (true, false) => FlushStrategy::OnWrite,
+            (false, true) => FlushStrategy::OnAck,
+            (true, true) => FlushStrategy::Aggressive,
+        }
+    }
+
+    /// Writes are flushed before they return
+    pub fn flushes_write(self) -> bool {
+        matches!(self, FlushStrategy::OnWrite | FlushStrategy::Aggressive)
+    }
+
+    /// ACKs are flushed after each input
+    pub fn flushes_ack(self) -> bool {
+        matches!(self, FlushStrategy::OnAck | FlushStrategy::Aggressive)
+    }
+
+    /// `self` with ACKs flushed after each input or not
+    pub(crate) fn with_ack(self, on_ack: bool) -> FlushStrategy {
+        FlushStrategy::from_flags(self.flushes_write(), on_ack)
+    }
+}
+
+/// Retries of `KcpStream::connect_retry`
 #[derive(Debug, Clone, Copy)]
+pub struct RetryConfig {
+    /// Maximum number of attempts, including the first one
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled after every failed attempt
+    pub initial_backoff: Duration,
+    /// Upper bound of the delay between attempts
+    pub max_backoff: Duration,
+    /// Delays are randomized by up to this fraction of them in both directions, in `[0, 1]`, so that clients
+    /// failed together don't retry together
+    pub jitter: f64,
+    /// Time for an attempt to get a response from server before it is considered as timed out
+    pub attempt_timeout: Duration,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            jitter: 0.2,
+            attempt_timeout: Duration::from_secs(3),
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Checks that the config could be applied, called by `KcpStream::connect_retry`
+    pub fn validate(&self) -> KcpResult<()> {
+        if self.max_attempts == 0 {
+            return Err(KcpError::ConfigInvalid("max_attempts must be positive".to_owned()));
+        }
+        if self.initial_backoff > self.max_backoff {
+            return Err(KcpError::ConfigInvalid(
+                "initial_backoff is larger than max_backoff".to_owned(),
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(KcpError::ConfigInvalid("jitter must be in [0, 1]".to_owned()));
+        }
+        if self.attempt_timeout.is_zero() {
+            return Err(KcpError::ConfigInvalid("attempt_timeout must be positive".to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Randomized delay of `backoff`
+    pub(crate) fn jittered(&self, backoff: Duration) -> Duration {
+        // In [-1, 1)
+        let r = (random_u64() >> 11) as f64 / (1u64 << 52) as f64 - 1.0;
+        backoff.mul_f64(1.0 + self.jitter * r)
+    }
+}
+
+/// Allocates conv of new sessions of a listener, see `KcpConfig::conv_allocator`
+///
+/// ```
+/// # use std::net::SocketAddr;
+/// # use tokio_kcp::ConvAllocator;
+/// /// Odd convs on this node, even ones on the other
+/// struct OddConvs {
+///     next: u32,
+/// }
+///
+/// impl ConvAllocator for OddConvs {
+///     fn allocate(&mut self, _peer: SocketAddr) -> Option<u32> {
+///         let conv = self.next;
+///         self.next = self.next.wrapping_add(2);
+///         Some(conv)
+///     }
+///
+///     fn release(&mut self, _conv: u32) {}
+/// }
+/// ```
+pub trait ConvAllocator: Send {
+    /// conv of a new session of `peer`, `None` if there is none left, which refuses the session.
+    ///
+    /// The listener skips conv 0 and convs used by active sessions, and releases them after it got a usable one.
+    fn allocate(&mut self, peer: SocketAddr) -> Option<u32>;
+
+    /// `conv` returned by `allocate` isn't used anymore, it may be allocated again
+    fn release(&mut self, conv: u32);
+}
+
+/// Creates a `ConvAllocator` for every listener bound with `KcpConfig::conv_allocator`
+#[derive(Clone)]
+pub struct ConvAllocatorFactory(Arc<dyn Fn() -> Box<dyn ConvAllocator> + Send + Sync>);
+
+impl ConvAllocatorFactory {
+    pub fn new<F>(f: F) -> ConvAllocatorFactory
+    where
+        F: Fn() -> Box<dyn ConvAllocator> + Send + Sync + 'static,
+    {
+        ConvAllocatorFactory(Arc::new(f))
+    }
+
+    pub(crate) fn create(&self) -> Box<dyn ConvAllocator> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for ConvAllocatorFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ConvAllocatorFactory")
+    }
+}
+
+/// Allocates convs in order, wraps around and skips the ones it allocated that are still in use.
+///
+/// The default allocator counts up from 1. `with_range` limits it to a range, like a partition of the conv space
+/// for a node of a cluster.
+#[derive(Debug)]
+pub struct SequentialConvAllocator {
+    range: RangeInclusive<u32>,
+    /// Allocated last time
+    last: Option<u32>,
+    used: HashSet<u32>,
+}
+
+impl SequentialConvAllocator {
+    pub fn new() -> SequentialConvAllocator {
+        SequentialConvAllocator::with_range(1..=u32::MAX)
+    }
+
+    /// Allocates convs in `range` only, conv 0 is skipped if the range contains it
+    pub fn with_range(range: RangeInclusive<u32>) -> SequentialConvAllocator {
+        SequentialConvAllocator {
+            range,
+            last: None,
+            used: HashSet::new(),
+        }
+    }
+
+    /// Number of convs in the range, up to 2^32
+    fn capacity(&self) -> u64 {
+        if self.range.is_empty() {
+            return 0;
+        }
+        u64::from(*self.range.end()) - u64::from(*self.range.start()) + 1
+    }
+}
+
+impl Default for SequentialConvAllocator {
+    fn default() -> SequentialConvAllocator {
+        SequentialConvAllocator::new()
+    }
+}
+
+impl ConvAllocator for SequentialConvAllocator {
+    fn allocate(&mut self, _peer: SocketAddr) -> Option<u32> {
+        // conv 0 is never returned
+        let reserved = u64::from(self.range.contains(&0));
+        if self.used.len() as u64 + reserved >= self.capacity() {
+            return None;
+        }
+
+        let (start, end) = (*self.range.start(), *self.range.end());
+        let mut conv = self.last.unwrap_or(end);
+        loop {
+            conv = if conv >= end { start } else { conv + 1 };
+            if conv != 0 && self.used.insert(conv) {
+                self.last = Some(conv);
+                return Some(conv);
+            }
+        }
+    }
+
+    fn release(&mut self, conv: u32) {
+        self.used.remove(&conv);
+    }
+}
+
+/// Kcp Config
+///
+/// With the `serde` feature, configs could be loaded from files. Fields are named as in the struct, missing ones
+/// take their defaults, and a deserialized config is checked by `validate`. Durations are integer milliseconds, or
+/// strings with a unit like `"90s"`, `"500ms"`, `"5m"` or `"1h"`. `obfuscator` and `conv_allocator` are skipped,
+/// and `CongestionMode::Custom` fails to serialize, they are only set in code.
+///
+/// ```
+/// # #[cfg(feature = "serde")]
+/// # {
+/// # use tokio_kcp::KcpConfig;
+/// let config: KcpConfig = serde_json::from_str(r#"{ "mtu": 1200, "session_expire": "30s", "linger": 500 }"#).unwrap();
+/// assert_eq!(config.mtu, 1200);
+/// assert_eq!(config.session_expire.as_secs(), 30);
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(remote = "Self", default))]
 pub struct KcpConfig {
     /// Max Transmission Unit
     pub mtu: usize,
@@ -67,43 +403,662 @@ pub struct KcpConfig {
     pub nodelay: KcpNoDelayConfig,
     /// Send window size
     pub wnd_size: (u16, u16),
+    /// Minimum retransmission timeout in milliseconds, at least 1.
+    ///
+    /// `None` for the default of KCP, 30 ms with `nodelay.nodelay` and 100 ms otherwise, which is conservative for
+    /// LANs. The current timeout is reported by `KcpStream::congestion_stats`.
+    pub rx_minrto: Option<u32>,
+    /// Retransmits a segment when this many ACKs of later segments arrived, without waiting for its retransmission
+    /// timeout. 0 disables fast resend.
+    ///
+    /// `None` for `nodelay.resend`. Can be changed by `KcpStream::set_fast_resend`, segments retransmitted by it are
+    /// counted by `KcpStream::fast_retransmissions`.
+    pub fast_resend: Option<u32>,
     /// Session expire duration, default is 90 seconds
+    #[cfg_attr(feature = "serde", serde(with = "duration_ms"))]
     pub session_expire: Duration,
-    /// Flush KCP state immediately after write
+    /// Closes the session if no application data is sent or received for this duration, on both client and server.
+    ///
+    /// Unlike `session_expire`, keepalives and ACKs don't keep the session alive, only `send` and `recv` of data
+    /// do. Calls on the stream fail with `KcpError::IdleTimeout` after that, and peer is notified with FIN. Servers
+    /// could reclaim idle connections with a short one. `None` to disable, which is the default.
+    #[cfg_attr(feature = "serde", serde(with = "option_duration_ms"))]
+    pub idle_timeout: Option<Duration>,
+    /// When data and ACKs are flushed besides the updates of KCP, `FlushStrategy::Interval` by default.
+    ///
+    /// Flushing of ACKs can be changed at runtime by `KcpStream::set_flush_acks_input`.
+    pub flush_strategy: FlushStrategy,
+    /// Adds `FlushStrategy::OnWrite` to `flush_strategy`: `Interval` becomes `OnWrite`, `OnAck` becomes
+    /// `Aggressive`
+    #[deprecated(note = "use `flush_strategy: FlushStrategy::OnWrite`")]
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
     pub flush_write: bool,
-    /// Flush ACKs immediately after input
+    /// Adds `FlushStrategy::OnAck` to `flush_strategy`: `Interval` becomes `OnAck`, `OnWrite` becomes `Aggressive`
+    #[deprecated(note = "use `flush_strategy: FlushStrategy::OnAck`")]
+    #[cfg_attr(feature = "serde", serde(skip_serializing))]
     pub flush_acks_input: bool,
     /// Stream mode
+    ///
+    /// Data is a byte stream, like TCP, which is what codecs like `tokio_util::codec::LengthDelimitedCodec` expect.
+    /// In message mode, every `send` is received as one message by `recv`, and fails if it takes more than 127 segments.
+    /// `AsyncWrite` splits larger writes into several messages in both modes.
     pub stream: bool,
+    /// Maximum times of retransmitting a segment before the link is considered as dead,
+    /// `send` and `recv` will fail with `KcpError::Timeout` (`TimedOut`) after that.
+    ///
+    /// `None` for retransmitting forever, a stream to a crashed peer stalls silently. Default is `Some(20)`.
+    /// Retransmitted segments are counted by `KcpStream::retransmissions`.
+    pub dead_link: Option<u32>,
+    /// Negotiate conv with an explicit handshake before sending any KCP segments,
+    /// instead of letting the server allocate it from the first segment (conv 0).
+    ///
+    /// Both client and server must agree on this option. Default is `false`.
+    pub handshake: bool,
+    /// Append CRC32 of every datagram to it, and drop received datagrams whose CRC32 doesn't match, for links
+    /// that corrupt payloads without UDP noticing, like broken checksum offloading.
+    ///
+    /// Both client and server must agree on this option. The 4 bytes are part of `mtu`, the MSS of KCP is 4 bytes
+    /// smaller. Corrupted datagrams never reach KCP or open sessions, they are counted by
+    /// `KcpListener::corrupted_packets` and `KcpStream::corrupted_packets`. Default is `false`.
+    pub checksum: bool,
+    /// Transforms every datagram sent and received by listeners, streams and `KcpClient`, see `PacketObfuscator`.
+    ///
+    /// Datagrams are sealed after the CRC32 of `checksum` is appended, and opened before anything is parsed from
+    /// them, datagrams that it refuses to open are dropped, or go to `KcpListener::raw_datagrams`.
+    /// `PacketObfuscator::overhead` is part of `mtu`, the MSS of KCP is smaller by it. Both client and server must
+    /// use the same obfuscation. Default is `None`.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub obfuscator: Option<Arc<dyn PacketObfuscator>>,
+    /// Keep sessions alive when client's address changes, for example, switching from WiFi to cellular.
+    ///
+    /// Server issues a signed resumption token for every session, client proves ownership of the session
+    /// with it when its packets arrive from a new address. Both client and server must enable it. Default is `false`.
+    ///
+    /// Sessions are identified by conv and peer address. With migration, packets with the conv of an existing
+    /// session from another address are challenged instead of opening a new session.
+    pub enable_migration: bool,
+    /// Replace the UDP socket of a client with a new one when sends keep failing, like after suspending or switching
+    /// networks, instead of failing forever.
+    ///
+    /// The session keeps its conv and all unacknowledged data, server sees it migrating to a new address, so
+    /// `enable_migration` is required on both client and server. Rebinds are reported by `KcpEvent::Rebound` and
+    /// counted by `KcpStream::rebinds`. Only affects `KcpStream::connect`, sessions of `KcpClient` share one socket.
+    /// Default is `false`.
+    pub auto_rebind: bool,
+    /// Binds UDP sockets to a network interface with `SO_BINDTODEVICE`, so that traffic only egresses (and is only
+    /// received from) it, for policy routing and VPNs.
+    ///
+    /// Applies to sockets of `KcpListener`, `KcpClient` and `KcpStream::connect`, before they send anything.
+    /// Only supported on Linux and Android, binding fails with `Unsupported` on other platforms. Linux before 5.7
+    /// requires `CAP_NET_RAW`. Default is `None`.
+    pub bind_device: Option<String>,
+    /// TTL of outgoing datagrams, or their hop limit on IPv6, from 1 to 255.
+    ///
+    /// Applies to the same sockets as `bind_device`. `None` keeps the default of the OS, which is the default.
+    pub ttl: Option<u32>,
+    /// ToS byte of outgoing datagrams (`IP_TOS`), or the traffic class on IPv6 (`IPV6_TCLASS`), from 0 to 255.
+    /// DSCP is the upper 6 bits, like `0xb8` for EF (expedited forwarding).
+    ///
+    /// Applies to the same sockets as `bind_device`. Only supported on Linux, Android, macOS, iOS and FreeBSD,
+    /// binding fails with `Unsupported` on other platforms, or with the error of the OS if it rejects the value.
+    /// `None` keeps the default of the OS, which is the default.
+    pub tos: Option<u32>,
+    /// Drive all sessions of a listener in one task, instead of spawning a task with its own timer for every session.
+    ///
+    /// Reduces memory and timer overhead for servers with a large number of (mostly idle) sessions.
+    /// Only affects `KcpListener`. Default is `false`.
+    pub shared_driver: bool,
+    /// Whether the task receiving datagrams waits for a session whose channel is full or drops its datagrams,
+    /// trades losing datagrams of a stalled session for isolating the other sessions from it, see `DispatchMode`.
+    ///
+    /// Only affects `KcpListener` and `KcpClient`. Default is `DispatchMode::Shared`.
+    pub dispatch_mode: DispatchMode,
+    /// Spread outgoing datagrams over time instead of sending a whole flush back to back.
+    ///
+    /// Reduces self-inflicted loss on paths with shallow (or bloated) buffers. `None` for no pacing, which is the default.
+    /// With `PacingConfig::adaptive`, datagrams are paced at the rate estimated for the path instead of a fixed one.
+    pub pacing: Option<PacingConfig>,
+    /// Congestion control of sessions, on both client and server.
+    ///
+    /// `CongestionMode::Default` keeps KCP's congestion window unless `nodelay.nc` disables it, `Off` disables it,
+    /// and `Custom` replaces it with a `CongestionController`. The effective window is reported by
+    /// `KcpStream::congestion_stats`.
+    pub congestion: CongestionMode,
+    /// Pool of buffers for received datagrams in listener and datagrams waiting to be sent.
+    ///
+    /// Pool hits and misses of a listener are reported by `KcpListener::buffer_pool_stats`.
+    pub buffer_pool: BufferPoolConfig,
+    /// Maximum number of sessions of a listener, including sessions that haven't been accepted yet.
+    ///
+    /// Packets opening new sessions are refused when it is reached, clients are notified to close if they
+    /// haven't got a conv (without `handshake`). Refused packets are counted by `KcpListener::refused_sessions`.
+    /// `None` for unlimited, which is the default. Only affects `KcpListener`.
+    pub max_sessions: Option<usize>,
+    /// Allocates conv of new sessions instead of counting up from 1, for reproducible tests or for partitioning
+    /// the conv space across a cluster of servers. Every listener creates its own allocator from the factory.
+    ///
+    /// conv 0 and convs used by active sessions are skipped, allocation fails after `CONV_ALLOC_MAX_ATTEMPTS`
+    /// of them in a row. A failed allocation refuses the session like `max_sessions`, and is counted by
+    /// `KcpListener::refused_sessions`. Convs are released when their sessions are removed. `None` for
+    /// `SequentialConvAllocator::new`, which is the default. Only affects `KcpListener`.
+    ///
+    /// ```
+    /// # use tokio_kcp::{ConvAllocator, ConvAllocatorFactory, KcpConfig, SequentialConvAllocator};
+    /// // Node 3 of a cluster owns convs from 0x0300_0000 to 0x03ff_ffff
+    /// let config = KcpConfig {
+    ///     conv_allocator: Some(ConvAllocatorFactory::new(|| {
+    ///         Box::new(SequentialConvAllocator::with_range(0x0300_0000..=0x03ff_ffff)) as Box<dyn ConvAllocator>
+    ///     })),
+    ///     ..Default::default()
+    /// };
+    /// ```
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub conv_allocator: Option<ConvAllocatorFactory>,
+    /// Delays flushing small writes for up to this duration, so that successive writes are sent in fewer segments,
+    /// like Nagle's algorithm.
+    ///
+    /// Data is flushed immediately once it fills a segment, or by `flush`. KCP also flushes every `nodelay.interval`,
+    /// which bounds the delay too. Only works in stream mode, messages are never merged. `None` for flushing every
+    /// write immediately, which is the default.
+    #[cfg_attr(feature = "serde", serde(with = "option_duration_ms"))]
+    pub write_coalesce: Option<Duration>,
+    /// Maximum time of delivering sent data after a stream is dropped, like `SO_LINGER`.
+    ///
+    /// A dropped stream closes its session with FIN after all data sent has been acknowledged by peer. With `linger`,
+    /// it is closed after this duration even if some data is still unacknowledged, `Some(Duration::ZERO)` closes it
+    /// immediately. `None` for waiting until all data is acknowledged or the link is dead, which is the default.
+    #[cfg_attr(feature = "serde", serde(with = "option_duration_ms"))]
+    pub linger: Option<Duration>,
+    /// Probes the path MTU of client sessions and changes their MTU within the bounds of `PmtuConfig`, `mtu` is the
+    /// one to start with.
+    ///
+    /// Datagrams are sent with DF (don't fragment) set on Linux, Android, macOS, iOS and FreeBSD, probes are not
+    /// reliable on other platforms because they may be fragmented. Sizes are probed with padded frames acknowledged
+    /// by server, which it does for all sessions whatever its config is. Only affects `KcpStream::connect`.
+    /// `None` for a fixed MTU, which is the default.
+    ///
+    /// MTU changes apply to new segments, segments in flight keep their size. When the MTU falls back after a route
+    /// change, segments in flight that are too large for the new route are only delivered if the route recovers.
+    pub pmtu: Option<PmtuConfig>,
+    /// Halves the receive window of sessions while the receive buffer of their socket overflows, see
+    /// `KcpEvent::ReceiveOverload`, and restores it when the overload is cleared.
+    ///
+    /// Peers send less while the smaller window is advertised, which leaves room to the sessions that are read.
+    /// KCP doesn't go below its minimum receive window of 128 segments, so it only helps with larger `wnd_size`.
+    /// Disabled by default, overload is only reported.
+    pub adaptive_window: bool,
+    /// Drops, duplicates, delays and reorders datagrams sent and received by sessions, for testing retransmissions.
+    ///
+    /// Strictly for testing, it has a performance cost. `None` for a normal network, which is the default.
+    #[cfg(feature = "testing")]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub test_netem: Option<NetEmConfig>,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for KcpConfig {
+    #[allow(deprecated)]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if self.flush_write || self.flush_acks_input {
+            // Written as the strategy they add up to
+            let config = KcpConfig {
+                flush_strategy: self.effective_flush_strategy(),
+                flush_write: false,
+                flush_acks_input: false,
+                ..self.clone()
+            };
+            return KcpConfig::serialize(&config, serializer);
+        }
+        // Derived with `remote = "Self"`
+        KcpConfig::serialize(self, serializer)
+    }
+}
+
+/// Fails with the error of `KcpConfig::validate` if the config is invalid
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for KcpConfig {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<KcpConfig, D::Error> {
+        let config = KcpConfig::deserialize(deserializer)?;
+        config.validate().map_err(serde::de::Error::custom)?;
+        Ok(config)
+    }
 }
 
+// Deprecated fields still have their defaults
+#[allow(deprecated)]
 impl Default for KcpConfig {
     fn default() -> KcpConfig {
         KcpConfig {
             mtu: 1400,
             nodelay: KcpNoDelayConfig::normal(),
             wnd_size: (256, 256),
+            rx_minrto: None,
+            fast_resend: None,
             session_expire: Duration::from_secs(90),
+            idle_timeout: None,
+            flush_strategy: FlushStrategy::Interval,
             flush_write: false,
             flush_acks_input: false,
             stream: true,
+            dead_link: Some(20),
+            handshake: false,
+            checksum: false,
+            obfuscator: None,
+            enable_migration: false,
+            auto_rebind: false,
+            bind_device: None,
+            ttl: None,
+            tos: None,
+            shared_driver: false,
+            dispatch_mode: DispatchMode::Shared,
+            pacing: None,
+            congestion: CongestionMode::Default,
+            buffer_pool: BufferPoolConfig::default(),
+            max_sessions: None,
+            conv_allocator: None,
+            write_coalesce: None,
+            linger: None,
+            pmtu: None,
+            adaptive_window: false,
+            #[cfg(feature = "testing")]
+            test_netem: None,
         }
     }
 }
 
 impl KcpConfig {
+    /// `flush_strategy` with the deprecated `flush_write` and `flush_acks_input` added
+    #[allow(deprecated)]
+    pub(crate) fn effective_flush_strategy(&self) -> FlushStrategy {
+        FlushStrategy::from_flags(
+            self.flush_strategy.flushes_write() || self.flush_write,
+            self.flush_strategy.flushes_ack() || self.flush_acks_input,
+        )
+    }
+
+    /// `buffer_pool` with the size of buffers resolved, see `BufferPoolConfig::size`
+    pub(crate) fn buffer_pool_config(&self) -> BufferPoolConfig {
+        let mut buffer_pool = self.buffer_pool;
+        if buffer_pool.size == 0 {
+            let max_mtu = self.pmtu.map_or(0, |pmtu| pmtu.max_mtu);
+            buffer_pool.size = self.mtu.max(max_mtu).max(BUFFER_MIN_MTU) + BUFFER_SIZE_MARGIN;
+        }
+        buffer_pool
+    }
+
+    /// Options of UDP sockets bound for sessions
+    pub(crate) fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            bind_device: self.bind_device.clone(),
+            ttl: self.ttl,
+            tos: self.tos,
+        }
+    }
+
+    /// Preset of kcptun's `normal` mode
+    ///
+    /// 1. Disable NoDelay
+    /// 2. Set ticking interval to be 40ms
+    /// 3. Set fast resend to be 2
+    /// 4. Disable congestion control
+    /// 5. Send window 128, receive window 512
+    ///
+    /// Lowest CPU usage and fewest retransmissions of the presets, at the cost of higher latency on lossy links.
+    pub fn normal() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nodelay: false,
+                interval: 40,
+                resend: 2,
+                nc: true,
+            },
+            wnd_size: (128, 512),
+            ..Default::default()
+        }
+    }
+
+    /// Preset of kcptun's `fast` mode
+    ///
+    /// 1. Disable NoDelay
+    /// 2. Set ticking interval to be 30ms
+    /// 3. Set fast resend to be 2
+    /// 4. Disable congestion control
+    /// 5. Send window 256, receive window 1024
+    ///
+    /// Balances CPU usage and latency, suitable for most interactive traffic.
+    pub fn fast() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nodelay: false,
+                interval: 30,
+                resend: 2,
+                nc: true,
+            },
+            wnd_size: (256, 1024),
+            ..Default::default()
+        }
+    }
+
+    /// Preset of kcptun's `fast3` mode
+    ///
+    /// 1. Enable NoDelay
+    /// 2. Set ticking interval to be 10ms
+    /// 3. Set fast resend to be 2
+    /// 4. Disable congestion control
+    /// 5. Send window 512, receive window 1024
+    ///
+    /// Lowest latency, at the cost of more CPU (ticking every 10ms) and more bandwidth spent on aggressive
+    /// retransmissions. Only use it on links that you are entitled to saturate.
+    pub fn turbo() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            wnd_size: (512, 1024),
+            ..Default::default()
+        }
+    }
+
+    /// Checks that the config could be applied, called by `KcpListener::bind` and `KcpStream::connect`
+    pub fn validate(&self) -> KcpResult<()> {
+        if self.mtu < MIN_MTU + self.datagram_overhead() {
+            return Err(KcpError::ConfigInvalid(format!(
+                "mtu {} is smaller than {}",
+                self.mtu,
+                MIN_MTU + self.datagram_overhead()
+            )));
+        }
+        if self.session_expire.is_zero() {
+            return Err(KcpError::ConfigInvalid("session_expire must be positive".to_owned()));
+        }
+        if self.rx_minrto == Some(0) {
+            return Err(KcpError::ConfigInvalid("rx_minrto must be at least 1 ms".to_owned()));
+        }
+        if self.idle_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(KcpError::ConfigInvalid("idle_timeout must be positive".to_owned()));
+        }
+        if self.bind_device.as_ref().is_some_and(|device| device.is_empty()) {
+            return Err(KcpError::ConfigInvalid("bind_device must not be empty".to_owned()));
+        }
+        if self.ttl.is_some_and(|ttl| ttl == 0 || ttl > 255) {
+            return Err(KcpError::ConfigInvalid("ttl must be from 1 to 255".to_owned()));
+        }
+        if self.tos.is_some_and(|tos| tos > 255) {
+            return Err(KcpError::ConfigInvalid("tos must be from 0 to 255".to_owned()));
+        }
+        if self.shared_driver && self.dispatch_mode == DispatchMode::PerSession {
+            return Err(KcpError::ConfigInvalid(
+                "shared_driver requires DispatchMode::Shared".to_owned(),
+            ));
+        }
+        if self.auto_rebind && !self.enable_migration {
+            return Err(KcpError::ConfigInvalid(
+                "auto_rebind requires enable_migration".to_owned(),
+            ));
+        }
+        if let Some(ref pacing) = self.pacing {
+            if pacing.rate == 0 || pacing.packet_rate == Some(0) {
+                return Err(KcpError::ConfigInvalid("pacing rate must be positive".to_owned()));
+            }
+        }
+        #[cfg(feature = "testing")]
+        if let Some(ref netem) = self.test_netem {
+            let rates = [netem.loss_rate, netem.dup_rate, netem.reorder_rate];
+            if rates.iter().any(|rate| !(0.0..=1.0).contains(rate)) {
+                return Err(KcpError::ConfigInvalid("netem rates must be in [0, 1]".to_owned()));
+            }
+        }
+        if self.buffer_pool.size != 0 && self.buffer_pool.size < self.mtu {
+            return Err(KcpError::ConfigInvalid(format!(
+                "buffer size {} is smaller than mtu {}",
+                self.buffer_pool.size, self.mtu
+            )));
+        }
+        if let Some(ref pmtu) = self.pmtu {
+            if pmtu.min_mtu < MIN_MTU.max(PROBE_HEADER_LEN)
+                || pmtu.min_mtu > pmtu.max_mtu
+                || pmtu.max_mtu > u16::MAX as usize
+            {
+                return Err(KcpError::ConfigInvalid(format!(
+                    "pmtu bounds [{}, {}] are invalid",
+                    pmtu.min_mtu, pmtu.max_mtu
+                )));
+            }
+            if !(pmtu.min_mtu..=pmtu.max_mtu).contains(&self.mtu) {
+                return Err(KcpError::ConfigInvalid(format!(
+                    "mtu {} is out of pmtu bounds [{}, {}]",
+                    self.mtu, pmtu.min_mtu, pmtu.max_mtu
+                )));
+            }
+            if self.buffer_pool.size != 0 && self.buffer_pool.size < pmtu.max_mtu {
+                return Err(KcpError::ConfigInvalid(format!(
+                    "buffer size {} is smaller than pmtu max_mtu {}",
+                    self.buffer_pool.size, pmtu.max_mtu
+                )));
+            }
+            if pmtu.probe_timeout.is_zero() {
+                return Err(KcpError::ConfigInvalid(
+                    "pmtu probe_timeout must be positive".to_owned(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Minimum retransmission timeout in milliseconds, set by `nodelay` unless `rx_minrto` overrides it
+    pub(crate) fn min_rto(&self) -> u32 {
+        self.rx_minrto.unwrap_or(self.nodelay.min_rto())
+    }
+
+    /// KCP's congestion window is enabled
+    pub(crate) fn kcp_window(&self) -> bool {
+        matches!(self.congestion, CongestionMode::Default) && !self.nodelay.nc
+    }
+
+    /// Bytes of datagrams that are not KCP, which `mtu` includes
+    pub(crate) fn datagram_overhead(&self) -> usize {
+        Framing::new(self).overhead()
+    }
+
     /// Applies config onto `Kcp`
     #[doc(hidden)]
     pub fn apply_config<W: Write>(&self, k: &mut Kcp<W>) {
-        k.set_mtu(self.mtu).expect("invalid MTU");
+        k.set_mtu(self.mtu - self.datagram_overhead())
+            .expect("invalid MTU, check it with KcpConfig::validate");
 
         k.set_nodelay(
             self.nodelay.nodelay,
             self.nodelay.interval,
             self.nodelay.resend,
-            self.nodelay.nc,
+            !self.kcp_window(),
         );
+        // Overwritten by `set_nodelay`
+        if let Some(rto) = self.rx_minrto {
+            k.set_rx_minrto(rto);
+        }
+        if let Some(resend) = self.fast_resend {
+            k.set_fast_resend(resend);
+        }
 
         k.set_wndsize(self.wnd_size.0, self.wnd_size.1);
+
+        k.set_maximum_resend_times(self.dead_link.unwrap_or(u32::MAX));
+    }
+}
+
+/// Durations of configs, serialized as integer milliseconds, deserialized from them or from strings with a unit
+#[cfg(feature = "serde")]
+mod duration_ms {
+    use std::{convert::TryFrom, fmt, time::Duration};
+
+    use serde::{
+        de::{self, Unexpected, Visitor},
+        Deserializer, Serializer,
+    };
+
+    pub fn serialize<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(u64::try_from(duration.as_millis()).unwrap_or(u64::MAX))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        deserializer.deserialize_any(DurationVisitor)
+    }
+
+    struct DurationVisitor;
+
+    impl Visitor<'_> for DurationVisitor {
+        type Value = Duration;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+            formatter.write_str("milliseconds, or a duration like \"90s\", \"500ms\", \"5m\" or \"1h\"")
+        }
+
+        fn visit_u64<E: de::Error>(self, millis: u64) -> Result<Duration, E> {
+            Ok(Duration::from_millis(millis))
+        }
+
+        fn visit_i64<E: de::Error>(self, millis: i64) -> Result<Duration, E> {
+            u64::try_from(millis)
+                .map(Duration::from_millis)
+                .map_err(|_| E::invalid_value(Unexpected::Signed(millis), &self))
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Duration, E> {
+            parse(s).ok_or_else(|| E::invalid_value(Unexpected::Str(s), &self))
+        }
+    }
+
+    /// Parses an integer with an optional unit, milliseconds without one
+    fn parse(s: &str) -> Option<Duration> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let value: u64 = s[..split].parse().ok()?;
+        let millis = match s[split..].trim_start() {
+            "" | "ms" => value,
+            "s" => value.checked_mul(1000)?,
+            "m" | "min" => value.checked_mul(60 * 1000)?,
+            "h" => value.checked_mul(60 * 60 * 1000)?,
+            _ => return None,
+        };
+        Some(Duration::from_millis(millis))
+    }
+}
+
+/// Optional durations of configs, see `duration_ms`
+#[cfg(feature = "serde")]
+mod option_duration_ms {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Millis(#[serde(with = "super::duration_ms")] Duration);
+
+    pub fn serialize<S: Serializer>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        duration.map(Millis).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<Millis>::deserialize(deserializer)?.map(|Millis(duration)| duration))
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod test {
+    use std::time::Duration;
+
+    use super::{DispatchMode, FlushStrategy, KcpConfig, PmtuConfig};
+    use crate::{congestion::CongestionMode, pacing::PacingConfig};
+
+    #[test]
+    fn serde_defaults() {
+        let config: KcpConfig = serde_json::from_str("{}").unwrap();
+        let default = KcpConfig::default();
+        assert_eq!(config.mtu, default.mtu);
+        assert_eq!(config.wnd_size, default.wnd_size);
+        assert_eq!(config.session_expire, default.session_expire);
+        assert_eq!(config.dead_link, default.dead_link);
+
+        // Missing fields of nested configs too
+        let config: KcpConfig =
+            serde_json::from_str(r#"{ "nodelay": { "interval": 20 }, "pmtu": { "probe_timeout": "2s" } }"#).unwrap();
+        assert_eq!(config.nodelay.interval, 20);
+        assert!(!config.nodelay.nodelay);
+        let pmtu = config.pmtu.unwrap();
+        assert_eq!(pmtu.probe_timeout, Duration::from_secs(2));
+        assert_eq!(pmtu.max_mtu, PmtuConfig::default().max_mtu);
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let config = KcpConfig {
+            wnd_size: (512, 1024),
+            idle_timeout: Some(Duration::from_secs(60)),
+            linger: Some(Duration::ZERO),
+            dead_link: None,
+            dispatch_mode: DispatchMode::PerSession,
+            congestion: CongestionMode::Off,
+            pacing: Some(PacingConfig {
+                max_burst: 16 * 1024,
+                rate: 1_000_000,
+                packet_rate: None,
+                adaptive: true,
+            }),
+            ..KcpConfig::fast()
+        };
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["idle_timeout"], 60000);
+        assert_eq!(json["session_expire"], 90000);
+        assert_eq!(json["dispatch_mode"], "per_session");
+        assert_eq!(json["congestion"], "off");
+
+        let deserialized: KcpConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(deserialized.wnd_size, (512, 1024));
+        assert_eq!(deserialized.nodelay.interval, config.nodelay.interval);
+        assert_eq!(deserialized.idle_timeout, Some(Duration::from_secs(60)));
+        assert_eq!(deserialized.linger, Some(Duration::ZERO));
+        assert_eq!(deserialized.dead_link, None);
+        assert_eq!(deserialized.dispatch_mode, DispatchMode::PerSession);
+        assert!(matches!(deserialized.congestion, CongestionMode::Off));
+        assert_eq!(deserialized.pacing.unwrap().rate, 1_000_000);
+    }
+
+    #[test]
+    fn serde_durations() {
+        for (value, expected) in [
+            ("1500", Duration::from_millis(1500)),
+            (r#""1500""#, Duration::from_millis(1500)),
+            (r#""250ms""#, Duration::from_millis(250)),
+            (r#""30s""#, Duration::from_secs(30)),
+            (r#""5m""#, Duration::from_secs(300)),
+            (r#""1h""#, Duration::from_secs(3600)),
+        ] {
+            let config: KcpConfig = serde_json::from_str(&format!(r#"{{ "session_expire": {} }}"#, value)).unwrap();
+            assert_eq!(config.session_expire, expected, "{}", value);
+        }
+
+        for value in ["-1", r#""1.5s""#, r#""10 days""#, r#""s""#] {
+            let json = format!(r#"{{ "session_expire": {} }}"#, value);
+            assert!(serde_json::from_str::<KcpConfig>(&json).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn serde_flush_strategy() {
+        let config: KcpConfig = serde_json::from_str(r#"{ "flush_strategy": "on_ack" }"#).unwrap();
+        assert_eq!(config.effective_flush_strategy(), FlushStrategy::OnAck);
+
+        // Deprecated flags of older configs are added to the strategy, and written as the strategy
+        let config: KcpConfig = serde_json::from_str(r#"{ "flush_strategy": "on_ack", "flush_write": true }"#).unwrap();
+        assert_eq!(config.effective_flush_strategy(), FlushStrategy::Aggressive);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["flush_strategy"], "aggressive");
+        assert!(json.get("flush_write").is_none() && json.get("flush_acks_input").is_none());
+    }
+
+    #[test]
+    fn serde_validates() {
+        let err = serde_json::from_str::<KcpConfig>(r#"{ "mtu": 10 }"#).unwrap_err();
+        assert!(err.to_string().contains("mtu"), "{}", err);
+        let err = serde_json::from_str::<KcpConfig>(r#"{ "session_expire": 0 }"#).unwrap_err();
+        assert!(err.to_string().contains("session_expire"), "{}", err);
     }
 }
@@ -0,0 +1,84 @@
+//! Congestion control
+//!
+//! Selected by `KcpConfig::congestion`. KCP has a built-in congestion window, which `CongestionMode::Default`
+//! keeps unless `KcpNoDelayConfig::nc` disables it. `CongestionMode::Off` disables it, sending is then only limited
+//! by the send window and the receive window of peer, which suits private links that are never congested.
+//!
+//! `CongestionMode::Custom` replaces it with a `CongestionController` of every session. The controller observes
+//! RTT samples of acknowledged segments and retransmissions, and decides the send window, which is capped by
+//! `KcpConfig::wnd_size`, and the rate of the pacer if `KcpConfig::pacing` is enabled.
+
+use std::{fmt, sync::Arc, time::Duration};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Congestion controller of a session, for `CongestionMode::Custom`
+pub trait CongestionController: Send {
+    /// A data segment was acknowledged, `rtt` is measured from its last transmission
+    fn on_ack(&mut self, rtt: Duration);
+
+    /// `segments` data segments were retransmitted, because they were lost or their acknowledgements were late
+    fn on_loss(&mut self, segments: usize);
+
+    /// Maximum data segments in flight, capped by `KcpConfig::wnd_size`
+    fn cwnd(&self) -> u16;
+
+    /// Sending rate in bytes per second, up to `PacingConfig::rate`. Only applies if `KcpConfig::pacing` is enabled.
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Creates the controller of every session
+#[derive(Clone)]
+pub struct ControllerFactory(Arc<dyn Fn() -> Box<dyn CongestionController> + Send + Sync>);
+
+impl ControllerFactory {
+    pub fn new<F>(f: F) -> ControllerFactory
+    where
+        F: Fn() -> Box<dyn CongestionController> + Send + Sync + 'static,
+    {
+        ControllerFactory(Arc::new(f))
+    }
+
+    pub(crate) fn create(&self) -> Box<dyn CongestionController> {
+        (self.0)()
+    }
+}
+
+impl fmt::Debug for ControllerFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ControllerFactory")
+    }
+}
+
+/// Congestion control of sessions
+///
+/// Serialized as `"default"` or `"off"`, `Custom` can't be serialized.
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(rename_all = "snake_case"))]
+pub enum CongestionMode {
+    /// KCP's congestion window, unless `KcpNoDelayConfig::nc` disables it
+    #[default]
+    Default,
+    /// No congestion control, like `KcpNoDelayConfig::nc`
+    Off,
+    /// KCP's congestion window is replaced by a controller created for every session
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Custom(ControllerFactory),
+}
+
+/// Congestion control of a session, reported by `KcpStream::congestion_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CongestionStats {
+    /// KCP's congestion window is active, it is private to KCP and further limits `cwnd`
+    pub kcp_window: bool,
+    /// Maximum data segments in flight, the smaller of the send window and the receive window of peer.
+    /// The send window is decided by the controller with `CongestionMode::Custom`.
+    pub cwnd: u16,
+    /// Sending rate decided by the controller
+    pub pacing_rate: Option<u64>,
+    /// Retransmission timeout, estimated from RTT samples in the same way as KCP, at least `KcpConfig::rx_minrto`
+    pub rto: Duration,
+}
@@ -0,0 +1,227 @@
+//! Connector for hyper clients
+//!
+//! Enabled by the `connect` feature. `KcpConnector` is a `tower::Service<Uri>`, which can be used by
+//! `hyper_util::client::legacy::Client` (and clients built on it, like tonic) to send requests over KCP.
+//!
+//! ```no_run
+//! # use bytes::Bytes;
+//! # use http_body_util::Empty;
+//! # use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+//! # use tokio_kcp::{connect::KcpConnector, KcpConfig};
+//! # async fn run() {
+//! let connector = KcpConnector::new(KcpConfig::default());
+//! let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+//! let response = client.get("http://127.0.0.1:3100/".parse().unwrap()).await.unwrap();
+//! # }
+//! ```
+
+use std::{
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::Uri;
+use hyper::rt::{Read, ReadBufCursor, Write};
+use hyper_util::{
+    client::legacy::connect::{Connected, Connection},
+    rt::TokioIo,
+};
+use tokio::{net, time};
+use tower_service::Service;
+
+use crate::{config::KcpConfig, stream::KcpStream};
+
+/// Connects to the host and port of an URI with `KcpStream`
+#[derive(Debug, Clone)]
+pub struct KcpConnector {
+    config: KcpConfig,
+    connect_timeout: Option<Duration>,
+}
+
+impl KcpConnector {
+    /// Creates a connector, all connections are opened with `config`
+    pub fn new(config: KcpConfig) -> KcpConnector {
+        KcpConnector {
+            config,
+            connect_timeout: None,
+        }
+    }
+
+    /// Fails connecting with `TimedOut` if resolving the host and connecting take longer than `timeout`.
+    ///
+    /// Default is `None`, no timeout. KCP has no handshake unless `KcpConfig::handshake` is enabled,
+    /// so without it the timeout only covers resolving.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+}
+
+impl Service<Uri> for KcpConnector {
+    type Response = KcpConnection;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<KcpConnection>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Ok(()).into()
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let config = self.config.clone();
+        let connect_timeout = self.connect_timeout;
+
+        Box::pin(async move {
+            let connect = connect(config, uri);
+            let stream = match connect_timeout {
+                Some(timeout) => match time::timeout(timeout, connect).await {
+                    Ok(result) => result?,
+                    Err(..) => return Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")),
+                },
+                None => connect.await?,
+            };
+            Ok(KcpConnection {
+                inner: TokioIo::new(stream),
+            })
+        })
+    }
+}
+
+async fn connect(config: KcpConfig, uri: Uri) -> io::Result<KcpStream> {
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?;
+    // IPv6 addresses are bracketed in URIs
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = match uri.port_u16() {
+        Some(port) => port,
+        None if uri.scheme_str() == Some("https") => 443,
+        None => 80,
+    };
+
+    let mut last_err = None;
+    for addr in net::lookup_host((host, port)).await? {
+        match KcpStream::connect(&config, addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    Err(match last_err {
+        Some(err) => err.into(),
+        None => io::Error::new(io::ErrorKind::NotFound, format!("no address resolved for {}", host)),
+    })
+}
+
+/// Connection made by `KcpConnector`
+pub struct KcpConnection {
+    inner: TokioIo<KcpStream>,
+}
+
+impl KcpConnection {
+    /// Address of the remote peer
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.inner.inner().peer_addr()
+    }
+
+    pub fn into_inner(self) -> KcpStream {
+        self.inner.into_inner()
+    }
+}
+
+impl Connection for KcpConnection {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl Read for KcpConnection {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl Write for KcpConnection {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{convert::Infallible, time::Duration};
+
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::{server::conn::http1, service::service_fn, Request, Response};
+    use hyper_util::{
+        client::legacy::Client,
+        rt::{TokioExecutor, TokioIo},
+    };
+    use tokio::time;
+
+    use super::KcpConnector;
+    use crate::{config::KcpConfig, KcpListener, KcpNoDelayConfig};
+
+    #[tokio::test]
+    async fn hyper_hello_world() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let service = service_fn(|_: Request<hyper::body::Incoming>| async {
+                        Ok::<_, Infallible>(Response::new(Full::new(Bytes::from_static(b"Hello, World!"))))
+                    });
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        let mut connector = KcpConnector::new(config);
+        connector.set_connect_timeout(Some(Duration::from_secs(1)));
+        let client = Client::builder(TokioExecutor::new()).build::<_, Empty<Bytes>>(connector);
+
+        let uri = format!("http://{}/", server_addr).parse().unwrap();
+        let response = time::timeout(Duration::from_secs(5), client.get(uri))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"Hello, World!");
+    }
+}
@@ -0,0 +1,182 @@
+//! Snapshots of KCP internals for diagnostics, see `KcpStream::debug_state`
+//!
+//! **The format is unstable**, fields may be added, removed or change their meaning in any release. It is meant
+//! for tools that visualize what a session is doing, not for decisions of applications.
+//!
+//! The control block of `kcp` is private, so segments are tracked from the datagrams that pass through the session:
+//! data segments sent and not yet acknowledged (`snd_buf`), data segments received out of order (`rcv_buf`), and
+//! received data segments that haven't been acknowledged (`ack_list`). Resend timestamps are private to `kcp` and
+//! are not available.
+
+use std::collections::BTreeMap;
+
+use bytes::Buf;
+
+use crate::skcp::{KCP_CMD_ACK, KCP_CMD_PUSH, KCP_HEADER_LEN};
+
+/// Snapshot of a session taken by `KcpStream::debug_state`, the format is unstable
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KcpDebugState {
+    pub conv: u32,
+    pub mtu: usize,
+    pub mss: usize,
+    /// Send window, in segments
+    pub snd_wnd: u16,
+    /// Receive window, in segments
+    pub rcv_wnd: u16,
+    /// Receive window last advertised by peer, in segments
+    pub rmt_wnd: u16,
+    /// Segments queued or in flight
+    pub wait_snd: usize,
+    /// `sn` of the oldest unacknowledged data segment
+    pub snd_una: u32,
+    /// `sn` of the next data segment to be sent for the first time
+    pub snd_nxt: u32,
+    /// `sn` of the next data segment expected from peer
+    pub rcv_nxt: u32,
+    /// A segment was retransmitted more than `KcpConfig::dead_link` times
+    pub dead_link: bool,
+    /// Data segments sent and not acknowledged yet, ordered by `sn`
+    pub snd_buf: Vec<KcpDebugSegment>,
+    /// Data segments received ahead of `rcv_nxt`, ordered by `sn`
+    pub rcv_buf: Vec<KcpDebugSegment>,
+    /// `(sn, ts)` of data segments received but not acknowledged yet, sent with the next flush
+    pub ack_list: Vec<(u32, u32)>,
+}
+
+/// Data segment in a `KcpDebugState`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct KcpDebugSegment {
+    pub sn: u32,
+    /// Fragment number, counts down to 0 in the last segment of a message
+    pub frg: u8,
+    /// Length of payload
+    pub len: usize,
+    /// KCP clock (milliseconds) of the last transmission
+    pub ts: u32,
+    /// Transmissions, more than 1 if it was retransmitted, 0 for received segments
+    pub xmit: u32,
+}
+
+/// Header of a segment in a datagram
+struct Header {
+    cmd: u8,
+    frg: u8,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    len: usize,
+}
+
+fn headers(mut buf: &[u8]) -> impl Iterator<Item = Header> + '_ {
+    std::iter::from_fn(move || {
+        if buf.len() < KCP_HEADER_LEN {
+            return None;
+        }
+        let mut header = &buf[4..];
+        let cmd = header.get_u8();
+        let frg = header.get_u8();
+        header.advance(2);
+        let ts = header.get_u32_le();
+        let sn = header.get_u32_le();
+        let una = header.get_u32_le();
+        let len = (header.get_u32_le() as usize).min(buf.len() - KCP_HEADER_LEN);
+        buf = &buf[KCP_HEADER_LEN + len..];
+        Some(Header {
+            cmd,
+            frg,
+            ts,
+            sn,
+            una,
+            len,
+        })
+    })
+}
+
+/// Shadow of the segment buffers of KCP, fed with datagrams sent and received by a session
+#[derive(Default)]
+pub struct SegmentTracker {
+    snd_buf: BTreeMap<u32, KcpDebugSegment>,
+    rcv_buf: BTreeMap<u32, KcpDebugSegment>,
+    ack_list: Vec<(u32, u32)>,
+    /// `una` of the last datagram sent, advanced by segments received in order
+    rcv_nxt: u32,
+}
+
+impl SegmentTracker {
+    /// Datagram produced by KCP
+    pub fn on_output(&mut self, buf: &[u8]) {
+        for header in headers(buf) {
+            self.rcv_nxt = header.una;
+            match header.cmd {
+                KCP_CMD_PUSH => {
+                    let segment = self.snd_buf.entry(header.sn).or_insert(KcpDebugSegment {
+                        sn: header.sn,
+                        frg: header.frg,
+                        len: header.len,
+                        ts: header.ts,
+                        xmit: 0,
+                    });
+                    segment.ts = header.ts;
+                    segment.xmit += 1;
+                }
+                KCP_CMD_ACK => self.ack_list.retain(|&(sn, _)| sn != header.sn),
+                _ => {}
+            }
+        }
+        let rcv_nxt = self.rcv_nxt;
+        self.rcv_buf.retain(|&sn, _| before(rcv_nxt, sn));
+    }
+
+    /// Datagram accepted by KCP
+    pub fn on_input(&mut self, buf: &[u8]) {
+        for header in headers(buf) {
+            self.snd_buf.retain(|&sn, _| !before(sn, header.una));
+            match header.cmd {
+                KCP_CMD_PUSH => {
+                    self.ack_list.push((header.sn, header.ts));
+                    if !before(header.sn, self.rcv_nxt) {
+                        self.rcv_buf.entry(header.sn).or_insert(KcpDebugSegment {
+                            sn: header.sn,
+                            frg: header.frg,
+                            len: header.len,
+                            ts: header.ts,
+                            xmit: 0,
+                        });
+                    }
+                }
+                KCP_CMD_ACK => {
+                    self.snd_buf.remove(&header.sn);
+                }
+                _ => {}
+            }
+        }
+        // KCP moves segments that are in order to the receive queue
+        while self.rcv_buf.remove(&self.rcv_nxt).is_some() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        }
+    }
+
+    pub fn snd_buf(&self) -> Vec<KcpDebugSegment> {
+        self.snd_buf.values().copied().collect()
+    }
+
+    pub fn rcv_buf(&self) -> Vec<KcpDebugSegment> {
+        self.rcv_buf.values().copied().collect()
+    }
+
+    pub fn ack_list(&self) -> Vec<(u32, u32)> {
+        self.ack_list.clone()
+    }
+
+    pub fn rcv_nxt(&self) -> u32 {
+        self.rcv_nxt
+    }
+}
+
+/// `a` is before `b` in the wrapping sequence space
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
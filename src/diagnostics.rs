@@ -0,0 +1,135 @@
+//! Snapshots of sessions for troubleshooting, see `KcpStream::dump_state` and `KcpListener::dump_sessions`, and
+//! summaries of sessions for listing them, see `KcpListener::sessions`
+//!
+//! Unlike `KcpDebugState` of `debug-internals`, these are meant to be logged in production: fields are only added
+//! in minor releases, and they serialize to plain JSON with the `serde` feature. Times are relative to the
+//! snapshot in milliseconds, so they can be read without the clock of the process.
+//!
+//! The control block of `kcp` is private, so its queues are derived from the datagrams that pass through the
+//! session. The congestion window and the slow start threshold of KCP are not available, `cwnd` is the window that
+//! bounds sending otherwise.
+
+use std::net::SocketAddr;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+/// State of a session taken by `KcpStream::dump_state`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct SessionDiagnostics {
+    pub conv: u32,
+    /// Current address of peer, changes if the session migrated
+    pub peer_addr: SocketAddr,
+    /// A packet from peer was processed
+    pub connected: bool,
+    /// Closed by this side, FIN was sent or it is waiting to be sent
+    pub closed: bool,
+    /// Peer has sent FIN
+    pub peer_closed: bool,
+    /// Why the session is broken, every call of the stream fails with it
+    pub error: Option<String>,
+    /// Messages waiting for the send window, fragmented into segments
+    pub snd_queue: usize,
+    /// Data segments sent and not acknowledged yet
+    pub snd_buf: usize,
+    /// Bytes or messages received and ready to be read, see `RecvQueueLen::ready`
+    pub rcv_queue: usize,
+    /// Data segments received out of order, see `RecvQueueLen::out_of_order`
+    pub rcv_buf: usize,
+    /// `sn` of the oldest unacknowledged data segment
+    pub snd_una: u32,
+    /// `sn` of the next data segment to be sent for the first time
+    pub snd_nxt: u32,
+    /// Send window, in segments, reduced by the controller with `CongestionMode::Custom`
+    pub snd_wnd: u16,
+    /// Receive window last advertised by peer, in segments
+    pub rmt_wnd: u16,
+    /// Maximum data segments in flight, see `CongestionStats::cwnd`
+    pub cwnd: u16,
+    /// Retransmission timeout
+    pub rto_ms: u32,
+    /// Smoothed RTT, 0 before the first sample
+    pub srtt_ms: u32,
+    /// Variation of RTT
+    pub rttvar_ms: u32,
+    /// See `KcpStream::retransmissions`
+    pub retransmissions: u64,
+    /// See `KcpStream::fast_retransmissions`
+    pub fast_retransmissions: u64,
+    /// Time since KCP sent the last datagram, data or ACKs, since the session was created if none
+    pub since_last_send_ms: u64,
+    /// Time since the last datagram from peer was processed, since the session was created if none
+    pub since_last_recv_ms: u64,
+    /// Time since application data was sent or received
+    pub since_last_activity_ms: u64,
+    /// A segment was retransmitted more than `KcpConfig::dead_link` times
+    pub dead_link: bool,
+    /// Data segments in `snd_buf`, ordered by `sn`. Segments acknowledged ahead of `snd_una` remain until it
+    /// passes them.
+    pub segments: Vec<SegmentDiagnostics>,
+    /// Configuration of this session, including changes by `KcpStream::set_mtu` and the like
+    pub config: EffectiveConfig,
+}
+
+/// Summary of a session of a listener, taken by `KcpListener::sessions`
+///
+/// Cheaper than `SessionDiagnostics`, nothing is copied from the control block of KCP.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct SessionInfo {
+    pub conv: u32,
+    /// Current address of peer, changes if the session migrated
+    pub peer_addr: SocketAddr,
+    /// Time since the session was created
+    pub age_ms: u64,
+    /// Time since application data was sent or received, since the session was created if none
+    pub idle_ms: u64,
+    /// Application data accepted by `send`, including data not acknowledged yet
+    pub bytes_sent: u64,
+    /// Application data read by `recv`, data waiting to be read is not counted
+    pub bytes_received: u64,
+    /// Closed by either side, it is removed from the listener after FIN is exchanged
+    pub closed: bool,
+}
+
+/// Data segment in flight of a `SessionDiagnostics`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct SegmentDiagnostics {
+    pub sn: u32,
+    /// Transmissions, more than 1 if it was retransmitted
+    pub xmit: u32,
+    /// Time since its last transmission
+    pub since_sent_ms: u32,
+}
+
+/// Configuration in effect for a session, in a `SessionDiagnostics`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+#[non_exhaustive]
+pub struct EffectiveConfig {
+    pub mtu: usize,
+    /// Maximum payload of a segment
+    pub mss: usize,
+    pub stream: bool,
+    /// Send window of `KcpConfig::wnd_size`, in segments
+    pub snd_wnd: u16,
+    /// Receive window, in segments
+    pub rcv_wnd: u16,
+    /// See `KcpNoDelayConfig`
+    pub nodelay: bool,
+    pub interval_ms: u32,
+    /// ACKs of later segments that trigger a fast resend, 0 if disabled
+    pub fast_resend: u32,
+    pub min_rto_ms: u32,
+    /// KCP's congestion window is active, see `CongestionStats::kcp_window`
+    pub kcp_window: bool,
+    /// Writes are flushed before they return, see `FlushStrategy::flushes_write`
+    pub flush_write: bool,
+    /// ACKs are flushed after each input, see `FlushStrategy::flushes_ack`
+    pub flush_acks_input: bool,
+}
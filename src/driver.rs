@@ -0,0 +1,174 @@
+//! Shared driver of sessions
+//!
+//! Enabled by `KcpConfig::shared_driver`. Instead of spawning a task with its own timer for every session,
+//! one task keeps the next update time of all sessions of a listener in a binary heap, and updates the due
+//! sessions in batches. Packets are input to sessions directly by the listener task.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use log::trace;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::{self, Instant},
+};
+
+use crate::session::{KcpSession, UpdateState};
+
+enum DriverCommand {
+    Register { id: u64, session: Arc<KcpSession> },
+    Wake { id: u64, flush: bool },
+    FlushAt { id: u64, deadline: Instant },
+}
+
+/// Wakes a session that is driven by `SessionDriver`
+pub struct DriverWaker {
+    id: u64,
+    tx: mpsc::UnboundedSender<DriverCommand>,
+}
+
+impl DriverWaker {
+    /// Updates the session immediately, `flush` for sending data without waiting for the next interval
+    pub fn wake(&self, flush: bool) {
+        let _ = self.tx.send(DriverCommand::Wake { id: self.id, flush });
+    }
+
+    /// Flushes the session no later than `deadline`
+    pub fn flush_at(&self, deadline: Instant) {
+        let _ = self.tx.send(DriverCommand::FlushAt { id: self.id, deadline });
+    }
+}
+
+/// Handle of the task that drives sessions.
+///
+/// The task keeps running until all registered sessions are closed after this handle is dropped.
+pub struct SessionDriver {
+    tx: mpsc::UnboundedSender<DriverCommand>,
+    _shutdown_tx: oneshot::Sender<()>,
+    next_id: AtomicU64,
+}
+
+struct DrivenSession {
+    session: Arc<KcpSession>,
+    state: UpdateState,
+    deadline: Instant,
+}
+
+impl SessionDriver {
+    pub fn spawn() -> SessionDriver {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        tokio::spawn(SessionDriver::run(rx, shutdown_rx));
+
+        SessionDriver {
+            tx,
+            _shutdown_tx: shutdown_tx,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Identifies a session in this driver, which doesn't change if the session migrated
+    pub fn alloc_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Starts driving `session`, which will be updated immediately
+    pub fn register(&self, id: u64, session: Arc<KcpSession>) {
+        let _ = self.tx.send(DriverCommand::Register { id, session });
+    }
+
+    pub fn waker(&self, id: u64) -> DriverWaker {
+        DriverWaker {
+            id,
+            tx: self.tx.clone(),
+        }
+    }
+
+    async fn run(mut rx: mpsc::UnboundedReceiver<DriverCommand>, mut shutdown_rx: oneshot::Receiver<()>) {
+        let mut sessions: HashMap<u64, DrivenSession> = HashMap::new();
+        // Deadlines of sessions, entries that don't match `DrivenSession::deadline` were rescheduled
+        let mut deadlines: BinaryHeap<Reverse<(Instant, u64)>> = BinaryHeap::new();
+        let mut shutdown = false;
+
+        loop {
+            let next_deadline = deadlines.peek().map(|Reverse((deadline, _))| *deadline);
+
+            tokio::select! {
+                Some(command) = rx.recv() => {
+                    let now = Instant::now();
+                    match command {
+                        DriverCommand::Register { id, session } => {
+                            sessions.insert(id, DrivenSession {
+                                session,
+                                state: UpdateState::new(),
+                                deadline: now,
+                            });
+                            deadlines.push(Reverse((now, id)));
+                        }
+                        DriverCommand::Wake { id, flush } => {
+                            if let Some(driven) = sessions.get_mut(&id) {
+                                driven.state.flush_now |= flush;
+                                if driven.deadline > now {
+                                    driven.deadline = now;
+                                    deadlines.push(Reverse((now, id)));
+                                }
+                            }
+                        }
+                        DriverCommand::FlushAt { id, deadline } => {
+                            if let Some(driven) = sessions.get_mut(&id) {
+                                driven.state.flush_now = true;
+                                if driven.deadline > deadline {
+                                    driven.deadline = deadline;
+                                    deadlines.push(Reverse((deadline, id)));
+                                }
+                            }
+                        }
+                    }
+                }
+
+                _ = time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {
+                    let now = Instant::now();
+                    while let Some(Reverse((deadline, id))) = deadlines.peek().copied() {
+                        if deadline > now {
+                            break;
+                        }
+                        deadlines.pop();
+
+                        let driven = match sessions.get_mut(&id) {
+                            Some(driven) if driven.deadline == deadline => driven,
+                            // Rescheduled or closed
+                            _ => continue,
+                        };
+
+                        match driven.session.tick(&mut driven.state, deadline).await {
+                            Some(next) => {
+                                driven.deadline = next;
+                                deadlines.push(Reverse((next, id)));
+                            }
+                            None => {
+                                if let Some(driven) = sessions.remove(&id) {
+                                    driven.session.finish().await;
+                                }
+                            }
+                        }
+                    }
+                }
+
+                _ = &mut shutdown_rx, if !shutdown => {
+                    shutdown = true;
+                }
+            }
+
+            if shutdown && sessions.is_empty() {
+                trace!("[DRIVER] all sessions closed, stopped");
+                break;
+            }
+        }
+    }
+}
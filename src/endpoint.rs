@@ -0,0 +1,392 @@
+//! Connectionless API over the sessions of a listener, see `KcpEndpoint`
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
+
+use log::{debug, error, trace};
+use tokio::{
+    net::ToSocketAddrs,
+    sync::{mpsc, oneshot},
+    task::{AbortHandle, JoinSet},
+};
+
+use crate::{
+    config::KcpConfig,
+    error::{KcpError, KcpResult},
+    listener::KcpListener,
+    session::SessionKey,
+    stream::KcpStream,
+    transport::KcpTransport,
+};
+
+/// Messages received by all sessions waiting for `recv_from`, receiving from sessions pauses when it is full
+const MESSAGE_BACKLOG: usize = 256;
+
+/// Initial buffer of a session receiving messages, grown for larger messages
+const RECV_BUFFER_SIZE: usize = 2048;
+
+/// Streams of sessions, by conv and peer address. Their ids tell a session apart from a later one of the same key.
+type SessionMap = Arc<StdMutex<HashMap<SessionKey, (u64, KcpStream)>>>;
+
+type Message = (u32, SocketAddr, Vec<u8>);
+
+enum EndpointCommand {
+    /// Stream of the session, created if there is none
+    Session(SessionKey, oneshot::Sender<KcpResult<KcpStream>>),
+}
+
+/// Sends messages to and receives messages from many peers over one socket, without connecting or accepting.
+///
+/// Every `(conv, peer address)` is a session of its own, which is opened by the first message sent to it or received
+/// from it, and reliable and ordered as usual. Sessions are in message mode, `KcpConfig::stream` is ignored, so peers
+/// must not use stream mode. Peers are other endpoints, or clients connected with `KcpStream::connect_with_conv`, or
+/// with `KcpStream::connect`, whose conv is allocated by the endpoint.
+///
+/// ```no_run
+/// # use tokio_kcp::{KcpConfig, KcpEndpoint};
+/// # async fn run() {
+/// let mut endpoint = KcpEndpoint::bind(KcpConfig::default(), "0.0.0.0:3100").await.unwrap();
+/// endpoint.send_to(1, "203.0.113.7:3100".parse().unwrap(), b"PING").await.unwrap();
+/// while let Ok((conv, peer_addr, message)) = endpoint.recv_from().await {
+///     if message == b"PING" {
+///         endpoint.send_to(conv, peer_addr, b"PONG").await.unwrap();
+///     }
+/// }
+/// # }
+/// ```
+///
+/// It is built on `KcpListener`, whose options apply, except that `KcpConfig::handshake` is rejected, as sessions are
+/// opened by both sides. Sessions are closed like other streams, when `KcpConfig::session_expire` or
+/// `KcpConfig::idle_timeout` expires, or peer closes them, and the next message opens a new one. Dropping the
+/// endpoint closes all of them.
+pub struct KcpEndpoint {
+    local_addr: SocketAddr,
+    sessions: SessionMap,
+    command_tx: mpsc::Sender<EndpointCommand>,
+    message_rx: mpsc::Receiver<KcpResult<Message>>,
+    task: AbortHandle,
+}
+
+impl Drop for KcpEndpoint {
+    fn drop(&mut self) {
+        // Streams of the sessions are dropped with the task
+        self.task.abort();
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
+impl KcpEndpoint {
+    pub async fn bind<A: ToSocketAddrs>(config: KcpConfig, addr: A) -> KcpResult<KcpEndpoint> {
+        let config = KcpEndpoint::validate(config)?;
+        KcpEndpoint::from_listener(KcpListener::bind(config, addr).await?)
+    }
+
+    /// Creates an endpoint that sends and receives datagrams by `transport`, see `KcpListener::with_transport`
+    pub fn with_transport(config: KcpConfig, transport: Arc<dyn KcpTransport>) -> KcpResult<KcpEndpoint> {
+        let config = KcpEndpoint::validate(config)?;
+        KcpEndpoint::from_listener(KcpListener::with_transport(config, transport)?)
+    }
+
+    fn validate(config: KcpConfig) -> KcpResult<KcpConfig> {
+        if config.handshake {
+            return Err(KcpError::ConfigInvalid(
+                "handshake is not supported by KcpEndpoint".to_owned(),
+            ));
+        }
+        Ok(KcpConfig {
+            stream: false,
+            ..config
+        })
+    }
+
+    fn from_listener(listener: KcpListener) -> KcpResult<KcpEndpoint> {
+        let local_addr = listener.local_addr()?;
+        let sessions = SessionMap::default();
+        let (command_tx, command_rx) = mpsc::channel(64);
+        let (message_tx, message_rx) = mpsc::channel(MESSAGE_BACKLOG);
+        let task = tokio::spawn(run(listener, sessions.clone(), command_rx, message_tx)).abort_handle();
+        Ok(KcpEndpoint {
+            local_addr,
+            sessions,
+            command_tx,
+            message_rx,
+            task,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Sends `buf` as one message to the session of `conv` with `peer_addr`, opens the session if there is none.
+    ///
+    /// Returns when the message is queued, like `KcpStream::send`, and waits if the send window of the session is
+    /// full. Fails with `ConfigInvalid` if `conv` is 0, and `ListenerClosed` if the socket failed.
+    pub async fn send_to(&self, conv: u32, peer_addr: SocketAddr, buf: &[u8]) -> KcpResult<usize> {
+        if conv == 0 {
+            return Err(KcpError::ConfigInvalid("conv of KcpEndpoint must not be 0".to_owned()));
+        }
+
+        let key = (conv, peer_addr);
+        let stream = self
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|(_, stream)| stream.clone());
+        let mut stream = match stream {
+            Some(stream) => stream,
+            None => {
+                let (tx, rx) = oneshot::channel();
+                self.command_tx
+                    .send(EndpointCommand::Session(key, tx))
+                    .await
+                    .map_err(|_| KcpError::ListenerClosed(None))?;
+                rx.await.map_err(|_| KcpError::ListenerClosed(None))??
+            }
+        };
+        stream.send(buf).await
+    }
+
+    /// Receives the next message from any session, with its conv and the address of peer.
+    ///
+    /// Messages of one session are received in order, and sessions are received from in no particular order. If the
+    /// socket failed, it fails with `ListenerClosed` after the messages already received.
+    pub async fn recv_from(&mut self) -> KcpResult<(u32, SocketAddr, Vec<u8>)> {
+        match self.message_rx.recv().await {
+            Some(message) => message,
+            None => Err(KcpError::ListenerClosed(None)),
+        }
+    }
+
+    /// Number of open sessions
+    pub fn session_count(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+}
+
+/// Serves sessions of `listener`, until the endpoint is dropped or the listener fails
+async fn run(
+    mut listener: KcpListener,
+    sessions: SessionMap,
+    mut command_rx: mpsc::Receiver<EndpointCommand>,
+    message_tx: mpsc::Sender<KcpResult<Message>>,
+) {
+    let mut receivers = Receivers {
+        sessions,
+        tasks: JoinSet::new(),
+        next_id: 0,
+        message_tx,
+    };
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer_addr)) => receivers.spawn(stream, peer_addr).await,
+                Err(err) => {
+                    error!("endpoint stopped, error: {}", err);
+                    let _ = receivers.message_tx.send(Err(err)).await;
+                    break;
+                }
+            },
+
+            command = command_rx.recv() => match command {
+                Some(EndpointCommand::Session(key, tx)) => {
+                    let _ = tx.send(receivers.open(&mut listener, key).await);
+                }
+                None => break,
+            },
+
+            Some(ended) = receivers.tasks.join_next() => {
+                if let Ok((key, id)) = ended {
+                    receivers.remove(key, id);
+                }
+            }
+        }
+    }
+}
+
+/// Tasks receiving messages from sessions
+struct Receivers {
+    sessions: SessionMap,
+    tasks: JoinSet<(SessionKey, u64)>,
+    next_id: u64,
+    message_tx: mpsc::Sender<KcpResult<Message>>,
+}
+
+impl Receivers {
+    /// Stream of the session of `key`, prepared by `listener` if there is none
+    async fn open(&mut self, listener: &mut KcpListener, key: SessionKey) -> KcpResult<KcpStream> {
+        if let Some((_, stream)) = self.sessions.lock().unwrap().get(&key) {
+            return Ok(stream.clone());
+        }
+
+        let (conv, peer_addr) = key;
+        match listener.prepare_session(conv, peer_addr).await {
+            Ok(stream) => {
+                debug!("endpoint opened session conv: {}, peer: {}", conv, peer_addr);
+                self.spawn(stream.clone(), peer_addr).await;
+                Ok(stream)
+            }
+            Err(KcpError::IoError(err)) if err.kind() == io::ErrorKind::AlreadyExists => {
+                // Opened by peer, and waiting to be accepted
+                while let Some((stream, peer_addr)) = listener.accept_timeout(Duration::ZERO).await? {
+                    self.spawn(stream, peer_addr).await;
+                }
+                match self.sessions.lock().unwrap().get(&key) {
+                    Some((_, stream)) => Ok(stream.clone()),
+                    None => Err(KcpError::IoError(err)),
+                }
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Receives messages from `stream` until its session is closed
+    async fn spawn(&mut self, stream: KcpStream, peer_addr: SocketAddr) {
+        let key = (stream.conv().await, peer_addr);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.sessions.lock().unwrap().insert(key, (id, stream.clone()));
+
+        let message_tx = self.message_tx.clone();
+        self.tasks.spawn(async move {
+            let mut stream = stream;
+            let mut buf = vec![0u8; RECV_BUFFER_SIZE];
+            loop {
+                match stream.recv(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if message_tx.send(Ok((key.0, key.1, buf[..n].to_vec()))).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(KcpError::BufferTooSmall(size)) => buf.resize(size, 0),
+                    Err(err) => {
+                        trace!(
+                            "endpoint session conv: {} closed, peer: {}, error: {}",
+                            key.0,
+                            key.1,
+                            err
+                        );
+                        break;
+                    }
+                }
+            }
+            (key, id)
+        });
+    }
+
+    /// Forgets a closed session, unless it was replaced by a new one
+    fn remove(&mut self, key: SessionKey, id: u64) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(&key).is_some_and(|(current, _)| *current == id) {
+            sessions.remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::Arc};
+
+    use super::KcpEndpoint;
+    use crate::{config::KcpConfig, error::KcpError, memory::MemoryNetwork, stream::KcpStream};
+
+    #[tokio::test(start_paused = true)]
+    async fn endpoint_exchange() {
+        let network = MemoryNetwork::new();
+        let config = KcpConfig::default();
+        let mut a = KcpEndpoint::with_transport(config.clone(), Arc::new(network.bind())).unwrap();
+        let mut b = KcpEndpoint::with_transport(config.clone(), Arc::new(network.bind())).unwrap();
+        let (a_addr, b_addr) = (a.local_addr(), b.local_addr());
+
+        a.send_to(7, b_addr, b"HELLO").await.unwrap();
+        a.send_to(7, b_addr, b"WORLD").await.unwrap();
+        assert_eq!(b.recv_from().await.unwrap(), (7, a_addr, b"HELLO".to_vec()));
+        assert_eq!(b.recv_from().await.unwrap(), (7, a_addr, b"WORLD".to_vec()));
+
+        // Replies in the session opened by peer, messages larger than a segment keep their boundaries
+        let large = (0..10000).map(|i| i as u8).collect::<Vec<_>>();
+        b.send_to(7, a_addr, &large).await.unwrap();
+        assert_eq!(a.recv_from().await.unwrap(), (7, b_addr, large));
+        assert_eq!((a.session_count(), b.session_count()), (1, 1));
+
+        // Another conv is another session
+        b.send_to(8, a_addr, b"PING").await.unwrap();
+        assert_eq!(a.recv_from().await.unwrap(), (8, b_addr, b"PING".to_vec()));
+        assert_eq!(a.session_count(), 2);
+
+        assert!(matches!(
+            a.send_to(0, b_addr, b"HELLO").await,
+            Err(KcpError::ConfigInvalid(..))
+        ));
+        assert!(KcpEndpoint::with_transport(
+            KcpConfig {
+                handshake: true,
+                ..Default::default()
+            },
+            Arc::new(network.bind())
+        )
+        .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn endpoint_many_peers() {
+        let network = MemoryNetwork::new();
+        let config = KcpConfig::default();
+        let mut hub = KcpEndpoint::with_transport(config.clone(), Arc::new(network.bind())).unwrap();
+        let hub_addr = hub.local_addr();
+
+        let mut peers = (0..4)
+            .map(|_| KcpEndpoint::with_transport(config.clone(), Arc::new(network.bind())).unwrap())
+            .collect::<Vec<_>>();
+        for (i, peer) in peers.iter().enumerate() {
+            for j in 0..3 {
+                peer.send_to(1, hub_addr, format!("{} {}", i, j).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Messages of each peer are in order
+        let mut received = HashMap::<_, Vec<String>>::new();
+        for _ in 0..12 {
+            let (conv, peer_addr, message) = hub.recv_from().await.unwrap();
+            assert_eq!(conv, 1);
+            received
+                .entry(peer_addr)
+                .or_default()
+                .push(String::from_utf8(message).unwrap());
+        }
+        for (i, peer) in peers.iter_mut().enumerate() {
+            let expected = (0..3).map(|j| format!("{} {}", i, j)).collect::<Vec<_>>();
+            assert_eq!(received[&peer.local_addr()], expected);
+
+            hub.send_to(1, peer.local_addr(), b"BYE").await.unwrap();
+            assert_eq!(peer.recv_from().await.unwrap(), (1, hub_addr, b"BYE".to_vec()));
+        }
+
+        // A client of conv 0 is allocated one by the endpoint
+        let client = Arc::new(network.bind());
+        let client_addr = client.local_addr();
+        let client_config = KcpConfig {
+            stream: false,
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect_with_transport(&client_config, client, hub_addr)
+            .await
+            .unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (conv, peer_addr, message) = hub.recv_from().await.unwrap();
+        assert_eq!((peer_addr, message), (client_addr, b"HELLO".to_vec()));
+        hub.send_to(conv, client_addr, b"WORLD").await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = stream.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"WORLD");
+    }
+}
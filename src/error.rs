@@ -0,0 +1,157 @@
+//! Errors of sessions and listeners
+
+use std::{error::Error, fmt, io, sync::Arc};
+
+/// Error of KCP sessions and listeners
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum KcpError {
+    /// Peer didn't respond in time, the link is dead or connecting timed out, or the read or write timeout of the
+    /// stream expired
+    Timeout,
+    /// Session was closed by peer
+    ConnectionClosed,
+    /// Session was closed by the listener
+    ConnectionReset,
+    /// Stream was shut down for writing by `KcpStream::shutdown_write`, it can still receive
+    WriteShutdown,
+    /// Nothing is listening on the address of peer, reported by ICMP
+    ConnectionRefused,
+    /// Session was closed because it was inactive for longer than `KcpConfig::session_expire`
+    SessionExpired,
+    /// Session was closed because no data was sent or received for `KcpConfig::idle_timeout`
+    IdleTimeout,
+    /// `KcpConfig` is invalid
+    ConfigInvalid(String),
+    /// Listener was shut down, or its task failed with the given cause, like a panic or a fatal error of the socket
+    ListenerClosed(Option<Arc<io::Error>>),
+    /// All conversation IDs are used by sessions of the listener, or `KcpConfig::max_sessions` is reached
+    ConvExhausted,
+    /// Buffer of `recv` in message mode is smaller than the next message, which needs the given bytes.
+    /// The message is kept for a retry with a larger buffer.
+    BufferTooSmall(usize),
+    /// Message of `KcpStream::send_msg` is larger than one send of KCP takes, which is the given bytes, see
+    /// `KcpStream::max_message_size`
+    MessageTooLarge(usize),
+    /// `KcpStream::recv_msg` was called while the rest of a message partially read by `AsyncRead` of the same handle,
+    /// the given bytes, is still buffered. It is kept for `recv` or `AsyncRead`.
+    PartialMessage(usize),
+    /// Error of the KCP protocol
+    Kcp(kcp::Error),
+    /// Error of the underlying socket
+    IoError(io::Error),
+}
+
+/// Result of KCP sessions and listeners
+pub type KcpResult<T> = Result<T, KcpError>;
+
+impl fmt::Display for KcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            KcpError::Timeout => f.write_str("timed out, peer didn't respond"),
+            KcpError::ConnectionClosed => f.write_str("connection closed by peer"),
+            KcpError::ConnectionReset => f.write_str("session closed by listener"),
+            KcpError::WriteShutdown => f.write_str("stream shut down for writing"),
+            KcpError::ConnectionRefused => f.write_str("connection refused, peer isn't listening"),
+            KcpError::SessionExpired => f.write_str("session expired"),
+            KcpError::IdleTimeout => f.write_str("idle timeout, no data sent or received"),
+            KcpError::ConfigInvalid(ref msg) => write!(f, "invalid config, {}", msg),
+            KcpError::ListenerClosed(None) => f.write_str("listener closed"),
+            KcpError::ListenerClosed(Some(ref cause)) => write!(f, "listener closed, {}", cause),
+            KcpError::ConvExhausted => f.write_str("no conv available"),
+            KcpError::BufferTooSmall(required) => write!(f, "buffer too small, need {} bytes", required),
+            KcpError::MessageTooLarge(max) => write!(f, "message too large, at most {} bytes", max),
+            KcpError::PartialMessage(rest) => write!(f, "{} bytes of a partially read message left", rest),
+            KcpError::Kcp(ref err) => fmt::Display::fmt(err, f),
+            KcpError::IoError(ref err) => fmt::Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error for KcpError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            KcpError::Kcp(ref err) => Some(err),
+            KcpError::IoError(ref err) => Some(err),
+            KcpError::ListenerClosed(Some(ref cause)) => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for KcpError {
+    fn from(err: io::Error) -> KcpError {
+        if err.kind() == io::ErrorKind::ConnectionRefused {
+            return KcpError::ConnectionRefused;
+        }
+        KcpError::IoError(err)
+    }
+}
+
+impl From<kcp::Error> for KcpError {
+    fn from(err: kcp::Error) -> KcpError {
+        match err {
+            kcp::Error::IoError(err) => KcpError::IoError(err),
+            err => KcpError::Kcp(err),
+        }
+    }
+}
+
+impl From<KcpError> for io::Error {
+    fn from(err: KcpError) -> io::Error {
+        let kind = match err {
+            KcpError::IoError(err) => return err,
+            KcpError::Kcp(err) => return err.into(),
+            KcpError::Timeout | KcpError::SessionExpired | KcpError::IdleTimeout => io::ErrorKind::TimedOut,
+            KcpError::ConnectionClosed | KcpError::WriteShutdown => io::ErrorKind::BrokenPipe,
+            KcpError::ConnectionReset => io::ErrorKind::ConnectionReset,
+            KcpError::ConnectionRefused => io::ErrorKind::ConnectionRefused,
+            KcpError::ConfigInvalid(..) => io::ErrorKind::InvalidInput,
+            KcpError::ListenerClosed(..) => io::ErrorKind::NotConnected,
+            KcpError::ConvExhausted => io::ErrorKind::AddrNotAvailable,
+            KcpError::BufferTooSmall(..) => io::ErrorKind::Other,
+            KcpError::MessageTooLarge(..) => io::ErrorKind::InvalidInput,
+            KcpError::PartialMessage(..) => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, err)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::KcpError;
+
+    #[test]
+    fn into_io_error() {
+        let err: io::Error = KcpError::Timeout.into();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        let err: io::Error = KcpError::ConnectionReset.into();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+
+        // IO errors are unwrapped, from KCP too
+        let err: KcpError = kcp::Error::IoError(io::Error::new(io::ErrorKind::AddrInUse, "in use")).into();
+        assert!(matches!(err, KcpError::IoError(..)));
+        let err: io::Error = err.into();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        // Refusal reported by the socket has its own variant
+        let err: KcpError = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        assert!(matches!(err, KcpError::ConnectionRefused));
+        let err: io::Error = err.into();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+        let err: io::Error = KcpError::BufferTooSmall(3000).into();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.to_string(), "buffer too small, need 3000 bytes");
+
+        let err: io::Error = KcpError::MessageTooLarge(174_752).into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "message too large, at most 174752 bytes");
+
+        let err: io::Error = KcpError::PartialMessage(6).into();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(err.to_string(), "6 bytes of a partially read message left");
+    }
+}
@@ -0,0 +1,70 @@
+//! Connection events
+//!
+//! Sessions emit events into a bounded broadcast channel, subscribed by `KcpStream::events`.
+
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast;
+
+/// Capacity of the event channel of a session
+const EVENT_CHANNEL_CAPACITY: usize = 128;
+
+/// Event of a session
+///
+/// Emitting never blocks the session: a subscriber that falls behind loses its oldest events and gets
+/// `RecvError::Lagged` with the number of events it missed.
+///
+/// `Closed` is guaranteed to be delivered to every subscriber that is alive when the session closes,
+/// because it is the last event of a session and lagging only drops older events. `Connected` is emitted
+/// once per session, when the first packet from peer is processed, so it is only seen by subscribers that
+/// subscribed before that, like a client subscribing right after `KcpStream::connect`. Streams returned by
+/// `KcpListener::accept` have already processed the first packet. All other events are best-effort.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KcpEvent {
+    /// The first packet from peer was processed, `conv` is the conversation ID agreed with peer
+    Connected { conv: u32 },
+    /// A datagram carrying `bytes` bytes of payload was received, including duplicated segments
+    DataReceived { bytes: usize },
+    /// Sending is blocked because the send window is full
+    WindowFull,
+    /// A datagram with `segments` retransmitted data segments was sent
+    Retransmit { segments: usize },
+    /// Client replaced its UDP socket with a new one bound to `local_addr`, see `KcpConfig::auto_rebind`
+    Rebound { local_addr: SocketAddr },
+    /// MTU of the session was changed to `mtu` by probing the path, see `KcpConfig::pmtu`
+    MtuChanged { mtu: usize },
+    /// The receive buffer of the socket keeps overflowing, `dropped` datagrams were dropped by the system in the
+    /// last few hundred milliseconds. Emitted to all sessions of the socket, for shedding load, and receive windows
+    /// are shrunk with `KcpConfig::adaptive_window`.
+    ///
+    /// Detected on Linux UDP sockets and on transports that implement `KcpTransport::dropped_datagrams`.
+    ReceiveOverload { dropped: u64 },
+    /// No datagram was dropped for a second after `ReceiveOverload`
+    ReceiveOverloadCleared,
+    /// The session was closed, no events follow
+    Closed,
+}
+
+/// Sending half of the event channel of a session
+pub struct EventSender {
+    tx: broadcast::Sender<KcpEvent>,
+}
+
+impl EventSender {
+    pub fn new() -> EventSender {
+        let (tx, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        EventSender { tx }
+    }
+
+    /// Emits `event` to all subscribers, dropped if there is none
+    pub fn emit(&self, event: KcpEvent) {
+        if self.tx.receiver_count() > 0 {
+            let _ = self.tx.send(event);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<KcpEvent> {
+        self.tx.subscribe()
+    }
+}
@@ -0,0 +1,201 @@
+//! Framing of datagrams on the wire, around the KCP segments and the frames of this crate
+//!
+//! A datagram is sealed by appending the CRC32 of `KcpConfig::checksum`, then by `KcpConfig::obfuscator`, and
+//! opened in the reverse order, before anything is parsed from it. Both are part of `KcpConfig::mtu`, KCP is
+//! given what remains of it.
+
+use std::{borrow::Cow, fmt, sync::Arc};
+
+use bytes::BytesMut;
+
+use crate::{
+    checksum::{self, CHECKSUM_LEN},
+    config::KcpConfig,
+};
+
+/// Transforms every datagram on the wire, like padding them to bucketed sizes or scrambling their headers, to make
+/// the traffic harder to fingerprint. See `KcpConfig::obfuscator`.
+///
+/// ```
+/// # use tokio_kcp::PacketObfuscator;
+/// /// Pads datagrams to a multiple of 64 bytes, the last byte tells the length of the padding
+/// struct Padding;
+///
+/// impl PacketObfuscator for Padding {
+///     fn overhead(&self) -> usize {
+///         64
+///     }
+///
+///     fn seal(&self, packet: &mut Vec<u8>) {
+///         let padding = 64 - packet.len() % 64;
+///         packet.resize(packet.len() + padding, padding as u8);
+///     }
+///
+///     fn open(&self, packet: &mut Vec<u8>) -> bool {
+///         match packet.last() {
+///             Some(&padding) if padding != 0 && padding as usize <= packet.len() && packet.len().is_multiple_of(64) => {
+///                 packet.truncate(packet.len() - padding as usize);
+///                 true
+///             }
+///             _ => false,
+///         }
+///     }
+/// }
+/// ```
+pub trait PacketObfuscator: Send + Sync {
+    /// Maximum number of bytes that `seal` adds to a datagram, it is subtracted from the MTU given to KCP
+    fn overhead(&self) -> usize;
+
+    /// Transforms a datagram before it is sent, it may grow by at most `overhead` bytes
+    fn seal(&self, packet: &mut Vec<u8>);
+
+    /// Restores a received datagram sealed by peer, returns `false` to drop it
+    fn open(&self, packet: &mut Vec<u8>) -> bool;
+}
+
+impl fmt::Debug for dyn PacketObfuscator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PacketObfuscator")
+    }
+}
+
+/// Why a received datagram was dropped by `Framing::open`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// `PacketObfuscator::open` refused it, it may not come from a peer of this crate. The datagram is left as
+    /// it was received.
+    Rejected,
+    /// Checksum didn't match
+    Corrupted,
+}
+
+/// Framing of datagrams decided by `KcpConfig`
+#[derive(Clone, Default)]
+pub struct Framing {
+    checksum: bool,
+    obfuscator: Option<Arc<dyn PacketObfuscator>>,
+}
+
+impl Framing {
+    pub fn new(config: &KcpConfig) -> Framing {
+        Framing {
+            checksum: config.checksum,
+            obfuscator: config.obfuscator.clone(),
+        }
+    }
+
+    /// Datagrams are sent as KCP produces them
+    pub fn is_plain(&self) -> bool {
+        !self.checksum && self.obfuscator.is_none()
+    }
+
+    /// Maximum number of bytes added to a datagram
+    pub fn overhead(&self) -> usize {
+        let checksum = if self.checksum { CHECKSUM_LEN } else { 0 };
+        checksum + self.obfuscator.as_ref().map_or(0, |obfuscator| obfuscator.overhead())
+    }
+
+    /// Seals the datagram in `packet` in place
+    pub fn seal_vec(&self, packet: &mut Vec<u8>) {
+        if self.checksum {
+            checksum::seal(packet);
+        }
+        if let Some(ref obfuscator) = self.obfuscator {
+            obfuscator.seal(packet);
+        }
+    }
+
+    /// `payload` as it is sent
+    pub fn seal<'a>(&self, payload: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.is_plain() {
+            return Cow::Borrowed(payload);
+        }
+        let mut packet = Vec::with_capacity(payload.len() + self.overhead());
+        packet.extend_from_slice(payload);
+        self.seal_vec(&mut packet);
+        Cow::Owned(packet)
+    }
+
+    /// Opens the received datagram in `packet` in place, `scratch` is the buffer of `PacketObfuscator::open`
+    pub fn open(&self, packet: &mut BytesMut, scratch: &mut Vec<u8>) -> Result<(), OpenError> {
+        if let Some(ref obfuscator) = self.obfuscator {
+            scratch.clear();
+            scratch.extend_from_slice(packet);
+            if !obfuscator.open(scratch) {
+                return Err(OpenError::Rejected);
+            }
+            packet.clear();
+            packet.extend_from_slice(scratch);
+        }
+        if self.checksum {
+            let len = checksum::verify(packet).ok_or(OpenError::Corrupted)?.len();
+            packet.truncate(len);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+
+    use super::{Framing, OpenError, PacketObfuscator};
+    use crate::config::KcpConfig;
+
+    /// Reverses datagrams and prepends a marker
+    struct Reverse;
+
+    impl PacketObfuscator for Reverse {
+        fn overhead(&self) -> usize {
+            1
+        }
+
+        fn seal(&self, packet: &mut Vec<u8>) {
+            packet.reverse();
+            packet.insert(0, 0xa5);
+        }
+
+        fn open(&self, packet: &mut Vec<u8>) -> bool {
+            if packet.first() != Some(&0xa5) {
+                return false;
+            }
+            packet.remove(0);
+            packet.reverse();
+            true
+        }
+    }
+
+    #[test]
+    fn framing() {
+        let framing = Framing::new(&KcpConfig::default());
+        assert!(framing.is_plain());
+        assert_eq!(framing.overhead(), 0);
+
+        let framing = Framing::new(&KcpConfig {
+            checksum: true,
+            obfuscator: Some(Arc::new(Reverse)),
+            ..Default::default()
+        });
+        assert_eq!(framing.overhead(), 5);
+
+        let sealed = framing.seal(b"HELLO");
+        assert_eq!(sealed.len(), 10);
+        assert_eq!(sealed[0], 0xa5);
+
+        let mut scratch = Vec::new();
+        let mut packet = BytesMut::from(&sealed[..]);
+        assert_eq!(framing.open(&mut packet, &mut scratch), Ok(()));
+        assert_eq!(&packet[..], b"HELLO");
+
+        // Rejected datagrams are left untouched
+        let mut packet = BytesMut::from(&b"HELLO"[..]);
+        assert_eq!(framing.open(&mut packet, &mut scratch), Err(OpenError::Rejected));
+        assert_eq!(&packet[..], b"HELLO");
+
+        let mut packet = BytesMut::from(&sealed[..]);
+        packet[3] ^= 1;
+        assert_eq!(framing.open(&mut packet, &mut scratch), Err(OpenError::Corrupted));
+    }
+}
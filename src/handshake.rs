@@ -0,0 +1,437 @@
+//! Explicit connect handshake
+//!
+//! Enabled by `KcpConfig::handshake`. Instead of sending KCP segments with conv 0 and letting the server
+//! rewrite the header, the client negotiates the conv before any KCP segment is sent:
+//!
+//! ```plain
+//! Client                          Server
+//!   SYN(token)              ->
+//!                           <-    SYN-ACK(token, conv)
+//!   ACK(token, conv)        ->
+//!   KCP segments with conv  ->
+//! ```
+//!
+//! Handshake frames are shorter than a KCP header, so they can never be mistaken for KCP segments.
+//! The server also completes a half-open handshake when the first KCP segment with the allocated conv
+//! arrives from the same peer, so a lost ACK doesn't prevent the session from being established.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    mem,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::{Buf, BufMut, BytesMut};
+use log::{debug, trace};
+use tokio::{
+    net::UdpSocket,
+    time::{self, Instant},
+};
+
+use crate::{
+    error::{KcpError, KcpResult},
+    framing::Framing,
+};
+
+const MAGIC: &[u8; 4] = b"KCPH";
+
+const FRAME_SYN: u8 = 1;
+const FRAME_SYN_ACK: u8 = 2;
+const FRAME_ACK: u8 = 3;
+
+/// Length of a handshake frame, `MAGIC` + kind + token + conv
+pub const FRAME_LEN: usize = 4 + 1 + 8 + 4;
+
+/// Timeout of the first SYN, doubled on every retransmission
+pub const SYN_INITIAL_RTO: Duration = Duration::from_millis(200);
+/// Maximum number of SYN sent before giving up
+pub const SYN_MAX_ATTEMPTS: u32 = 6;
+
+/// Maximum number of half-open handshakes from one source IP
+pub const MAX_HALF_OPEN_PER_IP: usize = 16;
+/// Half-open handshakes are forgotten after this duration
+pub const HALF_OPEN_EXPIRE: Duration = Duration::from_secs(30);
+
+/// Frames exchanged during handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFrame {
+    Syn { token: u64 },
+    SynAck { token: u64, conv: u32 },
+    Ack { token: u64, conv: u32 },
+}
+
+impl HandshakeFrame {
+    pub fn encode(&self) -> [u8; FRAME_LEN] {
+        let (kind, token, conv) = match *self {
+            HandshakeFrame::Syn { token } => (FRAME_SYN, token, 0),
+            HandshakeFrame::SynAck { token, conv } => (FRAME_SYN_ACK, token, conv),
+            HandshakeFrame::Ack { token, conv } => (FRAME_ACK, token, conv),
+        };
+
+        let mut frame = [0u8; FRAME_LEN];
+        let mut buf = &mut frame[..];
+        buf.put_slice(MAGIC);
+        buf.put_u8(kind);
+        buf.put_u64_le(token);
+        buf.put_u32_le(conv);
+        frame
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Option<HandshakeFrame> {
+        if buf.len() != FRAME_LEN || &buf[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        buf.advance(MAGIC.len());
+
+        let kind = buf.get_u8();
+        let token = buf.get_u64_le();
+        let conv = buf.get_u32_le();
+
+        match kind {
+            FRAME_SYN => Some(HandshakeFrame::Syn { token }),
+            FRAME_SYN_ACK if conv != 0 => Some(HandshakeFrame::SynAck { token, conv }),
+            FRAME_ACK if conv != 0 => Some(HandshakeFrame::Ack { token, conv }),
+            _ => None,
+        }
+    }
+}
+
+/// Client side of the handshake
+pub struct HandshakeClient {
+    token: u64,
+    attempts: u32,
+    rto: Duration,
+}
+
+impl HandshakeClient {
+    pub fn new(token: u64) -> HandshakeClient {
+        HandshakeClient {
+            token,
+            attempts: 0,
+            rto: SYN_INITIAL_RTO,
+        }
+    }
+
+    /// Next SYN to send and how long to wait for the reply.
+    ///
+    /// Returns `None` if all attempts have been used.
+    pub fn next_syn(&mut self) -> Option<(HandshakeFrame, Duration)> {
+        if self.attempts >= SYN_MAX_ATTEMPTS {
+            return None;
+        }
+
+        let rto = self.rto;
+        self.attempts += 1;
+        self.rto *= 2;
+        Some((HandshakeFrame::Syn { token: self.token }, rto))
+    }
+
+    /// Handles a frame from server, returns the ACK to send and the allocated conv if the handshake completes
+    pub fn on_frame(&self, frame: HandshakeFrame) -> Option<(HandshakeFrame, u32)> {
+        match frame {
+            HandshakeFrame::SynAck { token, conv } if token == self.token => {
+                Some((HandshakeFrame::Ack { token, conv }, conv))
+            }
+            _ => None,
+        }
+    }
+}
+
+struct HalfOpen {
+    conv: u32,
+    created: Instant,
+}
+
+/// Server side of the handshake, tracks half-open handshakes
+///
+/// A client may handshake for several sessions on one socket at the same time, half-open handshakes are identified by
+/// peer address and token.
+pub struct HandshakeServer {
+    half_open: HashMap<(SocketAddr, u64), HalfOpen>,
+    /// Token of the half-open handshake by peer address and the allocated conv
+    tokens: HashMap<(SocketAddr, u32), u64>,
+    half_open_per_ip: HashMap<IpAddr, usize>,
+    /// convs of expired half-open handshakes, which may be allocated again
+    expired: Vec<u32>,
+}
+
+impl HandshakeServer {
+    pub fn new() -> HandshakeServer {
+        HandshakeServer {
+            half_open: HashMap::new(),
+            tokens: HashMap::new(),
+            half_open_per_ip: HashMap::new(),
+            expired: Vec::new(),
+        }
+    }
+
+    /// Handles SYN from `peer_addr`, returns the SYN-ACK to reply.
+    ///
+    /// Duplicated SYNs (retransmitted because SYN-ACK was lost) are answered with the same conv.
+    pub fn on_syn<F>(
+        &mut self,
+        peer_addr: SocketAddr,
+        token: u64,
+        now: Instant,
+        alloc_conv: F,
+    ) -> Option<HandshakeFrame>
+    where
+        F: FnOnce() -> KcpResult<u32>,
+    {
+        self.expire(now);
+
+        if let Some(ho) = self.half_open.get(&(peer_addr, token)) {
+            trace!("duplicated SYN from peer: {}, conv: {}", peer_addr, ho.conv);
+            return Some(HandshakeFrame::SynAck { token, conv: ho.conv });
+        }
+
+        let count = self.half_open_per_ip.entry(peer_addr.ip()).or_insert(0);
+        if *count >= MAX_HALF_OPEN_PER_IP {
+            debug!("too many half-open handshakes from {}, SYN dropped", peer_addr.ip());
+            return None;
+        }
+
+        let conv = match alloc_conv() {
+            Ok(conv) => conv,
+            Err(err) => {
+                debug!("failed to allocate conv for peer: {}, error: {}", peer_addr, err);
+                return None;
+            }
+        };
+        *count += 1;
+
+        self.half_open
+            .insert((peer_addr, token), HalfOpen { conv, created: now });
+        self.tokens.insert((peer_addr, conv), token);
+
+        Some(HandshakeFrame::SynAck { token, conv })
+    }
+
+    /// Handles ACK from `peer_addr`, returns `true` if it completes a half-open handshake
+    pub fn on_ack(&mut self, peer_addr: SocketAddr, token: u64, conv: u32) -> bool {
+        match self.half_open.get(&(peer_addr, token)) {
+            Some(ho) if ho.conv == conv => {
+                self.remove(peer_addr, token);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Completes a half-open handshake by a KCP segment, in case the ACK was lost
+    pub fn complete(&mut self, peer_addr: SocketAddr, conv: u32) -> bool {
+        match self.tokens.get(&(peer_addr, conv)) {
+            Some(&token) => {
+                self.remove(peer_addr, token);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn remove(&mut self, peer_addr: SocketAddr, token: u64) {
+        if let Some(ho) = self.half_open.remove(&(peer_addr, token)) {
+            self.tokens.remove(&(peer_addr, ho.conv));
+            if let Entry::Occupied(mut occ) = self.half_open_per_ip.entry(peer_addr.ip()) {
+                *occ.get_mut() -= 1;
+                if *occ.get() == 0 {
+                    occ.remove();
+                }
+            }
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let expired = self
+            .half_open
+            .iter()
+            .filter(|(_, ho)| now.saturating_duration_since(ho.created) > HALF_OPEN_EXPIRE)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for (addr, token) in expired {
+            trace!("half-open handshake from {} expired", addr);
+            if let Some(ho) = self.half_open.get(&(addr, token)) {
+                self.expired.push(ho.conv);
+            }
+            self.remove(addr, token);
+        }
+    }
+
+    /// convs of half-open handshakes that expired since the last call
+    pub fn take_expired(&mut self) -> Vec<u32> {
+        mem::take(&mut self.expired)
+    }
+}
+
+/// Performs the client side handshake on `udp` connected to `addr`, returns the conv allocated by server.
+///
+/// Frames are sealed and opened by `framing`, like all other datagrams.
+pub async fn connect(udp: &UdpSocket, addr: SocketAddr, token: u64, framing: &Framing) -> KcpResult<u32> {
+    let mut client = HandshakeClient::new(token);
+    let mut buf = BytesMut::with_capacity(FRAME_LEN + framing.overhead() + 1);
+    let mut framing_buffer = Vec::new();
+
+    while let Some((syn, rto)) = client.next_syn() {
+        udp.send(&framing.seal(&syn.encode())).await?;
+        trace!("[HANDSHAKE] sent SYN to {}, waiting {:?}", addr, rto);
+
+        let deadline = time::Instant::now() + rto;
+        loop {
+            // Fails with `ConnectionRefused` if the host of server reported the port unreachable
+            buf.clear();
+            match time::timeout_at(deadline, udp.recv_buf(&mut buf)).await {
+                Ok(r) => r?,
+                Err(..) => break,
+            };
+
+            if framing.open(&mut buf, &mut framing_buffer).is_err() {
+                continue;
+            }
+            let frame = match HandshakeFrame::decode(&buf) {
+                Some(f) => f,
+                None => continue,
+            };
+
+            if let Some((ack, conv)) = client.on_frame(frame) {
+                udp.send(&framing.seal(&ack.encode())).await?;
+                trace!("[HANDSHAKE] established with {}, conv: {}", addr, conv);
+                return Ok(conv);
+            }
+        }
+    }
+
+    debug!("[HANDSHAKE] no response from {}, timed out", addr);
+    Err(KcpError::Timeout)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{HandshakeClient, HandshakeFrame, HandshakeServer, HALF_OPEN_EXPIRE, MAX_HALF_OPEN_PER_IP};
+
+    #[test]
+    fn handshake_frame_codec() {
+        let frames = [
+            HandshakeFrame::Syn { token: 0x1234 },
+            HandshakeFrame::SynAck {
+                token: 0x1234,
+                conv: 10,
+            },
+            HandshakeFrame::Ack {
+                token: 0x1234,
+                conv: 10,
+            },
+        ];
+
+        for frame in &frames {
+            assert_eq!(HandshakeFrame::decode(&frame.encode()), Some(*frame));
+        }
+
+        // KCP segments are never decoded as handshake frames
+        assert_eq!(HandshakeFrame::decode(&[0u8; 24]), None);
+    }
+
+    #[test]
+    fn handshake_lost_syn() {
+        let mut client = HandshakeClient::new(1);
+
+        let (syn1, rto1) = client.next_syn().unwrap();
+        // syn1 lost, timed out
+        let (syn2, rto2) = client.next_syn().unwrap();
+        assert_eq!(syn1, syn2);
+        assert_eq!(rto2, rto1 * 2);
+
+        let mut server = HandshakeServer::new();
+        let peer = "127.0.0.1:1000".parse().unwrap();
+        let token = match syn2 {
+            HandshakeFrame::Syn { token } => token,
+            _ => unreachable!(),
+        };
+        let syn_ack = server.on_syn(peer, token, Instant::now(), || Ok(10)).unwrap();
+
+        let (ack, conv) = client.on_frame(syn_ack).unwrap();
+        assert_eq!(conv, 10);
+        assert_eq!(ack, HandshakeFrame::Ack { token: 1, conv: 10 });
+        assert!(server.on_ack(peer, 1, 10));
+
+        // Gives up eventually
+        while client.next_syn().is_some() {}
+    }
+
+    #[test]
+    fn handshake_lost_syn_ack() {
+        let mut client = HandshakeClient::new(1);
+        let mut server = HandshakeServer::new();
+        let peer = "127.0.0.1:1000".parse().unwrap();
+        let now = Instant::now();
+
+        client.next_syn().unwrap();
+        let _lost = server.on_syn(peer, 1, now, || Ok(10)).unwrap();
+
+        // Retransmitted SYN gets the same conv
+        client.next_syn().unwrap();
+        let syn_ack = server.on_syn(peer, 1, now, || panic!("conv allocated twice")).unwrap();
+        let (_lost_ack, conv) = client.on_frame(syn_ack).unwrap();
+        assert_eq!(conv, 10);
+
+        // ACK lost, completed by the first KCP segment
+        assert!(!server.complete(peer, 11));
+        assert!(server.complete(peer, 10));
+        assert!(!server.on_ack(peer, 1, 10));
+    }
+
+    #[test]
+    fn handshake_duplicated_syn() {
+        let mut server = HandshakeServer::new();
+        let peer = "127.0.0.1:1000".parse().unwrap();
+        let now = Instant::now();
+
+        let mut next_conv = 10;
+        let mut alloc = || {
+            next_conv += 1;
+            Ok(next_conv)
+        };
+
+        let first = server.on_syn(peer, 1, now, &mut alloc).unwrap();
+        let second = server.on_syn(peer, 1, now, &mut alloc).unwrap();
+        assert_eq!(first, second);
+
+        // SYN-ACK with another token is ignored by client
+        let client = HandshakeClient::new(2);
+        assert!(client.on_frame(first).is_none());
+
+        // New token from the same peer is another handshake, for another session on the same socket
+        let third = server.on_syn(peer, 2, now, &mut alloc).unwrap();
+        assert_eq!(third, HandshakeFrame::SynAck { token: 2, conv: 12 });
+        assert!(!server.on_ack(peer, 2, 11));
+        assert!(server.on_ack(peer, 2, 12));
+        assert!(server.on_ack(peer, 1, 11));
+    }
+
+    #[test]
+    fn handshake_half_open_limit() {
+        let mut server = HandshakeServer::new();
+        let now = Instant::now();
+
+        for port in 0..MAX_HALF_OPEN_PER_IP {
+            let peer = format!("127.0.0.1:{}", 1000 + port).parse().unwrap();
+            assert!(server.on_syn(peer, 1, now, || Ok(10)).is_some());
+        }
+
+        let peer = "127.0.0.1:2000".parse().unwrap();
+        assert!(server.on_syn(peer, 1, now, || Ok(10)).is_none());
+
+        // Other IPs are not affected
+        let other = "127.0.0.2:2000".parse().unwrap();
+        assert!(server.on_syn(other, 1, now, || Ok(10)).is_some());
+
+        // Expired half-open handshakes make room again
+        let later = now + HALF_OPEN_EXPIRE + Duration::from_secs(1);
+        assert!(server.on_syn(peer, 1, later, || Ok(10)).is_some());
+    }
+}
@@ -1,14 +1,74 @@
 //! Library of KCP on Tokio
 
+#[cfg(feature = "debug-internals")]
+pub use self::debug::{KcpDebugSegment, KcpDebugState};
+#[cfg(feature = "testing")]
+pub use self::memory::{MemoryNetwork, MemoryTransport};
+#[cfg(feature = "bytes")]
+pub use self::message::KcpMessageStream;
+#[cfg(feature = "testing")]
+pub use self::netem::{NetEmConfig, NetEmTransport};
+#[cfg(feature = "socks5")]
+pub use self::socks5::Socks5Auth;
 pub use self::{
-    config::{KcpConfig, KcpNoDelayConfig},
+    broadcast::{BroadcastOutcome, BroadcastPolicy, BroadcastResult},
+    client::KcpClient,
+    config::{
+        ConvAllocator, ConvAllocatorFactory, DispatchMode, FlushStrategy, KcpConfig, KcpNoDelayConfig, PmtuConfig,
+        RetryConfig, SequentialConvAllocator, CONV_ALLOC_MAX_ATTEMPTS,
+    },
+    congestion::{CongestionController, CongestionMode, CongestionStats, ControllerFactory},
+    diagnostics::{EffectiveConfig, SegmentDiagnostics, SessionDiagnostics, SessionInfo},
+    endpoint::KcpEndpoint,
+    error::{KcpError, KcpResult},
+    event::KcpEvent,
+    framing::PacketObfuscator,
     listener::KcpListener,
+    pacing::{PacingConfig, PacingStats},
+    pool::{BufferPoolConfig, BufferPoolStats},
+    recv_queue::RecvQueueLen,
+    split::{OwnedReadHalf, OwnedWriteHalf},
     stream::KcpStream,
+    transport::KcpTransport,
 };
 
+mod bandwidth;
+mod broadcast;
+mod checksum;
+mod client;
 mod config;
+mod congestion;
+#[cfg(feature = "connect")]
+pub mod connect;
+#[cfg(feature = "debug-internals")]
+mod debug;
+mod diagnostics;
+mod driver;
+mod endpoint;
+mod error;
+mod event;
+mod framing;
+mod handshake;
 mod listener;
+#[cfg(any(test, feature = "testing"))]
+mod memory;
+#[cfg(feature = "bytes")]
+mod message;
+mod migration;
+#[cfg(any(test, feature = "testing"))]
+mod netem;
+mod overload;
+mod pacing;
+mod pmtu;
+mod pool;
+mod recv_queue;
+#[cfg(feature = "axum")]
+pub mod serve;
 mod session;
 mod skcp;
+#[cfg(feature = "socks5")]
+mod socks5;
+mod split;
 mod stream;
+mod transport;
 mod utils;
@@ -1,150 +1,957 @@
 use std::{
-    io::{self, ErrorKind},
+    any::Any,
+    io, mem,
     net::SocketAddr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
 use byte_string::ByteStr;
-use kcp::{Error as KcpError, KcpResult};
 use log::{debug, error, trace};
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
-    sync::mpsc,
-    task::JoinHandle,
-    time,
+    sync::{mpsc, oneshot},
+    time::{self, Instant, MissedTickBehavior},
 };
 
-use crate::{config::KcpConfig, session::KcpSessionManager, stream::KcpStream};
+use crate::{
+    broadcast::{self, BroadcastPolicy, BroadcastResult},
+    config::KcpConfig,
+    diagnostics::{SessionDiagnostics, SessionInfo},
+    error::{KcpError, KcpResult},
+    framing::{Framing, OpenError},
+    handshake::{HandshakeFrame, HandshakeServer},
+    migration::MigrationFrame,
+    overload::{OverloadMonitor, OVERLOAD_SAMPLE_INTERVAL},
+    pmtu::PmtuFrame,
+    pool::{BufferPool, BufferPoolStats},
+    session::{KcpSession, KcpSessionManager, SessionKey},
+    skcp::{fin_segment, is_close_segment, is_datagram_segment, is_kcp_packet},
+    stream::KcpStream,
+    transport::{self, Endpoint, KcpTransport},
+    utils::bind_on,
+};
 
-pub struct KcpListener {
-    udp: Arc<UdpSocket>,
-    accept_rx: mpsc::Receiver<(KcpStream, SocketAddr)>,
-    task_watcher: JoinHandle<()>,
+/// Requests served by the listener task, which owns all sessions
+enum ListenerCommand {
+    SessionCount(oneshot::Sender<usize>),
+    Peers(oneshot::Sender<Vec<(u32, SocketAddr)>>),
+    Sessions(oneshot::Sender<Vec<Arc<KcpSession>>>),
+    LiveSessions(oneshot::Sender<Vec<(u32, Arc<KcpSession>)>>),
+    RefusedSessions(oneshot::Sender<u64>),
+    FilteredPackets(oneshot::Sender<u64>),
+    CorruptedPackets(oneshot::Sender<u64>),
+    CloseSession(u32, oneshot::Sender<bool>),
+    ClosePeer(SocketAddr, oneshot::Sender<bool>),
+    PrepareSession(u32, SocketAddr, oneshot::Sender<KcpResult<KcpStream>>),
+    /// Replaces the receiver of datagrams that are not KCP
+    RawDatagrams(mpsc::Sender<(Vec<u8>, SocketAddr)>, oneshot::Sender<()>),
+    /// Stops accepting, resets sessions that are still open at the deadline, replies when all are closed
+    Shutdown(Instant, oneshot::Sender<()>),
+}
+
+/// Decides whether a peer may open a session, by its source address
+type AcceptFilterFn = Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>;
+
+/// Datagrams that are not KCP waiting in the channel of `raw_datagrams`, more are dropped
+const RAW_DATAGRAM_BACKLOG: usize = 64;
+
+/// Minimum interval of logging packets dropped by the accept filter
+const FILTER_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Accept filter of the listener, counts what it drops
+struct AcceptFilter {
+    filter: Option<AcceptFilterFn>,
+    dropped: u64,
+    /// Dropped packets that haven't been logged, and the last time they were
+    unlogged: u64,
+    last_log: Option<Instant>,
 }
 
-impl Drop for KcpListener {
-    fn drop(&mut self) {
-        self.task_watcher.abort();
+impl AcceptFilter {
+    fn new(filter: Option<AcceptFilterFn>) -> AcceptFilter {
+        AcceptFilter {
+            filter,
+            dropped: 0,
+            unlogged: 0,
+            last_log: None,
+        }
+    }
+
+    /// Checks a packet that would open a session, it should be dropped silently if `false`
+    fn accepts(&mut self, peer_addr: SocketAddr) -> bool {
+        let filter = match self.filter {
+            Some(ref filter) => filter,
+            None => return true,
+        };
+        if filter(peer_addr) {
+            return true;
+        }
+
+        self.dropped += 1;
+        self.unlogged += 1;
+        let now = Instant::now();
+        if self.last_log.is_none_or(|t| now - t >= FILTER_LOG_INTERVAL) {
+            debug!(
+                "dropped {} packets rejected by accept filter, last peer: {}",
+                self.unlogged, peer_addr
+            );
+            self.unlogged = 0;
+            self.last_log = Some(now);
+        }
+        false
     }
 }
 
+/// Listener of KCP sessions on a UDP socket.
+///
+/// Dropping the listener stops accepting. Sessions of streams that are still in use keep running until the streams
+/// are dropped, the others are reset immediately, and the socket is closed after the last session is gone.
+///
+/// A session whose accepted stream is dropped discards data it receives, and is removed once its sent data is
+/// delivered. Packets of it are answered with FIN for a while after that, instead of opening a new session, so
+/// a client that keeps sending learns about the close.
+///
+/// All packets are received by one task. Receiving consumes the coop budget of tokio, so the task yields to other
+/// tasks on its worker under a flood of packets, instead of starving them.
+pub struct KcpListener {
+    endpoint: Endpoint,
+    accept_rx: mpsc::Receiver<(KcpStream, SocketAddr)>,
+    command_tx: mpsc::Sender<ListenerCommand>,
+    buffer_pool: Arc<BufferPool>,
+    /// Why the listener task stopped, if it failed
+    failure: Arc<Mutex<Option<Arc<io::Error>>>>,
+}
+
 impl KcpListener {
     pub async fn bind<A: ToSocketAddrs>(config: KcpConfig, addr: A) -> KcpResult<KcpListener> {
-        let udp = UdpSocket::bind(addr).await?;
-        let udp = Arc::new(udp);
-        let server_udp = udp.clone();
+        config.validate()?;
+        let udp = bind_on(addr, &config.socket_options()).await?;
+        Ok(KcpListener::from_udp(config, udp, None))
+    }
+
+    /// Binds a listener that only opens sessions for peers accepted by `filter`.
+    ///
+    /// `filter` is called with the source address of packets that would open a session, like a packet with
+    /// conv 0 or a handshake SYN. Packets it rejects are dropped without any reply, and counted by
+    /// `filtered_packets`. Packets of existing sessions are not checked again.
+    ///
+    /// ```no_run
+    /// # use std::net::IpAddr;
+    /// # use tokio_kcp::{KcpConfig, KcpListener};
+    /// # async fn run() {
+    /// let peer: IpAddr = "203.0.113.7".parse().unwrap();
+    /// let listener = KcpListener::bind_with_filter(KcpConfig::default(), "0.0.0.0:3100", move |addr| addr.ip() == peer)
+    ///     .await
+    ///     .unwrap();
+    /// # }
+    /// ```
+    pub async fn bind_with_filter<A, F>(config: KcpConfig, addr: A, filter: F) -> KcpResult<KcpListener>
+    where
+        A: ToSocketAddrs,
+        F: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        config.validate()?;
+        let udp = bind_on(addr, &config.socket_options()).await?;
+        Ok(KcpListener::from_udp(config, udp, Some(Arc::new(filter))))
+    }
+
+    /// Binds a listener with `SO_REUSEPORT` enabled, so that multiple listeners can share the same address.
+    ///
+    /// The kernel load-balances incoming datagrams between all sockets bound on the same address,
+    /// which allows scaling a server across cores by running N listeners on N threads (or tasks
+    /// on a multi-threaded runtime):
+    ///
+    /// ```no_run
+    /// # use tokio_kcp::{KcpConfig, KcpListener};
+    /// # async fn run() {
+    /// let addr = "0.0.0.0:3100".parse().unwrap();
+    /// for _ in 0..4 {
+    ///     let mut listener = KcpListener::bind_reuseport(KcpConfig::default(), addr).await.unwrap();
+    ///     tokio::spawn(async move {
+    ///         while let Ok((stream, peer_addr)) = listener.accept().await {
+    ///             // ...
+    ///         }
+    ///     });
+    /// }
+    /// # }
+    /// ```
+    ///
+    /// Each listener owns its own sessions. On Linux the socket for a datagram is chosen by hashing
+    /// its 4-tuple (source and destination address and port), so all packets of a session are delivered
+    /// to the same listener, as long as the client keeps its address. Sessions are lost if the set of
+    /// listeners sharing the address changes (a listener is dropped or a new one is bound), because
+    /// the hash is rebalanced. Other platforms (like BSDs and macOS) may not balance by 4-tuple,
+    /// so this is only useful with a single listener there.
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    pub async fn bind_reuseport(config: KcpConfig, addr: SocketAddr) -> KcpResult<KcpListener> {
+        config.validate()?;
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_port(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+
+        let udp = UdpSocket::from_std(socket.into())?;
+        config.socket_options().apply(&udp)?;
+        Ok(KcpListener::from_udp(config, udp, None))
+    }
+
+    /// Creates a listener that receives datagrams from `transport`, instead of binding a UDP socket.
+    ///
+    /// For other carriers of datagrams than UDP, or `MemoryTransport` in tests. Socket options of `config` don't
+    /// apply, and `get_ref` returns `None`, the listener has no UDP socket.
+    pub fn with_transport(config: KcpConfig, transport: Arc<dyn KcpTransport>) -> KcpResult<KcpListener> {
+        config.validate()?;
+        Ok(KcpListener::from_endpoint(config, Endpoint::Custom(transport), None))
+    }
+
+    fn from_udp(config: KcpConfig, udp: UdpSocket, filter: Option<AcceptFilterFn>) -> KcpListener {
+        KcpListener::from_endpoint(config, Endpoint::Udp(Arc::new(udp)), filter)
+    }
+
+    fn from_endpoint(config: KcpConfig, endpoint: Endpoint, filter: Option<AcceptFilterFn>) -> KcpListener {
+        let server_endpoint = endpoint.clone();
+
+        let buffer_pool = BufferPool::new(&config.buffer_pool_config());
+        let server_buffer_pool = buffer_pool.clone();
 
         let (accept_tx, accept_rx) = mpsc::channel(1024 /* backlogs */);
-        let task_watcher = tokio::spawn(async move {
+        let (command_tx, mut command_rx) = mpsc::channel(16);
+
+        let failure = Arc::new(Mutex::new(None));
+        let task_failure = failure.clone();
+        // Keeps `accept` waiting until the failure is recorded
+        let task_accept_tx = accept_tx.clone();
+
+        // Stops accepting new sessions after the listener is dropped, and keeps serving
+        // the existing ones until all of them are closed
+        let task = tokio::spawn(async move {
             let (close_tx, mut close_rx) = mpsc::channel(64);
+            let mut shutdown = false;
+            let mut dropped = false;
+            let mut shutdown_deadline = None;
+            let mut shutdown_waiters = Vec::new();
 
-            let mut sessions = KcpSessionManager::new();
-            let mut packet_buffer = [0u8; 65536];
+            let mut sessions =
+                KcpSessionManager::new(buffer_pool.clone(), config.max_sessions, config.conv_allocator.clone());
+            let mut handshake = HandshakeServer::new();
+            let mut filter = AcceptFilter::new(filter);
+            let mut raw_tx: Option<mpsc::Sender<(Vec<u8>, SocketAddr)>> = None;
+            let framing = Framing::new(&config);
+            let mut framing_buffer = Vec::new();
+            // Datagrams dropped by `KcpConfig::checksum`
+            let mut corrupted = 0;
+            // Buffer of the next received packet, which is handed over to a session without copying.
+            // It returns to the pool after the session has processed it.
+            let mut packet = buffer_pool.get();
+            // Drops of the receive buffer, `None` if the transport doesn't count them
+            let mut overload = OverloadMonitor::new(endpoint.transport());
+            let mut overload_timer = time::interval(OVERLOAD_SAMPLE_INTERVAL);
+            overload_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
             loop {
+                packet.clear();
+
                 tokio::select! {
-                    conv = close_rx.recv() => {
-                        let conv = conv.expect("close_tx closed unexpectly");
-                        sessions.close_conv(conv);
-                        trace!("session conv: {} removed", conv);
+
+                    _ = time::sleep_until(shutdown_deadline.unwrap_or_else(Instant::now)), if shutdown_deadline.is_some() => {
+                        debug!("listener shutdown timed out, resetting {} sessions", sessions.len());
+                        shutdown_deadline = None;
+                        for session in sessions.sessions() {
+                            session.reset();
+                        }
+                    }
+
+                    _ = overload_timer.tick(), if overload.is_some() => {
+                        let now = Instant::now();
+                        if let Some(event) = overload.as_mut().and_then(|monitor| monitor.poll(endpoint.transport(), now)) {
+                            debug!("listener {:?}, notifying {} sessions", event, sessions.len());
+                            for session in sessions.sessions() {
+                                session.set_receive_overload(event);
+                            }
+                        }
+                    }
+
+                    key = close_rx.recv() => {
+                        let (conv, peer_addr) = match key {
+                            Some(key) => key,
+                            None => return Err(io::Error::other("close channel of sessions closed")),
+                        };
+                        sessions.close_conv(conv, peer_addr);
+                        trace!("session conv: {} removed, peer: {}", conv, peer_addr);
+                    }
+
+                    command = command_rx.recv(), if !dropped => {
+                        match command {
+                            None => {
+                                // Sessions of streams in use are still served, the others are gone with the listener
+                                sessions.cancel_closed();
+                                debug!("listener dropped, waiting for {} sessions to close", sessions.len());
+                                dropped = true;
+                                shutdown = true;
+                            }
+                            Some(command) => match command {
+                                ListenerCommand::SessionCount(tx) => {
+                                    let _ = tx.send(sessions.len());
+                                }
+                                ListenerCommand::Peers(tx) => {
+                                    let _ = tx.send(sessions.peers());
+                                }
+                                ListenerCommand::Sessions(tx) => {
+                                    let _ = tx.send(sessions.sessions());
+                                }
+                                ListenerCommand::LiveSessions(tx) => {
+                                    let _ = tx.send(sessions.live_sessions());
+                                }
+                                ListenerCommand::RefusedSessions(tx) => {
+                                    let _ = tx.send(sessions.refused());
+                                }
+                                ListenerCommand::FilteredPackets(tx) => {
+                                    let _ = tx.send(filter.dropped);
+                                }
+                                ListenerCommand::CorruptedPackets(tx) => {
+                                    let _ = tx.send(corrupted);
+                                }
+                                ListenerCommand::CloseSession(conv, tx) => {
+                                    let _ = tx.send(close_sessions(sessions.get_all(conv)));
+                                }
+                                ListenerCommand::ClosePeer(peer_addr, tx) => {
+                                    let _ = tx.send(close_sessions(sessions.get_by_peer(peer_addr)));
+                                }
+                                ListenerCommand::PrepareSession(conv, peer_addr, tx) => {
+                                    let result = if shutdown {
+                                        Err(KcpError::ListenerClosed(None))
+                                    } else if sessions.get(conv, peer_addr).is_some() {
+                                        Err(io::Error::new(
+                                            io::ErrorKind::AlreadyExists,
+                                            format!("session of conv {} already exists, peer: {}", conv, peer_addr),
+                                        )
+                                        .into())
+                                    } else {
+                                        sessions
+                                            .get_or_create(&config, conv, &endpoint, peer_addr, &close_tx)
+                                            .map(|(session, _)| KcpStream::with_session(session))
+                                    };
+                                    if result.is_ok() {
+                                        debug!("session conv: {} prepared, peer: {}", conv, peer_addr);
+                                    }
+                                    let _ = tx.send(result);
+                                }
+                                ListenerCommand::RawDatagrams(raw, tx) => {
+                                    raw_tx = Some(raw);
+                                    let _ = tx.send(());
+                                }
+                                ListenerCommand::Shutdown(deadline, tx) => {
+                                    debug!("listener shutting down, waiting for {} sessions to close", sessions.len());
+                                    shutdown = true;
+                                    if shutdown_deadline.is_none_or(|d| deadline < d) {
+                                        shutdown_deadline = Some(deadline);
+                                    }
+                                    shutdown_waiters.push(tx);
+                                }
+                            },
+                        }
                     }
 
-                    recv_res = udp.recv_from(&mut packet_buffer) => {
+                    recv_res = transport::recv_buf_from(endpoint.transport(), &mut *packet) => {
                         match recv_res {
+                            Err(err) if is_fatal_recv_error(&err) => return Err(err),
                             Err(err) => {
                                 error!("udp.recv_from failed, error: {}", err);
                                 time::sleep(Duration::from_secs(1)).await;
                             }
                             Ok((n, peer_addr)) => {
-                                let packet = &mut packet_buffer[..n];
+                                log::trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(&packet));
+
+                                if !framing.is_plain() {
+                                    if let Err(err) = framing.open(&mut packet, &mut framing_buffer) {
+                                        // Datagrams that are not KCP are neither checksummed nor obfuscated
+                                        let is_raw = err == OpenError::Rejected
+                                            || (config.obfuscator.is_none() && !is_kcp_packet(&packet));
+                                        match raw_tx {
+                                            Some(ref raw) if is_raw => {
+                                                if raw.try_send((packet.to_vec(), peer_addr)).is_err() {
+                                                    trace!("raw datagram dropped, {} bytes, peer: {}", n, peer_addr);
+                                                }
+                                            }
+                                            _ if err == OpenError::Corrupted => {
+                                                corrupted += 1;
+                                                trace!("checksum mismatch, {} bytes dropped, peer: {}", n, peer_addr);
+                                            }
+                                            _ => trace!("datagram refused by obfuscator, {} bytes, peer: {}", n, peer_addr),
+                                        }
+                                        continue;
+                                    }
+                                }
+
+                                if config.handshake {
+                                    if let Some(frame) = HandshakeFrame::decode(&packet) {
+                                        match frame {
+                                            HandshakeFrame::Syn { token } => {
+                                                if shutdown || !filter.accepts(peer_addr) {
+                                                    continue;
+                                                }
+                                                let syn_ack = handshake.on_syn(peer_addr, token, Instant::now(), || sessions.alloc_conv(peer_addr));
+                                                for conv in handshake.take_expired() {
+                                                    sessions.release_conv(conv);
+                                                }
+                                                if let Some(syn_ack) = syn_ack {
+                                                    if let Err(err) = transport::send_to(endpoint.transport(), &framing.seal(&syn_ack.encode()), peer_addr).await {
+                                                        error!("failed to send SYN-ACK, peer: {}, error: {}", peer_addr, err);
+                                                    }
+                                                }
+                                            }
+                                            HandshakeFrame::Ack { token, conv } => {
+                                                if !shutdown && handshake.on_ack(peer_addr, token, conv) {
+                                                    debug!("handshake completed, conv: {}, peer: {}", conv, peer_addr);
+                                                    let _ = open_session(&mut sessions, &config, conv, &endpoint, peer_addr, &close_tx, &accept_tx);
+                                                }
+                                            }
+                                            HandshakeFrame::SynAck { .. } => {}
+                                        }
+                                        continue;
+                                    }
+                                }
 
-                                log::trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(packet));
+                                if config.enable_migration {
+                                    if let Some(frame) = MigrationFrame::decode(&packet) {
+                                        match frame {
+                                            MigrationFrame::Resume { conv, token } => {
+                                                if !sessions.resume(conv, &token, peer_addr) {
+                                                    trace!("invalid resumption token, conv: {}, peer: {}", conv, peer_addr);
+                                                }
+                                            }
+                                            MigrationFrame::Token { .. } | MigrationFrame::Challenge { .. } => {}
+                                        }
+                                        continue;
+                                    }
+                                }
 
-                                let mut conv = kcp::get_conv(packet);
-                                if conv == 0 {
-                                    // Allocate a conv for client.
-                                    conv = sessions.alloc_conv();
-                                    debug!("allocate {} conv for peer: {}", conv, peer_addr);
+                                if let Some(frame) = PmtuFrame::decode(&packet) {
+                                    // Never reflected to addresses without a session
+                                    if let PmtuFrame::Probe { conv, seq, size } = frame {
+                                        if sessions.get(conv, peer_addr).is_some() {
+                                            let ack = PmtuFrame::Ack { conv, seq, size }.encode();
+                                            if let Err(err) = transport::send_to(endpoint.transport(), &framing.seal(&ack), peer_addr).await {
+                                                error!("failed to send PROBE-ACK, peer: {}, error: {}", peer_addr, err);
+                                            }
+                                        }
+                                    }
+                                    continue;
+                                }
+
+                                if !is_kcp_packet(&packet) {
+                                    match raw_tx {
+                                        Some(ref raw) => {
+                                            if raw.try_send((packet.to_vec(), peer_addr)).is_err() {
+                                                trace!("raw datagram dropped, {} bytes, peer: {}", n, peer_addr);
+                                            }
+                                        }
+                                        None => trace!("not a KCP packet, {} bytes, peer: {}", n, peer_addr),
+                                    }
+                                    continue;
+                                }
 
-                                    kcp::set_conv(packet, conv);
+                                let mut conv = kcp::get_conv(&packet);
+                                if config.enable_migration && conv != 0 && sessions.get(conv, peer_addr).is_none() && sessions.contains_conv(conv) {
+                                    // Packets from a new address are dropped until it proves ownership of the session
+                                    trace!("conv: {} from new address {}, challenging", conv, peer_addr);
+                                    let challenge = MigrationFrame::Challenge { conv }.encode();
+                                    if let Err(err) = transport::send_to(endpoint.transport(), &framing.seal(&challenge), peer_addr).await {
+                                        error!("failed to send CHALLENGE, peer: {}, error: {}", peer_addr, err);
+                                    }
+                                    continue;
                                 }
 
-                                let session = match sessions.get_or_create(&config, conv, &udp, peer_addr, &close_tx) {
-                                    Ok((s, created)) => {
-                                        if created {
-                                            // Created a new session, constructed a new accepted client
-                                            let stream = KcpStream::with_session(s.clone());
-                                            if let Err(..) = accept_tx.try_send((stream, peer_addr)) {
-                                                debug!("failed to create accepted stream due to channel failure");
+                                if is_close_segment(&packet) || is_datagram_segment(&packet) {
+                                    // Never opens a session, it may arrive after the session was removed
+                                    match sessions.get(conv, peer_addr) {
+                                        Some(session) => session.input(mem::replace(&mut packet, buffer_pool.get())).await,
+                                        None => trace!("close segment or datagram with unknown conv: {}, peer: {}", conv, peer_addr),
+                                    }
+                                    continue;
+                                }
 
-                                                // remove it from session
-                                                sessions.close_conv(conv);
-                                                continue;
+                                if sessions.get(conv, peer_addr).is_none() {
+                                    if shutdown {
+                                        trace!("listener shut down, packet with conv: {} refused, peer: {}", conv, peer_addr);
+                                        if conv == 0 && !config.handshake {
+                                            // Client fails immediately instead of waiting for its dead link
+                                            if let Err(err) = transport::send_to(endpoint.transport(), &framing.seal(&fin_segment(0)), peer_addr).await {
+                                                error!("failed to send FIN, peer: {}, error: {}", peer_addr, err);
                                             }
                                         }
+                                        continue;
+                                    }
+                                    if !filter.accepts(peer_addr) {
+                                        continue;
+                                    }
+                                }
 
-                                        s
-                                    },
-                                    Err(err) => {
-                                        error!("failed to create session, error: {}, peer: {}, conv: {}", err, peer_addr, conv);
+                                if config.handshake {
+                                    // Sessions are only created by handshake
+                                    if sessions.get(conv, peer_addr).is_none() && (conv == 0 || !handshake.complete(peer_addr, conv)) {
+                                        trace!("packet with unknown conv: {}, peer: {}", conv, peer_addr);
                                         continue;
                                     }
+                                } else if conv == 0 {
+                                    // Allocate a conv for client.
+                                    conv = match sessions.alloc_conv_for(peer_addr) {
+                                        Ok(conv) => conv,
+                                        Err(err) => {
+                                            // Client fails immediately instead of waiting for its dead link
+                                            trace!("failed to allocate conv for peer: {}, error: {}", peer_addr, err);
+                                            if let Err(err) = transport::send_to(endpoint.transport(), &framing.seal(&fin_segment(0)), peer_addr).await {
+                                                error!("failed to send FIN, peer: {}, error: {}", peer_addr, err);
+                                            }
+                                            continue;
+                                        }
+                                    };
+                                    debug!("allocate {} conv for peer: {}", conv, peer_addr);
+
+                                    kcp::set_conv(&mut packet, conv);
+                                } else {
+                                    sessions.conv_learnt(peer_addr, conv);
+                                }
+
+                                if sessions.get(conv, peer_addr).is_none() && sessions.is_recently_closed(conv, peer_addr) {
+                                    // Stream was dropped, peer may not know it yet and keeps sending
+                                    trace!("packet of closed session, conv: {}, peer: {}", conv, peer_addr);
+                                    if let Err(err) = transport::send_to(endpoint.transport(), &framing.seal(&fin_segment(conv)), peer_addr).await {
+                                        error!("failed to send FIN, peer: {}, error: {}", peer_addr, err);
+                                    }
+                                    continue;
+                                }
+
+                                let session = match open_session(&mut sessions, &config, conv, &endpoint, peer_addr, &close_tx, &accept_tx) {
+                                    Some(s) => s,
+                                    None => continue,
                                 };
 
-                                // let mut kcp = session.kcp_socket().lock().await;
-                                // if let Err(err) = kcp.input(packet) {
-                                //     error!("kcp.input failed, peer: {}, conv: {}, error: {}, packet: {:?}", peer_addr, conv, err, ByteStr::new(packet));
-                                // }
-                                session.input(packet).await;
+                                session.input(mem::replace(&mut packet, buffer_pool.get())).await;
                             }
                         }
                     }
                 }
+
+                if shutdown && sessions.len() == 0 {
+                    trace!("all sessions closed, listener stopped");
+                    for tx in shutdown_waiters {
+                        let _ = tx.send(());
+                    }
+                    return Ok(());
+                }
             }
         });
 
-        Ok(KcpListener {
-            udp: server_udp,
+        tokio::spawn(async move {
+            let cause = match task.await {
+                Ok(Ok(())) => return,
+                Ok(Err(err)) => err,
+                // Runtime is shutting down
+                Err(err) if err.is_cancelled() => return,
+                Err(err) => {
+                    let panic = err.into_panic();
+                    io::Error::other(format!("listener task panicked, {}", panic_message(&*panic)))
+                }
+            };
+            error!("listener task failed, error: {}", cause);
+            *task_failure.lock().unwrap() = Some(Arc::new(cause));
+            drop(task_accept_tx);
+        });
+
+        KcpListener {
+            endpoint: server_endpoint,
             accept_rx,
-            task_watcher,
-        })
+            command_tx,
+            buffer_pool: server_buffer_pool,
+            failure,
+        }
     }
 
+    /// `ListenerClosed` with the cause of the failure of the listener task, if it failed
+    fn closed_error(&self) -> KcpError {
+        KcpError::ListenerClosed(self.failure.lock().unwrap().clone())
+    }
+
+    /// Accepts a new connection, fails with `ListenerClosed` after `shutdown`.
+    ///
+    /// If the task of the listener failed, like a panic of the accept filter or a fatal error of the socket,
+    /// `ListenerClosed` carries the cause, and so do all the following calls.
     pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
         match self.accept_rx.recv().await {
             Some(s) => Ok(s),
-            None => Err(KcpError::IoError(io::Error::new(
-                ErrorKind::Other,
-                "accept channel closed unexpectly",
-            ))),
+            None => Err(self.closed_error()),
+        }
+    }
+
+    /// Accepts a new connection, or returns `Ok(None)` if there is none within `timeout`.
+    ///
+    /// Connections arriving after the timeout are returned by the next call.
+    pub async fn accept_timeout(&mut self, timeout: Duration) -> KcpResult<Option<(KcpStream, SocketAddr)>> {
+        // Receiving from the channel is cancel safe, nothing is lost on timeout
+        match time::timeout(timeout, self.accept_rx.recv()).await {
+            Ok(Some(s)) => Ok(Some(s)),
+            Ok(None) => Err(self.closed_error()),
+            Err(..) => Ok(None),
         }
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.udp.local_addr()
+        self.endpoint.transport().local_addr()
+    }
+
+    /// UDP socket of the listener, shared by all of its sessions, for tools that work on the socket itself, like
+    /// `SO_TIMESTAMPING` or a BPF socket filter. Its file descriptor is available by `AsFd` and `AsRawFd` of the
+    /// socket.
+    ///
+    /// Reading its address and options doesn't interfere with the listener. So does setting options that don't
+    /// change what `recv_from` returns, like timestamping, TTL or buffer sizes. A socket filter that drops KCP
+    /// packets breaks sessions, and receiving from the socket steals packets of them. Connecting it, switching it
+    /// to blocking mode, or closing the descriptor breaks the listener.
+    ///
+    /// `None` if the listener was created by `with_transport`.
+    pub fn get_ref(&self) -> Option<&UdpSocket> {
+        self.endpoint.udp().map(|udp| &**udp)
+    }
+
+    /// Sends a datagram that is not KCP from the socket of the listener, like a STUN request or a hole punching
+    /// probe, which must leave from the same port as KCP to share its NAT mapping.
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+        transport::send_to(self.endpoint.transport(), buf, addr).await
+    }
+
+    /// Receives datagrams that are not KCP, like replies to `send_to`, instead of dropping them.
+    ///
+    /// A datagram is not KCP if it is shorter than a KCP header (24 bytes), or the command of its first segment
+    /// (the 5th byte) isn't a KCP command, which is checked after the handshake, migration and PMTU probe frames of
+    /// this crate. Other protocols must keep the 5th byte out of 81 to 84 and 90, STUN always does with its magic
+    /// cookie. Nothing else is routed to the receiver, whether or not its conv belongs to a session.
+    ///
+    /// Replaces the receiver of the previous call. Datagrams are dropped when the receiver falls behind by 64 of them.
+    pub async fn raw_datagrams(&self) -> KcpResult<mpsc::Receiver<(Vec<u8>, SocketAddr)>> {
+        let (raw_tx, raw_rx) = mpsc::channel(RAW_DATAGRAM_BACKLOG);
+        match self.request(|tx| ListenerCommand::RawDatagrams(raw_tx, tx)).await {
+            Some(()) => Ok(raw_rx),
+            None => Err(self.closed_error()),
+        }
+    }
+
+    /// Hits and misses of the buffer pool, which is configured by `KcpConfig::buffer_pool`.
+    ///
+    /// Misses keep growing if the pool is too small for the traffic.
+    pub fn buffer_pool_stats(&self) -> BufferPoolStats {
+        self.buffer_pool.stats()
+    }
+
+    /// Datagrams dropped by the receive buffer of the socket since it was bound, because they arrived faster than
+    /// the listener received them. `None` if they are not counted, see `KcpTransport::dropped_datagrams`.
+    ///
+    /// Sessions are notified of sustained drops by `KcpEvent::ReceiveOverload`.
+    pub fn dropped_datagrams(&self) -> Option<u64> {
+        self.endpoint.transport().dropped_datagrams()
+    }
+
+    /// Number of active sessions, including sessions that haven't been accepted yet
+    pub async fn session_count(&self) -> usize {
+        self.request(ListenerCommand::SessionCount).await.unwrap_or(0)
+    }
+
+    /// conv and peer address of active sessions, ordered by conv, then by peer address
+    pub async fn peers(&self) -> Vec<(u32, SocketAddr)> {
+        self.request(ListenerCommand::Peers).await.unwrap_or_default()
+    }
+
+    /// Snapshots of active sessions, ordered by conv, then by peer address. See `KcpStream::dump_state`.
+    ///
+    /// Sessions are locked one at a time, after the listener task has handed them over, so neither the listener
+    /// nor other sessions wait for the snapshots.
+    pub async fn dump_sessions(&self) -> Vec<SessionDiagnostics> {
+        let sessions = self.request(ListenerCommand::Sessions).await.unwrap_or_default();
+        let mut dumps = Vec::with_capacity(sessions.len());
+        for session in sessions {
+            dumps.push(session.diagnostics());
+        }
+        dumps.sort_unstable_by_key(|dump| (dump.conv, dump.peer_addr));
+        dumps
+    }
+
+    /// Summaries of active sessions, ordered by conv, then by peer address, including sessions that haven't been
+    /// accepted yet and sessions of dropped streams that are still closing.
+    ///
+    /// The set of sessions is taken at once by the listener task, between packets, like `peers`. Each session is
+    /// then read one at a time without stopping the others, so the summaries are not taken at the same instant:
+    /// a session may have closed meanwhile, sessions opened meanwhile are not included. Nothing stays locked after
+    /// this returns.
+    pub async fn sessions(&self) -> Vec<SessionInfo> {
+        let sessions = self.request(ListenerCommand::Sessions).await.unwrap_or_default();
+        let mut infos = sessions.iter().map(|session| session.info()).collect::<Vec<_>>();
+        infos.sort_unstable_by_key(|info| (info.conv, info.peer_addr));
+        infos
+    }
+
+    /// Number of packets refused because they would open a session beyond `KcpConfig::max_sessions`, or no conv
+    /// could be allocated for it by `KcpConfig::conv_allocator`.
+    ///
+    /// A client may be counted more than once, because it retransmits until it is notified.
+    pub async fn refused_sessions(&self) -> u64 {
+        self.request(ListenerCommand::RefusedSessions).await.unwrap_or(0)
+    }
+
+    /// Number of packets dropped because `filter` of `bind_with_filter` rejected their source address
+    pub async fn filtered_packets(&self) -> u64 {
+        self.request(ListenerCommand::FilteredPackets).await.unwrap_or(0)
+    }
+
+    /// Number of datagrams dropped because their CRC32 didn't match, see `KcpConfig::checksum`. They never reach
+    /// sessions, or open new ones.
+    pub async fn corrupted_packets(&self) -> u64 {
+        self.request(ListenerCommand::CorruptedPackets).await.unwrap_or(0)
+    }
+
+    /// Terminates sessions of `conv` immediately, from any peer, returns `false` if there is none.
+    ///
+    /// Peer is notified to close, and pending and later calls on streams of these sessions fail with
+    /// `ConnectionReset`. They are removed from the listener after their tasks stopped, shortly after this returns.
+    ///
+    /// Sessions that have already stopped by themselves, closed or broken by an error, are left as they are and
+    /// don't count. A session stopping at the same moment is either closed by this or not counted, so `true` means
+    /// that a running session was terminated by this call.
+    pub async fn close_session(&self, conv: u32) -> bool {
+        self.request(|tx| ListenerCommand::CloseSession(conv, tx))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Terminates sessions from `peer_addr` immediately, of any conv, returns `false` if there is none. See
+    /// `close_session`.
+    pub async fn close_peer(&self, peer_addr: SocketAddr) -> bool {
+        self.request(|tx| ListenerCommand::ClosePeer(peer_addr, tx))
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Sends `data` to every session whose stream is in use, like `send` of each stream, returns what happened in
+    /// each of them, ordered by conv, then by peer address. For the same update to all clients, like a state snapshot
+    /// of a game server.
+    ///
+    /// Sessions whose send window is full are skipped or waited for by `policy`, a slow peer doesn't get the data
+    /// later by itself. `data` is one message in message mode, in stream mode it is queued as a whole or not at
+    /// all, and fails with `Kcp(UserBufTooBig)` if one send can't take it. Sessions that haven't been accepted yet
+    /// are included, sessions of dropped streams are not.
+    ///
+    /// The sessions are taken at once by the listener task, then each of them is locked once to queue the data,
+    /// without stopping the others, see `sessions`.
+    pub async fn broadcast(&self, data: &[u8], policy: BroadcastPolicy) -> Vec<BroadcastResult> {
+        self.broadcast_filter(data, policy, |_, _| true).await
+    }
+
+    /// `broadcast` to the sessions that `filter` accepts by their conv and the current address of peer, like a list
+    /// of convs.
+    pub async fn broadcast_filter<F>(&self, data: &[u8], policy: BroadcastPolicy, mut filter: F) -> Vec<BroadcastResult>
+    where
+        F: FnMut(u32, SocketAddr) -> bool,
+    {
+        let mut sessions = self.request(ListenerCommand::LiveSessions).await.unwrap_or_default();
+        sessions.retain(|(conv, session)| filter(*conv, session.peer_addr()));
+        broadcast::broadcast(sessions, data, policy).await
+    }
+
+    /// Creates the session of `conv` for `peer_addr` before any packet from it, for a client that is told to
+    /// connect with `KcpStream::connect_with_conv` by another channel. The stream is returned instead of being
+    /// accepted.
+    ///
+    /// The first packet of the client finds the session ready, and server may send first. Otherwise the session
+    /// is the same as an accepted one, it expires after `KcpConfig::session_expire` if the client never comes.
+    ///
+    /// `peer_addr` must be the address that packets come from, as seen by the listener. If it differs, like behind
+    /// NAT, packets of the client open another session, which is accepted as usual, or they are challenged and
+    /// dropped with `KcpConfig::enable_migration`, as the client has no resumption token of the prepared one.
+    ///
+    /// Fails with `ConfigInvalid` if `conv` is 0, `ConvExhausted` if `KcpConfig::max_sessions` is reached,
+    /// `ListenerClosed` after `shutdown`, and an `AlreadyExists` IO error if the session already exists.
+    pub async fn prepare_session(&self, conv: u32, peer_addr: SocketAddr) -> KcpResult<KcpStream> {
+        if conv == 0 {
+            return Err(KcpError::ConfigInvalid(
+                "conv of a prepared session must not be 0".to_owned(),
+            ));
+        }
+        match self
+            .request(|tx| ListenerCommand::PrepareSession(conv, peer_addr, tx))
+            .await
+        {
+            Some(result) => result,
+            None => Err(self.closed_error()),
+        }
+    }
+
+    /// Shuts down the listener gracefully, for stopping a server without cutting transfers.
+    ///
+    /// New sessions are refused immediately, and connections that haven't been accepted are closed. Existing
+    /// sessions are served until they are closed, those still open after `timeout` are closed like
+    /// `close_session`. Returns when all sessions are closed, `accept` fails with `ListenerClosed` after that.
+    ///
+    /// The socket is closed when the listener is dropped.
+    pub async fn shutdown(&mut self, timeout: Duration) {
+        self.accept_rx.close();
+        // Streams that were waiting are dropped, which closes their sessions
+        while self.accept_rx.try_recv().is_ok() {}
+
+        let deadline = Instant::now() + timeout;
+        let _ = self.request(|tx| ListenerCommand::Shutdown(deadline, tx)).await;
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(oneshot::Sender<T>) -> ListenerCommand) -> Option<T> {
+        let (tx, rx) = oneshot::channel();
+        self.command_tx.send(command(tx)).await.ok()?;
+        rx.await.ok()
+    }
+}
+
+/// Errors of receiving that the socket doesn't recover from, unlike ICMP errors or running out of buffers
+fn is_fatal_recv_error(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::NotConnected | io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported
+    )
+}
+
+/// Resets `sessions` that are still running for `KcpListener::close_session`, returns whether there was any
+fn close_sessions(sessions: Vec<Arc<KcpSession>>) -> bool {
+    // All of them are reset, not only the first one
+    sessions.iter().filter(|session| session.reset_running()).count() > 0
+}
+
+/// Message of a panic, if it is a string
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = panic.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = panic.downcast_ref::<String>() {
+        msg
+    } else {
+        "unknown panic"
+    }
+}
+
+/// Gets the session of `conv`, creates and sends it to `accept()` if it doesn't exist
+fn open_session(
+    sessions: &mut KcpSessionManager,
+    config: &KcpConfig,
+    conv: u32,
+    endpoint: &Endpoint,
+    peer_addr: SocketAddr,
+    close_tx: &mpsc::Sender<SessionKey>,
+    accept_tx: &mpsc::Sender<(KcpStream, SocketAddr)>,
+) -> Option<Arc<KcpSession>> {
+    match sessions.get_or_create(config, conv, endpoint, peer_addr, close_tx) {
+        Ok((s, created)) => {
+            if created {
+                // Created a new session, constructed a new accepted client
+                let stream = KcpStream::with_session(s.clone());
+                if accept_tx.try_send((stream, peer_addr)).is_err() {
+                    debug!("failed to create accepted stream due to channel failure");
+
+                    // remove it from session
+                    sessions.close_conv(conv, peer_addr);
+                    return None;
+                }
+            }
+
+            Some(s)
+        }
+        Err(KcpError::ConvExhausted) => {
+            trace!("session refused, peer: {}, conv: {}", peer_addr, conv);
+            None
+        }
+        Err(err) => {
+            error!(
+                "failed to create session, error: {}, peer: {}, conv: {}",
+                err, peer_addr, conv
+            );
+            None
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::{
+        io::{self, Write},
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex as StdMutex,
+        },
+        time::Duration,
+    };
+
+    use kcp::Kcp;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+        task::JoinHandle,
+        time::{self, Instant},
+    };
+
     use super::KcpListener;
-    use crate::{config::KcpConfig, stream::KcpStream};
-    use futures::future;
+    use crate::{
+        broadcast::{BroadcastOutcome, BroadcastPolicy},
+        checksum,
+        config::{
+            ConvAllocator, ConvAllocatorFactory, FlushStrategy, KcpConfig, KcpNoDelayConfig, SequentialConvAllocator,
+        },
+        error::KcpError,
+        event::KcpEvent,
+        framing::PacketObfuscator,
+        memory::MemoryNetwork,
+        migration::{MigrationFrame, TOKEN_LEN},
+        netem::{NetEmConfig, NetEmTransport},
+        pool::BufferPoolConfig,
+        session::KcpSession,
+        skcp::{fin_segment, KcpSocket, KCP_CMD_PUSH},
+        stream::KcpStream,
+    };
+    use futures::{future, FutureExt};
 
     #[tokio::test]
     async fn multi_echo() {
         let _ = env_logger::try_init();
 
-        let mut listener = KcpListener::bind(KcpConfig::default(), "127.0.0.1:0").await.unwrap();
-        let server_addr = listener.local_addr().unwrap();
+        multi_echo_with(KcpConfig::default()).await;
+        // ACKs sent right after input
+        multi_echo_with(KcpConfig {
+            flush_strategy: FlushStrategy::OnAck,
+            ..Default::default()
+        })
+        .await;
+    }
+
+    /// Many sessions to a listener, each from its own end of a network with some jitter, duplication and reordering
+    async fn multi_echo_with(config: KcpConfig) {
+        let link = NetEmConfig {
+            dup_rate: 0.01,
+            reorder_rate: 0.01,
+            extra_latency: Duration::from_millis(1),
+            jitter: Duration::from_millis(2),
+            seed: 100,
+            ..Default::default()
+        };
+        let network = MemoryNetwork::new();
+        let server = network.bind();
+        let server_addr = server.local_addr();
+        let mut listener =
+            KcpListener::with_transport(config.clone(), Arc::new(NetEmTransport::new(server, link))).unwrap();
 
         tokio::spawn(async move {
             loop {
                 let (mut stream, _) = listener.accept().await.unwrap();
-
                 tokio::spawn(async move {
                     let mut buffer = [0u8; 8192];
                     while let Ok(n) = stream.recv(&mut buffer).await {
+                        if n == 0 {
+                            break;
+                        }
                         let data = &buffer[..n];
 
                         let mut sent = 0;
@@ -159,9 +966,13 @@ mod test {
 
         let mut vfut = Vec::new();
 
-        for _ in 1..100 {
+        for i in 1..100 {
+            let client = NetEmTransport::new(network.bind(), NetEmConfig { seed: i, ..link });
+            let config = config.clone();
             vfut.push(async move {
-                let mut stream = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+                let mut stream = KcpStream::connect_with_transport(&config, Arc::new(client), server_addr)
+                    .await
+                    .unwrap();
 
                 for _ in 1..20 {
                     const SEND_BUFFER: &[u8] = b"HELLO WORLD";
@@ -176,4 +987,1606 @@ mod test {
 
         future::join_all(vfut).await;
     }
+
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    #[tokio::test]
+    async fn bind_reuseport() {
+        let _ = env_logger::try_init();
+
+        let listener1 = KcpListener::bind_reuseport(KcpConfig::default(), "127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = listener1.local_addr().unwrap();
+
+        let listener2 = KcpListener::bind_reuseport(KcpConfig::default(), addr).await.unwrap();
+        assert_eq!(listener2.local_addr().unwrap(), addr);
+
+        // Sockets without SO_REUSEPORT cannot share the address
+        assert!(KcpListener::bind(KcpConfig::default(), addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn conv_zero_back_to_back() {
+        let _ = env_logger::try_init();
+
+        struct UdpOutput(std::net::UdpSocket, SocketAddr);
+
+        impl Write for UdpOutput {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.send_to(buf, self.1)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut listener = KcpListener::bind(KcpConfig::default(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Client that never reads the reply from server, so it keeps sending conv 0
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut kcp = Kcp::new_stream(0, UdpOutput(udp, server_addr));
+        kcp.input_conv();
+        kcp.set_nodelay(true, 10, 0, true);
+        kcp.update(0).unwrap();
+
+        // Flushed separately, each message goes out in its own datagram
+        kcp.send(b"HELLO").unwrap();
+        kcp.flush().unwrap();
+        kcp.send(b" WORLD").unwrap();
+        kcp.flush().unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        const EXPECTED: &[u8] = b"HELLO WORLD";
+        let mut buffer = [0u8; 1024];
+        let mut n = 0;
+        while n < EXPECTED.len() {
+            n += stream.recv(&mut buffer[n..]).await.unwrap();
+        }
+        assert_eq!(&buffer[..n], EXPECTED);
+
+        // No other session was created for the second datagram
+        assert!(time::timeout(Duration::from_millis(500), listener.accept())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn drop_releases_socket() {
+        let _ = env_logger::try_init();
+
+        struct UdpOutput(std::net::UdpSocket, SocketAddr);
+
+        impl Write for UdpOutput {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.send_to(buf, self.1)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let addr = std::net::UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut listener = KcpListener::bind(KcpConfig::default(), addr).await.unwrap();
+
+        // Clients that crashed after the first message, they never acknowledge anything
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            let mut kcp = Kcp::new_stream(0, UdpOutput(udp, addr));
+            kcp.input_conv();
+            kcp.update(0).unwrap();
+            kcp.send(b"HELLO").unwrap();
+            kcp.flush().unwrap();
+            clients.push(kcp);
+        }
+
+        // One is accepted, and has a reply that would be retransmitted for a long time
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        stream.recv(&mut buffer).await.unwrap();
+        stream.send(b"WORLD").await.unwrap();
+        time::timeout(Duration::from_secs(1), async {
+            while listener.session_count().await < 2 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("second session not created");
+
+        drop(stream);
+        drop(listener);
+
+        // Released after the tasks are cancelled, without waiting for any timer
+        let mut rebound = None;
+        for _ in 0..16 {
+            tokio::task::yield_now().await;
+            if let Ok(listener) = KcpListener::bind(KcpConfig::default(), addr).await {
+                rebound = Some(listener);
+                break;
+            }
+        }
+        assert!(rebound.is_some(), "{} still in use", addr);
+    }
+
+    #[tokio::test]
+    async fn prepare_session() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Client with conv 42 from a known address, like `connect_with_conv`
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let client_addr = udp.local_addr().unwrap();
+        udp.connect(server_addr).await.unwrap();
+        let mut socket = KcpSocket::new(&config, 42, Arc::new(udp), server_addr, config.stream).unwrap();
+        socket.set_strict_conv();
+        let (session, _) = KcpSession::new_shared(socket, &config, None, None);
+        let mut client = KcpStream::with_session(session);
+
+        let mut server = listener.prepare_session(42, client_addr).await.unwrap();
+        match listener.prepare_session(42, client_addr).await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::AlreadyExists),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("prepared twice"),
+        }
+        match listener.prepare_session(0, client_addr).await {
+            Err(KcpError::ConfigInvalid(..)) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("prepared conv 0"),
+        }
+
+        // Server speaks first, then the same session serves the client
+        server.send(b"WELCOME").await.unwrap();
+        let mut buffer = [0u8; 7];
+        client.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"WELCOME");
+
+        client.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 5];
+        server.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"HELLO");
+
+        assert_eq!(listener.session_count().await, 1);
+        assert!(listener
+            .accept_timeout(Duration::from_millis(100))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_removed() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        drop(server);
+
+        // Client keeps sending until it learns about the close, packets in flight must not reopen the session
+        let sending = time::timeout(Duration::from_secs(5), async {
+            loop {
+                match stream.send(&[0u8; 1024]).await {
+                    Ok(..) => time::sleep(Duration::from_millis(5)).await,
+                    Err(KcpError::ConnectionClosed) => break,
+                    Err(err) => panic!("unexpected error {}", err),
+                }
+            }
+        });
+        sending.await.expect("close not received by client");
+
+        time::timeout(Duration::from_secs(1), async {
+            while listener.session_count().await > 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session not removed");
+        // No buffers are allocated for packets of the session any more
+        let misses = listener.buffer_pool_stats().misses;
+        for _ in 0..10 {
+            assert_eq!(listener.session_count().await, 0);
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(listener.buffer_pool_stats().misses, misses);
+        assert!(listener
+            .accept_timeout(Duration::from_millis(10))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn handshake_echo() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            handshake: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buffer = [0u8; 8192];
+            while let Ok(n) = stream.recv(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+
+        const SEND_BUFFER: &[u8] = b"HELLO WORLD";
+        assert_eq!(SEND_BUFFER.len(), stream.send(SEND_BUFFER).await.unwrap());
+
+        let mut buffer = [0u8; 1024];
+        let n = stream.recv(&mut buffer).await.unwrap();
+        assert_eq!(SEND_BUFFER, &buffer[..n]);
+    }
+
+    #[tokio::test]
+    async fn close_notify() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Client closes, server sees EOF and fails to send
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+
+        drop(client);
+
+        let n = time::timeout(Duration::from_secs(3), server.recv(&mut buffer))
+            .await
+            .expect("server didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        let err = server.send(b"WORLD").await.unwrap_err();
+        match err {
+            KcpError::ConnectionClosed => {}
+            err => panic!("unexpected error {}", err),
+        }
+
+        // Server closes, client sees EOF
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+        server.send(b"BYE").await.unwrap();
+        drop(server);
+
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"BYE", &buffer[..n]);
+        let n = time::timeout(Duration::from_secs(3), client.recv(&mut buffer))
+            .await
+            .expect("client didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    /// Relays datagrams between one client and `server_addr`, the source address seen by server
+    /// changes every time `migrate` is called
+    struct Relay {
+        front: Arc<UdpSocket>,
+        back: Arc<StdMutex<Arc<UdpSocket>>>,
+        back_task: JoinHandle<()>,
+        client_addr: Arc<StdMutex<Option<SocketAddr>>>,
+        front_task: JoinHandle<()>,
+    }
+
+    impl Relay {
+        async fn new(server_addr: SocketAddr) -> Relay {
+            let front = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let client_addr = Arc::new(StdMutex::new(None));
+            let back_task = Relay::spawn_back(back.clone(), front.clone(), client_addr.clone());
+            let back = Arc::new(StdMutex::new(back));
+
+            let front_task = {
+                let (front, back, client_addr) = (front.clone(), back.clone(), client_addr.clone());
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 65536];
+                    while let Ok((n, peer_addr)) = front.recv_from(&mut buffer).await {
+                        *client_addr.lock().unwrap() = Some(peer_addr);
+                        let back = back.lock().unwrap().clone();
+                        let _ = back.send_to(&buffer[..n], server_addr).await;
+                    }
+                })
+            };
+
+            Relay {
+                front,
+                back,
+                back_task,
+                client_addr,
+                front_task,
+            }
+        }
+
+        fn spawn_back(
+            back: Arc<UdpSocket>,
+            front: Arc<UdpSocket>,
+            client_addr: Arc<StdMutex<Option<SocketAddr>>>,
+        ) -> JoinHandle<()> {
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 65536];
+                while let Ok(n) = back.recv(&mut buffer).await {
+                    let client_addr = *client_addr.lock().unwrap();
+                    if let Some(client_addr) = client_addr {
+                        let _ = front.send_to(&buffer[..n], client_addr).await;
+                    }
+                }
+            })
+        }
+
+        /// Switches to a new source address, packets sent to the old one are lost
+        async fn migrate(&mut self) {
+            let back = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            self.back_task.abort();
+            self.back_task = Relay::spawn_back(back.clone(), self.front.clone(), self.client_addr.clone());
+            *self.back.lock().unwrap() = back;
+        }
+    }
+
+    impl Drop for Relay {
+        fn drop(&mut self) {
+            self.back_task.abort();
+            self.front_task.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn session_migration() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            enable_migration: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 8192];
+                    while let Ok(n) = stream.recv(&mut buffer).await {
+                        if n == 0 {
+                            break;
+                        }
+                        stream.send(&buffer[..n]).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let mut relay = Relay::new(server_addr).await;
+        let relay_addr = relay.front.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, relay_addr).await.unwrap();
+        let mut buffer = [0u8; 1024];
+
+        stream.send(b"HELLO").await.unwrap();
+        let n = stream.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+
+        // Wait for the resumption token
+        time::sleep(Duration::from_millis(200)).await;
+
+        relay.migrate().await;
+
+        stream.send(b"WORLD").await.unwrap();
+        let n = time::timeout(Duration::from_secs(3), stream.recv(&mut buffer))
+            .await
+            .expect("session didn't migrate")
+            .unwrap();
+        assert_eq!(b"WORLD", &buffer[..n]);
+    }
+
+    #[tokio::test]
+    async fn session_migration_forged_token() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            enable_migration: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, peer_addr) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        server.recv(&mut buffer).await.unwrap();
+
+        // Another address pretends to be the owner of this session
+        let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // First conv allocated by listener
+        let conv = 1;
+        let resume = MigrationFrame::Resume {
+            conv,
+            token: [0u8; TOKEN_LEN],
+        };
+        attacker.send_to(&resume.encode(), server_addr).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(server.peer_addr(), peer_addr);
+    }
+
+    #[tokio::test]
+    async fn enumerate_peers() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        assert_eq!(listener.session_count().await, 0);
+        assert!(listener.peers().await.is_empty());
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            streams.push((stream, accepted));
+        }
+
+        assert_eq!(listener.session_count().await, 3);
+        let peers = listener.peers().await;
+        let convs = peers.iter().map(|(conv, _)| *conv).collect::<Vec<_>>();
+        assert_eq!(convs, vec![1, 2, 3]);
+        for (_, peer_addr) in peers.iter() {
+            assert!(peer_addr.ip().is_loopback());
+        }
+    }
+
+    #[tokio::test]
+    async fn session_infos() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        assert!(listener.sessions().await.is_empty());
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let client_addr = stream.get_ref().unwrap().local_addr().unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(accepted.recv(&mut buf).await.unwrap(), 5);
+        accepted.send(b"WORLD!!").await.unwrap();
+        assert_eq!(stream.recv(&mut buf).await.unwrap(), 7);
+        time::sleep(Duration::from_millis(100)).await;
+
+        let infos = listener.sessions().await;
+        assert_eq!(infos.len(), 1);
+        let info = &infos[0];
+        assert_eq!((info.conv, info.peer_addr), (1, client_addr));
+        assert_eq!((info.bytes_sent, info.bytes_received), (7, 5));
+        assert!(info.idle_ms >= 100 && info.age_ms >= info.idle_ms, "{:?}", info);
+        assert!(!info.closed);
+
+        // Sessions opened while they are listed
+        let opener = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            for _ in 0..8 {
+                let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+                stream.send(b"HELLO").await.unwrap();
+                streams.push(stream);
+            }
+            streams
+        });
+        let mut listed = 1;
+        while !opener.is_finished() {
+            let infos = listener.sessions().await;
+            assert!(infos.len() >= listed);
+            assert!(infos.windows(2).all(|w| w[0].conv < w[1].conv));
+            listed = infos.len();
+            tokio::task::yield_now().await;
+        }
+        let _streams = opener.await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(listener.sessions().await.len(), 9);
+    }
+
+    #[tokio::test]
+    async fn broadcast() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            wnd_size: (8, 128),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut clients = Vec::new();
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 16];
+            assert_eq!(server.recv(&mut buf).await.unwrap(), 5);
+            clients.push(stream);
+            accepted.push(server);
+        }
+
+        // Peer never acknowledges, its window is filled
+        let silent = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut prepared = listener
+            .prepare_session(100, silent.local_addr().unwrap())
+            .await
+            .unwrap();
+        let chunk = [0u8; 1024];
+        while let Some(result) = prepared.send(&chunk).now_or_never() {
+            result.unwrap();
+        }
+
+        let results = listener.broadcast(b"SNAPSHOT", BroadcastPolicy::Skip).await;
+        let convs = results.iter().map(|result| result.conv).collect::<Vec<_>>();
+        assert_eq!(convs, [1, 2, 3, 100]);
+        assert!(results[..3].iter().all(|result| result.outcome.is_sent()));
+        assert!(matches!(results[3].outcome, BroadcastOutcome::Skipped));
+
+        let start = Instant::now();
+        let results = listener
+            .broadcast(b"SNAPSHOT", BroadcastPolicy::Wait(Duration::from_millis(200)))
+            .await;
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(results[..3].iter().all(|result| result.outcome.is_sent()));
+        assert!(matches!(results[3].outcome, BroadcastOutcome::Skipped));
+
+        // Neither a dropped stream nor the sessions filtered out
+        drop(accepted.remove(2));
+        let results = listener
+            .broadcast_filter(b"SNAPSHOT", BroadcastPolicy::Skip, |conv, _| conv != 1)
+            .await;
+        let convs = results.iter().map(|result| result.conv).collect::<Vec<_>>();
+        assert_eq!(convs, [2, 100]);
+        assert!(results[0].outcome.is_sent());
+
+        // Doesn't fit into one send, nothing is queued
+        let results = listener.broadcast(&vec![0u8; 256 * 1024], BroadcastPolicy::Skip).await;
+        assert!(results.iter().all(|result| matches!(
+            result.outcome,
+            BroadcastOutcome::Failed(KcpError::Kcp(kcp::Error::UserBufTooBig))
+        )));
+
+        let mut buf = [0u8; 32];
+        for (i, client) in clients.iter_mut().enumerate() {
+            let expected = if i == 1 { 24 } else { 16 };
+            time::timeout(Duration::from_secs(5), client.read_exact(&mut buf[..expected]))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(buf[..expected].chunks(8).all(|chunk| chunk == b"SNAPSHOT"));
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_conv_allocator() {
+        let _ = env_logger::try_init();
+
+        struct Scripted(std::vec::IntoIter<u32>);
+
+        impl ConvAllocator for Scripted {
+            fn allocate(&mut self, _peer: SocketAddr) -> Option<u32> {
+                Some(self.0.next().unwrap_or(1000))
+            }
+
+            fn release(&mut self, _conv: u32) {}
+        }
+
+        // conv 0 and convs in use are skipped
+        let config = KcpConfig {
+            conv_allocator: Some(ConvAllocatorFactory::new(|| {
+                Box::new(Scripted(vec![1000, 0, 1000, 2000].into_iter()))
+            })),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            streams.push((stream, accepted));
+        }
+
+        let convs = listener.peers().await.iter().map(|(conv, _)| *conv).collect::<Vec<_>>();
+        assert_eq!(convs, vec![1000, 2000]);
+
+        // Only convs in use are returned from now on, the client is refused
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(2), stream.recv(&mut buffer))
+            .await
+            .expect("client not notified")
+            .unwrap();
+        assert_eq!(n, 0);
+        assert_eq!(listener.session_count().await, 2);
+        assert!(listener.refused_sessions().await >= 1);
+    }
+
+    #[tokio::test]
+    async fn conv_range() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            conv_allocator: Some(ConvAllocatorFactory::new(|| {
+                Box::new(SequentialConvAllocator::with_range(100..=101))
+            })),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut streams = Vec::new();
+        for _ in 0..2 {
+            let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            streams.push((stream, accepted));
+        }
+        let convs = listener.peers().await.iter().map(|(conv, _)| *conv).collect::<Vec<_>>();
+        assert_eq!(convs, vec![100, 101]);
+
+        // Range is used up, refused like max_sessions
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(2), stream.recv(&mut buffer))
+            .await
+            .expect("client not notified")
+            .unwrap();
+        assert_eq!(n, 0);
+        assert!(listener.refused_sessions().await >= 1);
+
+        // Released with its session
+        let (_, accepted) = streams.remove(0);
+        assert!(listener.close_session(100).await);
+        drop(accepted);
+        time::timeout(Duration::from_secs(3), async {
+            while listener.session_count().await != 1 {
+                time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("session not removed");
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let n = accepted.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+        let convs = listener.peers().await.iter().map(|(conv, _)| *conv).collect::<Vec<_>>();
+        assert_eq!(convs, vec![100, 101]);
+    }
+
+    /// Connects with `conv` chosen by client, instead of letting the server allocate one
+    async fn connect_with_conv(config: &KcpConfig, conv: u32, addr: SocketAddr) -> (KcpStream, SocketAddr) {
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let local_addr = udp.local_addr().unwrap();
+        let socket = KcpSocket::new(config, conv, udp, addr, config.stream).unwrap();
+        let (session, _) = KcpSession::new_shared(socket, config, None, None);
+        (KcpStream::with_session(session), local_addr)
+    }
+
+    #[tokio::test]
+    async fn same_conv_different_peers() {
+        let _ = env_logger::try_init();
+
+        for shared_driver in [false, true] {
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                shared_driver,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let (mut alice, alice_addr) = connect_with_conv(&config, 7, server_addr).await;
+            let (mut bob, bob_addr) = connect_with_conv(&config, 7, server_addr).await;
+            alice.send(b"ALICE").await.unwrap();
+            bob.send(b"BOB").await.unwrap();
+
+            // Each peer has its own session, packets of one never go into the other
+            let mut buf = [0u8; 1024];
+            let mut servers = Vec::new();
+            for _ in 0..2 {
+                let (mut server, peer_addr) = listener.accept().await.unwrap();
+                let n = server.recv(&mut buf).await.unwrap();
+                let expected: &[u8] = if peer_addr == alice_addr {
+                    b"ALICE"
+                } else {
+                    assert_eq!(peer_addr, bob_addr);
+                    b"BOB"
+                };
+                assert_eq!(&buf[..n], expected);
+                server.send(&buf[..n]).await.unwrap();
+                servers.push(server);
+            }
+
+            let n = alice.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ALICE");
+            let n = bob.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"BOB");
+
+            let peers = listener.peers().await;
+            assert_eq!(peers.len(), 2);
+            assert!(peers.iter().all(|(conv, _)| *conv == 7));
+        }
+    }
+
+    #[tokio::test]
+    async fn shared_driver_echo() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            shared_driver: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const CLIENTS: usize = 20;
+
+        let mut clients = Vec::new();
+        for _ in 0..CLIENTS {
+            clients.push(tokio::spawn(async move {
+                let mut stream = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+
+                for _ in 0..10 {
+                    const SEND_BUFFER: &[u8] = b"HELLO WORLD";
+                    assert_eq!(SEND_BUFFER.len(), stream.send(SEND_BUFFER).await.unwrap());
+
+                    let mut buffer = [0u8; 1024];
+                    let n = stream.recv(&mut buffer).await.unwrap();
+                    assert_eq!(SEND_BUFFER, &buffer[..n]);
+                }
+            }));
+        }
+
+        for _ in 0..CLIENTS {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 8192];
+                while let Ok(n) = stream.recv(&mut buffer).await {
+                    if n == 0 {
+                        break;
+                    }
+                    stream.send(&buffer[..n]).await.unwrap();
+                }
+            });
+        }
+
+        for client in clients {
+            client.await.unwrap();
+        }
+
+        // Clients closed, all sessions are removed from the driver
+        time::timeout(Duration::from_secs(5), async {
+            while listener.session_count().await > 0 {
+                time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await
+        .expect("sessions not closed");
+    }
+
+    #[tokio::test]
+    async fn close_session() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        server.recv(&mut buffer).await.unwrap();
+
+        let mut other_client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let other_addr = other_client.get_ref().unwrap().local_addr().unwrap();
+        other_client.send(b"HELLO").await.unwrap();
+        let (mut other_server, _) = listener.accept().await.unwrap();
+        other_server.recv(&mut buffer).await.unwrap();
+
+        assert!(!listener.close_session(100).await);
+        assert!(listener.close_session(1).await);
+        // Already stopped, even if it hasn't been removed yet
+        assert!(!listener.close_session(1).await);
+
+        let err = server.recv(&mut buffer).await.unwrap_err();
+        match err {
+            KcpError::ConnectionReset => {}
+            err => panic!("unexpected error {}", err),
+        }
+
+        // By the address of peer
+        assert!(!listener.close_peer(server_addr).await);
+        assert!(listener.close_peer(other_addr).await);
+        match other_server.recv(&mut buffer).await.unwrap_err() {
+            KcpError::ConnectionReset => {}
+            err => panic!("unexpected error {}", err),
+        }
+        let n = time::timeout(Duration::from_secs(3), other_client.recv(&mut buffer))
+            .await
+            .expect("client didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        // Client is notified
+        let n = time::timeout(Duration::from_secs(3), client.recv(&mut buffer))
+            .await
+            .expect("client didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+
+        time::timeout(Duration::from_secs(3), async {
+            while listener.session_count().await > 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session not removed");
+    }
+
+    #[tokio::test]
+    async fn session_expired() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            session_expire: Duration::from_millis(500),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        server.recv(&mut buffer).await.unwrap();
+
+        // Nobody sends anything after that
+        let err = time::timeout(Duration::from_secs(3), server.recv(&mut buffer))
+            .await
+            .expect("session didn't expire")
+            .unwrap_err();
+        match err {
+            KcpError::SessionExpired => {}
+            err => panic!("unexpected error {}", err),
+        }
+
+        // Client is notified
+        let n = time::timeout(Duration::from_secs(3), client.recv(&mut buffer))
+            .await
+            .expect("client didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn idle_timeout() {
+        let _ = env_logger::try_init();
+
+        let client_config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let server_config = KcpConfig {
+            idle_timeout: Some(Duration::from_millis(500)),
+            ..client_config.clone()
+        };
+
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&client_config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        server.recv(&mut buffer).await.unwrap();
+
+        // Data keeps it open longer than the idle timeout
+        for _ in 0..4 {
+            time::sleep(Duration::from_millis(250)).await;
+            client.send(b"HELLO").await.unwrap();
+            server.recv(&mut buffer).await.unwrap();
+        }
+
+        // Only ACKs are exchanged after that, which doesn't count
+        let start = Instant::now();
+        let err = time::timeout(Duration::from_secs(3), server.recv(&mut buffer))
+            .await
+            .expect("idle session not closed")
+            .unwrap_err();
+        match err {
+            KcpError::IdleTimeout => {}
+            err => panic!("unexpected error {}", err),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::TimedOut);
+
+        // Client is notified, and the session is gone long before `session_expire`
+        let n = time::timeout(Duration::from_secs(3), client.recv(&mut buffer))
+            .await
+            .expect("client didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+        time::timeout(Duration::from_secs(1), async {
+            while listener.session_count().await > 0 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session not removed");
+    }
+
+    #[tokio::test]
+    async fn max_sessions() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            max_sessions: Some(4),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client_config = &config;
+        let clients = (0..100)
+            .map(|_| async move {
+                let mut client = KcpStream::connect(client_config, server_addr).await.unwrap();
+                client.send(b"HELLO").await.unwrap();
+                // Refused clients see EOF, accepted ones wait for data that never comes
+                let mut buffer = [0u8; 1024];
+                let refused = time::timeout(Duration::from_secs(2), client.recv(&mut buffer))
+                    .await
+                    .map(|r| r.unwrap() == 0)
+                    .unwrap_or(false);
+                (client, refused)
+            })
+            .collect::<Vec<_>>();
+        let clients = future::join_all(clients).await;
+        assert_eq!(clients.iter().filter(|(_, refused)| *refused).count(), 96);
+
+        let mut accepted = Vec::new();
+        while let Some((stream, _)) = listener.accept_timeout(Duration::from_millis(100)).await.unwrap() {
+            accepted.push(stream);
+        }
+        assert_eq!(accepted.len(), 4);
+        assert!(listener.refused_sessions().await >= 96);
+
+        // Closing one session makes room for another client
+        let (conv, _) = listener.peers().await[0];
+        assert!(listener.close_session(conv).await);
+        time::timeout(Duration::from_secs(3), async {
+            while listener.session_count().await > 3 {
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("session not removed");
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = time::timeout(Duration::from_secs(3), listener.accept())
+            .await
+            .expect("client not accepted")
+            .unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+    }
+
+    #[tokio::test]
+    async fn accept_filter() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let allow = Arc::new(AtomicBool::new(true));
+        let filter_allow = allow.clone();
+        let mut listener = KcpListener::bind_with_filter(config.clone(), "127.0.0.1:0", move |_| {
+            filter_allow.load(Ordering::Relaxed)
+        })
+        .await
+        .unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut accepted = KcpStream::connect(&config, server_addr).await.unwrap();
+        accepted.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+
+        // New peers are rejected silently, without FIN
+        allow.store(false, Ordering::Relaxed);
+        let mut rejected = KcpStream::connect(&config, server_addr).await.unwrap();
+        rejected.send(b"HELLO").await.unwrap();
+        assert!(time::timeout(Duration::from_millis(500), rejected.recv(&mut buffer))
+            .await
+            .is_err());
+        assert!(listener
+            .accept_timeout(Duration::from_millis(100))
+            .await
+            .unwrap()
+            .is_none());
+        assert_eq!(listener.session_count().await, 1);
+        assert!(listener.filtered_packets().await >= 1);
+
+        // Existing sessions are not checked again
+        accepted.send(b"WORLD").await.unwrap();
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"WORLD", &buffer[..n]);
+    }
+
+    #[tokio::test]
+    async fn raw_datagrams() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut raw_rx = listener.raw_datagrams().await.unwrap();
+
+        // STUN binding request, with the magic cookie where KCP has its command
+        let mut stun = vec![0x00, 0x01, 0x00, 0x00, 0x21, 0x12, 0xa4, 0x42];
+        stun.extend_from_slice(&[7u8; 12]);
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let peer_addr = peer.local_addr().unwrap();
+        peer.send_to(&stun, server_addr).await.unwrap();
+        peer.send_to(b"PUNCH", server_addr).await.unwrap();
+
+        let (buf, addr) = raw_rx.recv().await.unwrap();
+        assert_eq!(buf, stun);
+        assert_eq!(addr, peer_addr);
+        let (buf, _) = raw_rx.recv().await.unwrap();
+        assert_eq!(buf, b"PUNCH");
+        assert_eq!(listener.session_count().await, 0);
+
+        // KCP is never routed to the receiver
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+        assert!(raw_rx.try_recv().is_err());
+
+        // Sent from the port of the listener
+        listener.send_to(b"PROBE", peer_addr).await.unwrap();
+        let (n, addr) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"PROBE", &buf[..n]);
+        assert_eq!(addr, server_addr);
+    }
+
+    #[tokio::test]
+    async fn flood_fairness() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Flooded from the socket of the stream, so packets go to its session on the server
+        let client_udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client_udp.set_nonblocking(true).unwrap();
+        let flood_udp = client_udp.try_clone().unwrap();
+        let client_udp = UdpSocket::from_std(client_udp).unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, Arc::new(client_udp), server_addr)
+            .await
+            .unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 16];
+        server.recv(&mut buf).await.unwrap();
+
+        // Retransmissions of the first segment, every one of them is input by the session and acknowledged
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&stream.conv().await.to_le_bytes());
+        segment.extend_from_slice(&[KCP_CMD_PUSH, 0]);
+        segment.extend_from_slice(&128u16.to_le_bytes());
+        for field in [0u32, 0, 0, 5] {
+            // ts, sn, una, len
+            segment.extend_from_slice(&field.to_le_bytes());
+        }
+        segment.extend_from_slice(b"HELLO");
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let flood_stop = stop.clone();
+        let flood = std::thread::spawn(move || {
+            while !flood_stop.load(Ordering::Relaxed) {
+                let _ = flood_udp.send_to(&segment, server_addr);
+            }
+        });
+
+        // Timer task on the same worker as the listener, which yields when the coop budget runs out
+        let mut lateness = Vec::new();
+        for _ in 0..200 {
+            let deadline = Instant::now() + Duration::from_millis(5);
+            time::sleep_until(deadline).await;
+            lateness.push(Instant::now() - deadline);
+        }
+        stop.store(true, Ordering::Relaxed);
+        flood.join().unwrap();
+
+        lateness.sort_unstable();
+        let p99 = lateness[lateness.len() * 99 / 100];
+        assert!(p99 < Duration::from_millis(20), "timer delayed by {:?}", p99);
+    }
+
+    #[tokio::test]
+    async fn task_failure() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind_with_filter(config.clone(), "127.0.0.1:0", |_| panic!("filter failed"))
+            .await
+            .unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        // Every following call fails with the cause, instead of waiting forever
+        for _ in 0..2 {
+            match time::timeout(Duration::from_secs(3), listener.accept())
+                .await
+                .expect("accept didn't fail")
+            {
+                Err(KcpError::ListenerClosed(Some(cause))) => {
+                    assert!(
+                        cause.to_string().contains("filter failed"),
+                        "unexpected cause {}",
+                        cause
+                    )
+                }
+                Err(err) => panic!("unexpected error {}", err),
+                Ok(..) => panic!("accepted by a failed listener"),
+            }
+        }
+        match listener.prepare_session(1, "127.0.0.1:9".parse().unwrap()).await {
+            Err(KcpError::ListenerClosed(Some(..))) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("session prepared by a failed listener"),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_graceful() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        server.recv(&mut buffer).await.unwrap();
+
+        // Transfer in flight finishes after shutdown started
+        let transfer = tokio::spawn(async move {
+            time::sleep(Duration::from_millis(200)).await;
+            server.send(b"WORLD").await.unwrap();
+            server.flush().await.unwrap();
+            time::sleep(Duration::from_millis(100)).await;
+        });
+
+        let shutdown = async {
+            let start = Instant::now();
+            listener.shutdown(Duration::from_secs(5)).await;
+            start.elapsed()
+        };
+        let new_client = async {
+            time::sleep(Duration::from_millis(50)).await;
+            let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+            client.send(b"HELLO").await.unwrap();
+            let mut buffer = [0u8; 1024];
+            time::timeout(Duration::from_secs(2), client.recv(&mut buffer))
+                .await
+                .expect("new client not refused")
+                .unwrap()
+        };
+        let (elapsed, refused) = tokio::join!(shutdown, new_client);
+        assert_eq!(refused, 0);
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_secs(5));
+        transfer.await.unwrap();
+
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"WORLD", &buffer[..n]);
+
+        match listener.accept().await {
+            Err(KcpError::ListenerClosed(None)) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("accepted after shutdown"),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_timeout() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: crate::KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        server.recv(&mut buffer).await.unwrap();
+
+        // Session is never closed, it is reset at the deadline
+        let start = Instant::now();
+        time::timeout(Duration::from_secs(3), listener.shutdown(Duration::from_millis(300)))
+            .await
+            .expect("shutdown didn't finish");
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        match server.recv(&mut buffer).await {
+            Err(KcpError::ConnectionReset) => {}
+            r => panic!("unexpected result {:?}", r),
+        }
+        let n = time::timeout(Duration::from_secs(3), client.recv(&mut buffer))
+            .await
+            .expect("client didn't see close")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn bind_invalid_config() {
+        let config = KcpConfig {
+            mtu: 20,
+            ..Default::default()
+        };
+        match KcpListener::bind(config, "127.0.0.1:0").await {
+            Err(KcpError::ConfigInvalid(..)) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("invalid config accepted"),
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_packets() {
+        let _ = env_logger::try_init();
+
+        let mut listener = KcpListener::bind(KcpConfig::default(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut unknown_cmd = [0u8; 30];
+        unknown_cmd[4] = 0xFF;
+        let mut fin_unknown_conv = [0u8; 24];
+        fin_unknown_conv[0] = 100;
+        fin_unknown_conv[4] = 90;
+        let packets: [&[u8]; 5] = [b"", b"\x01\x02\x03", &[0u8; 23], &unknown_cmd, &fin_unknown_conv];
+        for packet in packets.iter() {
+            udp.send_to(packet, server_addr).await.unwrap();
+        }
+
+        let mut stream = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        // Skips the session opened by the packet with unknown command
+        loop {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            if let Ok(Ok(n)) = time::timeout(Duration::from_millis(500), server.recv(&mut buffer)).await {
+                assert_eq!(b"HELLO", &buffer[..n]);
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn checksum() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            checksum: true,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // A PUSH that would open a session, with its payload corrupted
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut push = fin_segment(0);
+        push[4] = 81;
+        let mut corrupted = push.to_vec();
+        checksum::seal(&mut corrupted);
+        corrupted[8] ^= 1;
+        udp.send_to(&corrupted, server_addr).await.unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = accepted.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"HELLO");
+        accepted.send(&buf[..n]).await.unwrap();
+        let n = stream.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"HELLO");
+
+        assert_eq!(listener.corrupted_packets().await, 1);
+        assert_eq!(listener.session_count().await, 1);
+        assert_eq!(stream.corrupted_packets(), 0);
+        // The 4 bytes of CRC32 are part of the MTU
+        assert_eq!(stream.mtu(), config.mtu);
+
+        // Clients without checksum can't open sessions
+        let mut plain = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+        plain.send(b"HELLO").await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert!(listener.corrupted_packets().await > 1);
+        assert_eq!(listener.session_count().await, 1);
+    }
+
+    /// Pads datagrams to multiples of 32 bytes and scrambles them
+    struct Scramble;
+
+    impl PacketObfuscator for Scramble {
+        fn overhead(&self) -> usize {
+            32
+        }
+
+        fn seal(&self, packet: &mut Vec<u8>) {
+            let padding = 32 - packet.len() % 32;
+            packet.resize(packet.len() + padding, padding as u8);
+            packet.iter_mut().for_each(|b| *b ^= 0x5a);
+        }
+
+        fn open(&self, packet: &mut Vec<u8>) -> bool {
+            packet.iter_mut().for_each(|b| *b ^= 0x5a);
+            match packet.last() {
+                Some(&padding) if packet.len().is_multiple_of(32) && (1..=32).contains(&padding) => {
+                    packet.truncate(packet.len() - padding as usize);
+                    true
+                }
+                _ => false,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn obfuscator() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            checksum: true,
+            obfuscator: Some(Arc::new(Scramble)),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let data = vec![7u8; 5000];
+        stream.write_all(&data).await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        let mut buf = vec![0u8; data.len()];
+        accepted.read_exact(&mut buf).await.unwrap();
+        accepted.write_all(&buf).await.unwrap();
+        buf.fill(0);
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        // Padding is part of the MTU
+        assert_eq!(stream.mtu(), config.mtu);
+
+        // Datagrams that are not obfuscated never open sessions
+        let mut plain = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+        plain.send(b"HELLO").await.unwrap();
+        time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(listener.session_count().await, 1);
+        assert_eq!(listener.corrupted_packets().await, 0);
+    }
+
+    #[tokio::test]
+    async fn buffer_pool_stats() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            buffer_pool: BufferPoolConfig { count: 4, size: 2048 },
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        for _ in 0..10 {
+            let n = accepted.recv(&mut buf).await.unwrap();
+            accepted.send(&buf[..n]).await.unwrap();
+            let n = stream.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"HELLO");
+            stream.send(b"HELLO").await.unwrap();
+        }
+
+        // Buffers of processed packets were reused
+        let stats = listener.buffer_pool_stats();
+        assert!(stats.hits > stats.misses, "{:?}", stats);
+    }
+
+    #[tokio::test]
+    async fn accept_timeout() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let accepted = listener.accept_timeout(Duration::from_millis(200)).await.unwrap();
+        assert!(accepted.is_none());
+
+        // Still usable after timeout
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (_accepted, peer_addr) = listener
+            .accept_timeout(Duration::from_secs(1))
+            .await
+            .unwrap()
+            .expect("connection not accepted");
+        assert!(peer_addr.ip().is_loopback());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receive_overload() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            wnd_size: (1024, 1024),
+            adaptive_window: true,
+            ..Default::default()
+        };
+        // Holds fewer datagrams than the receive window, like a small receive buffer of a socket
+        let link = NetEmConfig {
+            extra_latency: Duration::from_millis(20),
+            limit: Some(600),
+            ..Default::default()
+        };
+        let network = MemoryNetwork::new();
+        let server = network.bind();
+        let server_addr = server.local_addr();
+        let mut listener =
+            KcpListener::with_transport(config.clone(), Arc::new(NetEmTransport::new(server, link))).unwrap();
+        assert_eq!(listener.dropped_datagrams(), Some(0));
+
+        let mut client = KcpStream::connect_with_transport(&config, Arc::new(network.bind()), server_addr)
+            .await
+            .unwrap();
+        client.write_all(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        // Records overload events, others may lag behind
+        let mut events = accepted.events();
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            loop {
+                match events.recv().await {
+                    Ok(event @ (KcpEvent::ReceiveOverload { .. } | KcpEvent::ReceiveOverloadCleared)) => {
+                        let _ = event_tx.send(event);
+                    }
+                    Ok(..) | Err(tokio::sync::broadcast::error::RecvError::Lagged(..)) => {}
+                    Err(..) => break,
+                }
+            }
+        });
+        tokio::spawn(async move {
+            let mut buffer = vec![0u8; 65536];
+            while accepted.read(&mut buffer).await.is_ok_and(|n| n > 0) {}
+        });
+
+        tokio::spawn(async move {
+            let chunk = vec![0u8; 16 * 1024];
+            while client.write_all(&chunk).await.is_ok() {}
+        });
+
+        let event = time::timeout(Duration::from_secs(10), event_rx.recv()).await.unwrap();
+        assert!(
+            matches!(event, Some(KcpEvent::ReceiveOverload { dropped }) if dropped > 0),
+            "{:?}",
+            event
+        );
+        assert!(listener.dropped_datagrams().unwrap() > 0);
+        assert_eq!(listener.dump_sessions().await[0].config.rcv_wnd, 512);
+
+        // Peer keeps sending, the smaller window fits in the link
+        let event = time::timeout(Duration::from_secs(30), event_rx.recv()).await.unwrap();
+        assert_eq!(event, Some(KcpEvent::ReceiveOverloadCleared));
+        assert_eq!(listener.dump_sessions().await[0].config.rcv_wnd, 1024);
+    }
 }
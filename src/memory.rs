@@ -0,0 +1,476 @@
+//! In-memory transport for tests
+//!
+//! `MemoryNetwork` carries datagrams between the `MemoryTransport`s bound on it by channels, without any socket,
+//! so listeners and streams of a test can talk to each other like on loopback UDP. `MemoryTransport::pair` is a
+//! network of two ends, for a listener and a stream. Datagrams sent by an end may be lost, delayed, or limited to a
+//! bandwidth, by `set_loss_rate`, `set_latency` and `set_rate`. Delays are timers of tokio, which follow
+//! `tokio::time::pause`, like timers of sessions do, so retransmissions and timeouts can be tested in virtual time
+//! without waiting for them.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures::ready;
+use tokio::{
+    io::ReadBuf,
+    sync::mpsc,
+    time::{self, Instant, Sleep},
+};
+
+use crate::{transport::KcpTransport, utils::Rng};
+
+/// Datagrams waiting to be received by an end, more are dropped like by a full socket buffer
+const QUEUE_LEN: usize = 1024;
+
+/// Seed of the PRNGs deciding losses, the same datagrams are lost in every run
+const LOSS_SEED: u64 = 0x6b63_705f_6d65_6d6f;
+
+/// Port of every end, which has an address of its own
+const PORT: u16 = 3100;
+
+struct Datagram {
+    data: Vec<u8>,
+    from: SocketAddr,
+    /// Time it is received by peer
+    due: Instant,
+}
+
+#[derive(Default)]
+struct Hosts {
+    /// Receivers of datagrams by address
+    hosts: HashMap<SocketAddr, mpsc::Sender<Datagram>>,
+    /// Number of ends ever bound, which gives the address of the next one
+    bound: u32,
+}
+
+/// Network of `MemoryTransport`s, any of them can send datagrams to all the others.
+///
+/// ```
+/// # use std::sync::Arc;
+/// # use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// # use tokio_kcp::{KcpConfig, KcpListener, KcpStream, MemoryNetwork};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let network = MemoryNetwork::new();
+/// let server = network.bind();
+/// let server_addr = server.local_addr();
+/// let mut listener = KcpListener::with_transport(KcpConfig::default(), Arc::new(server)).unwrap();
+///
+/// let mut streams = Vec::new();
+/// for _ in 0..4 {
+///     let transport = Arc::new(network.bind());
+///     let mut stream = KcpStream::connect_with_transport(&KcpConfig::default(), transport, server_addr)
+///         .await
+///         .unwrap();
+///     stream.write_all(b"HELLO").await.unwrap();
+///     streams.push(stream);
+/// }
+/// for _ in 0..4 {
+///     let (mut accepted, _) = listener.accept().await.unwrap();
+///     let mut buf = [0u8; 5];
+///     accepted.read_exact(&mut buf).await.unwrap();
+/// }
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    hosts: Arc<StdMutex<Hosts>>,
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// Binds a new end, at the next address of `198.18.0.0/15` on port 3100, `198.18.0.1:3100` for the first one
+    pub fn bind(&self) -> MemoryTransport {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts.bound += 1;
+        let host = hosts.bound;
+        let ip = Ipv4Addr::from(u32::from(Ipv4Addr::new(198, 18, 0, 0)) + host);
+        let local_addr = SocketAddr::new(ip.into(), PORT);
+
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        hosts.hosts.insert(local_addr, tx);
+
+        MemoryTransport {
+            local_addr,
+            network: self.clone(),
+            receiving: StdMutex::new(Receiving {
+                rx,
+                next: None,
+                sleep: Box::pin(time::sleep(Duration::ZERO)),
+            }),
+            link: StdMutex::new(Link {
+                latency: Duration::ZERO,
+                loss_rate: 0.0,
+                rate: None,
+                busy_until: Instant::now(),
+                rng: Rng::new(LOSS_SEED.wrapping_add(host as u64)),
+            }),
+        }
+    }
+
+    fn host(&self, addr: &SocketAddr) -> Option<mpsc::Sender<Datagram>> {
+        self.hosts.lock().unwrap().hosts.get(addr).cloned()
+    }
+}
+
+struct Receiving {
+    rx: mpsc::Receiver<Datagram>,
+    /// Datagram taken from `rx`, waiting until it is due
+    next: Option<Datagram>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+/// Impairments of datagrams sent by an end
+struct Link {
+    latency: Duration,
+    loss_rate: f64,
+    /// Bytes per second
+    rate: Option<u64>,
+    /// Time the last datagram sent leaves the link, when limited by `rate`
+    busy_until: Instant,
+    rng: Rng,
+}
+
+/// One end of an in-memory network of datagrams, a `KcpTransport` for tests.
+///
+/// Sending to an address that no end is bound to fails with `ConnectionRefused`, like ICMP port unreachable on
+/// loopback.
+///
+/// ```
+/// # use std::{sync::Arc, time::Duration};
+/// # use tokio::io::{AsyncReadExt, AsyncWriteExt};
+/// # use tokio_kcp::{KcpConfig, KcpListener, KcpStream, MemoryTransport};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (server, client) = MemoryTransport::pair();
+/// client.set_latency(Duration::from_millis(20));
+/// client.set_loss_rate(0.1);
+///
+/// let server_addr = server.local_addr();
+/// let mut listener = KcpListener::with_transport(KcpConfig::default(), Arc::new(server)).unwrap();
+/// let mut stream = KcpStream::connect_with_transport(&KcpConfig::default(), Arc::new(client), server_addr)
+///     .await
+///     .unwrap();
+///
+/// stream.write_all(b"HELLO").await.unwrap();
+/// let (mut accepted, _) = listener.accept().await.unwrap();
+/// let mut buf = [0u8; 5];
+/// accepted.read_exact(&mut buf).await.unwrap();
+/// assert_eq!(&buf, b"HELLO");
+/// # }
+/// ```
+pub struct MemoryTransport {
+    local_addr: SocketAddr,
+    network: MemoryNetwork,
+    receiving: StdMutex<Receiving>,
+    link: StdMutex<Link>,
+}
+
+impl MemoryTransport {
+    /// Creates both ends of a network of their own
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let network = MemoryNetwork::new();
+        (network.bind(), network.bind())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Delays datagrams sent by this end by `latency`, one way
+    pub fn set_latency(&self, latency: Duration) {
+        self.link.lock().unwrap().latency = latency;
+    }
+
+    /// Drops datagrams sent by this end with probability `loss_rate`, in `[0, 1]`
+    pub fn set_loss_rate(&self, loss_rate: f64) {
+        self.link.lock().unwrap().loss_rate = loss_rate;
+    }
+
+    /// Limits datagrams sent by this end to `rate` bytes per second, like a bottleneck link, `None` for no limit.
+    ///
+    /// Datagrams wait in a queue for their turn, which adds to `latency`. The queue is the one of the receiving end,
+    /// datagrams are dropped when it's full.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        self.link.lock().unwrap().rate = rate;
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        self.network.hosts.lock().unwrap().hosts.remove(&self.local_addr);
+    }
+}
+
+impl KcpTransport for MemoryTransport {
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.try_send_to(buf, target).into()
+    }
+
+    fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let host = self.network.host(&target).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::ConnectionRefused,
+                format!("no memory transport bound to {}", target),
+            )
+        })?;
+
+        let mut link = self.link.lock().unwrap();
+        let loss_rate = link.loss_rate;
+        if link.rng.chance(loss_rate) {
+            return Ok(buf.len());
+        }
+        let mut sent_at = Instant::now();
+        if let Some(rate) = link.rate {
+            // Serialized after the datagrams before
+            sent_at = sent_at.max(link.busy_until) + Duration::from_secs_f64(buf.len() as f64 / rate as f64);
+        }
+        let datagram = Datagram {
+            data: buf.to_vec(),
+            from: self.local_addr,
+            due: sent_at + link.latency,
+        };
+        // Dropped if the queue of `target` is full, its end may be gone since `host` was looked up
+        if host.try_send(datagram).is_ok() && link.rate.is_some() {
+            link.busy_until = sent_at;
+        }
+        Ok(buf.len())
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        let mut receiving = self.receiving.lock().unwrap();
+        let receiving = &mut *receiving;
+        loop {
+            if let Some(ref datagram) = receiving.next {
+                if datagram.due > Instant::now() {
+                    receiving.sleep.as_mut().reset(datagram.due);
+                    ready!(receiving.sleep.as_mut().poll(cx));
+                }
+                let datagram = receiving.next.take().expect("datagram");
+                let n = datagram.data.len().min(buf.remaining());
+                buf.put_slice(&datagram.data[..n]);
+                return Ok(datagram.from).into();
+            }
+            // The sender of this end is kept by the network until it is dropped
+            let datagram = ready!(receiving.rx.poll_recv(cx)).expect("memory transport unbound");
+            receiving.next = Some(datagram);
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::ErrorKind, sync::Arc, time::Duration};
+
+    use bytes::BytesMut;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::{self, Instant},
+    };
+
+    use super::{MemoryNetwork, MemoryTransport};
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig},
+        error::KcpError,
+        listener::KcpListener,
+        stream::KcpStream,
+        transport::{recv_buf_from, send_to, KcpTransport},
+    };
+
+    /// Echoes every accepted stream until it is closed
+    fn spawn_echo(mut listener: KcpListener) {
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n) = stream.read(&mut buf).await {
+                        if n == 0 || stream.write_all(&buf[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_transport() {
+        let network = MemoryNetwork::new();
+        let a = network.bind();
+        let b = network.bind();
+        let c = network.bind();
+        assert_eq!(a.local_addr(), "198.18.0.1:3100".parse().unwrap());
+        assert_ne!(a.local_addr(), b.local_addr());
+
+        send_to(&a, b"HELLO", b.local_addr()).await.unwrap();
+        c.try_send_to(b"WORLD", b.local_addr()).unwrap();
+
+        let mut buf = BytesMut::with_capacity(16);
+        let (n, addr) = recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!((n, addr), (5, a.local_addr()));
+        let (n, addr) = recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!((n, addr), (5, c.local_addr()));
+        assert_eq!(&buf[..], b"HELLOWORLD");
+
+        a.set_latency(Duration::from_millis(50));
+        let sent_at = Instant::now();
+        a.try_send_to(b"LATE", b.local_addr()).unwrap();
+        buf.clear();
+        recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"LATE");
+        assert_eq!(sent_at.elapsed(), Duration::from_millis(50));
+
+        a.set_loss_rate(1.0);
+        a.try_send_to(b"LOST", b.local_addr()).unwrap();
+        buf.clear();
+        assert!(time::timeout(Duration::from_secs(1), recv_buf_from(&b, &mut buf))
+            .await
+            .is_err());
+
+        // Nothing is bound there anymore
+        let a_addr = a.local_addr();
+        drop(a);
+        let err = b.try_send_to(b"HELLO", a_addr).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[tokio::test]
+    async fn memory_lossy_echo() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let (server, client) = MemoryTransport::pair();
+        for end in [&server, &client] {
+            end.set_latency(Duration::from_millis(5));
+            end.set_loss_rate(0.1);
+        }
+        let server_addr = server.local_addr();
+
+        let mut listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, Arc::new(client), server_addr)
+            .await
+            .unwrap();
+        assert_eq!(listener.local_addr().unwrap(), server_addr);
+        // No UDP socket under them
+        assert!(listener.get_ref().is_none());
+        assert!(stream.get_ref().is_none());
+
+        let data: Vec<u8> = (0..64 * 1024).map(|i| i as u8).collect();
+        stream.write_all(&data).await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        let mut buf = vec![0u8; data.len()];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+        accepted.write_all(&buf).await.unwrap();
+        buf.fill(0);
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, data);
+    }
+
+    /// Sessions told apart by conv and address, with convs allocated by server or negotiated by handshake
+    #[tokio::test(start_paused = true)]
+    async fn memory_conv_allocation() {
+        for handshake in [false, true] {
+            let config = KcpConfig {
+                handshake,
+                ..Default::default()
+            };
+            let network = MemoryNetwork::new();
+            let server = network.bind();
+            let server_addr = server.local_addr();
+            spawn_echo(KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap());
+
+            let mut streams = Vec::new();
+            for _ in 0..4 {
+                let transport = Arc::new(network.bind());
+                streams.push(
+                    KcpStream::connect_with_transport(&config, transport, server_addr)
+                        .await
+                        .unwrap(),
+                );
+            }
+            for (i, stream) in streams.iter_mut().enumerate() {
+                stream.write_all(&[i as u8; 32]).await.unwrap();
+            }
+            for (i, stream) in streams.iter_mut().enumerate() {
+                let mut buf = [0u8; 32];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(buf, [i as u8; 32]);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn memory_close() {
+        let config = KcpConfig::default();
+        let (server, client) = MemoryTransport::pair();
+        let server_addr = server.local_addr();
+        let mut listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, Arc::new(client), server_addr)
+            .await
+            .unwrap();
+
+        stream.write_all(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+
+        // Peer learns about the close by FIN
+        drop(stream);
+        assert_eq!(accepted.read(&mut buf).await.unwrap(), 0);
+    }
+
+    /// Retransmissions driven by RTO in virtual time, no second of the test is really waited for
+    #[tokio::test(start_paused = true)]
+    async fn memory_retransmission() {
+        let config = KcpConfig::default();
+        let (server, client) = MemoryTransport::pair();
+        let server_addr = server.local_addr();
+        let mut listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+        let client = Arc::new(client);
+        let mut stream = KcpStream::connect_with_transport(&config, client.clone(), server_addr)
+            .await
+            .unwrap();
+
+        // Lost until the link recovers
+        client.set_loss_rate(1.0);
+        let sent_at = Instant::now();
+        stream.write_all(b"HELLO").await.unwrap();
+        time::sleep(Duration::from_secs(1)).await;
+        assert!(stream.retransmissions() > 0);
+        client.set_loss_rate(0.0);
+
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+        assert!(sent_at.elapsed() >= Duration::from_secs(1));
+
+        // Link is dead after `KcpConfig::dead_link` retransmissions
+        client.set_loss_rate(1.0);
+        stream.write_all(b"WORLD").await.unwrap();
+        match stream.flush_acked().await {
+            Err(KcpError::Timeout) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
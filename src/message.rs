@@ -0,0 +1,227 @@
+//! Messages of a `KcpStream` as `Bytes`, see `KcpStream::into_messages`
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use futures::{ready, Sink, Stream};
+
+use crate::{
+    error::{KcpError, KcpResult},
+    stream::KcpStream,
+};
+
+/// Initial part of the receive buffer, messages are split off it until it's used up
+const RECV_BUFFER_SIZE: usize = 16 * 1024;
+
+/// `Sink` and `Stream` of whole messages of a `KcpStream`, created by `KcpStream::into_messages`
+///
+/// Every item sent is one message of KCP, and every item received is one message sent by peer, with boundaries
+/// preserved in message mode, which is `KcpConfig::stream` disabled. In stream mode, items received are what one
+/// `recv` returns, which may be parts of several items sent.
+///
+/// As a `Sink`, it keeps one message until it fits into the send window: `poll_ready` returns `Pending` while it
+/// doesn't. `poll_flush` sends it, then flushes KCP, so it goes out without waiting for the next update. `poll_close`
+/// also shuts down the stream for writing, see `KcpStream::shutdown_write`, which ends the `Stream` of peer. Empty
+/// messages are not sent, peer would take them for the end.
+///
+/// As a `Stream`, it ends after peer closed or shut down for writing, and all messages before were received.
+pub struct KcpMessageStream {
+    stream: KcpStream,
+    /// Accepted by `start_send`, not sent yet
+    pending: Option<Bytes>,
+    recv_buffer: BytesMut,
+}
+
+impl KcpMessageStream {
+    pub(crate) fn new(stream: KcpStream) -> KcpMessageStream {
+        KcpMessageStream {
+            stream,
+            pending: None,
+            recv_buffer: BytesMut::new(),
+        }
+    }
+
+    pub fn get_ref(&self) -> &KcpStream {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut KcpStream {
+        &mut self.stream
+    }
+
+    /// Returns the stream, a message accepted by `start_send` and not sent yet is dropped
+    pub fn into_inner(self) -> KcpStream {
+        self.stream
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.stream.peer_addr()
+    }
+
+    /// Sends the message accepted by `start_send`, if any
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        if let Some(ref message) = self.pending {
+            ready!(self.stream.poll_send(cx, message))?;
+            self.pending = None;
+        }
+        Ok(()).into()
+    }
+}
+
+impl Sink<Bytes> for KcpMessageStream {
+    type Error = KcpError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        self.get_mut().poll_send_pending(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Bytes) -> KcpResult<()> {
+        let this = self.get_mut();
+        debug_assert!(this.pending.is_none(), "start_send without poll_ready");
+        if !item.is_empty() {
+            this.pending = Some(item);
+        }
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_pending(cx))?;
+        this.stream.flush_kcp().into()
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        self.stream.shutdown_write().into()
+    }
+}
+
+impl Stream for KcpMessageStream {
+    type Item = KcpResult<Bytes>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<KcpResult<Bytes>>> {
+        let this = self.get_mut();
+        if this.recv_buffer.len() < RECV_BUFFER_SIZE {
+            this.recv_buffer.resize(RECV_BUFFER_SIZE, 0);
+        }
+        loop {
+            match ready!(this.stream.poll_recv(cx, &mut this.recv_buffer)) {
+                Ok(0) => return None.into(),
+                Ok(n) => return Some(Ok(this.recv_buffer.split_to(n).freeze())).into(),
+                // Kept for a larger buffer
+                Err(KcpError::BufferTooSmall(size)) => this.recv_buffer.resize(size, 0),
+                Err(err) => return Some(Err(err)).into(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{task::Context, time::Duration};
+
+    use bytes::Bytes;
+    use futures::{future, stream, task::noop_waker_ref, SinkExt, StreamExt};
+    use tokio::time;
+
+    use crate::{config::KcpConfig, listener::KcpListener, stream::KcpStream};
+
+    fn message_config() -> KcpConfig {
+        KcpConfig {
+            stream: false,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn relay_messages() {
+        let _ = env_logger::try_init();
+
+        let config = message_config();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Echoes every message, then closes after peer did
+        let server = tokio::spawn(async move {
+            let (server, _) = listener.accept().await.unwrap();
+            let (sink, stream) = server.into_messages().split();
+            stream.forward(sink).await.unwrap();
+            // Sessions are dropped with it
+            listener
+        });
+
+        // Larger than the receive buffer too
+        let messages = [1, 100, 1400, 4000, 20 * 1024, 3]
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| Bytes::from(vec![i as u8; len]))
+            .collect::<Vec<_>>();
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut sink, mut echoed) = stream.into_messages().split();
+        sink.send_all(&mut stream::iter(messages.clone()).map(Ok))
+            .await
+            .unwrap();
+        sink.close().await.unwrap();
+
+        for message in &messages {
+            let received = time::timeout(Duration::from_secs(5), echoed.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(&received, message);
+        }
+        let end = time::timeout(Duration::from_secs(5), echoed.next()).await.unwrap();
+        assert!(end.is_none());
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn ready_backpressure() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            wnd_size: (8, 8),
+            ..message_config()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut messages = KcpStream::connect(&config, server_addr).await.unwrap().into_messages();
+        messages.send(Bytes::from_static(b"HELLO")).await.unwrap();
+        // Doesn't read, the window of peer fills up
+        let (server, _) = listener.accept().await.unwrap();
+
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut full = false;
+        for _ in 0..1000 {
+            if SinkExt::<Bytes>::poll_ready_unpin(&mut messages, &mut cx).is_pending() {
+                full = true;
+                break;
+            }
+            messages.start_send_unpin(Bytes::from(vec![0u8; 1024])).unwrap();
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(full, "send window never filled");
+
+        // Room again after peer reads
+        let mut server = server.into_messages();
+        let ready =
+            tokio::spawn(
+                async move { future::poll_fn(|cx| SinkExt::<Bytes>::poll_ready_unpin(&mut messages, cx)).await },
+            );
+        while !ready.is_finished() {
+            time::timeout(Duration::from_secs(5), server.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap();
+        }
+        ready.await.unwrap().unwrap();
+    }
+}
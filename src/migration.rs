@@ -0,0 +1,196 @@
+//! Session migration across peer address changes
+//!
+//! Enabled by `KcpConfig::enable_migration`. When a client changes network, its packets arrive from
+//! a new address. The server proves that the new address belongs to the same client before sending
+//! anything there:
+//!
+//! ```plain
+//! Client                               Server
+//!                              <-      TOKEN(conv, token)          issued when session is created
+//!   RESUME(conv, token)        ->                                  acknowledges TOKEN
+//!   ... network changes ...
+//!   KCP segments from new addr ->                                  dropped
+//!                              <-      CHALLENGE(conv)
+//!   RESUME(conv, token)        ->                                  session rebound to the new address
+//! ```
+//!
+//! Tokens are signed with HMAC-SHA256 by a key that is private to the listener, and a fresh token is issued for
+//! every session, so a token of a closed session cannot be used to take over a new session with the same conv.
+
+use std::time::Duration;
+
+use bytes::{Buf, BufMut};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+const MAGIC: &[u8; 4] = b"KCPM";
+
+const FRAME_TOKEN: u8 = 1;
+const FRAME_RESUME: u8 = 2;
+const FRAME_CHALLENGE: u8 = 3;
+
+/// Length of the random nonce part of a token
+const NONCE_LEN: usize = 8;
+/// Length of the truncated HMAC part of a token
+const TAG_LEN: usize = 16;
+/// Length of a resumption token, nonce + truncated HMAC
+pub const TOKEN_LEN: usize = NONCE_LEN + TAG_LEN;
+
+/// Length of frames carrying a token, `MAGIC` + kind + conv + token
+const TOKEN_FRAME_LEN: usize = 4 + 1 + 4 + TOKEN_LEN;
+/// Length of `CHALLENGE`, `MAGIC` + kind + conv
+const CHALLENGE_FRAME_LEN: usize = 4 + 1 + 4;
+
+/// Interval of sending `TOKEN` until it is acknowledged
+pub const TOKEN_RESEND_INTERVAL: Duration = Duration::from_secs(1);
+/// Maximum number of `TOKEN` sent, peer may not support migration
+pub const TOKEN_MAX_ATTEMPTS: u32 = 10;
+
+/// Opaque token that proves ownership of a session
+pub type ResumptionToken = [u8; TOKEN_LEN];
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Frames exchanged for migrating sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationFrame {
+    Token { conv: u32, token: ResumptionToken },
+    Resume { conv: u32, token: ResumptionToken },
+    Challenge { conv: u32 },
+}
+
+impl MigrationFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(TOKEN_FRAME_LEN);
+        frame.put_slice(MAGIC);
+        match *self {
+            MigrationFrame::Token { conv, ref token } => {
+                frame.put_u8(FRAME_TOKEN);
+                frame.put_u32_le(conv);
+                frame.put_slice(token);
+            }
+            MigrationFrame::Resume { conv, ref token } => {
+                frame.put_u8(FRAME_RESUME);
+                frame.put_u32_le(conv);
+                frame.put_slice(token);
+            }
+            MigrationFrame::Challenge { conv } => {
+                frame.put_u8(FRAME_CHALLENGE);
+                frame.put_u32_le(conv);
+            }
+        }
+        frame
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Option<MigrationFrame> {
+        if buf.len() < CHALLENGE_FRAME_LEN || &buf[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let frame_len = buf.len();
+        buf.advance(MAGIC.len());
+
+        let kind = buf.get_u8();
+        let conv = buf.get_u32_le();
+
+        match kind {
+            FRAME_CHALLENGE if frame_len == CHALLENGE_FRAME_LEN => Some(MigrationFrame::Challenge { conv }),
+            FRAME_TOKEN | FRAME_RESUME if frame_len == TOKEN_FRAME_LEN => {
+                let mut token = [0u8; TOKEN_LEN];
+                buf.copy_to_slice(&mut token);
+
+                if kind == FRAME_TOKEN {
+                    Some(MigrationFrame::Token { conv, token })
+                } else {
+                    Some(MigrationFrame::Resume { conv, token })
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Issues and verifies resumption tokens
+pub struct TokenSigner {
+    key: [u8; 32],
+}
+
+impl TokenSigner {
+    /// Signer with a random key from the OS, which tokens can't be forged without
+    pub fn new() -> TokenSigner {
+        let mut key = [0u8; 32];
+        os_random(&mut key);
+        TokenSigner { key }
+    }
+
+    fn mac(&self, conv: u32, nonce: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC accepts any key length");
+        mac.update(&conv.to_le_bytes());
+        mac.update(nonce);
+        mac
+    }
+
+    /// Issues a new token for session `conv`
+    pub fn sign(&self, conv: u32) -> ResumptionToken {
+        let mut nonce = [0u8; NONCE_LEN];
+        os_random(&mut nonce);
+        let tag = self.mac(conv, &nonce).finalize().into_bytes();
+
+        let mut token = [0u8; TOKEN_LEN];
+        token[..NONCE_LEN].copy_from_slice(&nonce);
+        token[NONCE_LEN..].copy_from_slice(&tag[..TAG_LEN]);
+        token
+    }
+
+    /// Checks if `token` was issued by this signer for session `conv`
+    pub fn verify(&self, conv: u32, token: &ResumptionToken) -> bool {
+        let (nonce, tag) = token.split_at(NONCE_LEN);
+        self.mac(conv, nonce).verify_truncated_left(tag).is_ok()
+    }
+}
+
+/// Fills `buf` from the cryptographically secure random number generator of the OS
+fn os_random(buf: &mut [u8]) {
+    getrandom::getrandom(buf).expect("random number generator of the OS is unavailable");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn migration_frame_codec() {
+        let token = TokenSigner::new().sign(10);
+
+        let frames = [
+            MigrationFrame::Token { conv: 10, token },
+            MigrationFrame::Resume { conv: 10, token },
+            MigrationFrame::Challenge { conv: 10 },
+        ];
+        for frame in frames.iter() {
+            assert_eq!(MigrationFrame::decode(&frame.encode()), Some(*frame));
+        }
+
+        let buf = frames[0].encode();
+        assert_eq!(MigrationFrame::decode(&buf[..buf.len() - 1]), None);
+        assert_eq!(MigrationFrame::decode(b"KCPH\x01\x00\x00\x00\x00"), None);
+    }
+
+    #[test]
+    fn token_verify() {
+        let signer = TokenSigner::new();
+        let token = signer.sign(10);
+
+        assert!(signer.verify(10, &token));
+        // Bound to conv
+        assert!(!signer.verify(11, &token));
+        // Bound to key
+        assert!(!TokenSigner::new().verify(10, &token));
+
+        let mut forged = token;
+        forged[TOKEN_LEN - 1] ^= 1;
+        assert!(!signer.verify(10, &forged));
+
+        // Fresh nonce for every token
+        assert_ne!(signer.sign(10), token);
+    }
+}
@@ -0,0 +1,499 @@
+//! Network emulation for tests
+//!
+//! Enabled by the `testing` feature, for a session by `KcpConfig::test_netem`, or between two endpoints by
+//! wrapping their transports in `NetEmTransport`. Datagrams are dropped alone or in bursts, duplicated, delayed
+//! with jitter or reordered at random, which makes retransmissions of KCP testable without a real lossy network.
+//! Decisions are made by a PRNG seeded with `NetEmConfig::seed`, the same seed makes the same decisions for the
+//! same sequence of datagrams.
+//!
+//! Strictly for testing. Every datagram is copied and goes through a timer queue, which costs a lot more
+//! than sending it directly.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Mutex as StdMutex,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::ReadBuf,
+    time::{self, Instant, Sleep},
+};
+
+use crate::{transport::KcpTransport, utils::Rng};
+
+/// Additional delay of a reordered datagram, datagrams sent in the meantime arrive before it
+pub const REORDER_DELAY: Duration = Duration::from_millis(10);
+
+/// Impairments of datagrams in each direction of a session
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NetEmConfig {
+    /// Probability of dropping a datagram, in `[0, 1]`
+    pub loss_rate: f64,
+    /// Probability of delivering a datagram twice, in `[0, 1]`
+    pub dup_rate: f64,
+    /// Probability of delaying a datagram by `REORDER_DELAY` more than the others, in `[0, 1]`
+    pub reorder_rate: f64,
+    /// Delay of every datagram
+    pub extra_latency: Duration,
+    /// Random delay of a datagram in addition to `extra_latency`, up to it. Datagrams overtake each other by it
+    pub jitter: Duration,
+    /// Probability of a datagram starting a burst of losses, in `[0, 1]`
+    pub burst_rate: f64,
+    /// Datagrams dropped in a row by a burst, including the one that started it
+    pub burst_len: u32,
+    /// Seed of the PRNG
+    pub seed: u64,
+    /// Datagrams held by the link at most, like `limit` of netem. Datagrams that arrive at a full link are dropped
+    /// and counted by `NetEmTransport::dropped_datagrams`, like by the receive buffer of a socket.
+    pub limit: Option<usize>,
+}
+
+/// Direction of datagrams, which has its own sequence of decisions
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+    Send,
+    Recv,
+}
+
+/// Emulated link of one direction, holds datagrams until they are due
+pub struct NetEm<T = Vec<u8>> {
+    config: NetEmConfig,
+    rng: Rng,
+    /// Datagrams left to drop in the current burst of losses
+    burst_left: u32,
+    /// Datagrams ordered by due time, then by the order they were pushed
+    queue: BTreeMap<(Instant, u64), T>,
+    seq: u64,
+    /// Datagrams dropped because the link was full
+    overflowed: u64,
+}
+
+impl<T: Clone> NetEm<T> {
+    pub fn new(config: &NetEmConfig, direction: Direction) -> NetEm<T> {
+        let seed = match direction {
+            Direction::Send => config.seed,
+            Direction::Recv => !config.seed,
+        };
+        NetEm {
+            config: *config,
+            rng: Rng::new(seed),
+            burst_left: 0,
+            queue: BTreeMap::new(),
+            seq: 0,
+            overflowed: 0,
+        }
+    }
+
+    /// Puts a datagram on the link, it may be lost, or taken later by `pop_due` once or twice
+    pub fn push(&mut self, datagram: T, now: Instant) {
+        if self.config.limit.is_some_and(|limit| self.queue.len() >= limit) {
+            self.overflowed += 1;
+            return;
+        }
+        if self.burst_left > 0 {
+            self.burst_left -= 1;
+            return;
+        }
+        // Impairments that are off don't take numbers from the PRNG, which keeps decisions of the others
+        if self.config.burst_rate > 0.0 && self.rng.chance(self.config.burst_rate) {
+            self.burst_left = self.config.burst_len.saturating_sub(1);
+            return;
+        }
+        if self.rng.chance(self.config.loss_rate) {
+            return;
+        }
+        let copies = if self.rng.chance(self.config.dup_rate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut delay = self.config.extra_latency;
+            if self.rng.chance(self.config.reorder_rate) {
+                delay += REORDER_DELAY;
+            }
+            if !self.config.jitter.is_zero() {
+                delay += self.config.jitter.mul_f64(self.rng.next_f64());
+            }
+            self.queue.insert((now + delay, self.seq), datagram.clone());
+            self.seq += 1;
+        }
+    }
+
+    /// Takes the next datagram that is due at `now`
+    pub fn pop_due(&mut self, now: Instant) -> Option<T> {
+        let entry = self.queue.first_entry()?;
+        if entry.key().0 > now {
+            return None;
+        }
+        Some(entry.remove())
+    }
+
+    /// Datagrams dropped because more than `NetEmConfig::limit` were held
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed
+    }
+
+    /// Due time of the next datagram on the link
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.queue.keys().next().map(|(deadline, _)| *deadline)
+    }
+}
+
+struct Receiving {
+    netem: NetEm<(Vec<u8>, SocketAddr)>,
+    /// Buffer of receiving from the inner transport
+    buffer: Box<[u8]>,
+    sleep: Pin<Box<Sleep>>,
+}
+
+/// `KcpTransport` that puts datagrams received by `inner` on an emulated link, for tests of sessions between two
+/// endpoints on a path with losses and delays, without root privileges or `tc`.
+///
+/// Only received datagrams are impaired, with the decisions of `Direction::Recv`. Both ends of a connection are
+/// wrapped for impairments in both directions, like two `MemoryTransport`s of a pair.
+///
+/// ```
+/// # use std::{sync::Arc, time::Duration};
+/// # use tokio_kcp::{KcpConfig, KcpListener, KcpStream, MemoryTransport, NetEmConfig, NetEmTransport};
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let (server, client) = MemoryTransport::pair();
+/// let link = NetEmConfig {
+///     extra_latency: Duration::from_millis(50),
+///     jitter: Duration::from_millis(10),
+///     loss_rate: 0.01,
+///     seed: 42,
+///     ..Default::default()
+/// };
+/// let server_addr = server.local_addr();
+/// let server = NetEmTransport::new(server, link);
+/// let client = NetEmTransport::new(client, link);
+/// let listener = KcpListener::with_transport(KcpConfig::default(), Arc::new(server)).unwrap();
+/// let stream = KcpStream::connect_with_transport(&KcpConfig::default(), Arc::new(client), server_addr)
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+pub struct NetEmTransport<T> {
+    inner: T,
+    receiving: StdMutex<Receiving>,
+}
+
+impl<T: KcpTransport> NetEmTransport<T> {
+    pub fn new(inner: T, config: NetEmConfig) -> NetEmTransport<T> {
+        NetEmTransport {
+            inner,
+            receiving: StdMutex::new(Receiving {
+                netem: NetEm::new(&config, Direction::Recv),
+                buffer: vec![0u8; 65536].into_boxed_slice(),
+                sleep: Box::pin(time::sleep(Duration::ZERO)),
+            }),
+        }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T: KcpTransport> KcpTransport for NetEmTransport<T> {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.inner.poll_send_to(cx, buf, target)
+    }
+
+    fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.inner.try_send_to(buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        let mut receiving = self.receiving.lock().unwrap();
+        let receiving = &mut *receiving;
+        loop {
+            // Everything that arrived goes on the link, until `inner` has nothing more
+            loop {
+                let mut inner_buf = ReadBuf::new(&mut receiving.buffer);
+                match self.inner.poll_recv_from(cx, &mut inner_buf) {
+                    Poll::Ready(Ok(addr)) => {
+                        let datagram = inner_buf.filled().to_vec();
+                        receiving.netem.push((datagram, addr), Instant::now());
+                    }
+                    Poll::Ready(Err(err)) => return Err(err).into(),
+                    Poll::Pending => break,
+                }
+            }
+
+            if let Some((datagram, addr)) = receiving.netem.pop_due(Instant::now()) {
+                let n = datagram.len().min(buf.remaining());
+                buf.put_slice(&datagram[..n]);
+                return Ok(addr).into();
+            }
+            match receiving.netem.next_deadline() {
+                Some(deadline) => {
+                    receiving.sleep.as_mut().reset(deadline);
+                    if receiving.sleep.as_mut().poll(cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                }
+                None => return Poll::Pending,
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Datagrams dropped by `inner` and by the link when it was over `NetEmConfig::limit`
+    fn dropped_datagrams(&self) -> Option<u64> {
+        let overflowed = self.receiving.lock().unwrap().netem.overflowed();
+        Some(self.inner.dropped_datagrams().unwrap_or(0) + overflowed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use bytes::BytesMut;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use super::{Direction, NetEm, NetEmConfig, NetEmTransport, REORDER_DELAY};
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig},
+        listener::KcpListener,
+        memory::MemoryTransport,
+        stream::KcpStream,
+        transport::{recv_buf_from, KcpTransport},
+    };
+
+    fn deliveries(config: &NetEmConfig, now: Instant) -> Vec<u8> {
+        let mut netem = NetEm::new(config, Direction::Send);
+        for i in 0..100u8 {
+            netem.push(vec![i], now);
+        }
+        let mut delivered = Vec::new();
+        while let Some(buf) = netem.pop_due(now + config.extra_latency + REORDER_DELAY) {
+            delivered.push(buf[0]);
+        }
+        delivered
+    }
+
+    #[test]
+    fn netem_seeded() {
+        let config = NetEmConfig {
+            loss_rate: 0.2,
+            dup_rate: 0.1,
+            reorder_rate: 0.1,
+            extra_latency: Duration::from_millis(5),
+            seed: 42,
+            ..Default::default()
+        };
+        let now = Instant::now();
+
+        let delivered = deliveries(&config, now);
+        assert_eq!(delivered, deliveries(&config, now));
+        assert_ne!(delivered, deliveries(&NetEmConfig { seed: 43, ..config }, now));
+
+        // Some are lost, some are duplicated, some arrive late
+        let mut unique = delivered.clone();
+        unique.sort_unstable();
+        unique.dedup();
+        assert!(unique.len() < 100);
+        assert!(unique.len() < delivered.len());
+        assert!(delivered.windows(2).any(|w| w[0] > w[1]));
+    }
+
+    #[test]
+    fn netem_latency() {
+        let config = NetEmConfig {
+            extra_latency: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let mut netem = NetEm::new(&config, Direction::Recv);
+
+        let now = Instant::now();
+        netem.push(b"1".to_vec(), now);
+        netem.push(b"2".to_vec(), now);
+        assert_eq!(netem.next_deadline(), Some(now + config.extra_latency));
+        assert!(netem.pop_due(now).is_none());
+
+        // In order, nothing else happens without impairment
+        let due = now + config.extra_latency;
+        assert_eq!(netem.pop_due(due).unwrap(), b"1");
+        assert_eq!(netem.pop_due(due).unwrap(), b"2");
+        assert!(netem.pop_due(due).is_none());
+    }
+
+    #[test]
+    fn netem_burst_jitter() {
+        let config = NetEmConfig {
+            burst_rate: 0.1,
+            burst_len: 4,
+            extra_latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(5),
+            seed: 7,
+            ..Default::default()
+        };
+        let mut netem = NetEm::new(&config, Direction::Send);
+        let now = Instant::now();
+        for i in 0..200u8 {
+            netem.push(vec![i], now);
+        }
+
+        let mut delivered = Vec::new();
+        while let Some(deadline) = netem.next_deadline() {
+            assert!(deadline >= now + config.extra_latency);
+            assert!(deadline <= now + config.extra_latency + config.jitter);
+            delivered.push(netem.pop_due(deadline).unwrap()[0]);
+        }
+        assert!(delivered.windows(2).any(|w| w[0] > w[1]));
+
+        // Losses come in runs of `burst_len`, or longer if bursts follow each other
+        delivered.sort_unstable();
+        assert!(delivered.len() < 200);
+        let mut lost = (0..200u8).filter(|i| delivered.binary_search(i).is_err()).peekable();
+        while let Some(first) = lost.next() {
+            let mut last = first;
+            while lost.next_if_eq(&last.wrapping_add(1)).is_some() {
+                last += 1;
+            }
+            assert!(last - first + 1 >= 4 || last == 199, "run {}..={}", first, last);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn netem_transport() {
+        let (a, b) = MemoryTransport::pair();
+        let config = NetEmConfig {
+            extra_latency: Duration::from_millis(30),
+            ..Default::default()
+        };
+        let b = NetEmTransport::new(b, config);
+        let b_addr = b.get_ref().local_addr();
+
+        let sent_at = Instant::now();
+        a.try_send_to(b"HELLO", b_addr).unwrap();
+        a.try_send_to(b"WORLD", b_addr).unwrap();
+        // Sent without delay, only received datagrams are impaired
+        b.try_send_to(b"BACK", a.local_addr()).unwrap();
+
+        let mut buf = BytesMut::with_capacity(16);
+        let (n, addr) = recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!((n, addr), (5, a.local_addr()));
+        recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"HELLOWORLD");
+        assert_eq!(sent_at.elapsed(), config.extra_latency);
+
+        buf.clear();
+        recv_buf_from(&a, &mut buf).await.unwrap();
+        assert_eq!(&buf[..], b"BACK");
+    }
+
+    /// Listener and stream over a pair of memory transports, with `link` in both directions
+    fn connect_over(
+        config: &KcpConfig,
+        link: NetEmConfig,
+    ) -> (KcpListener, impl std::future::Future<Output = KcpStream> + '_) {
+        let (server, client) = MemoryTransport::pair();
+        let server_addr = server.local_addr();
+        let server = NetEmTransport::new(server, link);
+        let client = NetEmTransport::new(
+            client,
+            NetEmConfig {
+                seed: !link.seed,
+                ..link
+            },
+        );
+        let listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+        let stream = async move {
+            KcpStream::connect_with_transport(config, Arc::new(client), server_addr)
+                .await
+                .unwrap()
+        };
+        (listener, stream)
+    }
+
+    /// Echoes `data` over `link`, returns when it came back intact
+    async fn echo_over(config: &KcpConfig, link: NetEmConfig, data: &[u8]) {
+        let (mut listener, stream) = connect_over(config, link);
+        let mut stream = stream.await;
+        let len = data.len();
+        let echo = tokio::spawn(async move {
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; len];
+            accepted.read_exact(&mut buf).await.unwrap();
+            accepted.write_all(&buf).await.unwrap();
+            accepted.flush().await.unwrap();
+            // Keeps the session until the echo was received
+            let _ = accepted.read(&mut buf).await;
+        });
+
+        stream.write_all(data).await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert!(buf == data);
+        drop(stream);
+        echo.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn netem_burst_loss_stream() {
+        let _ = env_logger::try_init();
+
+        // About 30% of datagrams are lost, in bursts of 4
+        let link = NetEmConfig {
+            burst_rate: 0.1,
+            burst_len: 4,
+            extra_latency: Duration::from_millis(10),
+            seed: 30,
+            ..Default::default()
+        };
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let data: Vec<u8> = (0..128 * 1024).map(|i| (i * 7) as u8).collect();
+        echo_over(&config, link, &data).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn netem_rtt_throughput() {
+        let _ = env_logger::try_init();
+
+        // 100ms RTT, 1% loss
+        let link = NetEmConfig {
+            loss_rate: 0.01,
+            extra_latency: Duration::from_millis(50),
+            seed: 100,
+            ..Default::default()
+        };
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            wnd_size: (512, 512),
+            ..Default::default()
+        };
+        let data = vec![0x5au8; 4 * 1024 * 1024];
+
+        let (mut listener, stream) = connect_over(&config, link);
+        let mut stream = stream.await;
+        let start = Instant::now();
+        let sink = tokio::spawn(async move {
+            let (mut accepted, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 4 * 1024 * 1024];
+            accepted.read_exact(&mut buf).await.unwrap();
+            start.elapsed()
+        });
+        stream.write_all(&data).await.unwrap();
+        let elapsed = sink.await.unwrap();
+
+        // Virtual time, a window of 512 segments per RTT carries about 7 MB/s
+        let throughput = data.len() as f64 / elapsed.as_secs_f64();
+        assert!(throughput > 2_000_000.0, "{} bytes/s in {:?}", throughput, elapsed);
+    }
+}
@@ -0,0 +1,126 @@
+//! Detection of receive overload, see `KcpEvent::ReceiveOverload`
+//!
+//! When datagrams arrive faster than they are received, the receive buffer of the socket overflows and the kernel
+//! drops them before KCP sees them. KCP takes them for losses on the path and retransmits, which adds to the load.
+//! The counter of `KcpTransport::dropped_datagrams` is sampled periodically by whoever receives from the socket,
+//! overload is reported when it keeps growing, and cleared after it stopped.
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::{event::KcpEvent, transport::KcpTransport};
+
+/// Interval of sampling the counter of dropped datagrams
+pub const OVERLOAD_SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Samples in a row with drops that make an overload, a single burst doesn't
+const OVERLOAD_SAMPLES: u32 = 3;
+
+/// Samples in a row without drops that clear an overload
+const RECOVERY_SAMPLES: u32 = 10;
+
+/// Watches the datagrams dropped by the receive buffer of a socket
+pub struct OverloadMonitor {
+    next_sample: Instant,
+    /// Counter at the last sample
+    last: u64,
+    /// Samples in a row with drops, and the datagrams dropped in them
+    rising: u32,
+    rising_dropped: u64,
+    /// Samples in a row without drops
+    quiet: u32,
+    overloaded: bool,
+}
+
+impl OverloadMonitor {
+    /// `None` if `transport` doesn't count dropped datagrams
+    pub fn new(transport: &dyn KcpTransport) -> Option<OverloadMonitor> {
+        let last = transport.dropped_datagrams()?;
+        Some(OverloadMonitor {
+            next_sample: Instant::now() + OVERLOAD_SAMPLE_INTERVAL,
+            last,
+            rising: 0,
+            rising_dropped: 0,
+            quiet: 0,
+            overloaded: false,
+        })
+    }
+
+    /// Samples `transport` if it is due, returns the event of a change
+    pub fn poll(&mut self, transport: &dyn KcpTransport, now: Instant) -> Option<KcpEvent> {
+        if now < self.next_sample {
+            return None;
+        }
+        self.next_sample = now + OVERLOAD_SAMPLE_INTERVAL;
+        self.sample(transport.dropped_datagrams()?)
+    }
+
+    fn sample(&mut self, dropped: u64) -> Option<KcpEvent> {
+        let delta = dropped.saturating_sub(self.last);
+        self.last = dropped;
+        if delta > 0 {
+            self.rising += 1;
+            self.rising_dropped += delta;
+            self.quiet = 0;
+        } else {
+            self.rising = 0;
+            self.rising_dropped = 0;
+            self.quiet += 1;
+        }
+
+        if !self.overloaded && self.rising >= OVERLOAD_SAMPLES {
+            self.overloaded = true;
+            return Some(KcpEvent::ReceiveOverload {
+                dropped: self.rising_dropped,
+            });
+        }
+        if self.overloaded && self.quiet >= RECOVERY_SAMPLES {
+            self.overloaded = false;
+            return Some(KcpEvent::ReceiveOverloadCleared);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time::Instant;
+
+    use super::{OverloadMonitor, OVERLOAD_SAMPLES, RECOVERY_SAMPLES};
+    use crate::event::KcpEvent;
+
+    #[test]
+    fn overload_monitor() {
+        let mut monitor = OverloadMonitor {
+            next_sample: Instant::now(),
+            last: 100,
+            rising: 0,
+            rising_dropped: 0,
+            quiet: 0,
+            overloaded: false,
+        };
+
+        // A single burst is not an overload
+        assert_eq!(monitor.sample(150), None);
+        assert_eq!(monitor.sample(150), None);
+
+        let mut dropped = 150;
+        for _ in 1..OVERLOAD_SAMPLES {
+            dropped += 10;
+            assert_eq!(monitor.sample(dropped), None);
+        }
+        assert_eq!(
+            monitor.sample(dropped + 10),
+            Some(KcpEvent::ReceiveOverload {
+                dropped: 10 * OVERLOAD_SAMPLES as u64
+            })
+        );
+        assert_eq!(monitor.sample(dropped + 20), None);
+
+        for _ in 1..RECOVERY_SAMPLES {
+            assert_eq!(monitor.sample(dropped + 20), None);
+        }
+        assert_eq!(monitor.sample(dropped + 20), Some(KcpEvent::ReceiveOverloadCleared));
+    }
+}
@@ -0,0 +1,361 @@
+//! Packet pacing
+//!
+//! Enabled by `KcpConfig::pacing`. KCP may emit a whole window of segments in one flush, which are sent back to back
+//! and overflow shallow buffers on the path. The pacer is a token bucket: datagrams are sent immediately as long as
+//! the bucket has enough bytes, otherwise they are queued and released at `rate`.
+//!
+//! Every session has its own pacer, so it also caps the bandwidth of each session, like limiting every tenant
+//! behind a listener to 10 Mbps. Datagrams are delayed instead of dropped, KCP's congestion control never sees
+//! a loss caused by the limit.
+//!
+//! With `adaptive`, `rate` is only the ceiling. The pacer estimates how fast the path delivers from the data in
+//! flight and the smoothed RTT, and spreads datagrams of a flush over the RTT at `PACING_GAIN` times that rate, so
+//! the window doesn't leave in one burst that a policer or a shallow buffer drops together.
+
+use std::time::Duration;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+
+/// Datagrams are paced at this multiple of the estimated rate, so pacing doesn't become the bottleneck
+const PACING_GAIN: f64 = 2.0;
+
+/// The pacer sleeps at least this long, datagrams that become due meanwhile are sent together
+pub const TIMER_GRANULARITY: Duration = Duration::from_millis(1);
+
+/// Packet pacing config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PacingConfig {
+    /// Maximum bytes that could be sent back to back
+    pub max_burst: usize,
+    /// Sending rate in bytes per second
+    pub rate: u64,
+    /// Sending rate in datagrams per second, `None` for no limit on the number of datagrams.
+    ///
+    /// Datagrams could be sent back to back for as long as `max_burst` bytes at `rate`, but at least one.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub packet_rate: Option<u64>,
+    /// Paces at the rate estimated from the data in flight and the smoothed RTT, up to `rate`.
+    ///
+    /// Before the first RTT sample datagrams are paced at `rate`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub adaptive: bool,
+}
+
+/// Limit and usage of the pacer of a session, reported by `KcpStream::pacing_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacingStats {
+    /// Sending rate in bytes per second, the estimated rate if `PacingConfig::adaptive` is enabled
+    pub rate: u64,
+    /// Sending rate in datagrams per second
+    pub packet_rate: Option<u64>,
+    /// Bytes that could be sent immediately
+    pub available: usize,
+    /// Total bytes sent
+    pub consumed: u64,
+    /// Total datagrams sent
+    pub consumed_packets: u64,
+}
+
+struct TokenBucket {
+    max_burst: f64,
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(max_burst: f64, rate: f64) -> TokenBucket {
+        TokenBucket {
+            max_burst,
+            rate,
+            tokens: max_burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.max_burst);
+        self.last_refill = now;
+    }
+
+    /// Tokens required for `amount`, larger amounts than `max_burst` only need a full bucket
+    fn required(&self, amount: usize) -> f64 {
+        (amount as f64).min(self.max_burst)
+    }
+
+    /// Changes `rate`, tokens gained at the previous rate are kept
+    fn set_rate(&mut self, max_burst: f64, rate: f64, now: Instant) {
+        self.refill(now);
+        self.max_burst = max_burst;
+        self.rate = rate;
+        self.tokens = self.tokens.min(max_burst);
+    }
+
+    fn wait_time(&self, amount: usize) -> Duration {
+        let lacking = self.required(amount) - self.tokens;
+        if lacking <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(lacking / self.rate)
+        }
+    }
+}
+
+/// Estimates the delivery rate of the path from the data in flight and the smoothed RTT
+struct RateEstimator {
+    /// Smoothed RTT in seconds
+    srtt: Option<f64>,
+    /// Largest bytes in flight sampled recently, KCP's congestion window is not visible
+    window: usize,
+    window_sampled: Instant,
+}
+
+impl RateEstimator {
+    /// Returns the estimated rate in bytes per second, at least `min_window` per RTT
+    fn on_ack(&mut self, rtt: Duration, inflight: usize, min_window: usize, now: Instant) -> f64 {
+        let rtt = rtt.as_secs_f64().max(TIMER_GRANULARITY.as_secs_f64());
+        let srtt = match self.srtt {
+            Some(srtt) => (srtt * 7.0 + rtt) / 8.0,
+            None => rtt,
+        };
+        self.srtt = Some(srtt);
+
+        // Samples expire after 2 RTTs, so the window could shrink when KCP backs off
+        if inflight >= self.window || now.saturating_duration_since(self.window_sampled).as_secs_f64() > srtt * 2.0 {
+            self.window = inflight;
+            self.window_sampled = now;
+        }
+
+        self.window.max(min_window) as f64 / srtt
+    }
+}
+
+/// Token buckets of bytes and datagrams that decide when a datagram could be sent
+pub struct Pacer {
+    bytes: TokenBucket,
+    packets: Option<TokenBucket>,
+    max_burst: usize,
+    rate: u64,
+    packet_rate: Option<u64>,
+    estimator: Option<RateEstimator>,
+    consumed: u64,
+    consumed_packets: u64,
+}
+
+impl Pacer {
+    pub fn new(config: &PacingConfig) -> Pacer {
+        assert!(config.rate > 0, "pacing rate must be positive");
+
+        let packets = config.packet_rate.map(|packet_rate| {
+            assert!(packet_rate > 0, "pacing packet rate must be positive");
+
+            let burst_secs = config.max_burst as f64 / config.rate as f64;
+            TokenBucket::new((packet_rate as f64 * burst_secs).max(1.0), packet_rate as f64)
+        });
+
+        Pacer {
+            bytes: TokenBucket::new(config.max_burst as f64, config.rate as f64),
+            packets,
+            max_burst: config.max_burst,
+            rate: config.rate,
+            packet_rate: config.packet_rate,
+            estimator: if config.adaptive {
+                Some(RateEstimator {
+                    srtt: None,
+                    window: 0,
+                    window_sampled: Instant::now(),
+                })
+            } else {
+                None
+            },
+            consumed: 0,
+            consumed_packets: 0,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.bytes.refill(now);
+        if let Some(ref mut packets) = self.packets {
+            packets.refill(now);
+        }
+    }
+
+    /// Takes tokens for sending a datagram of `len` at `now`, returns `false` if it has to wait
+    pub fn try_send(&mut self, len: usize, now: Instant) -> bool {
+        self.refill(now);
+
+        let required = self.bytes.required(len);
+        if self.bytes.tokens < required {
+            return false;
+        }
+        if let Some(ref mut packets) = self.packets {
+            if packets.tokens < 1.0 {
+                return false;
+            }
+            packets.tokens -= 1.0;
+        }
+        self.bytes.tokens -= required;
+
+        self.consumed += len as u64;
+        self.consumed_packets += 1;
+        true
+    }
+
+    /// Earliest time that a datagram of `len` could be sent, not earlier than `TIMER_GRANULARITY` if it has to wait
+    pub fn next_send_time(&mut self, len: usize, now: Instant) -> Instant {
+        self.refill(now);
+
+        let mut wait = self.bytes.wait_time(len);
+        if let Some(ref packets) = self.packets {
+            wait = wait.max(packets.wait_time(1));
+        }
+        if wait > Duration::ZERO {
+            wait = wait.max(TIMER_GRANULARITY);
+        }
+        now + wait
+    }
+
+    /// Updates the estimated rate of an `adaptive` pacer with an RTT sample and bytes in flight
+    pub fn on_ack(&mut self, rtt: Duration, inflight: usize, now: Instant) {
+        let estimator = match self.estimator {
+            Some(ref mut estimator) => estimator,
+            None => return,
+        };
+
+        let ceiling = self.rate as f64;
+        let rate = (estimator.on_ack(rtt, inflight, self.max_burst, now) * PACING_GAIN).min(ceiling);
+        // Tokens of a coarse timer tick must fit in the bucket, or the rate couldn't be reached
+        let max_burst = (self.max_burst as f64).max(rate * TIMER_GRANULARITY.as_secs_f64());
+        self.bytes.set_rate(max_burst, rate, now);
+    }
+
+    /// Paces at `rate` decided by a congestion controller, up to the configured rate
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        let rate = rate.clamp(1, self.rate) as f64;
+        let max_burst = (self.max_burst as f64).max(rate * TIMER_GRANULARITY.as_secs_f64());
+        self.bytes.set_rate(max_burst, rate, now);
+    }
+
+    pub fn stats(&mut self, now: Instant) -> PacingStats {
+        self.refill(now);
+
+        PacingStats {
+            rate: self.bytes.rate as u64,
+            packet_rate: self.packet_rate,
+            available: self.bytes.tokens as usize,
+            consumed: self.consumed,
+            consumed_packets: self.consumed_packets,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::time::{self, Instant};
+
+    use super::{Pacer, PacingConfig, PacingStats, TIMER_GRANULARITY};
+
+    #[tokio::test]
+    async fn pacer_token_bucket() {
+        time::pause();
+
+        let mut pacer = Pacer::new(&PacingConfig {
+            max_burst: 1000,
+            rate: 10_000,
+            packet_rate: None,
+            adaptive: false,
+        });
+
+        // Burst
+        let now = Instant::now();
+        assert!(pacer.try_send(500, now));
+        assert!(pacer.try_send(500, now));
+        assert!(!pacer.try_send(500, now));
+
+        // 500 bytes at 10KB/s
+        assert_eq!(pacer.next_send_time(500, now), now + Duration::from_millis(50));
+        time::advance(Duration::from_millis(50)).await;
+        assert!(pacer.try_send(500, Instant::now()));
+
+        // Larger than max_burst, waits for a full bucket
+        let now = Instant::now();
+        assert_eq!(pacer.next_send_time(2000, now), now + Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn pacer_packet_rate() {
+        time::pause();
+
+        // 2 datagrams back to back, then one every 10ms
+        let mut pacer = Pacer::new(&PacingConfig {
+            max_burst: 10_000,
+            rate: 500_000,
+            packet_rate: Some(100),
+            adaptive: false,
+        });
+
+        let now = Instant::now();
+        assert!(pacer.try_send(100, now));
+        assert!(pacer.try_send(100, now));
+        assert!(!pacer.try_send(100, now));
+        assert_eq!(pacer.next_send_time(100, now), now + Duration::from_millis(10));
+
+        time::advance(Duration::from_millis(10)).await;
+        assert!(pacer.try_send(100, Instant::now()));
+
+        assert_eq!(
+            pacer.stats(Instant::now()),
+            PacingStats {
+                rate: 500_000,
+                packet_rate: Some(100),
+                available: 9_900,
+                consumed: 300,
+                consumed_packets: 3,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn pacer_adaptive_rate() {
+        time::pause();
+
+        let mut pacer = Pacer::new(&PacingConfig {
+            max_burst: 4_000,
+            rate: 1_000_000,
+            packet_rate: None,
+            adaptive: true,
+        });
+        assert_eq!(pacer.stats(Instant::now()).rate, 1_000_000);
+
+        // 20KB in flight over 100ms is 200KB/s, paced at twice that
+        pacer.on_ack(Duration::from_millis(100), 20_000, Instant::now());
+        assert_eq!(pacer.stats(Instant::now()).rate, 400_000);
+
+        // A larger window is taken immediately, but capped by the ceiling
+        pacer.on_ack(Duration::from_millis(100), 80_000, Instant::now());
+        assert_eq!(pacer.stats(Instant::now()).rate, 1_000_000);
+
+        // A smaller one only after the sample expires
+        pacer.on_ack(Duration::from_millis(100), 10_000, Instant::now());
+        assert_eq!(pacer.stats(Instant::now()).rate, 1_000_000);
+        time::advance(Duration::from_millis(300)).await;
+        pacer.on_ack(Duration::from_millis(100), 10_000, Instant::now());
+        assert_eq!(pacer.stats(Instant::now()).rate, 200_000);
+
+        // Never lower than max_burst per RTT
+        time::advance(Duration::from_millis(300)).await;
+        pacer.on_ack(Duration::from_millis(100), 0, Instant::now());
+        assert_eq!(pacer.stats(Instant::now()).rate, 80_000);
+
+        // Waiting is at least one timer tick
+        let now = Instant::now();
+        assert!(pacer.try_send(4_000, now));
+        assert!(pacer.next_send_time(1, now) >= now + TIMER_GRANULARITY);
+    }
+}
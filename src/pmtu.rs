@@ -0,0 +1,429 @@
+//! Path MTU probing of client sessions
+//!
+//! Enabled by `KcpConfig::pmtu`. Datagrams of the client are sent with DF (don't fragment) set, so a datagram
+//! larger than the path MTU is dropped instead of being fragmented. Client probes candidate sizes with padded
+//! `PROBE` frames, and server acknowledges each of them with a small `PROBE-ACK`:
+//!
+//! ```plain
+//! Client                               Server
+//!   PROBE(conv, seq, size)     ->                                  padded to `size` bytes
+//!                              <-      PROBE-ACK(conv, seq, size)  only for existing sessions
+//! ```
+//!
+//! A size is confirmed when its probe is acknowledged, and fails when `PROBE_ATTEMPTS` probes of it are lost or
+//! the OS refuses to send it. The MTU is found by a binary search between `PmtuConfig::min_mtu` and
+//! `PmtuConfig::max_mtu`, and searched again for a larger one every `PmtuConfig::probe_interval`.
+//!
+//! Losses may mean that the route changed to a smaller MTU, which drops all large datagrams silently. The
+//! current size is confirmed again after `BLACK_HOLE_RETRANSMISSIONS` retransmissions, or when the OS reports
+//! `EMSGSIZE`, and the MTU falls back to `PmtuConfig::min_mtu` at once if it fails.
+
+use bytes::{Buf, BufMut};
+use tokio::time::Instant;
+
+use crate::config::PmtuConfig;
+
+const MAGIC: &[u8; 4] = b"KCPP";
+
+const FRAME_PROBE: u8 = 1;
+const FRAME_ACK: u8 = 2;
+
+/// Length of frames without padding, `MAGIC` + kind + conv + seq + size
+pub const PROBE_HEADER_LEN: usize = 4 + 1 + 4 + 4 + 2;
+
+/// Probes of a size that are lost before the size fails
+pub const PROBE_ATTEMPTS: u32 = 2;
+/// Retransmissions after which the current size is confirmed again
+pub const BLACK_HOLE_RETRANSMISSIONS: u64 = 4;
+/// Search stops when the largest confirmed and the smallest failed size are this close
+const SEARCH_PRECISION: usize = 16;
+
+/// Frames exchanged for probing path MTU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmtuFrame {
+    /// Datagram of `size` bytes
+    Probe {
+        conv: u32,
+        seq: u32,
+        size: usize,
+    },
+    Ack {
+        conv: u32,
+        seq: u32,
+        size: usize,
+    },
+}
+
+impl PmtuFrame {
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, conv, seq, size) = match *self {
+            PmtuFrame::Probe { conv, seq, size } => (FRAME_PROBE, conv, seq, size),
+            PmtuFrame::Ack { conv, seq, size } => (FRAME_ACK, conv, seq, size),
+        };
+
+        let mut frame = Vec::with_capacity(size.max(PROBE_HEADER_LEN));
+        frame.put_slice(MAGIC);
+        frame.put_u8(kind);
+        frame.put_u32_le(conv);
+        frame.put_u32_le(seq);
+        frame.put_u16_le(size as u16);
+        if kind == FRAME_PROBE {
+            frame.resize(size.max(PROBE_HEADER_LEN), 0);
+        }
+        frame
+    }
+
+    pub fn decode(mut buf: &[u8]) -> Option<PmtuFrame> {
+        if buf.len() < PROBE_HEADER_LEN || &buf[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let frame_len = buf.len();
+        buf.advance(MAGIC.len());
+
+        let kind = buf.get_u8();
+        let conv = buf.get_u32_le();
+        let seq = buf.get_u32_le();
+        let size = buf.get_u16_le() as usize;
+
+        match kind {
+            // Truncated probes were not received as a whole
+            FRAME_PROBE if frame_len == size => Some(PmtuFrame::Probe { conv, seq, size }),
+            FRAME_ACK if frame_len == PROBE_HEADER_LEN => Some(PmtuFrame::Ack { conv, seq, size }),
+            _ => None,
+        }
+    }
+}
+
+/// What the session has to do for the prober
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PmtuAction {
+    /// Sends a `PROBE` of `size` bytes
+    Probe { seq: u32, size: usize },
+    /// Changes MTU of the session
+    SetMtu(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    /// Binary search between the largest confirmed size and the smallest failed size
+    Searching { low: usize, high: usize },
+    /// Current size was found, searches for a larger one at `next_search`
+    Found { next_search: Instant },
+    /// Current size may have stopped working
+    Confirming,
+}
+
+/// Probe waiting for its acknowledgement
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    seq: u32,
+    size: usize,
+    attempts: u32,
+    deadline: Instant,
+}
+
+/// Decides sizes of probes and MTU of a session from their results
+pub struct PmtuProber {
+    config: PmtuConfig,
+    mtu: usize,
+    phase: Phase,
+    /// The current MTU is probed first, before the binary search
+    probe_mtu_first: bool,
+    pending: Option<Pending>,
+    next_seq: u32,
+    /// Retransmissions counted until the last check for black holes
+    retransmissions: u64,
+}
+
+impl PmtuProber {
+    /// Prober of a session that starts with `mtu`, the search starts immediately
+    pub fn new(config: PmtuConfig, mtu: usize) -> PmtuProber {
+        PmtuProber {
+            config,
+            mtu,
+            phase: Phase::Searching {
+                low: config.min_mtu,
+                high: config.max_mtu + 1,
+            },
+            probe_mtu_first: true,
+            pending: None,
+            next_seq: 0,
+            retransmissions: 0,
+        }
+    }
+
+    /// Current MTU decided by the prober
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Next thing to do, call it again until it returns `None`
+    pub fn poll(&mut self, now: Instant) -> Option<PmtuAction> {
+        if let Some(pending) = self.pending {
+            if now < pending.deadline {
+                return None;
+            }
+            self.pending = None;
+            if pending.attempts < PROBE_ATTEMPTS {
+                return Some(self.probe(pending.size, pending.attempts + 1, now));
+            }
+            if let Some(action) = self.on_failed(pending.size, now) {
+                return Some(action);
+            }
+        }
+
+        match self.phase {
+            Phase::Searching { low, high } => {
+                if high - low <= SEARCH_PRECISION {
+                    self.phase = Phase::Found {
+                        next_search: now + self.config.probe_interval,
+                    };
+                    return self.set_mtu(low);
+                }
+                let size = if self.probe_mtu_first && low < self.mtu && self.mtu < high {
+                    self.mtu
+                } else {
+                    (low + high) / 2
+                };
+                self.probe_mtu_first = false;
+                Some(self.probe(size, 1, now))
+            }
+            Phase::Found { next_search } => {
+                if now < next_search {
+                    return None;
+                }
+                // Path may support a larger MTU now
+                self.phase = Phase::Searching {
+                    low: self.mtu,
+                    high: self.config.max_mtu + 1,
+                };
+                self.poll(now)
+            }
+            Phase::Confirming => Some(self.probe(self.mtu, 1, now)),
+        }
+    }
+
+    /// Time to call `poll` again
+    pub fn deadline(&self) -> Option<Instant> {
+        match self.pending {
+            Some(pending) => Some(pending.deadline),
+            None => match self.phase {
+                Phase::Found { next_search } => Some(next_search),
+                Phase::Searching { .. } | Phase::Confirming => None,
+            },
+        }
+    }
+
+    /// Probe `seq` was acknowledged by peer
+    pub fn on_ack(&mut self, seq: u32, size: usize, now: Instant) {
+        match self.pending {
+            Some(pending) if pending.seq == seq && pending.size == size => {}
+            _ => return,
+        }
+        self.pending = None;
+
+        match self.phase {
+            Phase::Searching { ref mut low, .. } => *low = (*low).max(size),
+            Phase::Confirming => {
+                self.phase = Phase::Found {
+                    next_search: now + self.config.probe_interval,
+                }
+            }
+            Phase::Found { .. } => {}
+        }
+    }
+
+    /// Probe `seq` can't be sent because it is larger than the MTU known by the OS
+    pub fn on_too_big(&mut self, seq: u32, now: Instant) -> Option<PmtuAction> {
+        match self.pending {
+            Some(pending) if pending.seq == seq => {
+                self.pending = None;
+                self.on_failed(pending.size, now)
+            }
+            _ => None,
+        }
+    }
+
+    /// Datagrams of the current size may be dropped, like when the OS reports `EMSGSIZE`
+    pub fn on_loss(&mut self) {
+        if let Phase::Found { .. } = self.phase {
+            self.phase = Phase::Confirming;
+        }
+    }
+
+    /// Checks `retransmissions` of the session for black holes
+    pub fn on_retransmissions(&mut self, retransmissions: u64) {
+        if retransmissions >= self.retransmissions + BLACK_HOLE_RETRANSMISSIONS {
+            self.retransmissions = retransmissions;
+            self.on_loss();
+        }
+    }
+
+    fn probe(&mut self, size: usize, attempts: u32, now: Instant) -> PmtuAction {
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.pending = Some(Pending {
+            seq,
+            size,
+            attempts,
+            deadline: now + self.config.probe_timeout,
+        });
+        PmtuAction::Probe { seq, size }
+    }
+
+    fn on_failed(&mut self, size: usize, now: Instant) -> Option<PmtuAction> {
+        match self.phase {
+            Phase::Searching { ref mut high, .. } => {
+                *high = (*high).min(size);
+                None
+            }
+            Phase::Confirming => {
+                // Route changed, falls back at once and searches between the bounds again
+                self.phase = Phase::Searching {
+                    low: self.config.min_mtu,
+                    high: size,
+                };
+                let action = self.set_mtu(self.config.min_mtu);
+                if action.is_none() {
+                    return self.poll(now);
+                }
+                action
+            }
+            Phase::Found { .. } => None,
+        }
+    }
+
+    fn set_mtu(&mut self, mtu: usize) -> Option<PmtuAction> {
+        if mtu == self.mtu {
+            return None;
+        }
+        self.mtu = mtu;
+        Some(PmtuAction::SetMtu(mtu))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn config() -> PmtuConfig {
+        PmtuConfig {
+            min_mtu: 576,
+            max_mtu: 1500,
+            probe_interval: Duration::from_secs(60),
+            probe_timeout: Duration::from_millis(100),
+        }
+    }
+
+    /// Runs the prober against a path of `path_mtu` until it settles, returns the number of probes sent
+    fn settle(prober: &mut PmtuProber, path_mtu: usize, now: &mut Instant) -> usize {
+        let mut probes = 0;
+        loop {
+            match prober.poll(*now) {
+                Some(PmtuAction::Probe { seq, size }) => {
+                    probes += 1;
+                    if size <= path_mtu {
+                        prober.on_ack(seq, size, *now);
+                    }
+                }
+                Some(PmtuAction::SetMtu(..)) => {}
+                None => match prober.deadline() {
+                    Some(deadline) if deadline < *now + config().probe_interval => *now = deadline,
+                    _ => return probes,
+                },
+            }
+        }
+    }
+
+    #[test]
+    fn pmtu_frame_codec() {
+        let probe = PmtuFrame::Probe {
+            conv: 10,
+            seq: 3,
+            size: 1200,
+        };
+        let buf = probe.encode();
+        assert_eq!(buf.len(), 1200);
+        assert_eq!(PmtuFrame::decode(&buf), Some(probe));
+        // Truncated
+        assert_eq!(PmtuFrame::decode(&buf[..1000]), None);
+
+        let ack = PmtuFrame::Ack {
+            conv: 10,
+            seq: 3,
+            size: 1200,
+        };
+        let buf = ack.encode();
+        assert_eq!(buf.len(), PROBE_HEADER_LEN);
+        assert_eq!(PmtuFrame::decode(&buf), Some(ack));
+        assert_eq!(
+            PmtuFrame::decode(b"KCPM\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00"),
+            None
+        );
+    }
+
+    #[test]
+    fn pmtu_search() {
+        let mut now = Instant::now();
+        let mut prober = PmtuProber::new(config(), 1400);
+        settle(&mut prober, 1280, &mut now);
+        assert!(prober.mtu() <= 1280 && prober.mtu() > 1280 - SEARCH_PRECISION);
+
+        // Nothing to do until the next search
+        assert_eq!(prober.poll(now), None);
+        assert!(prober.deadline().unwrap() > now);
+
+        // Path supports more later
+        now += config().probe_interval;
+        settle(&mut prober, 1500, &mut now);
+        assert!(prober.mtu() > 1500 - SEARCH_PRECISION);
+    }
+
+    #[test]
+    fn pmtu_black_hole() {
+        let mut now = Instant::now();
+        let mut prober = PmtuProber::new(config(), 1400);
+        settle(&mut prober, 1500, &mut now);
+        let mtu = prober.mtu();
+
+        // Random losses that are not caused by MTU
+        prober.on_retransmissions(BLACK_HOLE_RETRANSMISSIONS);
+        settle(&mut prober, 1500, &mut now);
+        assert_eq!(prober.mtu(), mtu);
+
+        // Route changed, falls back to the minimum before searching again
+        prober.on_retransmissions(2 * BLACK_HOLE_RETRANSMISSIONS);
+        let start = now;
+        let mut fallback_at = None;
+        loop {
+            match prober.poll(now) {
+                Some(PmtuAction::Probe { seq, size }) => {
+                    if size <= 1000 {
+                        prober.on_ack(seq, size, now);
+                    }
+                }
+                Some(PmtuAction::SetMtu(mtu)) => {
+                    fallback_at.get_or_insert((mtu, now));
+                }
+                None => match prober.deadline() {
+                    Some(deadline) if deadline < now + config().probe_interval => now = deadline,
+                    _ => break,
+                },
+            }
+        }
+        let (fallback, fallback_at) = fallback_at.unwrap();
+        assert_eq!(fallback, config().min_mtu);
+        assert_eq!(fallback_at - start, PROBE_ATTEMPTS * config().probe_timeout);
+        assert!(prober.mtu() <= 1000 && prober.mtu() > 1000 - SEARCH_PRECISION);
+
+        // Too big for the OS
+        let mut prober = PmtuProber::new(config(), 1400);
+        match prober.poll(now) {
+            Some(PmtuAction::Probe { seq, size: 1400 }) => assert_eq!(prober.on_too_big(seq, now), None),
+            action => panic!("unexpected action {:?}", action),
+        }
+        assert!(matches!(prober.poll(now), Some(PmtuAction::Probe { size, .. }) if size < 1400));
+    }
+}
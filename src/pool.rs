@@ -0,0 +1,176 @@
+//! Pool of packet buffers
+//!
+//! Buffers are taken for receiving datagrams in listener and for datagrams waiting to be sent, and returned
+//! when they are dropped. If the pool is empty, a new buffer is allocated instead of waiting for a returned one.
+
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::BytesMut;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Buffer pool config
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize), serde(default))]
+pub struct BufferPoolConfig {
+    /// Maximum number of idle buffers kept in pool, 0 to disable pooling
+    pub count: usize,
+    /// Size of each buffer, datagrams larger than this are truncated when they are received.
+    ///
+    /// 0 (the default) sizes buffers for the MTU: the larger of `KcpConfig::mtu`, `PmtuConfig::max_mtu` and 1500
+    /// (peers with the Ethernet MTU), plus 256 bytes for headers of layers wrapping KCP datagrams.
+    pub size: usize,
+}
+
+impl Default for BufferPoolConfig {
+    fn default() -> BufferPoolConfig {
+        BufferPoolConfig { count: 256, size: 0 }
+    }
+}
+
+/// Statistics of a buffer pool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers taken from pool
+    pub hits: u64,
+    /// Buffers allocated because pool was empty
+    pub misses: u64,
+}
+
+pub struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    count: usize,
+    size: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BufferPool {
+    pub fn new(config: &BufferPoolConfig) -> Arc<BufferPool> {
+        Arc::new(BufferPool {
+            buffers: Mutex::new(Vec::with_capacity(config.count)),
+            count: config.count,
+            size: config.size,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Takes an empty buffer with capacity of at least `size`
+    pub fn get(self: &Arc<Self>) -> PooledBuffer {
+        let pooled = self.buffers.lock().unwrap().pop();
+        let buf = match pooled {
+            Some(buf) => {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                buf
+            }
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                BytesMut::with_capacity(self.size)
+            }
+        };
+
+        PooledBuffer {
+            buf,
+            pool: self.clone(),
+        }
+    }
+
+    fn put(&self, mut buf: BytesMut) {
+        buf.clear();
+        if buf.capacity() < self.size {
+            return;
+        }
+
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.count {
+            buffers.push(buf);
+        }
+    }
+
+    /// Size of each buffer, the largest datagram that could be received
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Buffer that returns to its pool when dropped
+pub struct PooledBuffer {
+    buf: BytesMut,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.put(std::mem::take(&mut self.buf));
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.buf, f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::BufMut;
+
+    use super::{BufferPool, BufferPoolConfig, BufferPoolStats};
+
+    #[test]
+    fn buffer_pool_reuse() {
+        let pool = BufferPool::new(&BufferPoolConfig { count: 1, size: 1024 });
+
+        let mut buf = pool.get();
+        assert!(buf.capacity() >= 1024);
+        buf.put_slice(b"HELLO");
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        // Returned buffer is reused and empty
+        let buf1 = pool.get();
+        assert!(buf1.is_empty());
+        assert_eq!(buf1.as_ptr(), ptr);
+
+        // Exhausted, allocates
+        let buf2 = pool.get();
+        assert_ne!(buf2.as_ptr(), ptr);
+        assert_eq!(pool.stats(), BufferPoolStats { hits: 1, misses: 2 });
+
+        // Only keeps `count` buffers
+        drop(buf1);
+        drop(buf2);
+        let _buf1 = pool.get();
+        let _buf2 = pool.get();
+        assert_eq!(pool.stats(), BufferPoolStats { hits: 2, misses: 3 });
+    }
+}
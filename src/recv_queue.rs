@@ -0,0 +1,154 @@
+//! Received data waiting to be read, see `KcpStream::recv_queue_len`
+//!
+//! The receive buffers of `kcp` are private, so they are shadowed from the data segments it accepted: segments
+//! received out of order wait in `rcv_buf`, segments in order are moved to `rcv_queue`, where `recv` reads them
+//! one message at a time. Moving is limited by the receive window in the same way as KCP.
+
+use std::collections::{BTreeMap, VecDeque};
+
+/// Received data of a session, reported by `KcpStream::recv_queue_len`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvQueueLen {
+    /// Complete messages ready to be read, bytes in stream mode
+    pub ready: usize,
+    /// Segments received ahead of a missing one, waiting for reassembly. Also segments in order that wait for the
+    /// ready ones to be read, when they fill the receive window.
+    pub out_of_order: usize,
+}
+
+/// Data segment in the receive buffers
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    /// Fragment number, counts down to 0 in the last segment of a message
+    frg: u8,
+    len: usize,
+}
+
+/// Shadow of `rcv_buf` and `rcv_queue` of KCP
+#[derive(Default)]
+pub struct RecvTracker {
+    /// `sn` of the next segment moved to `queue`
+    rcv_nxt: u32,
+    /// Segments received out of order
+    buf: BTreeMap<u32, Segment>,
+    /// Segments in order, ready to be read
+    queue: VecDeque<Segment>,
+}
+
+impl RecvTracker {
+    /// Data segment accepted by KCP
+    pub fn on_data(&mut self, sn: u32, frg: u8, len: usize, rcv_wnd: u16) {
+        // Out of the receive window, dropped by KCP
+        if !before(sn, self.rcv_nxt.wrapping_add(rcv_wnd as u32)) || before(sn, self.rcv_nxt) {
+            return;
+        }
+        self.buf.entry(sn).or_insert(Segment { frg, len });
+        self.move_buf(rcv_wnd);
+    }
+
+    /// A message was read by `recv` of KCP
+    pub fn on_recv(&mut self, rcv_wnd: u16) {
+        while let Some(segment) = self.queue.pop_front() {
+            if segment.frg == 0 {
+                break;
+            }
+        }
+        self.move_buf(rcv_wnd);
+    }
+
+    fn move_buf(&mut self, rcv_wnd: u16) {
+        while self.queue.len() < rcv_wnd as usize {
+            match self.buf.remove(&self.rcv_nxt) {
+                Some(segment) => {
+                    self.queue.push_back(segment);
+                    self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn len(&self, stream: bool) -> RecvQueueLen {
+        let ready = if stream {
+            self.queue.iter().map(|segment| segment.len).sum()
+        } else {
+            // The last fragment completes a message
+            self.queue.iter().filter(|segment| segment.frg == 0).count()
+        };
+        RecvQueueLen {
+            ready,
+            out_of_order: self.buf.len(),
+        }
+    }
+}
+
+/// `a` is before `b` in the wrapping sequence space
+fn before(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RecvQueueLen, RecvTracker};
+
+    #[test]
+    fn reassembly() {
+        let mut tracker = RecvTracker::default();
+
+        // Message of 3 fragments, the second one is late
+        tracker.on_data(0, 2, 100, 128);
+        tracker.on_data(2, 0, 50, 128);
+        assert_eq!(
+            tracker.len(false),
+            RecvQueueLen {
+                ready: 0,
+                out_of_order: 1
+            }
+        );
+
+        tracker.on_data(1, 1, 100, 128);
+        tracker.on_data(3, 0, 10, 128);
+        // Duplicated and out of window segments are dropped
+        tracker.on_data(3, 0, 10, 128);
+        tracker.on_data(200, 0, 10, 128);
+        assert_eq!(
+            tracker.len(false),
+            RecvQueueLen {
+                ready: 2,
+                out_of_order: 0
+            }
+        );
+
+        tracker.on_recv(128);
+        assert_eq!(tracker.len(false).ready, 1);
+        tracker.on_recv(128);
+        assert_eq!(tracker.len(false).ready, 0);
+    }
+
+    #[test]
+    fn receive_window() {
+        let mut tracker = RecvTracker::default();
+
+        // Queue is full, the rest waits in the buffer until it is read
+        for sn in 0..4 {
+            tracker.on_data(sn, 0, 10, 2);
+        }
+        assert_eq!(
+            tracker.len(true),
+            RecvQueueLen {
+                ready: 20,
+                out_of_order: 2
+            }
+        );
+
+        tracker.on_recv(2);
+        tracker.on_recv(2);
+        assert_eq!(
+            tracker.len(true),
+            RecvQueueLen {
+                ready: 20,
+                out_of_order: 0
+            }
+        );
+    }
+}
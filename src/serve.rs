@@ -0,0 +1,164 @@
+//! Serving axum over KCP
+//!
+//! Enabled by the `axum` feature. `KcpListener` implements `axum::serve::Listener`:
+//!
+//! ```no_run
+//! # use axum::{extract::ConnectInfo, routing::get, Router};
+//! # use tokio_kcp::{serve::KcpConnectInfo, KcpConfig, KcpListener};
+//! # async fn run() {
+//! let listener = KcpListener::bind(KcpConfig::default(), "0.0.0.0:3100").await.unwrap();
+//! let app = Router::new().route(
+//!     "/",
+//!     get(|ConnectInfo(info): ConnectInfo<KcpConnectInfo>| async move { info.peer_addr.to_string() }),
+//! );
+//! axum::serve(listener, app.into_make_service_with_connect_info::<KcpConnectInfo>())
+//!     .await
+//!     .unwrap();
+//! # }
+//! ```
+//!
+//! With graceful shutdown, the listener is dropped when the server stops accepting. Sessions of the
+//! connections that are still being served keep running until they are closed, so responses are
+//! delivered before the sessions are closed with FIN.
+
+use std::{future, io, net::SocketAddr, time::Duration};
+
+use axum::{
+    extract::connect_info::Connected,
+    serve::{IncomingStream, Listener},
+};
+use log::error;
+use tokio::time;
+
+use crate::{error::KcpError, listener::KcpListener, stream::KcpStream};
+
+impl Listener for KcpListener {
+    type Io = KcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (KcpStream, SocketAddr) {
+        loop {
+            match KcpListener::accept(self).await {
+                Ok(s) => return s,
+                // Nothing will be accepted anymore, server is being shut down too
+                Err(KcpError::ListenerClosed(cause)) => {
+                    if let Some(cause) = cause {
+                        error!("listener failed, error: {}", cause);
+                    }
+                    future::pending::<()>().await
+                }
+                Err(err) => {
+                    error!("accept failed, error: {}", err);
+                    time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        KcpListener::local_addr(self)
+    }
+}
+
+/// Connection info of a request served by `KcpListener`, extracted by `axum::extract::ConnectInfo`
+#[derive(Debug, Clone, Copy)]
+pub struct KcpConnectInfo {
+    /// Address of the client when the session was accepted
+    pub peer_addr: SocketAddr,
+}
+
+impl Connected<IncomingStream<'_, KcpListener>> for KcpConnectInfo {
+    fn connect_info(stream: IncomingStream<'_, KcpListener>) -> KcpConnectInfo {
+        KcpConnectInfo {
+            peer_addr: *stream.remote_addr(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use axum::{extract::ConnectInfo, routing::get, Router};
+    use bytes::Bytes;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::Request;
+    use hyper_util::rt::TokioIo;
+    use tokio::{
+        sync::{oneshot, Notify},
+        time,
+    };
+
+    use super::KcpConnectInfo;
+    use crate::{KcpConfig, KcpListener, KcpNoDelayConfig, KcpStream};
+
+    #[tokio::test]
+    async fn axum_graceful_shutdown() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let requested = Arc::new(Notify::new());
+        let app = {
+            let requested = requested.clone();
+            Router::new().route(
+                "/",
+                get(move |ConnectInfo(info): ConnectInfo<KcpConnectInfo>| async move {
+                    requested.notify_one();
+                    // Still in progress when shutting down
+                    time::sleep(Duration::from_millis(200)).await;
+                    format!("{}\n{}", info.peer_addr, "x".repeat(256 * 1024))
+                }),
+            )
+        };
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app.into_make_service_with_connect_info::<KcpConnectInfo>())
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown_rx.await;
+                })
+                .await
+                .unwrap();
+        });
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        let request = Request::get("/")
+            .header("host", "kcp")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = tokio::spawn(sender.send_request(request));
+
+        requested.notified().await;
+        shutdown_tx.send(()).unwrap();
+
+        let response = time::timeout(Duration::from_secs(5), response)
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert!(response.status().is_success());
+        let body = time::timeout(Duration::from_secs(5), response.into_body().collect())
+            .await
+            .unwrap()
+            .unwrap()
+            .to_bytes();
+        let body = std::str::from_utf8(&body).unwrap();
+        let (peer_addr, padding) = body.split_once('\n').unwrap();
+        assert!(peer_addr.starts_with("127.0.0.1:"), "{}", peer_addr);
+        assert_eq!(padding.len(), 256 * 1024);
+
+        time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+    }
+}
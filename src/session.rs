@@ -1,87 +1,403 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{hash_map::Entry, HashMap, HashSet},
+    io::ErrorKind,
     net::SocketAddr,
+    ops::{Deref, DerefMut},
+    pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
-        Arc,
+        Arc, Mutex as StdMutex, MutexGuard, PoisonError,
     },
-    time::Duration,
+    task::{Context, Poll},
+    time::{Duration, SystemTime},
 };
 
 use byte_string::ByteStr;
-use kcp::KcpResult;
-use log::{error, trace};
+use bytes::BytesMut;
+use futures::ready;
+use log::{debug, error, trace};
 use tokio::{
     net::UdpSocket,
-    sync::{mpsc, Mutex},
-    time::{self, Instant},
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    task::AbortHandle,
+    time::{self, Instant, Sleep},
+};
+
+#[cfg(feature = "debug-internals")]
+use crate::debug::KcpDebugState;
+use crate::{
+    config::{
+        ConvAllocator, ConvAllocatorFactory, DispatchMode, KcpConfig, KcpNoDelayConfig, PmtuConfig,
+        SequentialConvAllocator, CONV_ALLOC_MAX_ATTEMPTS,
+    },
+    congestion::CongestionStats,
+    diagnostics::{SessionDiagnostics, SessionInfo},
+    driver::{DriverWaker, SessionDriver},
+    error::{KcpError, KcpResult},
+    event::KcpEvent,
+    handshake::HandshakeFrame,
+    migration::{MigrationFrame, ResumptionToken, TokenSigner, TOKEN_MAX_ATTEMPTS, TOKEN_RESEND_INTERVAL},
+    overload::OverloadMonitor,
+    pacing::PacingStats,
+    pmtu::{PmtuAction, PmtuFrame, PmtuProber},
+    pool::{BufferPool, PooledBuffer},
+    skcp::{KcpSocket, OutputState, UdpOutput},
+    transport::Endpoint,
+    utils::is_message_size_error,
 };
 
-use crate::{skcp::KcpSocket, KcpConfig};
+/// Identifies a session of a listener, different peers may use the same conv
+pub type SessionKey = (u32, SocketAddr);
+
+/// Packets from listener waiting in the input channel of a session task
+const SESSION_INPUT_BACKLOG: usize = 64;
+
+/// Maximum number of packets from listener that are input under one lock of the socket
+const INPUT_BATCH_SIZE: usize = 16;
+
+/// Time to keep a session after peer sent FIN, for absorbing delayed and duplicated packets
+const PEER_CLOSED_DRAIN: Duration = Duration::from_secs(1);
+
+/// Time to remember a removed session, its packets are answered with FIN instead of opening a new session
+const CLOSED_SESSION_WAIT: Duration = Duration::from_secs(30);
+
+/// Sends failed in a row before the socket is rebound, with `KcpConfig::auto_rebind`
+const REBIND_SEND_ERRORS: u32 = 3;
+
+/// Minimum interval between two rebinds, sends may keep failing until the network comes back
+const REBIND_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Gap between two ticks of the update timer that is considered as a time jump
+const TIME_JUMP_THRESHOLD: Duration = Duration::from_secs(5);
+
+/// Detects time jumps between ticks of the update timer.
+///
+/// Time jumps if the system was suspended, the process was stopped, or the wall clock was changed.
+/// Depending on the platform, the monotonic clock may or may not include the suspended time,
+/// so both the lateness of the timer and the drift between wall clock and monotonic clock are checked.
+struct TimeJumpDetector {
+    last_tick: Instant,
+    last_wall: SystemTime,
+}
+
+impl TimeJumpDetector {
+    fn new() -> TimeJumpDetector {
+        TimeJumpDetector {
+            last_tick: Instant::now(),
+            last_wall: SystemTime::now(),
+        }
+    }
+
+    /// Called when a timer scheduled at `deadline` fires, returns the gap if time jumped
+    fn tick(&mut self, deadline: Instant) -> Option<Duration> {
+        let now = Instant::now();
+        let wall = SystemTime::now();
+
+        let late = now.saturating_duration_since(deadline);
+
+        let elapsed = now.saturating_duration_since(self.last_tick);
+        let drift = match wall.duration_since(self.last_wall) {
+            Ok(wall_elapsed) if wall_elapsed > elapsed => wall_elapsed - elapsed,
+            Ok(wall_elapsed) => elapsed - wall_elapsed,
+            // Wall clock went backwards
+            Err(err) => err.duration() + elapsed,
+        };
+
+        self.last_tick = now;
+        self.last_wall = wall;
+
+        let gap = late.max(drift);
+        if gap > TIME_JUMP_THRESHOLD {
+            Some(gap)
+        } else {
+            None
+        }
+    }
+}
+
+/// States of updating a session, owned by whoever drives the session
+pub struct UpdateState {
+    time_jump: TimeJumpDetector,
+    token_attempts: u32,
+    token_last_sent: Option<Instant>,
+    /// Data was sent by stream, flush it without waiting for the next interval
+    pub flush_now: bool,
+    /// Session is closed by this time even if sent data hasn't been acknowledged, with `KcpConfig::linger`
+    linger_deadline: Option<Instant>,
+    last_rebind: Option<Instant>,
+    /// Probes path MTU, client only
+    pmtu: Option<PmtuProber>,
+    /// Samples drops of the UDP socket, only for clients that own their socket
+    overload: Option<OverloadMonitor>,
+}
+
+impl UpdateState {
+    pub fn new() -> UpdateState {
+        UpdateState {
+            time_jump: TimeJumpDetector::new(),
+            token_attempts: 0,
+            token_last_sent: None,
+            flush_now: false,
+            linger_deadline: None,
+            last_rebind: None,
+            pmtu: None,
+            overload: None,
+        }
+    }
+}
 
 pub struct KcpSession {
-    socket: Mutex<KcpSocket>,
+    /// Only locked for short synchronous sections, never across an `.await`
+    socket: StdMutex<KcpSocket>,
     closed: AtomicBool,
+    /// All streams of this session were dropped, received data will never be read
+    abandoned: AtomicBool,
     session_expire: Duration,
-    session_close_notifier: Option<mpsc::Sender<u32>>,
-    input_tx: mpsc::Sender<Vec<u8>>,
+    /// Client sessions never expire by `session_expire`
+    is_client: bool,
+    /// Replaces the socket if sends keep failing, only for clients that own their socket
+    auto_rebind: bool,
+    /// Probes path MTU, only for clients that own their socket
+    pmtu: Option<PmtuConfig>,
+    /// Closes the session if no data was sent or received for `KcpConfig::idle_timeout`
+    idle_timeout: Option<Duration>,
+    session_close_notifier: Option<mpsc::Sender<SessionKey>>,
+    /// Input channel of the session task, `None` if driven by `SessionDriver`
+    input_tx: Option<mpsc::Sender<PooledBuffer>>,
+    /// Packets are dropped instead of waiting for room in `input_tx`
+    dispatch_mode: DispatchMode,
+    output_state: Arc<OutputState>,
+    /// Sends datagrams produced by the socket after it is unlocked
+    output: UdpOutput,
+    /// Token issued to client for migrating this session, server only
+    resumption_token: Option<ResumptionToken>,
+    /// Client has received `resumption_token`
+    token_acked: AtomicBool,
+    /// Wakes the session task for sending data or closing immediately
+    update_notify: Notify,
+    /// Small writes are flushed after `KcpConfig::write_coalesce`
+    write_coalesce: Option<Duration>,
+    /// Maximum time of delivering sent data after the stream is dropped
+    linger: Option<Duration>,
+    /// Deadline of flushing coalesced writes, taken by the session task when it is notified
+    flush_at: StdMutex<Option<Instant>>,
+    /// Wakes the shared driver instead of the session task
+    driver_waker: Option<DriverWaker>,
+    /// Next update is scheduled after a long period
+    idle: AtomicBool,
+    #[cfg(test)]
+    update_count: std::sync::atomic::AtomicUsize,
 }
 
 impl KcpSession {
     fn new(
-        socket: KcpSocket,
-        session_expire: Duration,
-        session_close_notifier: Option<mpsc::Sender<u32>>,
-        input_tx: mpsc::Sender<Vec<u8>>,
+        mut socket: KcpSocket,
+        config: &KcpConfig,
+        is_client: bool,
+        session_close_notifier: Option<mpsc::Sender<SessionKey>>,
+        input_tx: Option<mpsc::Sender<PooledBuffer>>,
+        resumption_token: Option<ResumptionToken>,
+        driver_waker: Option<DriverWaker>,
     ) -> KcpSession {
+        let output_state = socket.output_state().clone();
+        let output = socket.defer_output();
         KcpSession {
-            socket: Mutex::new(socket),
+            socket: StdMutex::new(socket),
             closed: AtomicBool::new(false),
-            session_expire,
+            abandoned: AtomicBool::new(false),
+            session_expire: config.session_expire,
+            is_client,
+            auto_rebind: false,
+            pmtu: None,
+            idle_timeout: config.idle_timeout,
             session_close_notifier,
             input_tx,
+            dispatch_mode: config.dispatch_mode,
+            output_state,
+            output,
+            resumption_token,
+            token_acked: AtomicBool::new(false),
+            update_notify: Notify::new(),
+            write_coalesce: config.write_coalesce,
+            linger: config.linger,
+            flush_at: StdMutex::new(None),
+            driver_waker,
+            idle: AtomicBool::new(false),
+            #[cfg(test)]
+            update_count: std::sync::atomic::AtomicUsize::new(0),
         }
     }
 
+    /// Creates a session driven by its own task, which could be cancelled by the returned handle
     pub fn new_shared(
-        socket: KcpSocket,
-        session_expire: Duration,
-        session_close_notifier: Option<mpsc::Sender<u32>>,
-    ) -> Arc<KcpSession> {
+        mut socket: KcpSocket,
+        config: &KcpConfig,
+        session_close_notifier: Option<mpsc::Sender<SessionKey>>,
+        resumption_token: Option<ResumptionToken>,
+    ) -> (Arc<KcpSession>, AbortHandle) {
         let is_client = session_close_notifier.is_none();
 
-        let (input_tx, mut input_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(SESSION_INPUT_BACKLOG);
 
-        let udp_socket = socket.udp_socket().clone();
+        let pmtu = config.pmtu.filter(|_| is_client);
+        if pmtu.is_some() {
+            socket.start_pmtu_probing();
+        }
 
-        let session = Arc::new(KcpSession::new(
+        let mut session = KcpSession::new(
             socket,
-            session_expire,
+            config,
+            is_client,
             session_close_notifier,
-            input_tx,
+            Some(input_tx),
+            resumption_token,
+            None,
+        );
+        session.auto_rebind = is_client && config.auto_rebind;
+        session.pmtu = pmtu;
+        let session = Arc::new(session);
+
+        // Client receives from its own socket
+        let task = KcpSession::spawn(&session, input_rx, is_client, config.enable_migration);
+        (session, task)
+    }
+
+    /// Creates a client session that shares its UDP socket with other sessions, driven by its own task.
+    ///
+    /// Datagrams are received by the owner of the socket and handed over by `input`.
+    pub fn new_multiplexed(
+        socket: KcpSocket,
+        config: &KcpConfig,
+        session_close_notifier: mpsc::Sender<SessionKey>,
+    ) -> (Arc<KcpSession>, AbortHandle) {
+        let (input_tx, input_rx) = mpsc::channel(SESSION_INPUT_BACKLOG);
+
+        let session = Arc::new(KcpSession::new(
+            socket,
+            config,
+            true,
+            Some(session_close_notifier),
+            Some(input_tx),
+            None,
+            None,
         ));
 
-        {
+        let task = KcpSession::spawn(&session, input_rx, false, false);
+        (session, task)
+    }
+
+    /// Spawns the task of a session, which also receives from its UDP socket if `recv_udp`, when it owns the socket
+    fn spawn(
+        session: &Arc<KcpSession>,
+        mut input_rx: mpsc::Receiver<PooledBuffer>,
+        recv_udp: bool,
+        enable_migration: bool,
+    ) -> AbortHandle {
+        let task = {
             let session = session.clone();
             tokio::spawn(async move {
-                let mut input_buffer = [0u8; 65536];
+                // Datagrams that fit buffers of the pool, which is sized for the MTU
+                let mut input_buffer = vec![0u8; session.output_state.buffer_size()];
+                // Datagrams opened by `KcpConfig::checksum` or `KcpConfig::obfuscator`
+                let mut opened = BytesMut::new();
                 let update_timer = time::sleep(Duration::from_millis(10));
                 tokio::pin!(update_timer);
+                let mut update_state = UpdateState::new();
+                if let Some(pmtu) = session.pmtu {
+                    update_state.pmtu = Some(PmtuProber::new(pmtu, session.lock_socket().mtu()));
+                }
+                if recv_udp {
+                    if let Some(udp) = session.output_state.udp_socket() {
+                        update_state.overload = OverloadMonitor::new(&*udp);
+                    }
+                }
+                // Token received from server, client only
+                let mut client_token: Option<ResumptionToken> = None;
 
                 loop {
+                    // Replaced if the session is rebound
+                    let udp_socket = session.output_state.udp_socket().filter(|_| recv_udp);
+
                     tokio::select! {
                         // recv() then input()
                         // Drives the KCP machine forward
-                        recv_result = udp_socket.recv(&mut input_buffer), if is_client => {
+                        recv_result = async { udp_socket.as_ref().expect("UDP socket of session").recv(&mut input_buffer).await }, if udp_socket.is_some() => {
                             match recv_result {
+                                Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+                                    // Connected client socket, ICMP port unreachable from the host of server
+                                    debug!("[SESSION] UDP recv failed, peer {} refused", session.peer_addr());
+                                    session.output_state.set_refused();
+                                    update_timer.as_mut().reset(Instant::now());
+                                }
                                 Err(err) => {
                                     error!("[SESSION] UDP recv failed, error: {}", err);
                                 }
                                 Ok(n) => {
-                                    let input_buffer = &input_buffer[..n];
+                                    let input_buffer = match session.output_state.unwrap_received(&input_buffer[..n]) {
+                                        Some(input_buffer) => input_buffer,
+                                        None => {
+                                            trace!("[SESSION] UDP recv {} bytes, dropped, not relayed from peer", n);
+                                            continue;
+                                        }
+                                    };
+                                    let input_buffer = if session.output_state.framing().is_plain() {
+                                        input_buffer
+                                    } else {
+                                        opened.clear();
+                                        opened.extend_from_slice(input_buffer);
+                                        if !session.output_state.open_received(&mut opened) {
+                                            trace!("[SESSION] UDP recv {} bytes, dropped, failed to open", n);
+                                            continue;
+                                        }
+                                        &opened[..]
+                                    };
                                     trace!("[SESSION] UDP recv {} bytes, going to input {:?}", n, ByteStr::new(input_buffer));
 
-                                    let mut socket = session.socket.lock().await;
+                                    if HandshakeFrame::decode(input_buffer).is_some() {
+                                        // Duplicated SYN-ACK, handshake was already completed
+                                        trace!("[SESSION] ignored handshake frame");
+                                        continue;
+                                    }
+
+                                    let mut socket = session.lock_socket();
+
+                                    if let Some(PmtuFrame::Ack { conv, seq, size }) = PmtuFrame::decode(input_buffer) {
+                                        if let Some(ref mut prober) = update_state.pmtu {
+                                            if conv == socket.conv() {
+                                                prober.on_ack(seq, size, Instant::now());
+                                                // Probes the next size
+                                                update_timer.as_mut().reset(Instant::now());
+                                            }
+                                        }
+                                        continue;
+                                    }
+
+                                    if let Some(frame) = MigrationFrame::decode(input_buffer) {
+                                        let conv = socket.conv();
+                                        match frame {
+                                            // May arrive before the first segment that tells us the allocated conv
+                                            MigrationFrame::Token { conv: c, token } if enable_migration && (c == conv || conv == 0) => {
+                                                // Acknowledges with RESUME
+                                                client_token = Some(token);
+                                                socket.send_raw(&MigrationFrame::Resume { conv: c, token }.encode());
+                                            }
+                                            MigrationFrame::Challenge { conv: c } if c == conv => {
+                                                // Address changed, proves that we are the owner of this session
+                                                if let Some(token) = client_token {
+                                                    debug!("[SESSION] conv: {} challenged by server, resuming", conv);
+                                                    socket.send_raw(&MigrationFrame::Resume { conv, token }.encode());
+                                                }
+                                            }
+                                            _ => {
+                                                trace!("[SESSION] ignored migration frame {:?}", frame);
+                                            }
+                                        }
+                                        continue;
+                                    }
 
                                     match socket.input(input_buffer) {
                                         Ok(true) => {
@@ -92,6 +408,7 @@ impl KcpSession {
                                             error!("[SESSION] UDP input {} bytes error: {}, input buffer {:?}", n, err, ByteStr::new(input_buffer));
                                         }
                                     }
+                                    wake_idle(update_timer.as_mut(), &socket);
                                 }
                             }
                         }
@@ -99,152 +416,1193 @@ impl KcpSession {
                         // bytes received from listener socket
                         input_opt = input_rx.recv() => {
                             if let Some(input_buffer) = input_opt {
-                                let mut socket = session.socket.lock().await;
-                                match socket.input(&input_buffer) {
-                                    Ok(..) => {
-                                        trace!("[SESSION] UDP input {} bytes from channel {:?}", input_buffer.len(), ByteStr::new(&input_buffer));
+                                let mut socket = session.lock_socket();
+                                session.input_socket(&mut socket, &input_buffer);
+
+                                // Packets that arrived in the meantime are input under the same lock
+                                for _ in 1..INPUT_BATCH_SIZE {
+                                    match input_rx.try_recv() {
+                                        Ok(input_buffer) => session.input_socket(&mut socket, &input_buffer),
+                                        Err(..) => break,
                                     }
-                                    Err(err) => {
-                                        error!("[SESSION] UDP input {} bytes from channel failed, error: {}, input buffer {:?}",
-                                               input_buffer.len(), err, ByteStr::new(&input_buffer));
+                                }
+                                wake_idle(update_timer.as_mut(), &socket);
+                            }
+                        }
+
+                        // Stream sent data or closed
+                        _ = session.update_notify.notified() => {
+                            update_state.flush_now = true;
+                            match session.flush_at.lock().unwrap().take() {
+                                // Coalescing small writes, flushes at the deadline of the first one
+                                Some(deadline) => {
+                                    if update_timer.deadline() > deadline {
+                                        update_timer.as_mut().reset(deadline);
                                     }
                                 }
+                                None => update_timer.as_mut().reset(Instant::now()),
                             }
                         }
 
-                        // Call update() in period
+                        // Call update() when KCP needs, or in a long period if it is idle
                         _ = &mut update_timer => {
-                            let mut socket = session.socket.lock().await;
-
-                            let is_closed = session.closed.load(Ordering::Acquire);
-                            if is_closed && socket.can_close() {
-                                trace!("[SESSION] KCP session closed");
-                                break;
+                            match session.tick(&mut update_state, update_timer.deadline()).await {
+                                Some(next) => update_timer.as_mut().reset(next),
+                                None => break,
                             }
+                        }
+                    }
+                }
 
-                            // server socket expires
-                            if !is_client {
-                                // If this is a server stream, close it automatically after a period of time
-                                let last_update_time = socket.last_update_time();
-                                let elapsed = last_update_time.elapsed();
-
-                                if elapsed > session.session_expire {
-                                    if elapsed > session.session_expire * 2 {
-                                        // Force close. Client may have already gone.
-                                        trace!(
-                                            "[SESSION] force close inactive session, conv: {}, last_update: {}s ago",
-                                            socket.conv(),
-                                            elapsed.as_secs()
-                                        );
-                                        break;
-                                    }
+                session.finish().await;
+            })
+        };
 
-                                    if !is_closed {
-                                        trace!(
-                                            "[SESSION] closing inactive session, conv: {}, last_update: {}s ago",
-                                            socket.conv(),
-                                            elapsed.as_secs()
-                                        );
-                                        session.closed.store(true, Ordering::Release);
-                                    }
-                                }
-                            }
+        task.abort_handle()
+    }
 
-                            match socket.update() {
-                                Ok(next_next) => {
-                                    update_timer.as_mut().reset(Instant::from_std(next_next));
-                                }
-                                Err(err) => {
-                                    error!("[SESSION] KCP update failed, error: {}", err);
-                                    update_timer.as_mut().reset(Instant::now() + Duration::from_millis(10));
+    /// Creates a session driven by `driver`, which is shared with other sessions
+    pub fn new_driven(
+        socket: KcpSocket,
+        config: &KcpConfig,
+        session_close_notifier: mpsc::Sender<SessionKey>,
+        resumption_token: Option<ResumptionToken>,
+        driver: &SessionDriver,
+    ) -> Arc<KcpSession> {
+        let id = driver.alloc_id();
+        let session = Arc::new(KcpSession::new(
+            socket,
+            config,
+            false,
+            Some(session_close_notifier),
+            None,
+            resumption_token,
+            Some(driver.waker(id)),
+        ));
+        driver.register(id, session.clone());
+        session
+    }
+
+    /// Updates KCP and states of this session that was scheduled at `deadline`.
+    ///
+    /// Returns the time of the next update, or `None` if the session should be closed by `finish()`.
+    pub async fn tick(&self, state: &mut UpdateState, deadline: Instant) -> Option<Instant> {
+        if self.auto_rebind {
+            self.rebind_if_failing(state).await;
+        }
+
+        let mut socket = self.lock_socket();
+        #[cfg(test)]
+        self.update_count.fetch_add(1, Ordering::Relaxed);
+
+        if socket.is_shut_down() && !self.closed.swap(true, Ordering::AcqRel) {
+            // Both directions are done, closed like a dropped stream, but data received is still readable
+            trace!(
+                "[SESSION] KCP session shut down in both directions, conv: {}",
+                socket.conv()
+            );
+        }
+        let is_closed = self.closed.load(Ordering::Acquire);
+        if self.abandoned.load(Ordering::Acquire) {
+            // Peer may keep sending until it receives FIN, after sent data is delivered
+            socket.discard_received();
+        }
+        if is_closed && socket.can_close() {
+            trace!("[SESSION] KCP session closed");
+            socket.send_fin();
+            return None;
+        }
+        if is_closed {
+            if let Some(linger) = self.linger {
+                let linger_deadline = *state.linger_deadline.get_or_insert_with(|| Instant::now() + linger);
+                if Instant::now() >= linger_deadline {
+                    debug!(
+                        "[SESSION] KCP session closed with unacknowledged data after lingering {:?}, conv: {}",
+                        linger,
+                        socket.conv()
+                    );
+                    socket.send_fin();
+                    return None;
+                }
+            }
+        }
+
+        if let Some(peer_closed_time) = socket.peer_closed_time() {
+            if peer_closed_time.elapsed() > PEER_CLOSED_DRAIN {
+                trace!("[SESSION] KCP session closed by peer, conv: {}", socket.conv());
+                return None;
+            }
+        }
+
+        if self.output_state.refused() {
+            debug!("[SESSION] KCP session refused by peer, conv: {}", socket.conv());
+            return None;
+        }
+
+        if let Some(idle_timeout) = self.idle_timeout {
+            if !is_closed && socket.last_activity().elapsed() >= idle_timeout {
+                debug!(
+                    "[SESSION] idle session closed, conv: {}, no data for {:?}",
+                    socket.conv(),
+                    idle_timeout
+                );
+                socket.expire_idle();
+                return None;
+            }
+        }
+
+        // Peer only acknowledges probes of sessions it knows
+        if let Some(prober) = state.pmtu.as_mut().filter(|_| socket.is_connected()) {
+            if socket.take_pmtu_exceeded() {
+                debug!(
+                    "[PMTU] conv: {} datagram exceeds path MTU {}",
+                    socket.conv(),
+                    prober.mtu()
+                );
+                prober.on_loss();
+            }
+            prober.on_retransmissions(socket.output_state().retransmissions());
+
+            let now = Instant::now();
+            while let Some(action) = prober.poll(now) {
+                match action {
+                    PmtuAction::Probe { seq, size } => {
+                        let frame = PmtuFrame::Probe {
+                            conv: socket.conv(),
+                            seq,
+                            size,
+                        };
+                        match socket.send_pmtu_probe(&frame.encode()) {
+                            Ok(()) => trace!("[PMTU] conv: {} probing {} bytes", socket.conv(), size),
+                            Err(err) if is_message_size_error(&err) => {
+                                trace!("[PMTU] conv: {} probe of {} bytes is too big", socket.conv(), size);
+                                if let Some(action) = prober.on_too_big(seq, now) {
+                                    self.apply_pmtu_action(&mut socket, action);
                                 }
                             }
+                            Err(err) => trace!("[PMTU] conv: {} probe send failed, error: {}", socket.conv(), err),
                         }
                     }
+                    action => self.apply_pmtu_action(&mut socket, action),
                 }
+            }
+        }
 
-                {
-                    // Close the socket.
-                    // Wake all pending tasks and let all send/recv return EOF
+        if let Some(monitor) = state.overload.as_mut() {
+            // Socket may have been rebound, its counter starts over
+            if let Some(event) = self
+                .output_state
+                .udp_socket()
+                .and_then(|udp| monitor.poll(&*udp, Instant::now()))
+            {
+                debug!("[SESSION] conv: {} {:?}", socket.conv(), event);
+                socket.set_receive_overload(event);
+            }
+        }
+
+        if let Some(gap) = state.time_jump.tick(deadline) {
+            // System may have been suspended, everything looks expired now.
+            // Ask peer if it is still there instead of expiring immediately.
+            debug!(
+                "[SESSION] time jumped {:?}, probing peer liveness, conv: {}",
+                gap,
+                socket.conv()
+            );
+            socket.probe_liveness();
+        }
+
+        // Delivers resumption token to client
+        if let Some(ref token) = self.resumption_token {
+            if !self.token_acked.load(Ordering::Acquire)
+                && state.token_attempts < TOKEN_MAX_ATTEMPTS
+                && state
+                    .token_last_sent
+                    .is_none_or(|t| t.elapsed() >= TOKEN_RESEND_INTERVAL)
+            {
+                let frame = MigrationFrame::Token {
+                    conv: socket.conv(),
+                    token: *token,
+                };
+                socket.send_raw(&frame.encode());
+                state.token_attempts += 1;
+                state.token_last_sent = Some(Instant::now());
+            }
+        }
+
+        // server socket expires
+        if !self.is_client {
+            // If this is a server stream, close it automatically after a period of time
+            let last_update_time = socket.last_update_time();
+            let elapsed = last_update_time.elapsed();
 
-                    let mut socket = session.socket.lock().await;
-                    socket.close();
+            if elapsed > self.session_expire {
+                if elapsed > self.session_expire * 2 {
+                    // Force close. Client may have already gone.
+                    trace!(
+                        "[SESSION] force close inactive session, conv: {}, last_update: {}s ago",
+                        socket.conv(),
+                        elapsed.as_secs()
+                    );
+                    socket.expire();
+                    return None;
                 }
 
-                if let Some(ref notifier) = session.session_close_notifier {
-                    let socket = session.socket.lock().await;
-                    let _ = notifier.send(socket.conv()).await;
+                if !is_closed {
+                    if socket.can_close() {
+                        trace!(
+                            "[SESSION] inactive session expired, conv: {}, last_update: {}s ago",
+                            socket.conv(),
+                            elapsed.as_secs()
+                        );
+                        socket.expire();
+                        return None;
+                    }
+
+                    trace!(
+                        "[SESSION] closing inactive session, conv: {}, last_update: {}s ago",
+                        socket.conv(),
+                        elapsed.as_secs()
+                    );
+                    self.closed.store(true, Ordering::Release);
                 }
-            });
+            }
         }
 
-        session
+        let result = if state.flush_now {
+            state.flush_now = false;
+            socket.flush().and_then(|_| socket.update())
+        } else {
+            socket.update()
+        };
+        let shutting_down = socket.send_shutdown();
+        match result {
+            Ok(mut next) => {
+                if shutting_down {
+                    // Until peer acknowledges
+                    next = next.min(Instant::now() + socket.update_interval());
+                }
+                if let Some(idle_timeout) = self.idle_timeout {
+                    if !is_closed {
+                        next = next.min(socket.last_activity() + idle_timeout);
+                    }
+                }
+                if let Some(deadline) = state.pmtu.as_ref().and_then(PmtuProber::deadline) {
+                    next = next.min(deadline);
+                }
+                self.idle
+                    .store(next > Instant::now() + socket.update_interval(), Ordering::Release);
+                Some(next)
+            }
+            Err(err) if socket.is_broken() => {
+                error!("[SESSION] KCP session conv: {} stopped, error: {}", socket.conv(), err);
+                None
+            }
+            Err(err) => {
+                error!("[SESSION] KCP update failed, error: {}", err);
+                Some(Instant::now() + Duration::from_millis(10))
+            }
+        }
+    }
+
+    /// Replaces the socket of a client if sends keep failing, see `KcpConfig::auto_rebind`
+    async fn rebind_if_failing(&self, state: &mut UpdateState) {
+        let replacement = {
+            let socket = self.lock_socket();
+            if socket.send_errors() < REBIND_SEND_ERRORS
+                || state.last_rebind.is_some_and(|t| t.elapsed() < REBIND_INTERVAL)
+            {
+                return;
+            }
+            state.last_rebind = Some(Instant::now());
+            socket.bind_replacement()
+        };
+        // Not locked while binding
+        let replacement = replacement.await;
+
+        let mut socket = self.lock_socket();
+        match replacement.and_then(|udp| socket.rebind(udp)) {
+            Ok(local_addr) => debug!(
+                "[SESSION] conv: {} rebound to {}, sends kept failing",
+                socket.conv(),
+                local_addr
+            ),
+            Err(err) => error!("[SESSION] conv: {} rebind failed, error: {}", socket.conv(), err),
+        }
     }
 
-    pub fn kcp_socket(&self) -> &Mutex<KcpSocket> {
-        &self.socket
+    fn apply_pmtu_action(&self, socket: &mut KcpSocket, action: PmtuAction) {
+        if let PmtuAction::SetMtu(mtu) = action {
+            match socket.set_mtu(mtu) {
+                Ok(()) => {
+                    debug!("[PMTU] conv: {} path MTU is {}", socket.conv(), mtu);
+                    self.output_state.events().emit(KcpEvent::MtuChanged { mtu });
+                }
+                Err(err) => error!(
+                    "[PMTU] conv: {} failed to set mtu {}, error: {}",
+                    socket.conv(),
+                    mtu,
+                    err
+                ),
+            }
+        }
+    }
+
+    /// Closes the socket after the last `tick()`.
+    ///
+    /// Wakes all pending tasks and lets all send/recv return EOF
+    pub async fn finish(&self) {
+        let conv = {
+            let mut socket = self.lock_socket();
+            socket.close();
+            socket.conv()
+        };
+
+        self.output_state.events().emit(KcpEvent::Closed);
+
+        if let Some(ref notifier) = self.session_close_notifier {
+            let _ = notifier.send((conv, self.peer_addr())).await;
+        }
+    }
+
+    pub fn events(&self) -> broadcast::Receiver<KcpEvent> {
+        self.output_state.events().subscribe()
+    }
+
+    /// Current UDP socket, replaced by `KcpSocket::rebind`, `None` on a custom transport
+    pub fn udp_socket(&self) -> Option<Arc<UdpSocket>> {
+        self.output_state.udp_socket()
+    }
+
+    /// Locks the socket, datagrams produced while it is locked are sent after it is unlocked
+    pub fn lock_socket(&self) -> SocketGuard<'_> {
+        SocketGuard {
+            // A panicked task doesn't break other handles of the session
+            socket: Some(self.socket.lock().unwrap_or_else(PoisonError::into_inner)),
+            output: &self.output,
+        }
     }
 
     pub fn close(&self) {
         self.closed.store(true, Ordering::Release);
+        self.notify_update();
+    }
+
+    /// Shuts down the session for writing, see `KcpStream::shutdown_write`
+    pub fn shutdown_write(&self) -> KcpResult<()> {
+        {
+            let mut socket = self.lock_socket();
+            socket.shutdown_write()?;
+        }
+        // Flushes data sent before
+        self.notify_update();
+        Ok(())
+    }
+
+    /// Closes the session after its last stream was dropped
+    pub fn abandon(&self) {
+        self.abandoned.store(true, Ordering::Release);
+        self.close();
+    }
+
+    /// Stream of this session has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    /// Keeps delivering sent data for a while after the stream is dropped
+    pub fn is_lingering(&self) -> bool {
+        self.is_closed() && self.linger.is_some_and(|linger| !linger.is_zero())
+    }
+
+    /// Terminates this session immediately, and notifies peer with FIN.
+    ///
+    /// Stream fails with `ConnectionReset`, the session is removed from listener by the next update.
+    pub fn reset(&self) {
+        self.lock_socket().reset();
+        self.notify_update();
+    }
+
+    /// `reset` unless the session has already stopped by itself, returns whether it was reset.
+    ///
+    /// Checked under the lock of the socket, so a session stopping at the same moment is either reset or left as
+    /// it is, its stream never sees both errors.
+    pub fn reset_running(&self) -> bool {
+        {
+            let mut socket = self.lock_socket();
+            if socket.is_terminated() {
+                return false;
+            }
+            debug!(
+                "[SESSION] conv: {} closed by listener, peer: {}",
+                socket.conv(),
+                self.peer_addr()
+            );
+            socket.reset();
+        }
+        self.notify_update();
+        true
+    }
+
+    /// Sends all of `buf` at once or nothing, for `KcpListener::broadcast`, which doesn't leave a part of it in
+    /// the stream
+    pub fn poll_send_whole(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<()>> {
+        let mut socket = self.lock_socket();
+        if buf.len() > socket.max_send_len() {
+            return Err(KcpError::Kcp(kcp::Error::UserBufTooBig)).into();
+        }
+        match ready!(socket.poll_send(cx, buf)) {
+            // Closed by this side
+            Ok(0) if !buf.is_empty() => Err(KcpError::ConnectionClosed).into(),
+            Ok(..) => {
+                self.notify_sent(&socket);
+                Ok(()).into()
+            }
+            Err(err) => Err(err).into(),
+        }
+    }
+
+    /// Flushes data sent by stream, small writes are delayed for `KcpConfig::write_coalesce`
+    pub fn notify_sent(&self, socket: &KcpSocket) {
+        match self.write_coalesce {
+            Some(delay) if socket.can_coalesce() => self.notify_flush_at(Instant::now() + delay),
+            _ => self.notify_update(),
+        }
+    }
+
+    /// Flushes sent data no later than `deadline`
+    fn notify_flush_at(&self, deadline: Instant) {
+        match self.driver_waker {
+            Some(ref waker) => waker.flush_at(deadline),
+            None => {
+                {
+                    let mut flush_at = self.flush_at.lock().unwrap();
+                    if flush_at.is_none_or(|at| at > deadline) {
+                        *flush_at = Some(deadline);
+                    }
+                }
+                self.update_notify.notify_one();
+            }
+        }
+    }
+
+    /// Flushes sent data in the session task
+    pub fn notify_update(&self) {
+        match self.driver_waker {
+            Some(ref waker) => waker.wake(true),
+            None => {
+                if self.write_coalesce.is_some() {
+                    // Not delayed by coalesced writes
+                    *self.flush_at.lock().unwrap() = None;
+                }
+                self.update_notify.notify_one();
+            }
+        }
     }
 
-    pub async fn input(&self, buf: &[u8]) {
-        self.input_tx.send(buf.to_owned()).await.expect("input channel closed")
+    #[cfg(test)]
+    pub fn update_count(&self) -> usize {
+        self.update_count.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn sent_segments(&self) -> u32 {
+        self.output_state.sent_segments()
+    }
+
+    pub fn pacing_stats(&self) -> Option<PacingStats> {
+        self.output_state.pacing_stats()
+    }
+
+    pub fn retransmissions(&self) -> u64 {
+        self.output_state.retransmissions()
+    }
+
+    pub fn fast_retransmissions(&self) -> u64 {
+        self.output_state.fast_retransmissions()
+    }
+
+    pub fn ack_only_datagrams(&self) -> u64 {
+        self.output_state.ack_only_datagrams()
+    }
+
+    pub fn rebinds(&self) -> u64 {
+        self.output_state.rebinds()
+    }
+
+    pub fn corrupted_packets(&self) -> u64 {
+        self.output_state.corrupted_packets()
+    }
+
+    /// Nothing listens on the address of peer, client only
+    pub fn is_refused(&self) -> bool {
+        self.output_state.refused()
+    }
+
+    pub fn set_mtu(&self, mtu: usize) -> KcpResult<()> {
+        self.lock_socket().set_mtu(mtu)
+    }
+
+    pub fn set_fast_resend(&self, resend: u32) {
+        self.lock_socket().set_fast_resend(resend)
+    }
+
+    pub fn set_flush_acks_input(&self, enabled: bool) {
+        self.lock_socket().set_flush_acks_input(enabled)
+    }
+
+    pub fn set_nodelay(&self, nodelay: KcpNoDelayConfig) {
+        self.lock_socket().set_nodelay(nodelay)
+    }
+
+    pub fn congestion_stats(&self) -> CongestionStats {
+        self.lock_socket().congestion_stats()
+    }
+
+    pub fn bandwidth_estimate(&self) -> Option<u64> {
+        self.lock_socket().bandwidth_estimate()
+    }
+
+    #[cfg(feature = "experimental-cc")]
+    pub fn cwnd(&self) -> u16 {
+        self.lock_socket().cwnd()
+    }
+
+    #[cfg(feature = "experimental-cc")]
+    pub fn bytes_in_flight(&self) -> usize {
+        self.lock_socket().bytes_in_flight()
+    }
+
+    #[cfg(feature = "experimental-cc")]
+    pub fn set_cwnd(&self, cwnd: u16) {
+        self.lock_socket().set_cwnd(cwnd);
+        // A larger window may send what is waiting
+        self.notify_update();
+    }
+
+    pub fn set_receive_overload(&self, event: KcpEvent) {
+        self.lock_socket().set_receive_overload(event)
+    }
+
+    pub fn info(&self) -> SessionInfo {
+        self.lock_socket().info()
+    }
+
+    pub fn diagnostics(&self) -> SessionDiagnostics {
+        self.lock_socket().diagnostics()
+    }
+
+    #[cfg(feature = "debug-internals")]
+    pub fn debug_state(&self) -> KcpDebugState {
+        self.lock_socket().debug_state()
+    }
+
+    pub fn mtu(&self) -> usize {
+        self.lock_socket().mtu()
+    }
+
+    pub fn max_message_size(&self) -> usize {
+        self.lock_socket().max_send_len()
+    }
+
+    /// Current address of peer
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.output_state.peer_addr()
+    }
+
+    /// Token issued to client for migrating this session
+    pub fn resumption_token(&self) -> Option<&ResumptionToken> {
+        self.resumption_token.as_ref()
+    }
+
+    /// Client proved ownership of this session from `peer_addr`, sends all subsequent packets to it
+    pub fn resume(&self, peer_addr: SocketAddr) {
+        self.token_acked.store(true, Ordering::Release);
+
+        let prev_addr = self.output_state.peer_addr();
+        if prev_addr != peer_addr {
+            debug!("session migrated from {} to {}", prev_addr, peer_addr);
+            self.output_state.set_peer_addr(peer_addr);
+        }
+    }
+
+    /// Inputs a received packet, its buffer returns to the pool after it was processed
+    pub async fn input(&self, buf: PooledBuffer) {
+        match self.input_tx {
+            Some(ref input_tx) => match self.dispatch_mode {
+                DispatchMode::Shared => {
+                    if input_tx.send(buf).await.is_err() {
+                        // Task has finished, the listener will learn about the close from its notifier
+                        trace!("[SESSION] session closed, input dropped, peer: {}", self.peer_addr());
+                    }
+                }
+                DispatchMode::PerSession => match input_tx.try_send(buf) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(buf)) => {
+                        // Peer retransmits it
+                        trace!(
+                            "[SESSION] input channel full, {} bytes dropped, peer: {}",
+                            buf.len(),
+                            self.peer_addr()
+                        );
+                    }
+                    Err(TrySendError::Closed(..)) => {
+                        trace!("[SESSION] session closed, input dropped, peer: {}", self.peer_addr());
+                    }
+                },
+            },
+            None => {
+                // Driven by the shared driver
+                let mut socket = self.lock_socket();
+                self.input_socket(&mut socket, &buf);
+
+                // ACKs have to be sent in time
+                if self.idle.swap(false, Ordering::AcqRel) {
+                    if let Some(ref waker) = self.driver_waker {
+                        waker.wake(false);
+                    }
+                }
+            }
+        }
+    }
+
+    fn input_socket(&self, socket: &mut KcpSocket, buf: &[u8]) {
+        match socket.input(buf) {
+            Ok(..) => {
+                trace!(
+                    "[SESSION] UDP input {} bytes from channel {:?}",
+                    buf.len(),
+                    ByteStr::new(buf)
+                );
+            }
+            Err(err) => {
+                error!(
+                    "[SESSION] UDP input {} bytes from channel failed, error: {}, input buffer {:?}",
+                    buf.len(),
+                    err,
+                    ByteStr::new(buf)
+                );
+            }
+        }
+    }
+}
+
+/// Locked socket of a session, sends the datagrams it produced after it is unlocked
+pub struct SocketGuard<'a> {
+    socket: Option<MutexGuard<'a, KcpSocket>>,
+    output: &'a UdpOutput,
+}
+
+impl Deref for SocketGuard<'_> {
+    type Target = KcpSocket;
+
+    fn deref(&self) -> &KcpSocket {
+        self.socket.as_ref().expect("socket unlocked")
+    }
+}
+
+impl DerefMut for SocketGuard<'_> {
+    fn deref_mut(&mut self) -> &mut KcpSocket {
+        self.socket.as_mut().expect("socket unlocked")
+    }
+}
+
+impl Drop for SocketGuard<'_> {
+    fn drop(&mut self) {
+        self.socket = None;
+        self.output.transmit();
+    }
+}
+
+/// Updates immediately if socket was sleeping for idle, ACKs have to be sent in time
+fn wake_idle(update_timer: Pin<&mut Sleep>, socket: &KcpSocket) {
+    let now = Instant::now();
+    if update_timer.deadline() > now + socket.update_interval() {
+        update_timer.reset(now);
     }
 }
 
 pub struct KcpSessionManager {
-    sessions: HashMap<u32, Arc<KcpSession>>,
-    next_free_conv: u32,
+    sessions: HashMap<SessionKey, Arc<KcpSession>>,
+    /// Peers of sessions by conv, conv allocated by server is never shared
+    conv_peers: HashMap<u32, Vec<SocketAddr>>,
+    conv_allocator: Box<dyn ConvAllocator>,
+    /// convs from `conv_allocator`, released when they have no session anymore
+    allocated_convs: HashSet<u32>,
+    /// conv allocated for peers that haven't learnt it yet, they may send more packets with conv 0
+    allocated: HashMap<SocketAddr, u32>,
+    token_signer: TokenSigner,
+    /// Drives all sessions if `KcpConfig::shared_driver` is enabled
+    driver: Option<SessionDriver>,
+    /// Tasks of sessions that are driven by their own task, cancelled when the manager is dropped
+    tasks: HashMap<SessionKey, AbortHandle>,
+    /// Shared by sockets of all sessions
+    buffer_pool: Arc<BufferPool>,
+    max_sessions: Option<usize>,
+    /// Number of new sessions refused because of `max_sessions` or no conv could be allocated
+    refused: u64,
+    /// Removed sessions and when, for `CLOSED_SESSION_WAIT`
+    closed: HashMap<SessionKey, Instant>,
 }
 
 impl KcpSessionManager {
-    pub fn new() -> KcpSessionManager {
+    pub fn new(
+        buffer_pool: Arc<BufferPool>,
+        max_sessions: Option<usize>,
+        conv_allocator: Option<ConvAllocatorFactory>,
+    ) -> KcpSessionManager {
+        let conv_allocator = match conv_allocator {
+            Some(factory) => factory.create(),
+            None => Box::new(SequentialConvAllocator::new()),
+        };
         KcpSessionManager {
             sessions: HashMap::new(),
-            next_free_conv: 0,
+            conv_peers: HashMap::new(),
+            conv_allocator,
+            allocated_convs: HashSet::new(),
+            allocated: HashMap::new(),
+            token_signer: TokenSigner::new(),
+            driver: None,
+            tasks: HashMap::new(),
+            buffer_pool,
+            max_sessions,
+            refused: 0,
+            closed: HashMap::new(),
+        }
+    }
+
+    /// Fails with `ConvExhausted` if no more session could be created, and counts it as refused
+    fn check_capacity(&mut self) -> KcpResult<()> {
+        if self.max_sessions.is_some_and(|max| self.sessions.len() >= max) {
+            self.refused += 1;
+            return Err(KcpError::ConvExhausted);
+        }
+        Ok(())
+    }
+
+    /// Number of new sessions refused because `KcpConfig::max_sessions` was reached or no conv could be allocated
+    pub fn refused(&self) -> u64 {
+        self.refused
+    }
+
+    pub fn close_conv(&mut self, conv: u32, peer_addr: SocketAddr) {
+        let key = (conv, peer_addr);
+        self.tasks.remove(&key);
+        if self.sessions.remove(&key).is_none() {
+            return;
+        }
+        self.remove_conv_peer(conv, peer_addr);
+        if !self.conv_peers.contains_key(&conv) {
+            self.release_conv(conv);
+        }
+
+        // Allocation is kept until the session is forgotten, if peer hasn't learnt its conv
+        let allocated = &mut self.allocated;
+        self.closed.retain(|&(conv, peer_addr), closed_at| {
+            let keep = closed_at.elapsed() < CLOSED_SESSION_WAIT;
+            if !keep && allocated.get(&peer_addr) == Some(&conv) {
+                allocated.remove(&peer_addr);
+            }
+            keep
+        });
+        self.closed.insert(key, Instant::now());
+    }
+
+    /// Session was removed recently, packets from peer are late or retransmitted ones that it sent before it learnt
+    /// about the close
+    pub fn is_recently_closed(&self, conv: u32, peer_addr: SocketAddr) -> bool {
+        self.closed
+            .get(&(conv, peer_addr))
+            .is_some_and(|closed_at| closed_at.elapsed() < CLOSED_SESSION_WAIT)
+    }
+
+    fn remove_conv_peer(&mut self, conv: u32, peer_addr: SocketAddr) {
+        if let Entry::Occupied(mut occ) = self.conv_peers.entry(conv) {
+            occ.get_mut().retain(|addr| *addr != peer_addr);
+            if occ.get().is_empty() {
+                occ.remove();
+            }
+        }
+    }
+
+    /// Allocates a conv for a packet with conv 0 from `peer_addr`.
+    ///
+    /// Client keeps sending conv 0 until it receives the first packet from server,
+    /// so the same conv will be returned for `peer_addr` until `conv_learnt` is called.
+    pub fn alloc_conv_for(&mut self, peer_addr: SocketAddr) -> KcpResult<u32> {
+        if let Some(&conv) = self.allocated.get(&peer_addr) {
+            if self.sessions.contains_key(&(conv, peer_addr)) || self.is_recently_closed(conv, peer_addr) {
+                return Ok(conv);
+            }
         }
+
+        let conv = self.alloc_conv(peer_addr)?;
+        self.allocated.insert(peer_addr, conv);
+        Ok(conv)
     }
 
-    pub fn close_conv(&mut self, conv: u32) {
-        self.sessions.remove(&conv);
+    /// Called when `peer_addr` sends a packet with `conv`, which means it has learnt its allocated conv
+    pub fn conv_learnt(&mut self, peer_addr: SocketAddr, conv: u32) {
+        if self.allocated.is_empty() {
+            return;
+        }
+
+        if let Entry::Occupied(occ) = self.allocated.entry(peer_addr) {
+            if *occ.get() == conv {
+                occ.remove();
+            }
+        }
     }
 
-    pub fn alloc_conv(&mut self) -> u32 {
-        loop {
-            let (mut c, _) = self.next_free_conv.overflowing_add(1);
-            if c == 0 {
-                let (nc, _) = c.overflowing_add(1);
-                c = nc;
+    /// Allocates a conv for `peer_addr` that isn't used by any session, whichever peer it belongs to.
+    ///
+    /// Failures are counted as refused, like `max_sessions`.
+    pub fn alloc_conv(&mut self, peer_addr: SocketAddr) -> KcpResult<u32> {
+        self.check_capacity()?;
+
+        // Released after the loop, so the allocator doesn't return them again
+        let mut skipped = Vec::new();
+        let mut result = None;
+        for _ in 0..CONV_ALLOC_MAX_ATTEMPTS {
+            let conv = match self.conv_allocator.allocate(peer_addr) {
+                Some(conv) => conv,
+                None => {
+                    debug!("conv_allocator has no conv left, peer: {}", peer_addr);
+                    break;
+                }
+            };
+            // conv 0 is reserved for clients that haven't got one
+            if conv != 0 && !self.conv_peers.contains_key(&conv) && !self.allocated_convs.contains(&conv) {
+                result = Some(conv);
+                break;
+            }
+            trace!("conv: {} from conv_allocator is reserved or in use", conv);
+            skipped.push(conv);
+        }
+        for conv in skipped {
+            if !self.allocated_convs.contains(&conv) {
+                self.conv_allocator.release(conv);
+            }
+        }
+
+        match result {
+            Some(conv) => {
+                self.allocated_convs.insert(conv);
+                Ok(conv)
+            }
+            None => {
+                debug!("no free conv for peer: {}", peer_addr);
+                self.refused += 1;
+                Err(KcpError::ConvExhausted)
             }
-            self.next_free_conv = c;
+        }
+    }
+
+    /// Returns `conv` to `conv_allocator` if it was allocated by it and no session uses it anymore, like one
+    /// allocated for a handshake that never completed
+    pub fn release_conv(&mut self, conv: u32) {
+        if !self.conv_peers.contains_key(&conv) && self.allocated_convs.remove(&conv) {
+            trace!("conv: {} released", conv);
+            self.conv_allocator.release(conv);
+        }
+    }
+
+    /// Number of sessions
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Sessions whose streams are still in use, with their convs
+    pub fn live_sessions(&self) -> Vec<(u32, Arc<KcpSession>)> {
+        self.sessions
+            .iter()
+            .filter(|(_, session)| !session.is_closed())
+            .map(|((conv, _), session)| (*conv, session.clone()))
+            .collect()
+    }
 
-            if self.sessions.get(&self.next_free_conv).is_none() {
-                let conv = self.next_free_conv;
-                return conv;
+    /// All sessions, in no particular order
+    pub fn sessions(&self) -> Vec<Arc<KcpSession>> {
+        self.sessions.values().cloned().collect()
+    }
+
+    /// Resets sessions whose streams have been dropped, and removes them without waiting for their tasks.
+    ///
+    /// Called when the listener is dropped, sessions of streams still in use keep running, and sessions lingering
+    /// with `KcpConfig::linger` are closed by their deadlines.
+    pub fn cancel_closed(&mut self) {
+        let closed = self
+            .sessions
+            .iter()
+            .filter(|(_, session)| session.is_closed() && !session.is_lingering())
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        for key in closed {
+            if let Some(session) = self.sessions.get(&key) {
+                // Notifies peer, and stops sessions driven by the shared driver
+                session.reset();
+            }
+            if let Some(task) = self.tasks.get(&key) {
+                task.abort();
             }
+            self.close_conv(key.0, key.1);
         }
     }
 
+    /// conv and peer address of all sessions, ordered by conv, then by peer address
+    pub fn peers(&self) -> Vec<SessionKey> {
+        let mut peers = self.sessions.keys().copied().collect::<Vec<_>>();
+        peers.sort_unstable();
+        peers
+    }
+
+    pub fn get(&self, conv: u32, peer_addr: SocketAddr) -> Option<Arc<KcpSession>> {
+        self.sessions.get(&(conv, peer_addr)).cloned()
+    }
+
+    /// There is a session of `conv` from any peer
+    pub fn contains_conv(&self, conv: u32) -> bool {
+        self.conv_peers.contains_key(&conv)
+    }
+
+    /// Sessions from `peer_addr` of any conv
+    pub fn get_by_peer(&self, peer_addr: SocketAddr) -> Vec<Arc<KcpSession>> {
+        self.sessions
+            .iter()
+            .filter(|((_, addr), _)| *addr == peer_addr)
+            .map(|(_, session)| session.clone())
+            .collect()
+    }
+
+    /// Sessions of `conv` from any peer
+    pub fn get_all(&self, conv: u32) -> Vec<Arc<KcpSession>> {
+        self.conv_peers
+            .get(&conv)
+            .into_iter()
+            .flatten()
+            .filter_map(|peer_addr| self.get(conv, *peer_addr))
+            .collect()
+    }
+
+    /// Migrates the session that `token` was issued for to `peer_addr`, returns `false` if there is none
+    pub fn resume(&mut self, conv: u32, token: &ResumptionToken, peer_addr: SocketAddr) -> bool {
+        if !self.token_signer.verify(conv, token) {
+            return false;
+        }
+
+        // A token of a closed session with the same conv is also valid
+        let session = match self
+            .get_all(conv)
+            .into_iter()
+            .find(|session| session.resumption_token() == Some(token))
+        {
+            Some(session) => session,
+            None => return false,
+        };
+
+        let prev_addr = session.peer_addr();
+        if prev_addr != peer_addr {
+            if self.sessions.contains_key(&(conv, peer_addr)) {
+                debug!(
+                    "conv: {} is used by another session of peer: {}, not migrated",
+                    conv, peer_addr
+                );
+                return false;
+            }
+            if let Some(session) = self.sessions.remove(&(conv, prev_addr)) {
+                self.sessions.insert((conv, peer_addr), session);
+            }
+            if let Some(task) = self.tasks.remove(&(conv, prev_addr)) {
+                self.tasks.insert((conv, peer_addr), task);
+            }
+            self.remove_conv_peer(conv, prev_addr);
+            self.conv_peers.entry(conv).or_default().push(peer_addr);
+        }
+        session.resume(peer_addr);
+        true
+    }
+
+    /// Creates a client session of `conv` to `peer_addr`, on `udp` that is shared with other sessions.
+    ///
+    /// Fails with `ConvExhausted` if a session of the same conv and peer is still closing.
+    pub fn create_client(
+        &mut self,
+        config: &KcpConfig,
+        conv: u32,
+        socket: &Endpoint,
+        peer_addr: SocketAddr,
+        session_close_notifier: &mpsc::Sender<SessionKey>,
+    ) -> KcpResult<Arc<KcpSession>> {
+        let key = (conv, peer_addr);
+        if self.sessions.contains_key(&key) {
+            return Err(KcpError::ConvExhausted);
+        }
+
+        let socket = KcpSocket::with_buffer_pool(
+            config,
+            conv,
+            socket.clone(),
+            peer_addr,
+            config.stream,
+            self.buffer_pool.clone(),
+        )?;
+        let (session, task) = KcpSession::new_multiplexed(socket, config, session_close_notifier.clone());
+        trace!("created client session for conv: {}, peer: {}", conv, peer_addr);
+        self.sessions.insert(key, session.clone());
+        self.tasks.insert(key, task);
+        self.conv_peers.entry(conv).or_default().push(peer_addr);
+        Ok(session)
+    }
+
+    /// Removes a session and stops its task without closing, for a session that no stream was created for
+    pub fn abort(&mut self, conv: u32, peer_addr: SocketAddr) {
+        if let Some(task) = self.tasks.get(&(conv, peer_addr)) {
+            task.abort();
+        }
+        self.close_conv(conv, peer_addr);
+    }
+
+    /// Moves the client session to `peer_addr` that was waiting for a conv to `conv` allocated by server
+    pub fn conv_allocated(&mut self, peer_addr: SocketAddr, conv: u32) -> Option<Arc<KcpSession>> {
+        let session = self.sessions.remove(&(0, peer_addr))?;
+        self.sessions.insert((conv, peer_addr), session.clone());
+        if let Some(task) = self.tasks.remove(&(0, peer_addr)) {
+            self.tasks.insert((conv, peer_addr), task);
+        }
+        self.remove_conv_peer(0, peer_addr);
+        self.conv_peers.entry(conv).or_default().push(peer_addr);
+        Some(session)
+    }
+
     pub fn get_or_create(
         &mut self,
         config: &KcpConfig,
         conv: u32,
-        udp: &Arc<UdpSocket>,
+        socket: &Endpoint,
         peer_addr: SocketAddr,
-        session_close_notifier: &mpsc::Sender<u32>,
+        session_close_notifier: &mpsc::Sender<SessionKey>,
     ) -> KcpResult<(Arc<KcpSession>, bool)> {
-        match self.sessions.entry(conv) {
+        let key = (conv, peer_addr);
+        if !self.sessions.contains_key(&key) {
+            self.check_capacity()?;
+        }
+
+        match self.sessions.entry(key) {
             Entry::Occupied(occ) => Ok((occ.get().clone(), false)),
             Entry::Vacant(vac) => {
-                let socket = KcpSocket::new(config, conv, udp.clone(), peer_addr, config.stream)?;
-                let session =
-                    KcpSession::new_shared(socket, config.session_expire, Some(session_close_notifier.clone()));
+                let socket = KcpSocket::with_buffer_pool(
+                    config,
+                    conv,
+                    socket.clone(),
+                    peer_addr,
+                    config.stream,
+                    self.buffer_pool.clone(),
+                )?;
+                let resumption_token = if config.enable_migration {
+                    Some(self.token_signer.sign(conv))
+                } else {
+                    None
+                };
+                let session = if config.shared_driver {
+                    let driver = self.driver.get_or_insert_with(SessionDriver::spawn);
+                    KcpSession::new_driven(socket, config, session_close_notifier.clone(), resumption_token, driver)
+                } else {
+                    let (session, task) =
+                        KcpSession::new_shared(socket, config, Some(session_close_notifier.clone()), resumption_token);
+                    self.tasks.insert(key, task);
+                    session
+                };
                 trace!("created session for conv: {}, peer: {}", conv, peer_addr);
+                self.closed.remove(&key);
                 vac.insert(session.clone());
+                self.conv_peers.entry(conv).or_default().push(peer_addr);
                 Ok((session, true))
             }
         }
     }
 }
+
+impl Drop for KcpSessionManager {
+    fn drop(&mut self) {
+        for task in self.tasks.values() {
+            task.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::{
+        net::UdpSocket,
+        sync::mpsc,
+        time::{self, Instant},
+    };
+
+    use super::{KcpSession, TimeJumpDetector, SESSION_INPUT_BACKLOG};
+    use crate::{
+        config::{DispatchMode, KcpConfig},
+        pool::BufferPool,
+        skcp::KcpSocket,
+    };
+
+    #[tokio::test]
+    async fn time_jump_detected() {
+        time::pause();
+
+        let mut detector = TimeJumpDetector::new();
+
+        let deadline = Instant::now() + Duration::from_millis(100);
+        time::sleep_until(deadline).await;
+        assert!(detector.tick(deadline).is_none());
+
+        // Timer fires a long time after the deadline, like waking up from suspend
+        let deadline = Instant::now() + Duration::from_millis(100);
+        time::advance(Duration::from_secs(200)).await;
+        let gap = detector.tick(deadline).unwrap();
+        assert!(gap >= Duration::from_secs(199));
+
+        // Only reported once
+        let deadline = Instant::now() + Duration::from_millis(100);
+        time::sleep_until(deadline).await;
+        assert!(detector.tick(deadline).is_none());
+    }
+
+    #[tokio::test]
+    async fn dispatch_stalled_session() {
+        let _ = env_logger::try_init();
+
+        for dispatch_mode in [DispatchMode::Shared, DispatchMode::PerSession] {
+            let config = KcpConfig {
+                dispatch_mode,
+                ..Default::default()
+            };
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let peer_addr = udp.local_addr().unwrap();
+            let socket = KcpSocket::new(&config, 1, Arc::new(udp), peer_addr, config.stream).unwrap();
+            let (close_tx, _close_rx) = mpsc::channel(1);
+            // Stalled, nothing takes packets from its channel
+            let (input_tx, mut input_rx) = mpsc::channel(SESSION_INPUT_BACKLOG);
+            let session = KcpSession::new(socket, &config, false, Some(close_tx), Some(input_tx), None, None);
+            let buffer_pool = BufferPool::new(&config.buffer_pool_config());
+
+            let dispatched = time::timeout(Duration::from_millis(500), async {
+                for _ in 0..SESSION_INPUT_BACKLOG * 2 {
+                    session.input(buffer_pool.get()).await;
+                }
+            })
+            .await;
+
+            // The channel is filled up either way
+            let mut queued = 0;
+            while input_rx.try_recv().is_ok() {
+                queued += 1;
+            }
+            assert_eq!(queued, SESSION_INPUT_BACKLOG);
+            match dispatch_mode {
+                DispatchMode::Shared => assert!(dispatched.is_err(), "dispatched to a stalled session"),
+                DispatchMode::PerSession => assert!(dispatched.is_ok(), "waited for a stalled session"),
+            }
+        }
+    }
+}
@@ -1,274 +1,2247 @@
 use std::{
-    io::{self, ErrorKind, Write},
+    collections::VecDeque,
+    future::Future,
+    io::{self, ErrorKind, IoSlice, Write},
     net::SocketAddr,
-    sync::Arc,
-    task::{Context, Poll, Waker},
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
 
+use bytes::{Buf, BufMut, BytesMut};
 use futures::future;
-use kcp::{Error as KcpError, Kcp, KcpResult};
-use log::{error, trace};
-use tokio::{net::UdpSocket, sync::mpsc};
+use kcp::{Error as KcpProtoError, Kcp};
+use log::{debug, error, trace};
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+#[cfg(feature = "debug-internals")]
+use crate::debug::{KcpDebugState, SegmentTracker};
+#[cfg(feature = "testing")]
+use crate::netem::{Direction, NetEm, NetEmConfig};
+#[cfg(feature = "socks5")]
+use crate::socks5::Socks5Relay;
+use crate::{
+    bandwidth::{BandwidthEstimator, SendState},
+    config::{FlushStrategy, KcpNoDelayConfig, MIN_MTU},
+    congestion::{CongestionController, CongestionMode, CongestionStats},
+    diagnostics::{EffectiveConfig, SegmentDiagnostics, SessionDiagnostics, SessionInfo},
+    error::{KcpError, KcpResult},
+    event::{EventSender, KcpEvent},
+    framing::{Framing, OpenError},
+    pacing::{Pacer, PacingStats},
+    pool::{BufferPool, PooledBuffer},
+    recv_queue::{RecvQueueLen, RecvTracker},
+    transport::{self, Endpoint},
+    utils::{bind_for, connect_to, is_message_size_error, now_millis, set_dont_fragment, SocketOptions, WakerList},
+    KcpConfig,
+};
+
+/// Size of KCP segment header, datagrams shorter than this are not KCP packets
+pub const KCP_HEADER_LEN: usize = 24;
+
+/// Maximum number of segments queued by one send, KCP fails with `UserBufTooBig` from 128
+const MAX_SEND_SEGMENTS: usize = 127;
+
+/// KCP command of pushing data
+pub const KCP_CMD_PUSH: u8 = 81;
+/// KCP command of acknowledging data
+pub const KCP_CMD_ACK: u8 = 82;
+/// KCP command of asking peer for its window size
+const KCP_CMD_WASK: u8 = 83;
+/// KCP command of telling peer the window size
+const KCP_CMD_WINS: u8 = 84;
+/// Command of notifying peer that this side is closed.
+///
+/// Not a standard KCP command, peers that don't know it reject the segment as unsupported command.
+const KCP_CMD_FIN: u8 = 90;
+/// Command of notifying peer that this side won't send anymore, but still receives, like `shutdown(SHUT_WR)`.
+///
+/// Sent after all data has been acknowledged, so peer has received everything before it, and repeated until peer
+/// answers with `KCP_CMD_SHUT_ACK`. Not a standard KCP command, like `KCP_CMD_FIN`.
+const KCP_CMD_SHUT: u8 = 91;
+/// Command of acknowledging `KCP_CMD_SHUT`
+const KCP_CMD_SHUT_ACK: u8 = 92;
+/// Command of an unreliable datagram, whose payload follows the header. Never retransmitted or acknowledged, and
+/// not a standard KCP command, like `KCP_CMD_FIN`.
+const KCP_CMD_DATAGRAM: u8 = 93;
+
+/// Unreliable datagrams received and not read yet, older ones are dropped for newer ones
+const UNRELIABLE_QUEUE_LEN: usize = 256;
+
+/// Check if `buf` is a FIN segment
+pub fn is_fin_segment(buf: &[u8]) -> bool {
+    buf.len() >= KCP_HEADER_LEN && buf[4] == KCP_CMD_FIN
+}
+
+/// Check if `buf` is a segment of closing the session or one direction of it, which never opens a session
+pub fn is_close_segment(buf: &[u8]) -> bool {
+    buf.len() >= KCP_HEADER_LEN && matches!(buf[4], KCP_CMD_FIN | KCP_CMD_SHUT | KCP_CMD_SHUT_ACK)
+}
+
+/// Check if `buf` is an unreliable datagram, which never opens a session
+pub fn is_datagram_segment(buf: &[u8]) -> bool {
+    buf.len() >= KCP_HEADER_LEN && buf[4] == KCP_CMD_DATAGRAM
+}
+
+/// Check if `buf` may be a KCP packet, long enough for a header and starting with a known command.
+///
+/// Datagrams of other protocols sharing the socket, like STUN, fail this check. A STUN message has its
+/// magic cookie `0x2112A442` where KCP has its command, which is never a KCP command.
+pub fn is_kcp_packet(buf: &[u8]) -> bool {
+    buf.len() >= KCP_HEADER_LEN
+        && matches!(
+            buf[4],
+            KCP_CMD_PUSH
+                | KCP_CMD_ACK
+                | KCP_CMD_WASK
+                | KCP_CMD_WINS
+                | KCP_CMD_FIN
+                | KCP_CMD_SHUT
+                | KCP_CMD_SHUT_ACK
+                | KCP_CMD_DATAGRAM
+        )
+}
+
+/// FIN segment for a peer without session, like a client refused by the listener
+pub fn fin_segment(conv: u32) -> [u8; KCP_HEADER_LEN] {
+    let mut segment = [0u8; KCP_HEADER_LEN];
+    {
+        let mut buf = &mut segment[..];
+        buf.put_u32_le(conv);
+        buf.put_u8(KCP_CMD_FIN);
+        // frg, wnd, ts, sn, una and len are all 0
+    }
+    segment
+}
+
+/// Header of a data segment
+struct DataSegment {
+    sn: u32,
+    frg: u8,
+    ts: u32,
+    /// Length of payload
+    len: usize,
+}
+
+/// Data segments packed in a datagram
+fn data_segments(mut buf: &[u8]) -> impl Iterator<Item = DataSegment> + '_ {
+    std::iter::from_fn(move || {
+        while buf.len() >= KCP_HEADER_LEN {
+            let cmd = buf[4];
+            let frg = buf[5];
+            let ts = (&buf[8..]).get_u32_le();
+            let sn = (&buf[12..]).get_u32_le();
+            let len = ((&buf[20..]).get_u32_le() as usize).min(buf.len() - KCP_HEADER_LEN);
+            buf = &buf[KCP_HEADER_LEN + len..];
+            if cmd == KCP_CMD_PUSH {
+                return Some(DataSegment { sn, frg, ts, len });
+            }
+        }
+        None
+    })
+}
+
+/// Datagram of KCP only carries ACK segments
+fn is_ack_only(mut buf: &[u8]) -> bool {
+    let mut acks = false;
+    while buf.len() >= KCP_HEADER_LEN {
+        if buf[4] != KCP_CMD_ACK {
+            return false;
+        }
+        acks = true;
+        let len = ((&buf[20..]).get_u32_le() as usize).min(buf.len() - KCP_HEADER_LEN);
+        buf = &buf[KCP_HEADER_LEN + len..];
+    }
+    acks
+}
+
+/// Timestamps echoed by ACK segments packed in a datagram, which are the send times of the acknowledged segments
+fn ack_timestamps(mut buf: &[u8]) -> impl Iterator<Item = u32> + '_ {
+    std::iter::from_fn(move || {
+        while buf.len() >= KCP_HEADER_LEN {
+            let cmd = buf[4];
+            let ts = (&buf[8..]).get_u32_le();
+            let len = ((&buf[20..]).get_u32_le() as usize).min(buf.len() - KCP_HEADER_LEN);
+            buf = &buf[KCP_HEADER_LEN + len..];
+            if cmd == KCP_CMD_ACK {
+                return Some(ts);
+            }
+        }
+        None
+    })
+}
+
+/// Shadow of the retransmission timeout of KCP, which is private
+struct RtoEstimator {
+    srtt: u32,
+    rttval: u32,
+    rto: u32,
+    min_rto: u32,
+    interval: u32,
+}
+
+impl RtoEstimator {
+    fn new(min_rto: u32, interval: u32) -> RtoEstimator {
+        RtoEstimator {
+            srtt: 0,
+            rttval: 0,
+            // Initial RTO of KCP
+            rto: 200,
+            min_rto,
+            interval,
+        }
+    }
+
+    fn on_rtt(&mut self, rtt: u32) {
+        if self.srtt == 0 {
+            self.srtt = rtt;
+            self.rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.srtt);
+            self.rttval = (3 * self.rttval + delta) / 4;
+            self.srtt = ((7 * self.srtt + rtt) / 8).max(1);
+        }
+        let rto = self.srtt + self.interval.max(4 * self.rttval);
+        self.rto = rto.clamp(self.min_rto, 60_000);
+    }
+}
+
+/// Last transmission time (KCP clock) and number of transmissions of data segments in flight, indexed by `sn`,
+/// with the state of delivery at the last transmission for the bandwidth estimate
+#[derive(Default)]
+struct SentTimes {
+    /// `sn` of the first one
+    base: u32,
+    ts: VecDeque<(u32, u32)>,
+    /// Payload of the segments
+    lens: VecDeque<usize>,
+    /// Sum of `lens`
+    bytes: usize,
+    states: VecDeque<SendState>,
+    bandwidth: BandwidthEstimator,
+}
+
+impl SentTimes {
+    /// Records a transmission, returns the previous one if it's a retransmission
+    fn on_sent(&mut self, sn: u32, ts: u32, len: usize, now: Instant) -> Option<u32> {
+        let offset = sn.wrapping_sub(self.base) as i32;
+        if offset < 0 {
+            return None;
+        }
+        let offset = offset as usize;
+        let state = self.bandwidth.on_sent(self.ts.is_empty(), now);
+        if let Some((prev, xmit)) = self.ts.get_mut(offset) {
+            *xmit += 1;
+            self.states[offset] = state;
+            return Some(std::mem::replace(prev, ts));
+        }
+        if offset > self.ts.len() {
+            // Segments were sent before tracking, starts over
+            self.ts.clear();
+            self.lens.clear();
+            self.states.clear();
+            self.bytes = 0;
+            self.base = sn;
+        }
+        self.ts.push_back((ts, 1));
+        self.lens.push_back(len);
+        self.states.push_back(state);
+        self.bytes += len;
+        None
+    }
+
+    /// Segments before `una` were acknowledged by peer
+    fn on_acked(&mut self, una: u32, now: Instant) {
+        while !self.ts.is_empty() && (self.base.wrapping_sub(una) as i32) < 0 {
+            self.ts.pop_front();
+            let len = self.lens.pop_front().unwrap_or(0);
+            self.bytes -= len;
+            if let Some(state) = self.states.pop_front() {
+                self.bandwidth.on_delivered(state, len, now);
+            }
+            self.base = self.base.wrapping_add(1);
+        }
+    }
+
+    /// Segments in flight, ordered by `sn`
+    fn segments(&self, current: u32) -> Vec<SegmentDiagnostics> {
+        self.ts
+            .iter()
+            .enumerate()
+            .map(|(offset, &(ts, xmit))| SegmentDiagnostics {
+                sn: self.base.wrapping_add(offset as u32),
+                xmit,
+                since_sent_ms: current.wrapping_sub(ts),
+            })
+            .collect()
+    }
+}
+
+/// Interval of updating an idle socket, which has nothing to send or acknowledge
+pub const IDLE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// States shared between `UdpOutput` and `KcpSocket`
+pub struct OutputState {
+    /// Replaced by `KcpSocket::rebind`
+    socket: StdMutex<Endpoint>,
+    /// Socket is connected to peer, client only
+    connected: bool,
+    /// Peer's host reported that nothing listens on its port
+    refused: AtomicBool,
+    /// Sends that failed in a row, except `EMSGSIZE`
+    send_errors: AtomicU32,
+    /// Times the socket was replaced by `KcpSocket::rebind`
+    rebinds: AtomicU64,
+    /// Sends fail as if the network was unreachable, until rebound
+    #[cfg(test)]
+    fail_sends: AtomicBool,
+    /// Datagrams larger than it are dropped silently, like by a path with a smaller MTU, 0 for no limit
+    #[cfg(test)]
+    path_mtu: AtomicUsize,
+    /// MTU is probed by the session, `EMSGSIZE` is reported to the prober instead of breaking the socket
+    pmtu_probing: AtomicBool,
+    /// Output path reported `EMSGSIZE` while probing MTU
+    pmtu_exceeded: AtomicBool,
+    /// Output path reported `EMSGSIZE`
+    mtu_exceeded: AtomicBool,
+    /// `una` of the last sent segment
+    last_una: AtomicU32,
+    /// Address of peer, changes if session migrated
+    target_addr: StdMutex<SocketAddr>,
+    /// Datagrams waiting in the delayed sender
+    queued: AtomicUsize,
+    pacer: Option<StdMutex<Pacer>>,
+    /// Buffers of datagrams waiting in the delayed sender
+    buffer_pool: Arc<BufferPool>,
+    /// `sn` following the last data segment sent for the first time, lower ones are retransmissions
+    next_sn: AtomicU32,
+    /// Data segments retransmitted
+    retransmissions: AtomicU64,
+    /// Data segments retransmitted before their RTO, by fast resend
+    fast_retransmissions: AtomicU64,
+    /// Datagrams that only carry ACKs
+    ack_only_datagrams: AtomicU64,
+    /// KCP clock of the last datagram produced by KCP
+    last_output: AtomicU32,
+    /// Seals sent datagrams and opens received ones, see `KcpConfig::checksum` and `KcpConfig::obfuscator`
+    framing: Framing,
+    /// Buffer of sealing and opening datagrams
+    framing_buffer: StdMutex<Vec<u8>>,
+    /// Received datagrams dropped because their CRC32 didn't match
+    corrupted_packets: AtomicU64,
+    sent_times: StdMutex<SentTimes>,
+    /// Retransmission timeout in milliseconds, estimated by `KcpSocket`
+    rto: AtomicU32,
+    events: EventSender,
+    /// Datagrams are kept in `outbox` until `UdpOutput::transmit`, instead of being sent by KCP flush
+    deferred: AtomicBool,
+    outbox: StdMutex<Vec<PooledBuffer>>,
+    /// Held while sending datagrams taken from `outbox`, keeps them in order
+    transmitting: StdMutex<()>,
+    #[cfg(feature = "testing")]
+    netem: Option<NetEmConfig>,
+    #[cfg(feature = "debug-internals")]
+    tracker: StdMutex<SegmentTracker>,
+    /// Decides the send window with `CongestionMode::Custom`
+    controller: Option<StdMutex<Box<dyn CongestionController>>>,
+    /// Datagrams go through the UDP relay of a SOCKS5 proxy, which the socket is connected to
+    #[cfg(feature = "socks5")]
+    relay: Option<Socks5Relay>,
+}
+
+impl OutputState {
+    fn new(socket: Endpoint, target_addr: SocketAddr, c: &KcpConfig, buffer_pool: Arc<BufferPool>) -> OutputState {
+        OutputState {
+            connected: socket.udp().is_some_and(|udp| udp.peer_addr().is_ok()),
+            refused: AtomicBool::new(false),
+            socket: StdMutex::new(socket),
+            send_errors: AtomicU32::new(0),
+            rebinds: AtomicU64::new(0),
+            #[cfg(test)]
+            fail_sends: AtomicBool::new(false),
+            #[cfg(test)]
+            path_mtu: AtomicUsize::new(0),
+            pmtu_probing: AtomicBool::new(false),
+            pmtu_exceeded: AtomicBool::new(false),
+            mtu_exceeded: AtomicBool::new(false),
+            last_una: AtomicU32::new(0),
+            target_addr: StdMutex::new(target_addr),
+            queued: AtomicUsize::new(0),
+            pacer: c.pacing.as_ref().map(|c| StdMutex::new(Pacer::new(c))),
+            buffer_pool,
+            next_sn: AtomicU32::new(0),
+            retransmissions: AtomicU64::new(0),
+            fast_retransmissions: AtomicU64::new(0),
+            ack_only_datagrams: AtomicU64::new(0),
+            last_output: AtomicU32::new(now_millis()),
+            framing: Framing::new(c),
+            framing_buffer: StdMutex::new(Vec::new()),
+            corrupted_packets: AtomicU64::new(0),
+            sent_times: StdMutex::new(SentTimes::default()),
+            rto: AtomicU32::new(0),
+            events: EventSender::new(),
+            deferred: AtomicBool::new(false),
+            outbox: StdMutex::new(Vec::new()),
+            transmitting: StdMutex::new(()),
+            #[cfg(feature = "testing")]
+            netem: c.test_netem,
+            #[cfg(feature = "debug-internals")]
+            tracker: StdMutex::new(SegmentTracker::default()),
+            controller: match c.congestion {
+                CongestionMode::Custom(ref factory) => Some(StdMutex::new(factory.create())),
+                CongestionMode::Default | CongestionMode::Off => None,
+            },
+            #[cfg(feature = "socks5")]
+            relay: None,
+        }
+    }
+
+    /// Current address of peer
+    pub fn peer_addr(&self) -> SocketAddr {
+        *self.target_addr.lock().unwrap()
+    }
+
+    /// Sends all subsequent packets to `peer_addr`
+    pub fn set_peer_addr(&self, peer_addr: SocketAddr) {
+        *self.target_addr.lock().unwrap() = peer_addr;
+    }
+
+    pub fn events(&self) -> &EventSender {
+        &self.events
+    }
+
+    /// Current socket, changes if rebound
+    pub fn socket(&self) -> Endpoint {
+        self.socket.lock().unwrap().clone()
+    }
+
+    /// Current UDP socket, `None` on a custom transport
+    pub fn udp_socket(&self) -> Option<Arc<UdpSocket>> {
+        self.socket.lock().unwrap().udp().cloned()
+    }
+
+    /// Largest datagram that fits buffers of the pool
+    pub fn buffer_size(&self) -> usize {
+        self.buffer_pool.buffer_size()
+    }
+
+    /// Payload of a datagram received by the socket, `None` if it should be dropped
+    pub fn unwrap_received<'a>(&self, buf: &'a [u8]) -> Option<&'a [u8]> {
+        #[cfg(feature = "socks5")]
+        if let Some(ref relay) = self.relay {
+            return relay.unwrap(buf);
+        }
+        Some(buf)
+    }
+
+    pub fn framing(&self) -> &Framing {
+        &self.framing
+    }
+
+    /// Opens a datagram from peer in place, returns `false` if it should be dropped. Datagrams whose CRC32
+    /// doesn't match are counted as corrupted.
+    pub fn open_received(&self, packet: &mut BytesMut) -> bool {
+        let mut scratch = self.framing_buffer.lock().unwrap();
+        match self.framing.open(packet, &mut scratch) {
+            Ok(()) => true,
+            Err(OpenError::Rejected) => false,
+            Err(OpenError::Corrupted) => {
+                self.corrupted_packets.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Number of datagrams from peer dropped because their CRC32 didn't match
+    pub fn corrupted_packets(&self) -> u64 {
+        self.corrupted_packets.load(Ordering::Relaxed)
+    }
+
+    /// Copy of `buf` sealed by `KcpConfig::checksum` and `KcpConfig::obfuscator`, `None` if neither is enabled
+    fn seal(&self, buf: &[u8]) -> Option<PooledBuffer> {
+        if self.framing.is_plain() {
+            return None;
+        }
+        let mut scratch = self.framing_buffer.lock().unwrap();
+        scratch.clear();
+        scratch.extend_from_slice(buf);
+        self.framing.seal_vec(&mut scratch);
+
+        let mut packet = self.buffer_pool.get();
+        packet.extend_from_slice(&scratch);
+        Some(packet)
+    }
+
+    /// Address that the socket sends to, the relay instead of peer with a SOCKS5 proxy
+    fn next_hop(&self) -> SocketAddr {
+        #[cfg(feature = "socks5")]
+        if let Some(ref relay) = self.relay {
+            return relay.relay_addr();
+        }
+        self.peer_addr()
+    }
+
+    fn send_to(&self, socket: &Endpoint, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(test)]
+        if self.fail_sends.load(Ordering::Acquire) {
+            return Err(io::Error::other("network is unreachable"));
+        }
+        if let Some(packet) = self.seal(buf) {
+            return self.send_to_unsealed(socket, &packet).map(|_| buf.len());
+        }
+        self.send_to_unsealed(socket, buf)
+    }
+
+    fn send_to_unsealed(&self, socket: &Endpoint, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(test)]
+        {
+            let path_mtu = self.path_mtu.load(Ordering::Acquire);
+            if path_mtu != 0 && buf.len() > path_mtu {
+                return Ok(buf.len());
+            }
+        }
+        #[cfg(feature = "socks5")]
+        if let Some(ref relay) = self.relay {
+            let mut packet = self.buffer_pool.get();
+            packet.extend_from_slice(relay.header());
+            packet.extend_from_slice(buf);
+            return self
+                .try_send_datagram(socket, &packet)
+                .map(|n| n.saturating_sub(relay.header().len()));
+        }
+        self.try_send_datagram(socket, buf)
+    }
+
+    /// Sends a datagram to the next hop without waiting
+    fn try_send_datagram(&self, socket: &Endpoint, buf: &[u8]) -> io::Result<usize> {
+        match *socket {
+            Endpoint::Udp(ref udp) if self.connected => udp.try_send(buf),
+            _ => socket.transport().try_send_to(buf, self.next_hop()),
+        }
+    }
+
+    async fn send_async(&self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(packet) = self.seal(buf) {
+            return self.send_async_unsealed(&packet).await.map(|_| buf.len());
+        }
+        self.send_async_unsealed(buf).await
+    }
+
+    async fn send_async_unsealed(&self, buf: &[u8]) -> io::Result<usize> {
+        let socket = self.socket();
+        #[cfg(feature = "socks5")]
+        if let Some(ref relay) = self.relay {
+            let mut packet = self.buffer_pool.get();
+            packet.extend_from_slice(relay.header());
+            packet.extend_from_slice(buf);
+            return self
+                .send_datagram(&socket, &packet)
+                .await
+                .map(|n| n.saturating_sub(relay.header().len()));
+        }
+        self.send_datagram(&socket, buf).await
+    }
+
+    /// Sends a datagram to the next hop
+    async fn send_datagram(&self, socket: &Endpoint, buf: &[u8]) -> io::Result<usize> {
+        match *socket {
+            Endpoint::Udp(ref udp) if self.connected => udp.send(buf).await,
+            _ => transport::send_to(socket.transport(), buf, self.next_hop()).await,
+        }
+    }
+
+    /// Sends a probe of path MTU immediately, bypassing the outbox and the pacer, its failures are not counted
+    fn send_probe(&self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.send_to(&self.socket(), buf);
+        if matches!(result, Err(ref err) if err.kind() == ErrorKind::ConnectionRefused) {
+            self.set_refused();
+        }
+        result
+    }
+
+    /// Nothing listens on the address of peer, sessions can't recover from it
+    pub fn refused(&self) -> bool {
+        self.refused.load(Ordering::Acquire)
+    }
+
+    pub fn set_refused(&self) {
+        self.refused.store(true, Ordering::Release);
+    }
+
+    /// Counts a failed send, a socket with too many of them in a row may have to be rebound
+    fn on_send_result<T>(&self, result: &io::Result<T>) {
+        match result {
+            Ok(..) => {
+                if self.send_errors.load(Ordering::Relaxed) != 0 {
+                    self.send_errors.store(0, Ordering::Relaxed);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) if is_message_size_error(err) => {
+                if self.pmtu_probing.load(Ordering::Acquire) {
+                    self.pmtu_exceeded.store(true, Ordering::Release);
+                } else {
+                    self.mtu_exceeded.store(true, Ordering::Release);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::ConnectionRefused => self.set_refused(),
+            Err(..) => {
+                self.send_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Number of times the UDP socket was replaced by `KcpConfig::auto_rebind`
+    pub fn rebinds(&self) -> u64 {
+        self.rebinds.load(Ordering::Relaxed)
+    }
+
+    /// Number of data segments sent for the first time
+    #[cfg(test)]
+    pub fn sent_segments(&self) -> u32 {
+        self.next_sn.load(Ordering::Relaxed)
+    }
+
+    /// Feeds RTT samples and bytes in flight of a datagram from peer to an adaptive pacer and the congestion controller
+    fn on_input(&self, buf: &[u8], mss: usize) {
+        if self.pacer.is_none() && self.controller.is_none() {
+            return;
+        }
+
+        // Segments from peer carry its `rcv_nxt`, all lower `sn` are acknowledged
+        let una = (&buf[16..]).get_u32_le();
+        let next_sn = self.next_sn.load(Ordering::Relaxed);
+        let inflight = (next_sn.wrapping_sub(una) as i32).max(0) as usize * mss;
+
+        let current = now_millis();
+        let now = Instant::now();
+        let mut pacer = self.pacer.as_ref().map(|pacer| pacer.lock().unwrap());
+        let mut controller = self.controller.as_ref().map(|controller| controller.lock().unwrap());
+        for ts in ack_timestamps(buf) {
+            let rtt = current.wrapping_sub(ts) as i32;
+            if rtt >= 0 {
+                let rtt = Duration::from_millis(rtt as u64);
+                if let Some(ref mut pacer) = pacer {
+                    pacer.on_ack(rtt, inflight, now);
+                }
+                if let Some(ref mut controller) = controller {
+                    controller.on_ack(rtt);
+                }
+            }
+        }
+
+        // Rate of the controller overrides the estimate of an adaptive pacer
+        if let (Some(pacer), Some(rate)) = (pacer.as_mut(), controller.and_then(|c| c.pacing_rate())) {
+            pacer.set_rate(rate, now);
+        }
+    }
+
+    /// Send window decided by the congestion controller
+    fn congestion_window(&self) -> Option<u16> {
+        self.controller
+            .as_ref()
+            .map(|controller| controller.lock().unwrap().cwnd())
+    }
+
+    /// Sending rate decided by the congestion controller
+    fn congestion_pacing_rate(&self) -> Option<u64> {
+        self.controller
+            .as_ref()
+            .and_then(|controller| controller.lock().unwrap().pacing_rate())
+    }
+
+    /// Number of data segments retransmitted
+    pub fn retransmissions(&self) -> u64 {
+        self.retransmissions.load(Ordering::Relaxed)
+    }
+
+    /// Number of data segments retransmitted by fast resend
+    pub fn fast_retransmissions(&self) -> u64 {
+        self.fast_retransmissions.load(Ordering::Relaxed)
+    }
+
+    /// Number of datagrams sent with only ACKs
+    pub fn ack_only_datagrams(&self) -> u64 {
+        self.ack_only_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Limit and usage of the pacer, `None` if pacing is disabled
+    pub fn pacing_stats(&self) -> Option<PacingStats> {
+        self.pacer
+            .as_ref()
+            .map(|pacer| pacer.lock().unwrap().stats(Instant::now()))
+    }
+}
+
+/// Writer for sending packets to the underlying UdpSocket
+#[derive(Clone)]
+pub struct UdpOutput {
+    /// Datagrams to be sent by the delayed sender, and whether they have been admitted by the pacer
+    delay_tx: mpsc::UnboundedSender<(PooledBuffer, bool)>,
+    /// Datagrams to be sent through the emulated network
+    #[cfg(feature = "testing")]
+    netem_tx: Option<mpsc::UnboundedSender<PooledBuffer>>,
+    state: Arc<OutputState>,
+}
+
+impl UdpOutput {
+    /// Create a new Writer for writing packets to the UdpSocket of `state`
+    pub fn new(state: Arc<OutputState>) -> UdpOutput {
+        let (delay_tx, mut delay_rx) = mpsc::unbounded_channel::<(PooledBuffer, bool)>();
+
+        #[cfg(feature = "testing")]
+        let netem_tx = state.netem.as_ref().map(|config| {
+            let (netem_tx, netem_rx) = mpsc::unbounded_channel();
+            tokio::spawn(run_netem(state.clone(), NetEm::new(config, Direction::Send), netem_rx));
+            netem_tx
+        });
+
+        {
+            let state = state.clone();
+            #[cfg(feature = "testing")]
+            let netem_tx = netem_tx.clone();
+            tokio::spawn(async move {
+                while let Some((buf, paced)) = delay_rx.recv().await {
+                    if let (Some(pacer), false) = (&state.pacer, paced) {
+                        loop {
+                            let now = Instant::now();
+                            let send_time = {
+                                let mut pacer = pacer.lock().unwrap();
+                                if pacer.try_send(buf.len(), now) {
+                                    break;
+                                }
+                                pacer.next_send_time(buf.len(), now)
+                            };
+                            time::sleep_until(send_time).await;
+                        }
+                    }
+
+                    #[cfg(feature = "testing")]
+                    if let Some(ref netem_tx) = netem_tx {
+                        let _ = netem_tx.send(buf);
+                        state.queued.fetch_sub(1, Ordering::AcqRel);
+                        continue;
+                    }
+
+                    let result = state.send_async(&buf).await;
+                    state.on_send_result(&result);
+                    if let Err(err) = result {
+                        error!("[SEND] UDP delayed send failed, error: {}", err);
+                    }
+                    state.queued.fetch_sub(1, Ordering::AcqRel);
+                }
+            });
+        }
+
+        UdpOutput {
+            delay_tx,
+            #[cfg(feature = "testing")]
+            netem_tx,
+            state,
+        }
+    }
+}
+
+/// Sends datagrams when the emulated network delivers them
+#[cfg(feature = "testing")]
+async fn run_netem(state: Arc<OutputState>, mut netem: NetEm, mut netem_rx: mpsc::UnboundedReceiver<PooledBuffer>) {
+    loop {
+        let next_deadline = netem.next_deadline();
+        tokio::select! {
+            buf = netem_rx.recv() => match buf {
+                Some(buf) => netem.push(buf.to_vec(), Instant::now()),
+                None => break,
+            },
+            _ = time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {}
+        }
+
+        while let Some(buf) = netem.pop_due(Instant::now()) {
+            if let Err(err) = state.send_async(&buf).await {
+                error!("[SEND] UDP emulated send failed, error: {}", err);
+            }
+        }
+    }
+}
+
+impl UdpOutput {
+    /// Sends `buf` as one datagram, it is queued if the socket is not ready, or it has to wait for the pacer
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(ref pacer) = self.state.pacer {
+            // Keeps the order of datagrams that are already waiting
+            if self.state.queued.load(Ordering::Acquire) > 0
+                || !pacer.lock().unwrap().try_send(buf.len(), Instant::now())
+            {
+                trace!("[SEND] paced, packet.size: {} bytes, delayed send", buf.len());
+                self.send_delayed(buf, false);
+                return Ok(buf.len());
+            }
+        }
+
+        #[cfg(feature = "testing")]
+        if let Some(ref netem_tx) = self.netem_tx {
+            // Keeps the order of datagrams waiting in the delayed sender
+            if self.state.queued.load(Ordering::Acquire) > 0 {
+                self.send_delayed(buf, true);
+            } else {
+                let mut packet = self.state.buffer_pool.get();
+                packet.extend_from_slice(buf);
+                let _ = netem_tx.send(packet);
+            }
+            return Ok(buf.len());
+        }
+
+        let result = self.state.send_to(&self.state.socket(), buf);
+        self.state.on_send_result(&result);
+        match result {
+            Ok(n) => Ok(n),
+            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                // send return EAGAIN
+                // ignored as packet was lost in transmission
+                trace!("[SEND] UDP send EAGAIN, packet.size: {} bytes, delayed send", buf.len());
+
+                self.send_delayed(buf, true);
+
+                Ok(buf.len())
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl UdpOutput {
+    /// Sends `buf`, or keeps it in the outbox if output is deferred
+    fn output(&self, buf: &[u8]) -> io::Result<usize> {
+        if !self.state.deferred.load(Ordering::Acquire) {
+            return self.send(buf);
+        }
+
+        let mut packet = self.state.buffer_pool.get();
+        packet.extend_from_slice(buf);
+        self.state.outbox.lock().unwrap().push(packet);
+        Ok(buf.len())
+    }
+
+    /// Sends all datagrams in the outbox, in the order they were produced
+    pub fn transmit(&self) {
+        let _transmitting = self.state.transmitting.lock().unwrap();
+        let packets = {
+            let mut outbox = self.state.outbox.lock().unwrap();
+            if outbox.is_empty() {
+                return;
+            }
+            std::mem::take(&mut *outbox)
+        };
+
+        for packet in packets {
+            if let Err(err) = self.send(&packet) {
+                error!(
+                    "[SEND] UDP send failed, packet.size: {} bytes, error: {}",
+                    packet.len(),
+                    err
+                );
+            }
+        }
+    }
+
+    fn send_delayed(&self, buf: &[u8], paced: bool) {
+        let mut packet = self.state.buffer_pool.get();
+        packet.extend_from_slice(buf);
+
+        self.state.queued.fetch_add(1, Ordering::AcqRel);
+        self.delay_tx.send((packet, paced)).expect("channel closed unexpectly");
+    }
+}
+
+impl Write for UdpOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() >= KCP_HEADER_LEN {
+            let una = (&buf[16..]).get_u32_le();
+            self.state.last_una.store(una, Ordering::Relaxed);
+        }
+
+        // Only written by KCP flush, which is serialized by the socket
+        let mut next_sn = self.state.next_sn.load(Ordering::Relaxed);
+        let mut retransmitted = 0;
+        let mut fast_retransmitted = 0;
+        let rto = self.state.rto.load(Ordering::Relaxed);
+        let now = Instant::now();
+        let mut sent_times = self.state.sent_times.lock().unwrap();
+        for DataSegment { sn, ts, len, .. } in data_segments(buf) {
+            if (sn.wrapping_sub(next_sn) as i32) < 0 {
+                retransmitted += 1;
+            } else {
+                next_sn = sn.wrapping_add(1);
+            }
+            // KCP retransmits on timeout first, earlier ones are fast resends
+            if let Some(prev) = sent_times.on_sent(sn, ts, len, now) {
+                if ts.wrapping_sub(prev) < rto {
+                    fast_retransmitted += 1;
+                }
+            }
+        }
+        drop(sent_times);
+        self.state.next_sn.store(next_sn, Ordering::Relaxed);
+        if fast_retransmitted > 0 {
+            self.state
+                .fast_retransmissions
+                .fetch_add(fast_retransmitted, Ordering::Relaxed);
+        }
+        if is_ack_only(buf) {
+            self.state.ack_only_datagrams.fetch_add(1, Ordering::Relaxed);
+        }
+        self.state.last_output.store(now_millis(), Ordering::Relaxed);
+        #[cfg(feature = "debug-internals")]
+        self.state.tracker.lock().unwrap().on_output(buf);
+        if retransmitted > 0 {
+            self.state
+                .retransmissions
+                .fetch_add(retransmitted as u64, Ordering::Relaxed);
+            if let Some(ref controller) = self.state.controller {
+                controller.lock().unwrap().on_loss(retransmitted);
+            }
+            self.state.events.emit(KcpEvent::Retransmit {
+                segments: retransmitted,
+            });
+        }
+
+        self.output(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct KcpSocket {
+    kcp: Kcp<UdpOutput>,
+    last_update: Instant,
+    /// Last time a segment was input, ACKs may be pending until the next flush
+    last_input: Instant,
+    /// Flush interval of KCP
+    interval: Duration,
+    /// For sending packets that are not generated by KCP
+    output: UdpOutput,
+    /// `KcpConfig::flush_strategy`, changed by `set_flush_acks_input`
+    flush_strategy: FlushStrategy,
+    sent_first: bool,
+    pending_sender: WakerList,
+    pending_receiver: WakerList,
+    /// Waiting for all sent data to be acknowledged
+    pending_drain: WakerList,
+    /// Unreliable datagrams received, see `send_unreliable`
+    unreliable: VecDeque<Vec<u8>>,
+    pending_unreliable: WakerList,
+    closed: bool,
+    /// Peer has sent FIN at this time
+    peer_closed: Option<Instant>,
+    /// Shut down for writing, `KCP_CMD_SHUT` is sent once sent data has been acknowledged
+    write_shutdown: bool,
+    /// Peer has acknowledged `KCP_CMD_SHUT`
+    shutdown_acked: bool,
+    /// Peer has shut down for writing, receiving ends after the data that has arrived
+    peer_shutdown: bool,
+    /// Session was reset by listener
+    reset: bool,
+    /// Session was closed by listener for inactivity
+    expired: bool,
+    /// Session was closed because no application data was sent or received for `KcpConfig::idle_timeout`
+    idle_timed_out: bool,
+    /// Application data was sent or received at this time
+    last_activity: Instant,
+    created: Instant,
+    /// Application data sent and received, see `SessionInfo`
+    bytes_sent: u64,
+    bytes_received: u64,
+    /// Has processed a packet from peer
+    connected: bool,
+    /// conv was chosen by client, datagrams of other convs are protocol errors instead of being ignored
+    strict_conv: bool,
+    /// Sender is blocked by the send window
+    window_full: bool,
+    /// Buffers of a vectored send are gathered here in message mode
+    send_buffer: Vec<u8>,
+    /// Bytes sent since the last flush
+    unflushed: usize,
+    output_state: Arc<OutputState>,
+    /// `KcpConfig::wnd_size` caps the window of the congestion controller
+    snd_wnd_limit: u16,
+    /// Receive window of `KcpConfig::wnd_size`, restored when an overload is cleared
+    rcv_wnd_limit: u16,
+    /// `KcpConfig::adaptive_window`
+    adaptive_window: bool,
+    /// KCP's congestion window is enabled
+    kcp_window: bool,
+    /// `CongestionMode::Default`, `nodelay.nc` decides `kcp_window`
+    default_congestion: bool,
+    /// Send window set by `set_cwnd`, overrides the congestion controller
+    #[cfg(feature = "experimental-cc")]
+    cwnd_override: Option<u16>,
+    /// Current `KcpConfig::nodelay`, changed by `set_nodelay`
+    nodelay: KcpNoDelayConfig,
+    /// Overrides of `nodelay`, kept when it is changed by `set_nodelay`
+    rx_minrto: Option<u32>,
+    fast_resend: Option<u32>,
+    rto: RtoEstimator,
+    /// Options of sockets created by `rebind`
+    socket_options: SocketOptions,
+    recv_tracker: RecvTracker,
+    /// Emulated network of received datagrams
+    #[cfg(feature = "testing")]
+    netem: Option<NetEm>,
+}
+
+impl KcpSocket {
+    pub fn new(
+        c: &KcpConfig,
+        conv: u32,
+        socket: Arc<UdpSocket>,
+        target_addr: SocketAddr,
+        stream: bool,
+    ) -> KcpResult<KcpSocket> {
+        let buffer_pool = BufferPool::new(&c.buffer_pool_config());
+        KcpSocket::with_buffer_pool(c, conv, Endpoint::Udp(socket), target_addr, stream, buffer_pool)
+    }
+
+    /// Creates a socket that takes buffers from `buffer_pool`, which may be shared with other sockets
+    pub fn with_buffer_pool(
+        c: &KcpConfig,
+        conv: u32,
+        socket: Endpoint,
+        target_addr: SocketAddr,
+        stream: bool,
+        buffer_pool: Arc<BufferPool>,
+    ) -> KcpResult<KcpSocket> {
+        let output_state = OutputState::new(socket, target_addr, c, buffer_pool);
+        KcpSocket::with_output_state(c, conv, stream, output_state)
+    }
+
+    /// Creates a client socket whose datagrams go through the UDP relay of a SOCKS5 proxy, `socket` is connected to
+    /// the relay
+    #[cfg(feature = "socks5")]
+    pub fn with_socks5_relay(
+        c: &KcpConfig,
+        socket: Arc<UdpSocket>,
+        target_addr: SocketAddr,
+        stream: bool,
+        relay: Socks5Relay,
+    ) -> KcpResult<KcpSocket> {
+        let mut output_state = OutputState::new(
+            Endpoint::Udp(socket),
+            target_addr,
+            c,
+            BufferPool::new(&c.buffer_pool_config()),
+        );
+        output_state.relay = Some(relay);
+        KcpSocket::with_output_state(c, 0, stream, output_state)
+    }
+
+    fn with_output_state(c: &KcpConfig, conv: u32, stream: bool, output_state: OutputState) -> KcpResult<KcpSocket> {
+        let output_state = Arc::new(output_state);
+        let output = UdpOutput::new(output_state.clone());
+        let raw_output = output.clone();
+        let mut kcp = if stream {
+            Kcp::new_stream(conv, output)
+        } else {
+            Kcp::new(conv, output)
+        };
+        c.apply_config(&mut kcp);
+        let rto = RtoEstimator::new(c.min_rto(), c.nodelay.clamped_interval());
+        output_state.rto.store(rto.rto, Ordering::Relaxed);
+
+        // Initial window of the controller
+        if let Some(cwnd) = output_state.congestion_window() {
+            kcp.set_wndsize(cwnd.clamp(1, c.wnd_size.0), 0);
+        }
+
+        // Ask server to allocate one
+        if conv == 0 {
+            kcp.input_conv();
+        }
+
+        kcp.update(now_millis())?;
+
+        Ok(KcpSocket {
+            kcp,
+            last_update: Instant::now(),
+            last_input: Instant::now(),
+            interval: Duration::from_millis(c.nodelay.clamped_interval() as u64),
+            output: raw_output,
+            flush_strategy: c.effective_flush_strategy(),
+            sent_first: false,
+            pending_sender: WakerList::default(),
+            pending_receiver: WakerList::default(),
+            pending_drain: WakerList::default(),
+            unreliable: VecDeque::new(),
+            pending_unreliable: WakerList::default(),
+            closed: false,
+            peer_closed: None,
+            write_shutdown: false,
+            shutdown_acked: false,
+            peer_shutdown: false,
+            reset: false,
+            expired: false,
+            idle_timed_out: false,
+            last_activity: Instant::now(),
+            created: Instant::now(),
+            bytes_sent: 0,
+            bytes_received: 0,
+            connected: false,
+            strict_conv: false,
+            window_full: false,
+            send_buffer: Vec::new(),
+            unflushed: 0,
+            output_state,
+            snd_wnd_limit: c.wnd_size.0,
+            rcv_wnd_limit: c.wnd_size.1,
+            adaptive_window: c.adaptive_window,
+            kcp_window: c.kcp_window(),
+            default_congestion: matches!(c.congestion, CongestionMode::Default),
+            #[cfg(feature = "experimental-cc")]
+            cwnd_override: None,
+            nodelay: c.nodelay,
+            rx_minrto: c.rx_minrto,
+            fast_resend: c.fast_resend,
+            rto,
+            socket_options: c.socket_options(),
+            recv_tracker: RecvTracker::default(),
+            #[cfg(feature = "testing")]
+            netem: c.test_netem.as_ref().map(|config| NetEm::new(config, Direction::Recv)),
+        })
+    }
+
+    /// Call every time you got data from transmission
+    pub fn input(&mut self, buf: &[u8]) -> KcpResult<bool> {
+        #[cfg(feature = "testing")]
+        if let Some(ref mut netem) = self.netem {
+            // Datagrams that are not due yet are input by `update`
+            netem.push(buf.to_vec(), Instant::now());
+            return self.input_emulated();
+        }
+
+        self.input_datagram(buf)
+    }
+
+    /// Inputs datagrams delivered by the emulated network
+    #[cfg(feature = "testing")]
+    fn input_emulated(&mut self) -> KcpResult<bool> {
+        let mut waked = false;
+        while let Some(buf) = self.netem.as_mut().and_then(|netem| netem.pop_due(Instant::now())) {
+            match self.input_datagram(&buf) {
+                Ok(w) => waked |= w,
+                Err(err) => trace!("[INPUT] emulated datagram error: {}", err),
+            }
+        }
+        Ok(waked)
+    }
+
+    fn input_datagram(&mut self, buf: &[u8]) -> KcpResult<bool> {
+        if is_datagram_segment(buf) {
+            return Ok(self.input_unreliable(buf));
+        }
+
+        if is_close_segment(buf) {
+            let conv = kcp::get_conv(buf);
+            // Client may be closed before it learns the allocated conv
+            if conv != self.kcp.conv() && !self.kcp.waiting_conv() {
+                if self.strict_conv {
+                    return Err(KcpError::Kcp(KcpProtoError::ConvInconsistent(self.kcp.conv(), conv)));
+                }
+                trace!(
+                    "[INPUT] close segment conv expected={} actual={} ignored",
+                    self.kcp.conv(),
+                    conv
+                );
+                return Ok(false);
+            }
+
+            match buf[4] {
+                KCP_CMD_SHUT => {
+                    // Answered every time, the previous answer may be lost
+                    let segment = self.control_segment(KCP_CMD_SHUT_ACK);
+                    if let Err(err) = self.output.write(&segment) {
+                        trace!("[SHUT] conv {} ACK send failed, error: {}", conv, err);
+                    }
+                    if !self.peer_shutdown {
+                        trace!("[INPUT] conv {} shut down for writing by peer", conv);
+                        self.peer_shutdown = true;
+                        self.pending_receiver.wake_all();
+                    }
+                }
+                KCP_CMD_SHUT_ACK => {
+                    self.shutdown_acked = true;
+                    return Ok(false);
+                }
+                _ => {
+                    if self.peer_closed.is_none() {
+                        trace!("[INPUT] conv {} closed by peer", conv);
+                        self.peer_closed = Some(Instant::now());
+                        self.wake_all();
+                    }
+                }
+            }
+            return Ok(true);
+        }
+
+        match self.kcp.input(buf) {
+            Ok(..) => {}
+            Err(err @ KcpProtoError::ConvInconsistent(..)) if self.strict_conv => return Err(err.into()),
+            Err(KcpProtoError::ConvInconsistent(expected, actual)) => {
+                trace!("[INPUT] Conv expected={} actual={} ignored", expected, actual);
+                return Ok(false);
+            }
+            Err(err) => return Err(err.into()),
+        }
+        self.last_update = Instant::now();
+        self.last_input = self.last_update;
+        self.output_state.on_input(buf, self.kcp.mss() as usize);
+        self.apply_congestion_window();
+        let current = now_millis();
+        for ts in ack_timestamps(buf) {
+            let rtt = current.wrapping_sub(ts) as i32;
+            if rtt >= 0 {
+                self.rto.on_rtt(rtt as u32);
+            }
+        }
+        self.output_state.rto.store(self.rto.rto, Ordering::Relaxed);
+        let una = (&buf[16..]).get_u32_le();
+        self.output_state
+            .sent_times
+            .lock()
+            .unwrap()
+            .on_acked(una, Instant::now());
+        #[cfg(feature = "debug-internals")]
+        self.output_state.tracker.lock().unwrap().on_input(buf);
+
+        let events = self.output_state.events();
+        if !self.connected {
+            self.connected = true;
+            events.emit(KcpEvent::Connected { conv: self.kcp.conv() });
+        }
+        let mut bytes = 0;
+        for segment in data_segments(buf) {
+            bytes += segment.len;
+            self.recv_tracker
+                .on_data(segment.sn, segment.frg, segment.len, self.kcp.rcv_wnd());
+        }
+        if bytes > 0 {
+            events.emit(KcpEvent::DataReceived { bytes });
+        }
+
+        if self.flush_strategy.flushes_ack() {
+            // `flush_ack` of KCP only encodes ACKs into its buffer, which is written by the next flush
+            let result = self.kcp.flush();
+            self.check_output(result)?;
+        }
+
+        Ok(self.try_wake_pending_waker())
+    }
+
+    /// Call if you want to send some data
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        self.poll_send_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    /// Call if you want to send data in several buffers.
+    ///
+    /// In message mode, `bufs` are sent as one message.
+    pub fn poll_send_vectored(&mut self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        self.poll_send_split(cx, bufs, false)
+    }
+
+    /// Same as `poll_send_vectored`, but data that doesn't fit into one message is partially sent in message mode,
+    /// instead of failing with `UserBufTooBig`. For writing a byte stream over message mode.
+    pub fn poll_write_vectored(&mut self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        self.poll_send_split(cx, bufs, true)
+    }
+
+    fn poll_send_split(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+        split_message: bool,
+    ) -> Poll<KcpResult<usize>> {
+        if let Some(err) = self.broken_error() {
+            return Err(err).into();
+        }
+
+        if self.closed {
+            return Ok(0).into();
+        }
+
+        if self.write_shutdown {
+            return Err(KcpError::WriteShutdown).into();
+        }
+
+        if self.peer_closed.is_some() {
+            return Err(KcpError::ConnectionClosed).into();
+        }
+
+        let total_len = bufs.iter().map(|buf| buf.len()).sum::<usize>();
+        if total_len == 0 {
+            return Ok(0).into();
+        }
+
+        // If:
+        //     1. Have sent the first packet (asking for conv)
+        //     2. Too many pending packets
+        if self.sent_first && (self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize || self.kcp.waiting_conv()) {
+            if !self.window_full && !self.kcp.waiting_conv() {
+                self.window_full = true;
+                self.output_state.events().emit(KcpEvent::WindowFull);
+            }
+            trace!(
+                "[SEND] waitsnd={} sndwnd={} excceeded or waiting conv={}",
+                self.kcp.wait_snd(),
+                self.kcp.snd_wnd(),
+                self.kcp.waiting_conv()
+            );
+            self.pending_sender.register(cx.waker());
+            return Poll::Pending;
+        }
+
+        let mut max_len = total_len;
+        if !self.sent_first && self.kcp.waiting_conv() {
+            max_len = max_len.min(self.kcp.mss() as usize);
+        }
+        if self.kcp.is_stream() || split_message {
+            // KCP rejects data that takes more segments than its receive window
+            max_len = max_len.min(self.kcp.mss() as usize * MAX_SEND_SEGMENTS);
+        }
+
+        let n = if self.kcp.is_stream() {
+            // Appended to the stream one by one
+            let mut n = 0;
+            for buf in bufs {
+                if n == max_len {
+                    break;
+                }
+                let len = buf.len().min(max_len - n);
+                if len > 0 {
+                    n += self.kcp.send(&buf[..len])?;
+                }
+            }
+            n
+        } else {
+            match bufs.iter().find(|buf| !buf.is_empty()) {
+                Some(buf) if buf.len() == total_len => self.kcp.send(&buf[..max_len])?,
+                _ => {
+                    // Gathered into one message
+                    self.send_buffer.clear();
+                    for buf in bufs {
+                        let len = buf.len().min(max_len - self.send_buffer.len());
+                        self.send_buffer.extend_from_slice(&buf[..len]);
+                    }
+                    self.kcp.send(&self.send_buffer)?
+                }
+            }
+        };
+        self.sent_first = true;
+        self.window_full = false;
+        self.last_update = Instant::now();
+        self.last_activity = self.last_update;
+        self.bytes_sent += n as u64;
+        self.unflushed += n;
+
+        if self.flush_strategy.flushes_write() {
+            let result = self.kcp.flush();
+            self.check_output(result)?;
+            self.unflushed = 0;
+        }
+
+        Ok(n).into()
+    }
+
+    /// Call if you want to send some data
+    #[allow(dead_code)]
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    }
+
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        if let Some(err) = self.broken_error() {
+            return Err(err);
+        }
+
+        match self.kcp.recv(buf) {
+            Ok(n) => {
+                self.recv_tracker.on_recv(self.kcp.rcv_wnd());
+                self.bytes_received += n as u64;
+                Ok(n)
+            }
+            Err(KcpProtoError::RecvQueueEmpty | KcpProtoError::ExpectingFragment)
+                if self.closed || self.peer_closed.is_some() || self.peer_shutdown =>
+            {
+                Ok(0)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if let Some(err) = self.broken_error() {
+            return Err(err).into();
+        }
+
+        match self.kcp.recv(buf) {
+            Ok(n) => {
+                self.recv_tracker.on_recv(self.kcp.rcv_wnd());
+                self.last_activity = Instant::now();
+                self.bytes_received += n as u64;
+                Ok(n).into()
+            }
+            // Data received before close are still readable, then EOF
+            Err(KcpProtoError::RecvQueueEmpty | KcpProtoError::ExpectingFragment)
+                if self.closed || self.peer_closed.is_some() || self.peer_shutdown =>
+            {
+                Ok(0).into()
+            }
+            // A message is readable after all of its fragments arrived
+            Err(KcpProtoError::RecvQueueEmpty | KcpProtoError::ExpectingFragment) => {
+                self.pending_receiver.register(cx.waker());
+                Poll::Pending
+            }
+            Err(err) => Err(err.into()).into(),
+        }
+    }
+
+    #[allow(dead_code)]
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_recv(cx, buf)).await
+    }
+
+    pub fn flush(&mut self) -> KcpResult<()> {
+        if let Some(err) = self.broken_error() {
+            return Err(err);
+        }
+
+        // Segments are stamped with the clock of KCP, which is only advanced by updates, up to
+        // `IDLE_UPDATE_INTERVAL` ago. `update` itself flushes if it's due.
+        let result = self.kcp.update(now_millis()).and_then(|_| self.kcp.flush());
+        self.check_output(result)?;
+        self.last_update = Instant::now();
+        self.unflushed = 0;
+        Ok(())
+    }
+
+    /// Data sent since the last flush doesn't fill a segment yet, more writes could be appended to it.
+    ///
+    /// Only in stream mode, messages are never merged.
+    pub fn can_coalesce(&self) -> bool {
+        self.kcp.is_stream() && self.unflushed < self.kcp.mss() as usize
+    }
+
+    /// Inspects errors from the output path.
+    ///
+    /// `EMSGSIZE` means that the configured MTU doesn't fit the path, retrying would never succeed,
+    /// so it is reported to every subsequent `send`/`recv` on this socket.
+    fn check_output(&mut self, result: Result<(), KcpProtoError>) -> KcpResult<()> {
+        match result {
+            Err(..) if self.mtu_exceeded() => {
+                self.report_mtu_exceeded();
+                Err(self.mtu_error())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    fn report_mtu_exceeded(&mut self) {
+        error!(
+            "[SEND] conv {} datagram exceeds path MTU, configured mtu {}",
+            self.kcp.conv(),
+            self.mtu()
+        );
+        self.wake_all();
+    }
+
+    /// Errors that break this socket permanently, reported to every subsequent call
+    fn broken_error(&self) -> Option<KcpError> {
+        if self.reset {
+            Some(KcpError::ConnectionReset)
+        } else if self.output_state.refused() {
+            Some(KcpError::ConnectionRefused)
+        } else if self.expired {
+            Some(KcpError::SessionExpired)
+        } else if self.idle_timed_out {
+            Some(KcpError::IdleTimeout)
+        } else if self.mtu_exceeded() {
+            Some(self.mtu_error())
+        } else if self.kcp.is_dead_link() {
+            Some(KcpError::Timeout)
+        } else {
+            None
+        }
+    }
+
+    /// Check if this socket is broken by errors that cannot be recovered
+    pub fn is_broken(&self) -> bool {
+        self.broken_error().is_some()
+    }
+
+    /// Session of this socket has stopped, by an error or after it was closed
+    pub fn is_terminated(&self) -> bool {
+        self.closed || self.is_broken()
+    }
+
+    fn mtu_error(&self) -> KcpError {
+        let err = io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "datagram exceeds path MTU (EMSGSIZE), configured mtu {} is too large",
+                self.mtu()
+            ),
+        );
+        KcpError::IoError(err)
+    }
+
+    /// Check if the output path has reported that datagrams exceed the path MTU
+    pub fn mtu_exceeded(&self) -> bool {
+        self.output_state.mtu_exceeded.load(Ordering::Acquire)
+    }
+
+    fn try_wake_pending_waker(&mut self) -> bool {
+        let mut waked = false;
+
+        if !self.pending_sender.is_empty()
+            && self.kcp.wait_snd() < self.kcp.snd_wnd() as usize
+            && !self.kcp.waiting_conv()
+        {
+            self.pending_sender.wake_all();
+
+            waked = true;
+        }
+
+        if !self.pending_drain.is_empty() && self.kcp.wait_snd() == 0 {
+            self.pending_drain.wake_all();
+
+            waked = true;
+        }
+
+        if !self.pending_receiver.is_empty() {
+            if let Ok(peek) = self.kcp.peeksize() {
+                if peek > 0 {
+                    self.pending_receiver.wake_all();
+
+                    waked = true;
+                }
+            }
+        }
+
+        waked
+    }
+
+    pub fn update(&mut self) -> KcpResult<Instant> {
+        if self.reset {
+            return Err(KcpError::ConnectionReset);
+        }
+        if self.expired {
+            return Err(KcpError::SessionExpired);
+        }
+        if self.idle_timed_out {
+            return Err(KcpError::IdleTimeout);
+        }
+        if self.mtu_exceeded() {
+            // May be reported by the delayed sender
+            self.report_mtu_exceeded();
+            return Err(self.mtu_error());
+        }
+        if self.kcp.is_dead_link() {
+            return Err(KcpError::Timeout);
+        }
+
+        #[cfg(feature = "testing")]
+        self.input_emulated()?;
+
+        // Controller may have shrunk the window for retransmissions of the last flush
+        self.apply_congestion_window();
+
+        let now = now_millis();
+        let result = self.kcp.update(now);
+        self.check_output(result)?;
+
+        if self.kcp.is_dead_link() {
+            error!(
+                "[UPDATE] conv {} dead link, segment retransmitted too many times",
+                self.kcp.conv()
+            );
+            self.wake_all();
+            return Err(KcpError::Timeout);
+        }
+        let next = if self.is_idle() {
+            IDLE_UPDATE_INTERVAL
+        } else {
+            // `check` returns 0 while segments are due for retransmission, but they wait for the next flush of the
+            // interval, updating again in the same millisecond would spin
+            Duration::from_millis(self.kcp.check(now).max(1) as u64)
+        };
+
+        self.try_wake_pending_waker();
+
+        let next = Instant::now() + next;
+        #[cfg(feature = "testing")]
+        if let Some(deadline) = self.netem.as_ref().and_then(NetEm::next_deadline) {
+            return Ok(next.min(deadline));
+        }
+        Ok(next)
+    }
+
+    /// Nothing to send, and ACKs of the last input have been flushed
+    fn is_idle(&self) -> bool {
+        self.kcp.wait_snd() == 0 && self.last_input.elapsed() > self.interval * 2
+    }
+
+    /// Interval of calling `update` while the socket is busy
+    pub fn update_interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Asks peer to tell its window size, which it must reply, a cheap way to check if peer is still alive.
+    ///
+    /// Expiration of this socket restarts from now, so it expires normally if peer doesn't respond.
+    pub fn probe_liveness(&mut self) {
+        self.last_update = Instant::now();
+
+        let segment = self.control_segment(KCP_CMD_WASK);
+        if let Err(err) = self.output.write(&segment) {
+            trace!("[PROBE] conv {} send failed, error: {}", self.kcp.conv(), err);
+        }
+    }
+
+    /// Notifies peer that this side is closed, so it could tear down without waiting for expiration.
+    ///
+    /// Best effort, peer expires normally if it is lost.
+    pub fn send_fin(&mut self) {
+        if self.kcp.waiting_conv() || self.peer_closed.is_some() {
+            // Peer doesn't know us yet, or it is already gone
+            return;
+        }
+
+        let segment = self.control_segment(KCP_CMD_FIN);
+        // Twice, in case one is lost
+        for _ in 0..2 {
+            if let Err(err) = self.output.write(&segment) {
+                trace!("[FIN] conv {} send failed, error: {}", self.kcp.conv(), err);
+            }
+        }
+    }
+
+    /// Shuts down for writing, sends fail with `WriteShutdown` from now on. Data sent before is still delivered,
+    /// peer is notified by `send_shutdown` after that.
+    pub fn shutdown_write(&mut self) -> KcpResult<()> {
+        if let Some(err) = self.broken_error() {
+            return Err(err);
+        }
+        if !self.write_shutdown {
+            trace!("[SHUT] conv {} shut down for writing", self.kcp.conv());
+            self.write_shutdown = true;
+            self.pending_sender.wake_all();
+        }
+        Ok(())
+    }
+
+    /// Notifies peer that this side is shut down for writing, once everything sent has been acknowledged. Called on
+    /// every update until peer acknowledges it, returns whether it's still waiting for that.
+    pub fn send_shutdown(&mut self) -> bool {
+        if !self.write_shutdown || self.shutdown_acked || self.peer_closed.is_some() {
+            return false;
+        }
+        if self.kcp.waiting_conv() || !self.all_acked() {
+            // Peer would see it before the data
+            return true;
+        }
+
+        let segment = self.control_segment(KCP_CMD_SHUT);
+        if let Err(err) = self.output.write(&segment) {
+            trace!("[SHUT] conv {} send failed, error: {}", self.kcp.conv(), err);
+        }
+        true
+    }
+
+    /// Both directions are shut down and acknowledged, the session can be closed
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown_acked && self.peer_shutdown
+    }
+
+    /// Sends `buf` in a datagram of its own, which is never retransmitted, see `KcpStream::send_unreliable`
+    pub fn send_unreliable(&mut self, buf: &[u8]) -> KcpResult<()> {
+        if let Some(err) = self.broken_error() {
+            return Err(err);
+        }
+        if self.closed || self.write_shutdown || self.peer_closed.is_some() {
+            return Err(KcpError::ConnectionClosed);
+        }
+        if buf.len() > self.kcp.mss() as usize {
+            return Err(KcpError::Kcp(KcpProtoError::UserBufTooBig));
+        }
+        if self.kcp.waiting_conv() {
+            // Peer can't tell which session it belongs to
+            trace!("[SEND] datagram dropped before conv is allocated");
+            return Ok(());
+        }
 
-use crate::{utils::now_millis, KcpConfig};
+        let mut datagram = Vec::with_capacity(KCP_HEADER_LEN + buf.len());
+        datagram.extend_from_slice(&self.control_segment(KCP_CMD_DATAGRAM));
+        (&mut datagram[20..]).put_u32_le(buf.len() as u32);
+        datagram.extend_from_slice(buf);
+        self.output.output(&datagram)?;
+        self.last_activity = Instant::now();
+        Ok(())
+    }
 
-/// Writer for sending packets to the underlying UdpSocket
-struct UdpOutput {
-    socket: Arc<UdpSocket>,
-    target_addr: SocketAddr,
-    delay_tx: mpsc::UnboundedSender<Vec<u8>>,
-}
+    /// Queues an unreliable datagram from peer, returns whether a receiver was waiting
+    fn input_unreliable(&mut self, buf: &[u8]) -> bool {
+        let conv = kcp::get_conv(buf);
+        if conv != self.kcp.conv() {
+            trace!(
+                "[INPUT] datagram conv expected={} actual={} ignored",
+                self.kcp.conv(),
+                conv
+            );
+            return false;
+        }
+        let len = ((&buf[20..]).get_u32_le() as usize).min(buf.len() - KCP_HEADER_LEN);
+        if self.unreliable.len() == UNRELIABLE_QUEUE_LEN {
+            // The newest ones are the most useful
+            self.unreliable.pop_front();
+        }
+        self.unreliable
+            .push_back(buf[KCP_HEADER_LEN..KCP_HEADER_LEN + len].to_vec());
+        self.last_activity = Instant::now();
 
-impl UdpOutput {
-    /// Create a new Writer for writing packets to UdpSocket
-    pub fn new(socket: Arc<UdpSocket>, target_addr: SocketAddr) -> UdpOutput {
-        let (delay_tx, mut delay_rx) = mpsc::unbounded_channel::<Vec<u8>>();
+        let waked = !self.pending_unreliable.is_empty();
+        self.pending_unreliable.wake_all();
+        waked
+    }
 
-        {
-            let socket = socket.clone();
-            tokio::spawn(async move {
-                while let Some(buf) = delay_rx.recv().await {
-                    if let Err(err) = socket.send_to(&buf, target_addr).await {
-                        error!("[SEND] UDP delayed send failed, error: {}", err);
-                    }
-                }
-            });
+    /// Receives an unreliable datagram into `buf`, see `KcpStream::recv_unreliable`
+    pub fn poll_recv_unreliable(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if let Some(err) = self.broken_error() {
+            return Err(err).into();
+        }
+        match self.unreliable.front() {
+            Some(datagram) if datagram.len() > buf.len() => Err(KcpError::BufferTooSmall(datagram.len())).into(),
+            Some(..) => {
+                let datagram = self.unreliable.pop_front().unwrap();
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                Ok(datagram.len()).into()
+            }
+            None if self.closed || self.peer_closed.is_some() => Err(KcpError::ConnectionClosed).into(),
+            None => {
+                self.pending_unreliable.register(cx.waker());
+                Poll::Pending
+            }
         }
+    }
 
-        UdpOutput {
-            socket,
-            target_addr,
-            delay_tx,
+    /// Sends a frame that is not a KCP segment to peer
+    pub fn send_raw(&mut self, frame: &[u8]) {
+        if let Err(err) = self.output.output(frame) {
+            trace!("[SEND] conv {} raw frame send failed, error: {}", self.kcp.conv(), err);
         }
     }
-}
 
-impl Write for UdpOutput {
-    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.socket.try_send_to(buf, self.target_addr) {
-            Ok(n) => Ok(n),
-            Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
-                // send return EAGAIN
-                // ignored as packet was lost in transmission
-                trace!("[SEND] UDP send EAGAIN, packet.size: {} bytes, delayed send", buf.len());
+    /// States shared with the output path
+    pub fn output_state(&self) -> &Arc<OutputState> {
+        &self.output_state
+    }
 
-                self.delay_tx.send(buf.to_owned()).expect("channel closed unexpectly");
+    /// Keeps datagrams produced by this socket until `UdpOutput::transmit` is called on the returned output,
+    /// so they can be sent without holding the lock of this socket
+    pub fn defer_output(&mut self) -> UdpOutput {
+        self.output_state.deferred.store(true, Ordering::Release);
+        self.output.clone()
+    }
 
-                Ok(buf.len())
-            }
-            Err(err) => Err(err),
+    /// Time when peer sent FIN
+    pub fn peer_closed_time(&self) -> Option<Instant> {
+        self.peer_closed
+    }
+
+    /// Segment without payload that is not generated by KCP
+    fn control_segment(&self, cmd: u8) -> [u8; KCP_HEADER_LEN] {
+        let mut segment = [0u8; KCP_HEADER_LEN];
+        {
+            let mut buf = &mut segment[..];
+            buf.put_u32_le(self.kcp.conv());
+            buf.put_u8(cmd);
+            buf.put_u8(0); // frg
+            buf.put_u16_le(self.kcp.rcv_wnd());
+            buf.put_u32_le(now_millis()); // ts
+            buf.put_u32_le(0); // sn
+            buf.put_u32_le(self.output_state.last_una.load(Ordering::Relaxed));
+            buf.put_u32_le(0); // len
         }
+        segment
     }
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
+    /// Terminates this socket immediately, all subsequent calls fail with `ConnectionReset`
+    pub fn reset(&mut self) {
+        self.send_fin();
+        self.reset = true;
+        self.wake_all();
     }
-}
 
-pub struct KcpSocket {
-    kcp: Kcp<UdpOutput>,
-    last_update: Instant,
-    socket: Arc<UdpSocket>,
-    flush_write: bool,
-    flush_ack_input: bool,
-    sent_first: bool,
-    pending_sender: Option<Waker>,
-    pending_receiver: Option<Waker>,
-    closed: bool,
-}
+    /// Terminates this socket for inactivity, all subsequent calls fail with `SessionExpired`
+    pub fn expire(&mut self) {
+        self.send_fin();
+        self.expired = true;
+        self.wake_all();
+    }
 
-impl KcpSocket {
-    pub fn new(
-        c: &KcpConfig,
-        conv: u32,
-        socket: Arc<UdpSocket>,
-        target_addr: SocketAddr,
-        stream: bool,
-    ) -> KcpResult<KcpSocket> {
-        let output = UdpOutput::new(socket.clone(), target_addr);
-        let mut kcp = if stream {
-            Kcp::new_stream(conv, output)
-        } else {
-            Kcp::new(conv, output)
-        };
-        c.apply_config(&mut kcp);
+    /// Terminates this socket because it carries no data, all subsequent calls fail with `IdleTimeout`
+    pub fn expire_idle(&mut self) {
+        self.send_fin();
+        self.idle_timed_out = true;
+        self.wake_all();
+    }
 
-        // Ask server to allocate one
-        if conv == 0 {
-            kcp.input_conv();
-        }
+    /// Last time application data was sent or received, keepalives and ACKs don't count
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
 
-        kcp.update(now_millis())?;
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.wake_all();
+    }
 
-        Ok(KcpSocket {
-            kcp,
-            last_update: Instant::now(),
-            socket,
-            flush_write: c.flush_write,
-            flush_ack_input: c.flush_acks_input,
-            sent_first: false,
-            pending_sender: None,
-            pending_receiver: None,
-            closed: false,
-        })
+    fn wake_all(&mut self) {
+        self.pending_sender.wake_all();
+        self.pending_receiver.wake_all();
+        self.pending_drain.wake_all();
+        self.pending_unreliable.wake_all();
     }
 
-    /// Call every time you got data from transmission
-    pub fn input(&mut self, buf: &[u8]) -> KcpResult<bool> {
-        match self.kcp.input(buf) {
-            Ok(..) => {}
-            Err(KcpError::ConvInconsistent(expected, actual)) => {
-                trace!("[INPUT] Conv expected={} actual={} ignored", expected, actual);
-                return Ok(false);
+    /// Sets DF on the socket, `EMSGSIZE` is reported by `take_pmtu_exceeded` instead of breaking the socket
+    pub fn start_pmtu_probing(&mut self) {
+        // Custom transports decide fragmentation themselves
+        if let Some(udp) = self.output_state.udp_socket() {
+            if let Err(err) = set_dont_fragment(&udp) {
+                debug!("[PMTU] conv {} failed to set DF, error: {}", self.kcp.conv(), err);
             }
-            Err(err) => return Err(err),
         }
-        self.last_update = Instant::now();
+        self.output_state.pmtu_probing.store(true, Ordering::Release);
+    }
 
-        if self.flush_ack_input {
-            self.kcp.flush_ack()?;
-        }
+    /// Sends a padded probe of path MTU
+    pub fn send_pmtu_probe(&self, frame: &[u8]) -> io::Result<()> {
+        self.output_state.send_probe(frame).map(|_| ())
+    }
 
-        Ok(self.try_wake_pending_waker())
+    /// Output path reported `EMSGSIZE` since the last call
+    pub fn take_pmtu_exceeded(&self) -> bool {
+        self.output_state.pmtu_exceeded.swap(false, Ordering::AcqRel)
     }
 
-    /// Call if you want to send some data
-    pub fn poll_send(&mut self, cx: &mut Context<'_>, mut buf: &[u8]) -> Poll<KcpResult<usize>> {
-        if self.closed {
-            return Ok(0).into();
+    /// Following datagrams larger than `mtu` are dropped silently, like a route change to a smaller MTU
+    #[cfg(test)]
+    pub fn set_path_mtu(&self, mtu: usize) {
+        self.output_state.path_mtu.store(mtu, Ordering::Release);
+    }
+
+    /// Snapshot of KCP internals, see `KcpStream::debug_state`
+    #[cfg(feature = "debug-internals")]
+    pub fn debug_state(&self) -> KcpDebugState {
+        let tracker = self.output_state.tracker.lock().unwrap();
+        let snd_buf = tracker.snd_buf();
+        let snd_nxt = self.output_state.next_sn.load(Ordering::Relaxed);
+        KcpDebugState {
+            conv: self.kcp.conv(),
+            mtu: self.mtu(),
+            mss: self.kcp.mss() as usize,
+            snd_wnd: self.kcp.snd_wnd(),
+            rcv_wnd: self.kcp.rcv_wnd(),
+            rmt_wnd: self.kcp.rmt_wnd(),
+            wait_snd: self.kcp.wait_snd(),
+            snd_una: snd_buf.first().map_or(snd_nxt, |segment| segment.sn),
+            snd_nxt,
+            rcv_nxt: tracker.rcv_nxt(),
+            dead_link: self.kcp.is_dead_link(),
+            snd_buf,
+            rcv_buf: tracker.rcv_buf(),
+            ack_list: tracker.ack_list(),
         }
+    }
 
-        // If:
-        //     1. Have sent the first packet (asking for conv)
-        //     2. Too many pending packets
-        if self.sent_first && (self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize || self.kcp.waiting_conv()) {
-            trace!(
-                "[SEND] waitsnd={} sndwnd={} excceeded or waiting conv={}",
-                self.kcp.wait_snd(),
-                self.kcp.snd_wnd(),
-                self.kcp.waiting_conv()
-            );
-            self.pending_sender = Some(cx.waker().clone());
-            return Poll::Pending;
+    /// Applies the send window decided by the congestion controller, capped by `KcpConfig::wnd_size`
+    fn apply_congestion_window(&mut self) {
+        #[cfg(feature = "experimental-cc")]
+        let cwnd = self.cwnd_override.or_else(|| self.output_state.congestion_window());
+        #[cfg(not(feature = "experimental-cc"))]
+        let cwnd = self.output_state.congestion_window();
+        if let Some(cwnd) = cwnd {
+            let snd_wnd = cwnd.clamp(1, self.snd_wnd_limit);
+            if snd_wnd != self.kcp.snd_wnd() {
+                self.kcp.set_wndsize(snd_wnd, 0);
+            }
         }
+    }
 
-        if !self.sent_first && self.kcp.waiting_conv() && buf.len() > self.kcp.mss() as usize {
-            buf = &buf[..self.kcp.mss() as usize];
+    pub fn congestion_stats(&self) -> CongestionStats {
+        CongestionStats {
+            kcp_window: self.kcp_window,
+            cwnd: self.kcp.snd_wnd().min(self.kcp.rmt_wnd()),
+            pacing_rate: self.output_state.congestion_pacing_rate(),
+            rto: Duration::from_millis(self.rto.rto as u64),
         }
+    }
 
-        let n = self.kcp.send(buf)?;
-        self.sent_first = true;
-        self.last_update = Instant::now();
+    /// Maximum data segments in flight, see `CongestionStats::cwnd`
+    #[cfg(feature = "experimental-cc")]
+    pub fn cwnd(&self) -> u16 {
+        self.kcp.snd_wnd().min(self.kcp.rmt_wnd())
+    }
 
-        if self.flush_write {
-            self.kcp.flush()?;
-        }
+    /// Payload of data segments sent and not acknowledged yet. Segments acknowledged out of order are counted until
+    /// the ones before them are acknowledged too.
+    #[cfg(feature = "experimental-cc")]
+    pub fn bytes_in_flight(&self) -> usize {
+        self.output_state.sent_times.lock().unwrap().bytes
+    }
 
-        Ok(n).into()
+    /// Bytes per second that peer acknowledges, see `KcpStream::bandwidth_estimate`
+    pub fn bandwidth_estimate(&self) -> Option<u64> {
+        self.output_state.sent_times.lock().unwrap().bandwidth.estimate()
     }
 
-    /// Call if you want to send some data
-    #[allow(dead_code)]
-    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
-        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    /// Fixes the send window to `cwnd`, capped by `KcpConfig::wnd_size`, instead of the window of KCP or the
+    /// congestion controller
+    #[cfg(feature = "experimental-cc")]
+    pub fn set_cwnd(&mut self, cwnd: u16) {
+        self.cwnd_override = Some(cwnd);
+        if self.kcp_window {
+            // KCP's own window would still limit it
+            self.default_congestion = false;
+            self.set_nodelay(self.nodelay);
+        }
+        self.apply_congestion_window();
     }
 
-    #[allow(dead_code)]
-    pub fn try_recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        if self.closed {
-            return Ok(0);
+    /// Summary for `KcpListener::sessions`
+    pub fn info(&self) -> SessionInfo {
+        let now = Instant::now();
+        SessionInfo {
+            conv: self.kcp.conv(),
+            peer_addr: self.output_state.peer_addr(),
+            age_ms: now.saturating_duration_since(self.created).as_millis() as u64,
+            idle_ms: now.saturating_duration_since(self.last_activity).as_millis() as u64,
+            bytes_sent: self.bytes_sent,
+            bytes_received: self.bytes_received,
+            closed: self.closed || self.peer_closed.is_some(),
         }
-        self.kcp.recv(buf)
     }
 
-    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
-        if self.closed {
-            return Ok(0).into();
+    /// Snapshot for `KcpStream::dump_state`, copies the segments in flight
+    pub fn diagnostics(&self) -> SessionDiagnostics {
+        let now = Instant::now();
+        let current = now_millis();
+        let snd_nxt = self.output_state.next_sn.load(Ordering::Relaxed);
+        let (snd_una, segments) = {
+            let sent_times = self.output_state.sent_times.lock().unwrap();
+            let snd_una = if sent_times.ts.is_empty() {
+                snd_nxt
+            } else {
+                sent_times.base
+            };
+            (snd_una, sent_times.segments(current))
+        };
+        let recv_queue = self.recv_queue_len();
+        let since = |time: Instant| now.saturating_duration_since(time).as_millis() as u64;
+        SessionDiagnostics {
+            conv: self.kcp.conv(),
+            peer_addr: self.output_state.peer_addr(),
+            connected: self.connected,
+            closed: self.closed,
+            peer_closed: self.peer_closed.is_some(),
+            error: self.broken_error().map(|err| err.to_string()),
+            snd_queue: self.kcp.wait_snd().saturating_sub(segments.len()),
+            snd_buf: segments.len(),
+            rcv_queue: recv_queue.ready,
+            rcv_buf: recv_queue.out_of_order,
+            snd_una,
+            snd_nxt,
+            snd_wnd: self.kcp.snd_wnd(),
+            rmt_wnd: self.kcp.rmt_wnd(),
+            cwnd: self.kcp.snd_wnd().min(self.kcp.rmt_wnd()),
+            rto_ms: self.rto.rto,
+            srtt_ms: self.rto.srtt,
+            rttvar_ms: self.rto.rttval,
+            retransmissions: self.output_state.retransmissions(),
+            fast_retransmissions: self.output_state.fast_retransmissions(),
+            since_last_send_ms: current.wrapping_sub(self.output_state.last_output.load(Ordering::Relaxed)) as u64,
+            since_last_recv_ms: since(self.last_input),
+            since_last_activity_ms: since(self.last_activity),
+            dead_link: self.kcp.is_dead_link(),
+            segments,
+            config: EffectiveConfig {
+                mtu: self.mtu(),
+                mss: self.kcp.mss() as usize,
+                stream: self.kcp.is_stream(),
+                snd_wnd: self.snd_wnd_limit,
+                rcv_wnd: self.kcp.rcv_wnd(),
+                nodelay: self.nodelay.nodelay,
+                interval_ms: self.rto.interval,
+                fast_resend: self.fast_resend.unwrap_or(self.nodelay.resend.max(0) as u32),
+                min_rto_ms: self.rto.min_rto,
+                kcp_window: self.kcp_window,
+                flush_write: self.flush_strategy.flushes_write(),
+                flush_acks_input: self.flush_strategy.flushes_ack(),
+            },
         }
+    }
 
-        match self.kcp.recv(buf) {
-            Ok(n) => Ok(n).into(),
-            Err(KcpError::RecvQueueEmpty) => {
-                self.pending_receiver = Some(cx.waker().clone());
-                Poll::Pending
+    /// A packet from peer was processed, conv is agreed with peer
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Sends that failed in a row
+    pub fn send_errors(&self) -> u32 {
+        self.output_state.send_errors.load(Ordering::Relaxed)
+    }
+
+    /// Binds a UDP socket to an ephemeral port that replaces the current one by `rebind`.
+    ///
+    /// The returned future doesn't borrow the socket, so it is not kept locked while binding.
+    pub fn bind_replacement(&self) -> impl Future<Output = KcpResult<UdpSocket>> + Send + 'static {
+        let supported = self.output_state.udp_socket().is_some();
+        let next_hop = self.output_state.next_hop();
+        let connected = self.output_state.connected;
+        let pmtu_probing = self.output_state.pmtu_probing.load(Ordering::Acquire);
+        let socket_options = self.socket_options.clone();
+        let conv = self.kcp.conv();
+        async move {
+            if !supported {
+                return Err(io::Error::new(ErrorKind::Unsupported, "custom transport can't be rebound").into());
+            }
+            let udp = if connected {
+                connect_to(next_hop, &socket_options).await?
+            } else {
+                bind_for(next_hop, &socket_options).await?
+            };
+            if pmtu_probing {
+                if let Err(err) = set_dont_fragment(&udp) {
+                    debug!("[PMTU] conv {} failed to set DF, error: {}", conv, err);
+                }
             }
-            Err(err) => Err(err).into(),
+            Ok(udp)
         }
     }
 
-    #[allow(dead_code)]
-    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        future::poll_fn(|cx| self.poll_recv(cx, buf)).await
+    /// Replaces the UDP socket with `udp` of `bind_replacement`, keeps conv and all states of KCP.
+    ///
+    /// For a client whose socket stopped working, like after the network changed. Peer sees this session
+    /// migrating to a new address. Returns the new local address.
+    pub fn rebind(&mut self, udp: UdpSocket) -> KcpResult<SocketAddr> {
+        let local_addr = udp.local_addr()?;
+
+        *self.output_state.socket.lock().unwrap() = Endpoint::Udp(Arc::new(udp));
+        #[cfg(test)]
+        self.output_state.fail_sends.store(false, Ordering::Release);
+        self.output_state.send_errors.store(0, Ordering::Relaxed);
+        self.output_state.rebinds.fetch_add(1, Ordering::Relaxed);
+        self.output_state.events().emit(KcpEvent::Rebound { local_addr });
+
+        // Tells peer the new address, even if there is nothing to send
+        self.probe_liveness();
+        Ok(local_addr)
     }
 
-    pub fn flush(&mut self) -> KcpResult<()> {
-        self.kcp.flush()?;
-        self.last_update = Instant::now();
-        Ok(())
+    /// Following sends fail until rebound, like the network became unreachable
+    #[cfg(test)]
+    pub fn break_socket(&self) {
+        self.output_state.fail_sends.store(true, Ordering::Release);
     }
 
-    fn try_wake_pending_waker(&mut self) -> bool {
-        let mut waked = false;
+    /// Ready when all sent data has been acknowledged by peer, fails if it never will be
+    pub fn poll_drained(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        if self.kcp.wait_snd() == 0 {
+            return Ok(()).into();
+        }
+        if let Some(err) = self.broken_error() {
+            return Err(err).into();
+        }
+        if self.closed || self.peer_closed.is_some() {
+            return Err(KcpError::ConnectionClosed).into();
+        }
 
-        if self.pending_sender.is_some()
-            && self.kcp.wait_snd() < self.kcp.snd_wnd() as usize
-            && !self.kcp.waiting_conv()
-        {
-            let waker = self.pending_sender.take().unwrap();
-            waker.wake();
+        self.pending_drain.register(cx.waker());
+        Poll::Pending
+    }
 
-            waked = true;
+    /// Retransmits a segment after this many ACKs of later segments, 0 disables fast resend
+    pub fn set_fast_resend(&mut self, resend: u32) {
+        self.kcp.set_fast_resend(resend);
+        self.fast_resend = Some(resend);
+    }
+
+    /// Sends ACKs right after input instead of with the next flush
+    pub fn set_flush_acks_input(&mut self, enabled: bool) {
+        self.flush_strategy = self.flush_strategy.with_ack(enabled);
+    }
+
+    /// Reports a change of the overload of the receive buffer of the socket, `KcpEvent::ReceiveOverload` or
+    /// `KcpEvent::ReceiveOverloadCleared`, and shrinks or restores the receive window with `KcpConfig::adaptive_window`
+    pub fn set_receive_overload(&mut self, event: KcpEvent) {
+        if self.adaptive_window {
+            let rcv_wnd = match event {
+                KcpEvent::ReceiveOverload { .. } => (self.rcv_wnd_limit / 2).max(1),
+                _ => self.rcv_wnd_limit,
+            };
+            // KCP keeps the send window with 0
+            self.kcp.set_wndsize(0, rcv_wnd);
+            debug!(
+                "[CONFIG] conv {} receive window changed to {}",
+                self.kcp.conv(),
+                self.kcp.rcv_wnd()
+            );
         }
+        self.output_state.events().emit(event);
+    }
 
-        if self.pending_receiver.is_some() {
-            if let Ok(peek) = self.kcp.peeksize() {
-                if peek > 0 {
-                    let waker = self.pending_receiver.take().unwrap();
-                    waker.wake();
+    /// Changes `KcpConfig::nodelay`, `rx_minrto` and `fast_resend` still override it
+    pub fn set_nodelay(&mut self, nodelay: KcpNoDelayConfig) {
+        self.nodelay = nodelay;
+        self.kcp_window = self.default_congestion && !nodelay.nc;
+        self.kcp
+            .set_nodelay(nodelay.nodelay, nodelay.interval, nodelay.resend, !self.kcp_window);
+        if let Some(rto) = self.rx_minrto {
+            self.kcp.set_rx_minrto(rto);
+        }
+        if let Some(resend) = self.fast_resend {
+            self.kcp.set_fast_resend(resend);
+        }
 
-                    waked = true;
-                }
-            }
+        let interval = nodelay.clamped_interval();
+        self.interval = Duration::from_millis(interval as u64);
+        self.rto.min_rto = self.rx_minrto.unwrap_or(nodelay.min_rto());
+        self.rto.interval = interval;
+        debug!("[CONFIG] conv {} nodelay changed to {:?}", self.kcp.conv(), nodelay);
+    }
+
+    /// Changes MTU of KCP, applies to segments created after this call.
+    ///
+    /// Segments that are already queued or in flight keep their size.
+    pub fn set_mtu(&mut self, mtu: usize) -> KcpResult<()> {
+        let overhead = self.datagram_overhead();
+        if mtu < MIN_MTU + overhead {
+            return Err(KcpError::ConfigInvalid(format!(
+                "mtu {} is smaller than {}",
+                mtu,
+                MIN_MTU + overhead
+            )));
+        }
+        let buffer_size = self.output_state.buffer_pool.buffer_size();
+        if buffer_size < mtu {
+            return Err(KcpError::ConfigInvalid(format!(
+                "buffer size {} is smaller than mtu {}",
+                buffer_size, mtu
+            )));
         }
 
-        waked
+        let prev_mtu = self.mtu();
+        self.kcp.set_mtu(mtu - overhead)?;
+        debug!(
+            "[SEND] conv {} mtu changed from {} to {}",
+            self.kcp.conv(),
+            prev_mtu,
+            mtu
+        );
+        Ok(())
     }
 
-    pub fn update(&mut self) -> KcpResult<Instant> {
-        let now = now_millis();
-        self.kcp.update(now)?;
-        let next = self.kcp.check(now);
+    /// Size of the largest datagram, including the overhead of `KcpConfig::checksum` and `KcpConfig::obfuscator`
+    pub fn mtu(&self) -> usize {
+        self.kcp.mtu() + self.datagram_overhead()
+    }
 
-        self.try_wake_pending_waker();
+    /// Most data accepted by one send
+    pub fn max_send_len(&self) -> usize {
+        self.kcp.mss() as usize * MAX_SEND_SEGMENTS
+    }
 
-        Ok(Instant::now() + Duration::from_millis(next as u64))
+    /// Payload of one segment at most
+    pub fn mss(&self) -> usize {
+        self.kcp.mss() as usize
     }
 
-    pub fn close(&mut self) {
-        self.closed = true;
-        if let Some(w) = self.pending_sender.take() {
-            w.wake();
-        }
-        if let Some(w) = self.pending_receiver.take() {
-            w.wake();
+    fn datagram_overhead(&self) -> usize {
+        self.output_state.framing.overhead()
+    }
+
+    /// Takes all complete messages received and not read yet, concatenated, even if the socket is broken
+    pub fn take_received(&mut self) -> Vec<u8> {
+        let mut received = Vec::new();
+        while let Ok(size) = self.kcp.peeksize() {
+            let start = received.len();
+            received.resize(start + size, 0);
+            if self.kcp.recv(&mut received[start..]).is_err() {
+                received.truncate(start);
+                break;
+            }
+            self.recv_tracker.on_recv(self.kcp.rcv_wnd());
         }
+        self.bytes_received += received.len() as u64;
+        received
     }
 
-    pub fn udp_socket(&self) -> &Arc<UdpSocket> {
-        &self.socket
+    /// Drops received messages that will never be read
+    pub fn discard_received(&mut self) {
+        let mut discarded = 0;
+        let mut buf = Vec::new();
+        while let Ok(size) = self.kcp.peeksize() {
+            buf.resize(size, 0);
+            if self.kcp.recv(&mut buf).is_err() {
+                break;
+            }
+            self.recv_tracker.on_recv(self.kcp.rcv_wnd());
+            discarded += size;
+        }
+        if discarded > 0 {
+            trace!(
+                "[RECV] conv {} discarded {} bytes of abandoned session",
+                self.kcp.conv(),
+                discarded
+            );
+        }
     }
 
     pub fn can_close(&self) -> bool {
+        self.all_acked()
+    }
+
+    /// Send queue and send buffer of KCP are both empty, everything sent was acknowledged
+    pub fn all_acked(&self) -> bool {
         self.kcp.wait_snd() == 0
     }
 
+    /// Rejects datagrams of other convs with `ConvInconsistent`, for conv chosen by client
+    pub fn set_strict_conv(&mut self) {
+        self.strict_conv = true;
+    }
+
     pub fn conv(&self) -> u32 {
         self.kcp.conv()
     }
 
+    /// Data received and not read yet
+    pub fn recv_queue_len(&self) -> RecvQueueLen {
+        self.recv_tracker.len(self.kcp.is_stream())
+    }
+
+    pub fn is_stream(&self) -> bool {
+        self.kcp.is_stream()
+    }
+
     pub fn peek_size(&self) -> KcpResult<usize> {
-        self.kcp.peeksize()
+        Ok(self.kcp.peeksize()?)
     }
 
     pub fn last_update_time(&self) -> Instant {
@@ -279,17 +2252,20 @@ impl KcpSocket {
 #[cfg(test)]
 mod test {
 
-    use kcp::Error as KcpError;
     use log::trace;
-    use std::sync::Arc;
+    use std::{io::ErrorKind, sync::Arc, time::Duration};
     use tokio::{
         net::UdpSocket,
         sync::Mutex,
         time::{self, Instant},
     };
 
-    use super::KcpSocket;
-    use crate::config::KcpConfig;
+    use super::{KcpSocket, KCP_CMD_WASK, KCP_CMD_WINS, KCP_HEADER_LEN};
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig},
+        error::KcpError,
+        PacingConfig,
+    };
 
     #[tokio::test]
     async fn kcp_echo() {
@@ -321,7 +2297,7 @@ mod test {
                     let mut kcp = kcp1.lock().await;
                     let next = kcp.update().expect("update");
                     trace!("kcp1 next tick {:?}", next);
-                    time::sleep_until(Instant::from_std(next)).await;
+                    time::sleep_until(next).await;
                 }
             })
         };
@@ -333,7 +2309,7 @@ mod test {
                     let mut kcp = kcp2.lock().await;
                     let next = kcp.update().expect("update");
                     trace!("kcp2 next tick {:?}", next);
-                    time::sleep_until(Instant::from_std(next)).await;
+                    time::sleep_until(next).await;
                 }
             })
         };
@@ -366,7 +2342,7 @@ mod test {
                         let received = &buf[..n];
                         kcp2.send(received).await.unwrap();
                     }
-                    Err(KcpError::RecvQueueEmpty) => {
+                    Err(KcpError::Kcp(kcp::Error::RecvQueueEmpty)) => {
                         continue;
                     }
                     Err(err) => {
@@ -393,7 +2369,7 @@ mod test {
                         assert_eq!(received, SEND_BUFFER);
                         break;
                     }
-                    Err(KcpError::RecvQueueEmpty) => {
+                    Err(KcpError::Kcp(kcp::Error::RecvQueueEmpty)) => {
                         continue;
                     }
                     Err(err) => {
@@ -407,4 +2383,160 @@ mod test {
         kcp1_task.abort();
         kcp2_task.abort();
     }
+
+    #[tokio::test]
+    async fn kcp_mtu_exceeded() {
+        let _ = env_logger::try_init();
+
+        let s1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        // Larger than any UDP datagram on IPv4, sendto() always fails with EMSGSIZE
+        let config = KcpConfig {
+            mtu: 70000,
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 0xdeadbeef, Arc::new(s1), s2_addr, true).unwrap();
+
+        const SEND_BUFFER: &[u8] = &[0u8; 66000];
+        assert_eq!(kcp.send(SEND_BUFFER).await.unwrap(), SEND_BUFFER.len());
+
+        // The first datagram may be handed to the delayed sender if the socket is not ready yet
+        let _ = kcp.flush();
+        while !kcp.mtu_exceeded() {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        match kcp.update().unwrap_err() {
+            KcpError::IoError(err) => assert_eq!(err.kind(), ErrorKind::InvalidInput),
+            err => panic!("unexpected error: {:?}", err),
+        }
+
+        // Reported to subsequent calls instead of stalling
+        let mut buf = [0u8; 1024];
+        assert!(kcp.try_recv(&mut buf).is_err());
+        assert!(kcp.send(b"HELLO WORLD").await.is_err());
+        assert!(kcp.flush().is_err());
+    }
+
+    #[tokio::test]
+    async fn kcp_probe_liveness() {
+        let _ = env_logger::try_init();
+
+        static CONV: u32 = 0xdeadbeef;
+
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s1_addr = s1.local_addr().unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp1 = KcpSocket::new(&config, CONV, s1.clone(), s2_addr, true).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, CONV, s2.clone(), s1_addr, true).unwrap();
+
+        // Expiration restarts from the probe
+        let created = kcp1.last_update_time();
+        time::sleep(Duration::from_millis(10)).await;
+        kcp1.probe_liveness();
+        assert!(kcp1.last_update_time() > created);
+
+        let mut buf = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(1), s2.recv(&mut buf))
+            .await
+            .expect("probe not sent")
+            .unwrap();
+        assert_eq!(n, KCP_HEADER_LEN);
+        assert_eq!(&buf[..4], &CONV.to_le_bytes());
+        assert_eq!(buf[4], KCP_CMD_WASK);
+
+        // Peer accepts the probe and replies its window size on the next flush
+        kcp2.input(&buf[..n]).unwrap();
+        kcp2.flush().unwrap();
+        let n = time::timeout(Duration::from_secs(1), s1.recv(&mut buf))
+            .await
+            .expect("probe not answered")
+            .unwrap();
+        assert_eq!(n, KCP_HEADER_LEN);
+        assert_eq!(&buf[..4], &CONV.to_le_bytes());
+        assert_eq!(buf[4], KCP_CMD_WINS);
+        kcp1.input(&buf[..n]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn kcp_strict_conv() {
+        let _ = env_logger::try_init();
+
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s1_addr = s1.local_addr().unwrap();
+        let s2_addr = s2.local_addr().unwrap();
+
+        let config = KcpConfig::default();
+        let mut kcp1 = KcpSocket::new(&config, 1, s1.clone(), s2_addr, true).unwrap();
+        let mut kcp2 = KcpSocket::new(&config, 2, s2.clone(), s1_addr, true).unwrap();
+        kcp2.send(b"HELLO").await.unwrap();
+        kcp2.flush().unwrap();
+
+        let mut buf = [0u8; 1024];
+        let n = s1.recv(&mut buf).await.unwrap();
+
+        // Ignored by default
+        assert!(!kcp1.input(&buf[..n]).unwrap());
+
+        kcp1.set_strict_conv();
+        match kcp1.input(&buf[..n]) {
+            Err(KcpError::Kcp(kcp::Error::ConvInconsistent(1, 2))) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert!(kcp1.try_recv(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn kcp_pacing() {
+        let _ = env_logger::try_init();
+
+        time::pause();
+
+        let s1 = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // 1024 bytes datagram takes 10ms
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            pacing: Some(PacingConfig {
+                max_burst: 2048,
+                rate: 102_400,
+                packet_rate: None,
+                adaptive: false,
+            }),
+            ..Default::default()
+        };
+        let mut kcp = KcpSocket::new(&config, 1, s1, s2.local_addr().unwrap(), false).unwrap();
+
+        const SEGMENTS: usize = 10;
+        let payload = [0u8; 1024 - KCP_HEADER_LEN];
+        for _ in 0..SEGMENTS {
+            kcp.send(&payload).await.unwrap();
+        }
+        let start = Instant::now();
+        kcp.flush().unwrap();
+
+        let mut arrivals = Vec::with_capacity(SEGMENTS);
+        let mut buffer = [0u8; 2048];
+        while arrivals.len() < SEGMENTS {
+            let n = s2.recv(&mut buffer).await.unwrap();
+            assert_eq!(n, 1024);
+            arrivals.push(Instant::now() - start);
+        }
+
+        // Burst of 2 segments, then one every 10ms.
+        // Receiving may be observed later than sending, but never earlier.
+        for (i, arrival) in arrivals.iter().enumerate().skip(2) {
+            let slot = Duration::from_millis(10) * (i as u32 - 1);
+            assert!(*arrival >= slot, "segment {} arrived at {:?}", i, arrival);
+        }
+        assert!(arrivals[SEGMENTS - 1] < Duration::from_millis(100));
+    }
 }
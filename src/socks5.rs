@@ -0,0 +1,371 @@
+//! UDP ASSOCIATE of SOCKS5 (RFC 1928), for clients that reach servers through a proxy
+//!
+//! Datagrams are sent to the relay of the proxy with a header addressing the server, and received from it with a
+//! header of the address they came from. The association lasts as long as the TCP connection that requested it.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use bytes::Buf;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::error::{KcpError, KcpResult};
+
+const VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+/// Version of the username/password subnegotiation (RFC 1929)
+const AUTH_VERSION: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 0x03;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Username and password of a SOCKS5 proxy (RFC 1929), both 1 to 255 bytes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Socks5Auth {
+    pub username: String,
+    pub password: String,
+}
+
+/// Association with the UDP relay of a proxy
+pub struct Socks5Relay {
+    /// Relay stops when it's closed
+    _control: TcpStream,
+    relay_addr: SocketAddr,
+    target_addr: SocketAddr,
+    /// Header of datagrams to `target_addr`
+    header: Vec<u8>,
+}
+
+impl Socks5Relay {
+    /// Requests UDP ASSOCIATE from `proxy` for datagrams to `target`
+    pub async fn associate(proxy: SocketAddr, target: SocketAddr, auth: Option<&Socks5Auth>) -> KcpResult<Socks5Relay> {
+        if let Some(auth) = auth {
+            if !(1..=255).contains(&auth.username.len()) || !(1..=255).contains(&auth.password.len()) {
+                return Err(KcpError::ConfigInvalid(
+                    "SOCKS5 username and password must be 1 to 255 bytes".to_owned(),
+                ));
+            }
+        }
+
+        let mut control = TcpStream::connect(proxy).await?;
+        control.set_nodelay(true)?;
+
+        let method = if auth.is_some() {
+            METHOD_USERNAME_PASSWORD
+        } else {
+            METHOD_NO_AUTH
+        };
+        control.write_all(&[VERSION, 1, method]).await?;
+        let mut reply = [0u8; 2];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(invalid_data("not a SOCKS5 proxy"));
+        }
+        if reply[1] == METHOD_NOT_ACCEPTABLE || reply[1] != method {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 authentication method not accepted",
+            )
+            .into());
+        }
+
+        if let Some(auth) = auth {
+            let mut request = vec![AUTH_VERSION, auth.username.len() as u8];
+            request.extend_from_slice(auth.username.as_bytes());
+            request.push(auth.password.len() as u8);
+            request.extend_from_slice(auth.password.as_bytes());
+            control.write_all(&request).await?;
+
+            control.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "SOCKS5 authentication failed").into());
+            }
+        }
+
+        // Address of datagrams from client is unknown behind NAT, any is accepted
+        let mut request = vec![VERSION, CMD_UDP_ASSOCIATE, 0];
+        encode_addr(&mut request, SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0));
+        control.write_all(&request).await?;
+
+        let mut reply = [0u8; 3];
+        control.read_exact(&mut reply).await?;
+        if reply[0] != VERSION {
+            return Err(invalid_data("not a SOCKS5 proxy"));
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::other(format!("SOCKS5 UDP ASSOCIATE failed, reply {}", reply[1])).into());
+        }
+        let mut relay_addr = read_addr(&mut control).await?;
+        // Proxies bound to all interfaces reply with an unspecified address
+        if relay_addr.ip().is_unspecified() {
+            relay_addr.set_ip(proxy.ip());
+        }
+
+        let mut header = vec![0, 0, 0];
+        encode_addr(&mut header, target);
+
+        Ok(Socks5Relay {
+            _control: control,
+            relay_addr,
+            target_addr: target,
+            header,
+        })
+    }
+
+    /// Address that datagrams are sent to and received from
+    pub fn relay_addr(&self) -> SocketAddr {
+        self.relay_addr
+    }
+
+    /// Prepended to every datagram sent to the relay
+    pub fn header(&self) -> &[u8] {
+        &self.header
+    }
+
+    /// Payload of a datagram from the relay, `None` if it isn't a whole datagram from target
+    pub fn unwrap<'a>(&self, buf: &'a [u8]) -> Option<&'a [u8]> {
+        // RSV, FRAG, fragments are not supported
+        if buf.len() < 3 || buf[..3] != [0, 0, 0] {
+            return None;
+        }
+        let (addr, len) = decode_addr(&buf[3..])?;
+        if addr != self.target_addr {
+            return None;
+        }
+        Some(&buf[3 + len..])
+    }
+}
+
+fn invalid_data(msg: &str) -> KcpError {
+    io::Error::new(io::ErrorKind::InvalidData, msg).into()
+}
+
+/// Appends ATYP, ADDR and PORT
+fn encode_addr(buf: &mut Vec<u8>, addr: SocketAddr) {
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            buf.push(ATYP_IPV4);
+            buf.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            buf.push(ATYP_IPV6);
+            buf.extend_from_slice(&ip.octets());
+        }
+    }
+    buf.extend_from_slice(&addr.port().to_be_bytes());
+}
+
+/// Address at the beginning of `buf` and its length, domain names are not supported
+fn decode_addr(buf: &[u8]) -> Option<(SocketAddr, usize)> {
+    let len = match *buf.first()? {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        _ => return None,
+    };
+    if buf.len() < 3 + len {
+        return None;
+    }
+    let mut addr = &buf[1..];
+    let ip: IpAddr = if len == 4 {
+        Ipv4Addr::from(addr.get_u32()).into()
+    } else {
+        Ipv6Addr::from(addr.get_u128()).into()
+    };
+    Some((SocketAddr::new(ip, addr.get_u16()), 3 + len))
+}
+
+/// Reads ATYP, ADDR and PORT of a reply
+async fn read_addr(control: &mut TcpStream) -> KcpResult<SocketAddr> {
+    let ip: IpAddr = match control.read_u8().await? {
+        ATYP_IPV4 => {
+            let mut octets = [0u8; 4];
+            control.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).into()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0u8; 16];
+            control.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return Err(invalid_data("unsupported SOCKS5 relay address")),
+    };
+    let port = control.read_u16().await?;
+    Ok(SocketAddr::new(ip, port))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io, net::SocketAddr};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream, UdpSocket},
+    };
+
+    use super::{decode_addr, encode_addr, Socks5Auth, Socks5Relay};
+    use crate::{config::KcpConfig, error::KcpError, listener::KcpListener, stream::KcpStream, KcpNoDelayConfig};
+
+    /// Minimal SOCKS5 proxy that only serves UDP ASSOCIATE
+    async fn serve_proxy(auth: Option<Socks5Auth>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (control, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve_association(control, auth.clone()));
+            }
+        });
+        proxy_addr
+    }
+
+    async fn serve_association(mut control: TcpStream, auth: Option<Socks5Auth>) -> io::Result<()> {
+        let mut header = [0u8; 2];
+        control.read_exact(&mut header).await?;
+        let mut methods = vec![0u8; header[1] as usize];
+        control.read_exact(&mut methods).await?;
+        let method = if auth.is_some() { 0x02 } else { 0x00 };
+        if !methods.contains(&method) {
+            return control.write_all(&[5, 0xff]).await;
+        }
+        control.write_all(&[5, method]).await?;
+
+        if let Some(auth) = auth {
+            let mut credentials = vec![0u8; 2];
+            control.read_exact(&mut credentials).await?;
+            let mut username = vec![0u8; credentials[1] as usize];
+            control.read_exact(&mut username).await?;
+            let mut password = vec![0u8; control.read_u8().await? as usize];
+            control.read_exact(&mut password).await?;
+            if username != auth.username.as_bytes() || password != auth.password.as_bytes() {
+                return control.write_all(&[1, 1]).await;
+            }
+            control.write_all(&[1, 0]).await?;
+        }
+
+        // VER, CMD, RSV, IPv4 address of client
+        let mut request = [0u8; 10];
+        control.read_exact(&mut request).await?;
+        assert_eq!(request[1], 3);
+
+        let relay = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut reply = vec![5, 0, 0];
+        encode_addr(&mut reply, relay.local_addr()?);
+        control.write_all(&reply).await?;
+
+        let mut client_addr = None;
+        let mut buf = vec![0u8; 65536];
+        loop {
+            tokio::select! {
+                // Association ends with the control connection
+                _ = control.read_u8() => return Ok(()),
+                result = relay.recv_from(&mut buf) => {
+                    let (n, from) = result?;
+                    if client_addr.is_none_or(|client_addr| client_addr == from) {
+                        client_addr = Some(from);
+                        let (target, len) = decode_addr(&buf[3..n]).unwrap();
+                        relay.send_to(&buf[3 + len..n], target).await?;
+                    } else if let Some(client_addr) = client_addr {
+                        let mut packet = vec![0, 0, 0];
+                        encode_addr(&mut packet, from);
+                        packet.extend_from_slice(&buf[..n]);
+                        relay.send_to(&packet, client_addr).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn header() {
+        let target: SocketAddr = "[::1]:4000".parse().unwrap();
+        let mut header = vec![0, 0, 0];
+        encode_addr(&mut header, target);
+        assert_eq!(header.len(), 22);
+        assert_eq!(decode_addr(&header[3..]), Some((target, 19)));
+
+        let mut header = vec![0, 0, 0];
+        encode_addr(&mut header, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(header, [0, 0, 0, 1, 127, 0, 0, 1, 0x0f, 0xa0]);
+        assert_eq!(decode_addr(&header[3..9]), None);
+    }
+
+    #[tokio::test]
+    async fn connect_via_socks5() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let auth = Socks5Auth {
+            username: "kcp".to_owned(),
+            password: "secret".to_owned(),
+        };
+        let proxy_addr = serve_proxy(Some(auth.clone())).await;
+
+        let mut stream = KcpStream::connect_via_socks5(&config, proxy_addr, server_addr, Some(auth.clone()))
+            .await
+            .unwrap();
+
+        const DATA_SIZE: usize = 64 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        stream.write_all(&data).await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; DATA_SIZE];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        server.write_all(&data).await.unwrap();
+        stream.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        let wrong_auth = Socks5Auth {
+            password: "guess".to_owned(),
+            ..auth
+        };
+        match KcpStream::connect_via_socks5(&config, proxy_addr, server_addr, Some(wrong_auth)).await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("authenticated with a wrong password"),
+        }
+        match KcpStream::connect_via_socks5(&config, proxy_addr, server_addr, None).await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), io::ErrorKind::PermissionDenied),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("authentication skipped"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unwrap_from_target() {
+        let proxy_addr = serve_proxy(None).await;
+        let target: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let relay = Socks5Relay::associate(proxy_addr, target, None).await.unwrap();
+        assert_eq!(relay.header().len(), 10);
+
+        let mut packet = relay.header().to_vec();
+        packet.extend_from_slice(b"PAYLOAD");
+        assert_eq!(relay.unwrap(&packet), Some(&b"PAYLOAD"[..]));
+
+        // Fragmented
+        packet[2] = 1;
+        assert_eq!(relay.unwrap(&packet), None);
+
+        // From another address
+        let mut packet = vec![0, 0, 0];
+        encode_addr(&mut packet, "127.0.0.1:4001".parse().unwrap());
+        packet.extend_from_slice(b"PAYLOAD");
+        assert_eq!(relay.unwrap(&packet), None);
+    }
+}
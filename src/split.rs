@@ -0,0 +1,218 @@
+//! Owned halves of a `KcpStream`, see `KcpStream::into_split`
+//!
+//! Both halves are handles of the same session, like clones of the stream. Each half only exposes one direction,
+//! so a reader can be moved into a spawned task while the writer stays with the caller.
+
+use std::{
+    io::{self, IoSlice},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{error::KcpResult, stream::KcpStream};
+
+/// Receiving half of a `KcpStream`, created by `KcpStream::into_split`
+///
+/// Dropping it doesn't close the session while the `OwnedWriteHalf` is alive. Data received after that is never
+/// read, it stays queued until the receive window is full, then peer stops sending.
+pub struct OwnedReadHalf {
+    stream: KcpStream,
+}
+
+/// Sending half of a `KcpStream`, created by `KcpStream::into_split`
+///
+/// Dropping it doesn't close the session while the `OwnedReadHalf` is alive, peer still receives all data sent and
+/// may keep sending. KCP has no half-close, FIN is sent after both halves are dropped, or by `finish`.
+pub struct OwnedWriteHalf {
+    stream: KcpStream,
+}
+
+impl OwnedReadHalf {
+    pub(crate) fn new(stream: KcpStream) -> OwnedReadHalf {
+        OwnedReadHalf { stream }
+    }
+
+    /// Receives data into `buf`, see `KcpStream::poll_recv`
+    pub fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.stream.poll_recv(cx, buf)
+    }
+
+    /// Receives data into `buf`, see `KcpStream::recv`
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        self.stream.recv(buf).await
+    }
+
+    pub fn poll_recv_buf(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<KcpResult<usize>> {
+        self.stream.poll_recv_buf(cx, buf)
+    }
+
+    pub async fn recv_buf(&mut self, buf: &mut ReadBuf<'_>) -> KcpResult<usize> {
+        self.stream.recv_buf(buf).await
+    }
+
+    /// See `KcpStream::set_read_timeout`
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KcpResult<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.stream.read_timeout()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.stream.peer_addr()
+    }
+}
+
+impl OwnedWriteHalf {
+    pub(crate) fn new(stream: KcpStream) -> OwnedWriteHalf {
+        OwnedWriteHalf { stream }
+    }
+
+    /// Sends data in `buf`, see `KcpStream::poll_send`
+    pub fn poll_send(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        self.stream.poll_send(cx, buf)
+    }
+
+    pub fn poll_send_vectored(&self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        self.stream.poll_send_vectored(cx, bufs)
+    }
+
+    /// Sends data in `buf`, see `KcpStream::send`
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.stream.send(buf).await
+    }
+
+    pub async fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> KcpResult<usize> {
+        self.stream.send_vectored(bufs).await
+    }
+
+    /// See `KcpStream::flush_acked`
+    pub async fn flush_acked(&mut self) -> KcpResult<()> {
+        self.stream.flush_acked().await
+    }
+
+    /// Closes the session gracefully, see `KcpStream::finish`.
+    ///
+    /// The read half receives what peer has sent before FIN, then receives 0.
+    pub async fn finish(self) -> KcpResult<()> {
+        self.stream.finish().await
+    }
+
+    /// See `KcpStream::set_write_timeout`
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KcpResult<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.stream.write_timeout()
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.stream.peer_addr()
+    }
+}
+
+impl AsyncRead for OwnedReadHalf {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for OwnedWriteHalf {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time,
+    };
+
+    use super::{OwnedReadHalf, OwnedWriteHalf};
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig},
+        listener::KcpListener,
+        stream::KcpStream,
+    };
+
+    fn assert_send_static<T: Send + 'static>() {}
+
+    #[tokio::test]
+    async fn split_owned() {
+        let _ = env_logger::try_init();
+
+        assert_send_static::<OwnedReadHalf>();
+        assert_send_static::<OwnedWriteHalf>();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut read_half, mut write_half) = stream.into_split();
+        // Detached, the read half outlives this scope
+        let reader = tokio::spawn(async move {
+            let mut buf = [0u8; 5];
+            read_half.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        write_half.write_all(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+        server.write_all(b"WORLD").await.unwrap();
+        let received = time::timeout(Duration::from_secs(5), reader).await.unwrap().unwrap();
+        assert_eq!(&received, b"WORLD");
+
+        // Read half was dropped by the task, the write half still sends
+        write_half.write_all(b"AGAIN").await.unwrap();
+        time::timeout(Duration::from_secs(5), server.read_exact(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf, b"AGAIN");
+
+        // Closed after both halves are dropped
+        drop(write_half);
+        let n = time::timeout(Duration::from_secs(5), server.read(&mut buf))
+            .await
+            .expect("FIN not received")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+}
@@ -1,97 +1,150 @@
 use std::{
-    io::{self, ErrorKind},
-    net::{IpAddr, SocketAddr},
+    future::Future,
+    io::{self, IoSlice},
+    net::SocketAddr,
     pin::Pin,
-    sync::Arc,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
     task::{Context, Poll},
+    time::Duration,
 };
 
+#[cfg(feature = "bytes")]
+use bytes::{Buf, Bytes};
 use futures::{future, ready};
-use kcp::{Error as KcpError, KcpResult};
-use log::trace;
+use log::{debug, trace};
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
     net::UdpSocket,
+    sync::broadcast,
+    time::{self, Instant, Sleep},
 };
 
-use crate::{config::KcpConfig, session::KcpSession, skcp::KcpSocket};
+#[cfg(feature = "debug-internals")]
+use crate::debug::KcpDebugState;
+#[cfg(feature = "bytes")]
+use crate::message::KcpMessageStream;
+#[cfg(feature = "socks5")]
+use crate::socks5::{Socks5Auth, Socks5Relay};
+use crate::{
+    client::KcpClient,
+    config::{KcpConfig, KcpNoDelayConfig, RetryConfig},
+    congestion::CongestionStats,
+    diagnostics::SessionDiagnostics,
+    error::{KcpError, KcpResult},
+    event::KcpEvent,
+    framing::Framing,
+    handshake,
+    pacing::PacingStats,
+    recv_queue::RecvQueueLen,
+    session::KcpSession,
+    skcp::KcpSocket,
+    split::{OwnedReadHalf, OwnedWriteHalf},
+    transport::KcpTransport,
+    utils::{connect_to, random_u64},
+};
 
+/// A KCP session, like a TCP stream.
+///
+/// Cloning it is cheap, clones are handles of the same session, which is closed after all of them are dropped.
+/// Sends from clones never interleave within a message in message mode, and `send` never splits one. In stream
+/// mode, `send` may accept only a part of `buf`, so data written concurrently by `write_all` may interleave.
+/// Concurrent receives from clones are not ordered, each message or chunk of the stream goes to whichever
+/// caller wins. A message partially read by a clone stays in that clone.
+///
+/// Data received and not read yet is discarded when the last clone is dropped, only sent data is still delivered.
+/// Use `into_remaining` to take it before dropping, like the last message of peer that arrived with its close.
 pub struct KcpStream {
     session: Arc<KcpSession>,
-    recv_buffer: Vec<u8>,
-    recv_buffer_pos: usize,
-    recv_buffer_cap: usize,
+    /// Shared by clones of this stream
+    closer: Arc<SessionCloser>,
+    /// Per handle, clones start with the same timeouts. Locked by polls, which take `&self`, the session is locked
+    /// after them.
+    recv: Mutex<RecvState>,
+    write_timeout: Mutex<IoTimeout>,
 }
 
-impl Drop for KcpStream {
-    fn drop(&mut self) {
-        self.session.close();
-    }
+/// Receiving state of a handle of the stream
+#[derive(Default)]
+struct RecvState {
+    /// The rest of data that didn't fit in the buffer of the caller
+    buffer: Vec<u8>,
+    pos: usize,
+    cap: usize,
+    timeout: IoTimeout,
 }
 
-impl KcpStream {
-    pub async fn connect(config: &KcpConfig, addr: SocketAddr) -> KcpResult<KcpStream> {
-        let udp = match addr.ip() {
-            IpAddr::V4(..) => UdpSocket::bind("0.0.0.0:0").await?,
-            IpAddr::V6(..) => UdpSocket::bind("[::]:0").await?,
-        };
-
-        let udp = Arc::new(udp);
-        let socket = KcpSocket::new(config, 0, udp, addr, config.stream)?;
+/// Timeout of receives or sends of a stream. The timer is only allocated once, and rearmed for every operation.
+#[derive(Default)]
+struct IoTimeout {
+    timeout: Option<Duration>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    /// Timer is running for the operation in progress
+    armed: bool,
+}
 
-        let session = KcpSession::new_shared(socket, config.session_expire, None);
+impl IoTimeout {
+    fn new(timeout: Option<Duration>) -> IoTimeout {
+        IoTimeout {
+            timeout,
+            ..Default::default()
+        }
+    }
 
-        Ok(KcpStream::with_session(session))
+    /// Changes the timeout, an operation in progress starts timing again
+    fn set(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+        self.armed = false;
     }
 
-    pub(crate) fn with_session(session: Arc<KcpSession>) -> KcpStream {
-        KcpStream {
-            session,
-            recv_buffer: Vec::new(),
-            recv_buffer_pos: 0,
-            recv_buffer_cap: 0,
-        }
+    /// Starts timing a new operation, even if the last one was cancelled while pending
+    fn restart(&mut self) {
+        self.armed = false;
     }
 
-    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
-        // Mutex doesn't have poll_lock, spinning on it.
-        let socket = self.session.kcp_socket();
-        let mut kcp = match socket.try_lock() {
-            Ok(guard) => guard,
-            Err(..) => {
-                cx.waker().wake_by_ref();
-                return Poll::Pending;
+    /// Fails a pending operation with `Timeout` when the timer fires
+    fn poll<T>(&mut self, cx: &mut Context<'_>, result: Poll<KcpResult<T>>) -> Poll<KcpResult<T>> {
+        let timeout = match self.timeout {
+            Some(timeout) if result.is_pending() => timeout,
+            _ => {
+                self.armed = false;
+                return result;
             }
         };
 
-        kcp.poll_send(cx, buf)
-    }
-
-    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
-        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+        let deadline = Instant::now() + timeout;
+        let sleep = self.sleep.get_or_insert_with(|| Box::pin(time::sleep_until(deadline)));
+        if !self.armed {
+            sleep.as_mut().reset(deadline);
+            self.armed = true;
+        }
+        if sleep.as_mut().poll(cx).is_ready() {
+            self.armed = false;
+            return Err(KcpError::Timeout).into();
+        }
+        Poll::Pending
     }
+}
 
-    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+impl RecvState {
+    fn poll_recv(
+        &mut self,
+        session: &KcpSession,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        split_message: bool,
+    ) -> Poll<KcpResult<usize>> {
         loop {
             // Consumes all data in buffer
-            if self.recv_buffer_pos < self.recv_buffer_cap {
-                let remaining = self.recv_buffer_cap - self.recv_buffer_pos;
+            if self.pos < self.cap {
+                let remaining = self.cap - self.pos;
                 let copy_length = remaining.min(buf.len());
 
-                buf.copy_from_slice(&self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_pos + copy_length]);
-                self.recv_buffer_pos += copy_length;
+                buf[..copy_length].copy_from_slice(&self.buffer[self.pos..self.pos + copy_length]);
+                self.pos += copy_length;
                 return Ok(copy_length).into();
             }
 
-            // Mutex doesn't have poll_lock, spinning on it.
-            let socket = self.session.kcp_socket();
-            let mut kcp = match socket.try_lock() {
-                Ok(guard) => guard,
-                Err(..) => {
-                    cx.waker().wake_by_ref();
-                    return Poll::Pending;
-                }
-            };
+            let mut kcp = session.lock_socket();
 
             // Try to read from KCP
             // 1. Read directly with user provided `buf`
@@ -100,73 +153,3734 @@ impl KcpStream {
                     trace!("[CLIENT] recv directly {} bytes", n);
                     return Ok(n).into();
                 }
-                Err(KcpError::UserBufTooSmall) => {}
+                Err(KcpError::Kcp(kcp::Error::UserBufTooSmall)) => {}
                 Err(err) => return Err(err).into(),
             }
 
-            // 2. User `buf` too small, read to recv_buffer
+            // 2. User `buf` too small, read to buffer
             let required_size = kcp.peek_size()?;
-            if self.recv_buffer.len() < required_size {
-                self.recv_buffer.resize(required_size, 0);
+            if !kcp.is_stream() && !split_message {
+                return Err(KcpError::BufferTooSmall(required_size)).into();
+            }
+            if self.buffer.len() < required_size {
+                self.buffer.resize(required_size, 0);
             }
 
-            match ready!(kcp.poll_recv(cx, &mut self.recv_buffer)) {
+            match ready!(kcp.poll_recv(cx, &mut self.buffer)) {
                 Ok(n) => {
                     trace!("[CLIENT] recv buffered {} bytes", n);
-                    self.recv_buffer_pos = 0;
-                    self.recv_buffer_cap = n;
+                    self.pos = 0;
+                    self.cap = n;
                 }
                 Err(err) => return Err(err).into(),
             }
         }
     }
 
+    /// Receives the next data of KCP into `buffer` if it's used up, for a destination of `max_len` bytes that KCP
+    /// can't copy into
+    fn poll_recv_buffered(
+        &mut self,
+        session: &KcpSession,
+        cx: &mut Context<'_>,
+        max_len: usize,
+        split_message: bool,
+    ) -> Poll<KcpResult<()>> {
+        if self.pos < self.cap {
+            return Ok(()).into();
+        }
+
+        let mut kcp = session.lock_socket();
+        // Nothing is readable yet if it fails, `poll_recv` registers the waker then, or reports the end
+        if let Ok(required_size) = kcp.peek_size() {
+            if !kcp.is_stream() && !split_message && required_size > max_len {
+                return Err(KcpError::BufferTooSmall(required_size)).into();
+            }
+            if self.buffer.len() < required_size {
+                self.buffer.resize(required_size, 0);
+            }
+        }
+
+        let n = ready!(kcp.poll_recv(cx, &mut self.buffer))?;
+        trace!("[CLIENT] recv buffered {} bytes", n);
+        self.pos = 0;
+        self.cap = n;
+        Ok(()).into()
+    }
+}
+
+/// Closes the session when the last clone of a stream is dropped
+struct SessionCloser(Arc<KcpSession>);
+
+impl Drop for SessionCloser {
+    fn drop(&mut self) {
+        self.0.abandon();
+    }
+}
+
+impl Clone for KcpStream {
+    fn clone(&self) -> KcpStream {
+        KcpStream {
+            session: self.session.clone(),
+            closer: self.closer.clone(),
+            recv: Mutex::new(RecvState {
+                timeout: IoTimeout::new(self.read_timeout()),
+                ..Default::default()
+            }),
+            write_timeout: Mutex::new(IoTimeout::new(self.write_timeout())),
+        }
+    }
+}
+
+impl KcpStream {
+    pub async fn connect(config: &KcpConfig, addr: SocketAddr) -> KcpResult<KcpStream> {
+        config.validate()?;
+        let udp = connect_to(addr, &config.socket_options()).await?;
+
+        // Ask server to allocate one
+        let mut conv = 0;
+        if config.handshake {
+            conv = handshake::connect(&udp, addr, random_u64(), &Framing::new(config)).await?;
+        }
+
+        let socket = KcpSocket::new(config, conv, Arc::new(udp), addr, config.stream)?;
+        let (session, _) = KcpSession::new_shared(socket, config, None, None);
+
+        Ok(KcpStream::with_session(session))
+    }
+
+    /// Connects and sends `first_data` with the datagram that asks server to allocate a conv, like TCP Fast Open.
+    ///
+    /// Server creates the session on its first datagram and reads `first_data` by `recv` as soon as it's accepted,
+    /// so a request and its response take one round trip, instead of waiting for the next update to flush. Up to one
+    /// segment is carried by that datagram, the rest of it in stream mode follows once the conv is allocated, which
+    /// this waits for. With `KcpConfig::handshake`, `first_data` is flushed right after the handshake completes.
+    ///
+    /// In message mode, `first_data` is one message, which fails with `UserBufTooBig` if it doesn't fit in one
+    /// segment.
+    pub async fn connect_with_data(config: &KcpConfig, addr: SocketAddr, first_data: &[u8]) -> KcpResult<KcpStream> {
+        config.validate()?;
+        let udp = connect_to(addr, &config.socket_options()).await?;
+
+        let mut conv = 0;
+        if config.handshake {
+            conv = handshake::connect(&udp, addr, random_u64(), &Framing::new(config)).await?;
+        }
+
+        let mut socket = KcpSocket::new(config, conv, Arc::new(udp), addr, config.stream)?;
+        if !config.stream && first_data.len() > socket.mss() {
+            return Err(KcpError::Kcp(kcp::Error::UserBufTooBig));
+        }
+        // Before the session task runs, so nothing is sent ahead of it
+        let mut sent = 0;
+        if !first_data.is_empty() {
+            sent = future::poll_fn(|cx| socket.poll_send(cx, first_data)).await?;
+            socket.flush()?;
+        }
+        let (session, _) = KcpSession::new_shared(socket, config, None, None);
+
+        let mut stream = KcpStream::with_session(session);
+        while sent < first_data.len() {
+            match stream.send(&first_data[sent..]).await? {
+                0 => return Err(KcpError::ConnectionClosed),
+                n => sent += n,
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Connects by `transport` instead of a UDP socket, like `MemoryTransport` in tests.
+    ///
+    /// The session is served by a `KcpClient` of its own, whose task receives from `transport` until the stream is
+    /// closed. Fails with `ConfigInvalid` if `KcpConfig::enable_migration` is enabled, which `KcpClient` doesn't
+    /// support.
+    pub async fn connect_with_transport(
+        config: &KcpConfig,
+        transport: Arc<dyn KcpTransport>,
+        addr: SocketAddr,
+    ) -> KcpResult<KcpStream> {
+        KcpClient::with_transport(config.clone(), transport)?
+            .connect(addr)
+            .await
+    }
+
+    /// Connects with retries, for servers that may be briefly unavailable.
+    ///
+    /// Unlike `connect`, every attempt waits for a response from server, for `RetryConfig::attempt_timeout`.
+    /// Attempts that time out, are refused by server or fail on the socket are retried with a new socket and conv,
+    /// after a backoff that grows exponentially. Returns the error of the last attempt after
+    /// `RetryConfig::max_attempts`, other errors are returned immediately.
+    pub async fn connect_retry(config: &KcpConfig, addr: SocketAddr, retry: RetryConfig) -> KcpResult<KcpStream> {
+        retry.validate()?;
+
+        let mut backoff = retry.initial_backoff;
+        let mut attempt = 1;
+        loop {
+            match KcpStream::connect_verified(config, addr, retry.attempt_timeout).await {
+                Err(ref err) if attempt < retry.max_attempts && is_retryable(err) => {
+                    let delay = retry.jittered(backoff);
+                    debug!(
+                        "connect to {} failed, attempt: {}, error: {}, retrying in {:?}",
+                        addr, attempt, err, delay
+                    );
+                    time::sleep(delay).await;
+                    backoff = (backoff * 2).min(retry.max_backoff);
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Connects and waits until server is known to be reachable, so the stream isn't connected but dead.
+    ///
+    /// Unless `KcpConfig::handshake` already waited for a response, a window probe of KCP is sent, which server
+    /// answers with the conv it allocated. Fails with `Timeout` if nothing is received within `timeout`, or with
+    /// `ConnectionRefused` if nothing is listening on `addr`.
+    pub async fn connect_verified(config: &KcpConfig, addr: SocketAddr, timeout: Duration) -> KcpResult<KcpStream> {
+        match time::timeout(timeout, KcpStream::connect_confirmed(config, addr)).await {
+            Ok(result) => result,
+            Err(..) => Err(KcpError::Timeout),
+        }
+    }
+
+    /// Connects and waits for the first response from server
+    async fn connect_confirmed(config: &KcpConfig, addr: SocketAddr) -> KcpResult<KcpStream> {
+        let stream = KcpStream::connect(config, addr).await?;
+        if config.handshake {
+            // Server has responded to the handshake
+            return Ok(stream);
+        }
+
+        let mut events = stream.events();
+        // Server allocates a conv for the probe and replies with it
+        stream.session.lock_socket().probe_liveness();
+        loop {
+            match events.recv().await {
+                Ok(KcpEvent::Connected { .. }) => return Ok(stream),
+                Ok(KcpEvent::Closed) | Err(broadcast::error::RecvError::Closed) => {
+                    if stream.session.is_refused() {
+                        return Err(KcpError::ConnectionRefused);
+                    }
+                    return Err(KcpError::ConnectionClosed);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Connects with `conv` chosen by client, for servers that expect it to be configured on both ends, like kcp-go.
+    ///
+    /// `conv` is used by all segments from the beginning, instead of asking the server to allocate one. Datagrams
+    /// of other convs are dropped as protocol errors. Fails with `ConfigInvalid` if `conv` is 0, which asks for an
+    /// allocation, or if `KcpConfig::handshake` is enabled, which allocates conv by handshake.
+    pub async fn connect_with_conv(config: &KcpConfig, conv: u32, addr: SocketAddr) -> KcpResult<KcpStream> {
+        config.validate()?;
+        if conv == 0 {
+            return Err(KcpError::ConfigInvalid(
+                "conv chosen by client must not be 0".to_owned(),
+            ));
+        }
+        if config.handshake {
+            return Err(KcpError::ConfigInvalid(
+                "conv can't be chosen by client with handshake".to_owned(),
+            ));
+        }
+        let udp = connect_to(addr, &config.socket_options()).await?;
+
+        let mut socket = KcpSocket::new(config, conv, Arc::new(udp), addr, config.stream)?;
+        socket.set_strict_conv();
+        let (session, _) = KcpSession::new_shared(socket, config, None, None);
+
+        Ok(KcpStream::with_session(session))
+    }
+
+    /// Connects to `addr` through the UDP relay of a SOCKS5 proxy, for clients that can only reach it by the proxy.
+    ///
+    /// UDP ASSOCIATE is requested from `proxy`, authenticated with `auth` if it's given, and lasts as long as the
+    /// session. Every datagram carries a SOCKS5 header of 10 bytes for IPv4 `addr` or 22 bytes for IPv6 on the way
+    /// between client and relay, which `KcpConfig::mtu` should leave room for. Fails with `ConfigInvalid` if
+    /// `KcpConfig::handshake` is enabled, which isn't supported through a relay.
+    #[cfg(feature = "socks5")]
+    pub async fn connect_via_socks5(
+        config: &KcpConfig,
+        proxy: SocketAddr,
+        addr: SocketAddr,
+        auth: Option<Socks5Auth>,
+    ) -> KcpResult<KcpStream> {
+        config.validate()?;
+        if config.handshake {
+            return Err(KcpError::ConfigInvalid(
+                "handshake is not supported through a SOCKS5 relay".to_owned(),
+            ));
+        }
+        let relay = Socks5Relay::associate(proxy, addr, auth.as_ref()).await?;
+        let udp = connect_to(relay.relay_addr(), &config.socket_options()).await?;
+
+        let socket = KcpSocket::with_socks5_relay(config, Arc::new(udp), addr, config.stream, relay)?;
+        let (session, _) = KcpSession::new_shared(socket, config, None, None);
+
+        Ok(KcpStream::with_session(session))
+    }
+
+    pub(crate) fn with_session(session: Arc<KcpSession>) -> KcpStream {
+        KcpStream {
+            closer: Arc::new(SessionCloser(session.clone())),
+            session,
+            recv: Mutex::default(),
+            write_timeout: Mutex::default(),
+        }
+    }
+
+    /// A panicked poll doesn't break the handle, like `KcpSession::lock_socket`
+    fn lock_recv(&self) -> MutexGuard<'_, RecvState> {
+        self.recv.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_write_timeout(&self) -> MutexGuard<'_, IoTimeout> {
+        self.write_timeout.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// conv of the session, allocated by server if the client connected with 0
+    pub(crate) async fn conv(&self) -> u32 {
+        self.session.lock_socket().conv()
+    }
+
+    /// Sets the timeout of receiving, like `recv` and `AsyncRead`. A receive that is still waiting after `timeout`
+    /// fails with `Timeout`, which is `TimedOut` as an IO error, the session is not affected. `None` waits forever,
+    /// which is the default.
+    ///
+    /// Only applies to this handle, clones made after this have the same timeout. Fails with `ConfigInvalid` if
+    /// `timeout` is zero, like `std::net::TcpStream::set_read_timeout`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> KcpResult<()> {
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(KcpError::ConfigInvalid("read timeout must be positive".to_owned()));
+        }
+        self.lock_recv().timeout.set(timeout);
+        Ok(())
+    }
+
+    /// Sets the timeout of sending, like `send` and `AsyncWrite`, which waits while the send window is full.
+    /// See `set_read_timeout`.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> KcpResult<()> {
+        if timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(KcpError::ConfigInvalid("write timeout must be positive".to_owned()));
+        }
+        self.lock_write_timeout().set(timeout);
+        Ok(())
+    }
+
+    /// Timeout of receiving, see `set_read_timeout`
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.lock_recv().timeout.timeout
+    }
+
+    /// Timeout of sending, see `set_write_timeout`
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.lock_write_timeout().timeout
+    }
+
+    /// Address of the remote peer, changes if the session migrated
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.session.peer_addr()
+    }
+
+    /// UDP socket of this session, see `KcpListener::get_ref` for what may be done with it. Sessions of a listener
+    /// or a `KcpClient` share its socket.
+    ///
+    /// The socket is replaced when the session rebinds (see `KcpConfig::auto_rebind`), the returned one is stale
+    /// after that.
+    ///
+    /// `None` if the session is on a custom `KcpTransport`.
+    pub fn get_ref(&self) -> Option<Arc<UdpSocket>> {
+        self.session.udp_socket()
+    }
+
+    /// Subscribes to events of this session, see `KcpEvent` for which events are guaranteed to be received.
+    ///
+    /// The channel is bounded, a subscriber that falls behind loses its oldest events instead of blocking the session.
+    pub fn events(&self) -> broadcast::Receiver<KcpEvent> {
+        self.session.events()
+    }
+
+    /// Sending rate limit of this session and how much of it is used, `None` if `KcpConfig::pacing` is disabled
+    pub fn pacing_stats(&self) -> Option<PacingStats> {
+        self.session.pacing_stats()
+    }
+
+    /// Number of data segments retransmitted by this session, the link is dead when one of them is
+    /// retransmitted more than `KcpConfig::dead_link` times
+    pub fn retransmissions(&self) -> u64 {
+        self.session.retransmissions()
+    }
+
+    /// Number of data segments retransmitted by fast resend, before their retransmission timeout. Included in
+    /// `retransmissions`.
+    ///
+    /// Classified by the time since their last transmission, a fast resend shortly after the RTO changed may be
+    /// counted as a timeout.
+    pub fn fast_retransmissions(&self) -> u64 {
+        self.session.fast_retransmissions()
+    }
+
+    /// Number of datagrams sent by this session that only carry ACKs, which `FlushStrategy::OnAck` trades
+    /// for latency of acknowledgement
+    pub fn ack_only_datagrams(&self) -> u64 {
+        self.session.ack_only_datagrams()
+    }
+
+    /// Changes the fast resend threshold of this session, see `KcpConfig::fast_resend`
+    pub fn set_fast_resend(&self, resend: u32) {
+        self.session.set_fast_resend(resend)
+    }
+
+    /// Flushes ACKs after each input or not, like `FlushStrategy::OnAck` added to or removed from
+    /// `KcpConfig::flush_strategy` of this session. Applies to the next input.
+    pub fn set_flush_acks_input(&self, enabled: bool) {
+        self.session.set_flush_acks_input(enabled)
+    }
+
+    /// Re-tunes `KcpConfig::nodelay` of this session, like disabling the congestion window with `nc`.
+    ///
+    /// Applies to the next flush. `KcpConfig::rx_minrto` and `fast_resend` (or `set_fast_resend`) still override
+    /// it, and `nc` is always on unless `KcpConfig::congestion` is `CongestionMode::Default`. Only changes this
+    /// side, peer keeps its own.
+    pub fn set_nodelay(&self, nodelay: KcpNoDelayConfig) {
+        self.session.set_nodelay(nodelay)
+    }
+
+    /// Number of times the UDP socket was replaced because sends kept failing, see `KcpConfig::auto_rebind`
+    pub fn rebinds(&self) -> u64 {
+        self.session.rebinds()
+    }
+
+    /// Number of datagrams from peer dropped because their CRC32 didn't match, see `KcpConfig::checksum`.
+    ///
+    /// Always 0 for streams accepted by `KcpListener`, which verifies datagrams before they reach sessions,
+    /// see `KcpListener::corrupted_packets`.
+    pub fn corrupted_packets(&self) -> u64 {
+        self.session.corrupted_packets()
+    }
+
+    /// Changes MTU of this session without reconnecting, for applications that probe the path by themselves.
+    ///
+    /// Applies to data sent after this call, segments already queued or in flight keep their size. Fails with
+    /// `ConfigInvalid`, which is `InvalidInput` as `io::Error`, if `mtu` is smaller than KCP allows or larger than
+    /// `BufferPoolConfig::size`. Peer doesn't have to change its MTU, but its buffers must fit the datagrams.
+    pub fn set_mtu(&self, mtu: usize) -> KcpResult<()> {
+        self.session.set_mtu(mtu)
+    }
+
+    /// Current MTU of this session, `KcpConfig::mtu` unless changed by `set_mtu`
+    pub fn mtu(&self) -> usize {
+        self.session.mtu()
+    }
+
+    /// Largest message that `send_msg` takes, in bytes.
+    ///
+    /// A message is sent as up to 127 segments of `mtu - 24` bytes each, where 24 is the header of KCP and `mtu` is
+    /// the current MTU less the overhead of `KcpConfig::checksum` and `KcpConfig::obfuscator`. Peer has to hold all
+    /// of them in its receive window to reassemble the message, `min(rcv_wnd, 128) - 1` segments, and KCP never
+    /// sets a receive window below 128 whatever `KcpConfig::wnd_size` is. That is 174752 bytes with the default
+    /// MTU of 1400. It changes with the MTU, by `set_mtu` or path MTU discovery.
+    pub fn max_message_size(&self) -> usize {
+        self.session.max_message_size()
+    }
+
+    /// Congestion control of this session, for checking which `KcpConfig::congestion` mode is effective
+    pub fn congestion_stats(&self) -> CongestionStats {
+        self.session.congestion_stats()
+    }
+
+    /// Estimated bandwidth of the path to peer in bytes per second, for adapting the bitrate of a media stream.
+    ///
+    /// It is the highest rate that data sent by this stream was acknowledged at, over the last 10 round trips. Rates
+    /// are sampled from the acknowledged payload and the time it took, like the delivery rate estimator of BBR.
+    /// `None` until data has been sent and acknowledged for a whole round trip.
+    ///
+    /// The estimate only reaches the bandwidth while sends fill the path, it's lower if the application sends less,
+    /// or if the send window is smaller than the bandwidth-delay product. Headers are not counted, and a bandwidth
+    /// that drops is noticed after the window only. Data received from peer doesn't count at all.
+    pub fn bandwidth_estimate(&self) -> Option<u64> {
+        self.session.bandwidth_estimate()
+    }
+
+    /// Maximum data segments in flight, the send window limited by the receive window of peer, see
+    /// `CongestionStats::cwnd`. Without `set_cwnd`, KCP's own congestion window may be smaller, see
+    /// `CongestionStats::kcp_window`.
+    #[cfg(feature = "experimental-cc")]
+    pub fn cwnd(&self) -> u16 {
+        self.session.cwnd()
+    }
+
+    /// Bytes of data sent and not acknowledged yet, for an external congestion controller
+    #[cfg(feature = "experimental-cc")]
+    pub fn bytes_in_flight(&self) -> usize {
+        self.session.bytes_in_flight()
+    }
+
+    /// Takes over congestion control, for a controller outside of the crate: sends are limited to `cwnd` data
+    /// segments in flight, between 1 and the send window of `KcpConfig::wnd_size`, until it is set again. KCP still
+    /// retransmits lost segments.
+    ///
+    /// KCP's congestion window is disabled for this session from now on, and the window of the controller of
+    /// `CongestionMode::Custom` is ignored, its pacing rate still applies. Nothing else backs off on loss, a window
+    /// larger than the path can take keeps it congested, and the retransmissions make it worse, up to a congestion
+    /// collapse. Experimental, it may change in minor releases.
+    #[cfg(feature = "experimental-cc")]
+    pub fn set_cwnd(&self, cwnd: u16) {
+        self.session.set_cwnd(cwnd)
+    }
+
+    /// Data received and not read yet, for sizing batches of reads and noticing that peer is far ahead.
+    ///
+    /// Counted under the lock of the session. The rest of a message partially read by `AsyncRead` of this handle
+    /// counts as one ready message, or its bytes in stream mode.
+    pub fn recv_queue_len(&self) -> RecvQueueLen {
+        let recv = self.lock_recv();
+        let socket = self.session.lock_socket();
+        let mut len = socket.recv_queue_len();
+        let buffered = recv.cap - recv.pos;
+        if buffered > 0 {
+            len.ready += if socket.is_stream() { buffered } else { 1 };
+        }
+        len
+    }
+
+    /// Whether peer has acknowledged all data sent so far, read under the lock of the session.
+    ///
+    /// A cheap check for polling in loops of the caller, `flush_acked` waits for the same condition. Data that is
+    /// sent after it returns isn't counted, and it is `true` before anything was sent.
+    pub fn all_acked(&self) -> bool {
+        self.session.lock_socket().all_acked()
+    }
+
+    /// Snapshots the state of this session for troubleshooting, like queues, RTT estimates and segments in flight.
+    ///
+    /// Taken under the lock of the session, which is held while the segments in flight are copied. Unlike
+    /// `debug_state`, it is cheap enough for logging a misbehaving connection in production, see
+    /// `SessionDiagnostics`.
+    pub fn dump_state(&self) -> SessionDiagnostics {
+        self.session.diagnostics()
+    }
+
+    /// Snapshots the control block of KCP under the lock of the session, for diagnostics tools.
+    ///
+    /// It copies all segments in flight, don't call it on hot paths. **The format is unstable**, see `KcpDebugState`.
+    #[cfg(feature = "debug-internals")]
+    pub fn debug_state(&self) -> KcpDebugState {
+        self.session.debug_state()
+    }
+
+    /// Splits the stream into a receiving and a sending half, which can be moved to different tasks.
+    ///
+    /// The session is closed after both halves are dropped, in the same way as dropping the stream: FIN is sent
+    /// after all data sent has been acknowledged, bounded by `KcpConfig::linger`. Dropping only one of them closes
+    /// nothing, see `OwnedReadHalf` and `OwnedWriteHalf`. Clones of the stream that are still alive keep the session
+    /// open as well.
+    pub fn into_split(self) -> (OwnedReadHalf, OwnedWriteHalf) {
+        let write = OwnedWriteHalf::new(self.clone());
+        // Data partially read stays with the read half
+        (OwnedReadHalf::new(self), write)
+    }
+
+    /// Wraps the stream into a `Sink` and `Stream` of messages as `Bytes`, see `KcpMessageStream`.
+    ///
+    /// For relaying with `StreamExt::forward` or `SinkExt::send_all` without buffers of its own. Use clones of the
+    /// stream, or `KcpMessageStream::get_ref`, for the other methods.
+    #[cfg(feature = "bytes")]
+    pub fn into_messages(self) -> KcpMessageStream {
+        KcpMessageStream::new(self)
+    }
+
+    /// Sends data in `buf`, the primitive that `send` and `AsyncWrite` are built on.
+    ///
+    /// Returns `Pending` while the send window is full, the task of `cx` is woken once acknowledgements make room,
+    /// or the session fails. The session is only locked during the call, and a waker is kept once per task, until
+    /// the next wakeup, so polling again or giving up on a send doesn't accumulate them. It takes `&self`, sends and
+    /// receives of a handle can be polled together, like by a combinator that holds a reference to the stream.
+    ///
+    /// In message mode, `buf` is sent as one message. In stream mode, a part of it may be accepted.
+    pub fn poll_send(&self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        self.poll_send_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    /// Sends data in `bufs` without concatenating them first.
+    ///
+    /// In message mode, `bufs` are sent as one message.
+    pub fn poll_send_vectored(&self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        self.poll_send_split(cx, bufs, false)
+    }
+
+    /// `split_message` for `AsyncWrite`, which writes a byte stream and may write partially
+    fn poll_send_split(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+        split_message: bool,
+    ) -> Poll<KcpResult<usize>> {
+        let mut write_timeout = self.lock_write_timeout();
+        let result = self.poll_send_session(cx, bufs, split_message);
+        write_timeout.poll(cx, result)
+    }
+
+    fn poll_send_session(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+        split_message: bool,
+    ) -> Poll<KcpResult<usize>> {
+        let mut kcp = self.session.lock_socket();
+
+        let result = if split_message {
+            kcp.poll_write_vectored(cx, bufs)
+        } else {
+            kcp.poll_send_vectored(cx, bufs)
+        };
+        if let Poll::Ready(Ok(n)) = result {
+            if n > 0 {
+                self.session.notify_sent(&kcp);
+            }
+        }
+        result
+    }
+
+    /// Closes the stream gracefully, returns when all data sent has been acknowledged by peer.
+    ///
+    /// Peer is notified with FIN after that, like a TCP socket closed with `SO_LINGER`. Fails if the data can't be
+    /// delivered, like `Timeout` if peer stops responding. Wrap it with `tokio::time::timeout` to wait for a limited
+    /// time, the session keeps sending in background after that, like a dropped stream does.
+    ///
+    /// The session is closed for all clones of this stream. Use `flush_acked` to wait without closing.
+    pub async fn finish(self) -> KcpResult<()> {
+        self.session.close();
+        self.drained().await
+    }
+
+    /// Consumes the stream, returns data received and not read yet, which would be discarded by dropping it.
+    ///
+    /// This includes the rest of a message partially read by this handle, and every complete message that has
+    /// arrived, concatenated in message mode. Also after peer closed the session, or it was broken by an error.
+    /// Messages whose fragments have not all arrived are not, and clones of this stream don't get anything that was
+    /// taken. The stream is dropped after that, like by `drop`.
+    pub fn into_remaining(self) -> Vec<u8> {
+        let recv = self.lock_recv();
+        let mut remaining = recv.buffer[recv.pos..recv.cap].to_vec();
+        remaining.extend_from_slice(&self.session.lock_socket().take_received());
+        remaining
+    }
+
+    /// Shuts down the stream for writing, like `shutdown(SHUT_WR)` of TCP, for a request that is followed by the
+    /// response on the same stream.
+    ///
+    /// Sends fail with `WriteShutdown` from now on, in all clones of the stream. Data sent before is delivered and
+    /// retransmitted as usual, then peer is notified, whose receives return EOF after the data. Receiving on this
+    /// side goes on until peer shuts down for writing too, or closes, then the session is closed. Also called by
+    /// `AsyncWriteExt::shutdown`.
+    ///
+    /// Peers that don't know the notification, which is not a standard KCP command, reject it like FIN.
+    pub fn shutdown_write(&self) -> KcpResult<()> {
+        self.session.shutdown_write()
+    }
+
+    /// Sends `buf` to peer in a datagram of its own, beside the reliable data, over the same socket and conv.
+    ///
+    /// It is sent immediately and never retransmitted, so it may be lost, duplicated or reordered like UDP, but a lost
+    /// one never holds the data sent by `send`. Peer gets it by `recv_unreliable`. Fails with `Kcp(UserBufTooBig)` if
+    /// `buf` is larger than one segment of KCP, and with `ConnectionClosed` after either side closed, or this side shut
+    /// down for writing.
+    ///
+    /// Datagrams sent before the conv is allocated by the server are dropped. Peers that don't know them, which are
+    /// not a standard KCP command, reject them like FIN.
+    pub fn send_unreliable(&self, buf: &[u8]) -> KcpResult<()> {
+        self.session.lock_socket().send_unreliable(buf)
+    }
+
+    /// Receives a datagram sent by `send_unreliable` of peer into `buf`, returns its length.
+    ///
+    /// If `buf` is smaller than the datagram, it fails with `KcpError::BufferTooSmall` carrying its length, and it is
+    /// kept for a retry with a larger buffer. The latest 256 datagrams that have not been received are kept, older
+    /// ones are dropped. Fails with `ConnectionClosed` when the session is closed and all of them were received.
+    pub async fn recv_unreliable(&self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_recv_unreliable(cx, buf)).await
+    }
+
+    /// `recv_unreliable` for manual polling, the task of `cx` is woken when a datagram arrives
+    pub fn poll_recv_unreliable(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.session.lock_socket().poll_recv_unreliable(cx, buf)
+    }
+
+    /// Sends segments queued in KCP now, without waiting for the next update
+    pub(crate) fn flush_kcp(&self) -> KcpResult<()> {
+        self.session.lock_socket().flush()
+    }
+
+    /// Flushes, then returns when all data sent has been acknowledged by peer, the stream stays open.
+    ///
+    /// Fails if the data can't be delivered: `Timeout` if the link is considered dead, or the error that broke the
+    /// session, `ConnectionClosed` if either side closed it meanwhile.
+    pub async fn flush_acked(&mut self) -> KcpResult<()> {
+        self.session.lock_socket().flush()?;
+        self.drained().await
+    }
+
+    /// `flush_acked` that fails with `Timeout` if data hasn't been acknowledged in `timeout`.
+    ///
+    /// Unacknowledged data is still retransmitted after that.
+    pub async fn flush_acked_timeout(&mut self, timeout: Duration) -> KcpResult<()> {
+        match time::timeout(timeout, self.flush_acked()).await {
+            Ok(result) => result,
+            Err(..) => Err(KcpError::Timeout),
+        }
+    }
+
+    /// Returns when all data sent has been acknowledged by peer
+    async fn drained(&self) -> KcpResult<()> {
+        future::poll_fn(|cx| {
+            let mut kcp = self.session.lock_socket();
+            kcp.poll_drained(cx)
+        })
+        .await
+    }
+
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.lock_write_timeout().restart();
+        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    }
+
+    /// Sends data in `bufs` in order, without concatenating them first, see `poll_send_vectored`.
+    ///
+    /// Takes `&self`, so a header and a body can be sent through a shared reference to the stream. Sends of one
+    /// handle share its write timeout.
+    pub async fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> KcpResult<usize> {
+        self.lock_write_timeout().restart();
+        future::poll_fn(|cx| self.poll_send_vectored(cx, bufs)).await
+    }
+
+    /// Sends all of `data`, returns its length.
+    ///
+    /// `data` is sliced without copying if it has to be sent in several parts, but KCP still copies
+    /// every part into its segments, the same as `send`.
+    #[cfg(feature = "bytes")]
+    pub async fn send_bytes(&mut self, mut data: Bytes) -> KcpResult<usize> {
+        let len = data.len();
+        while !data.is_empty() {
+            let n = self.send(&data).await?;
+            if n == 0 {
+                // Closed
+                return Ok(len - data.len());
+            }
+            data.advance(n);
+        }
+        Ok(len)
+    }
+
+    /// Receives data into `buf`, the primitive that `recv` and `AsyncRead` are built on.
+    ///
+    /// In message mode, one message is received at a time. If `buf` is smaller than the next message, it fails with
+    /// `KcpError::BufferTooSmall` carrying the size of the message, which is kept for a retry with a larger buffer.
+    ///
+    /// Returns `Pending` until data arrives, the task of `cx` is woken then, or when the session closes or fails.
+    /// Wakers are kept as by `poll_send`.
+    pub fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.poll_recv_split(cx, buf, false)
+    }
+
+    /// `split_message` for `AsyncRead`, which reads a byte stream and may read a message partially
+    fn poll_recv_split(&self, cx: &mut Context<'_>, buf: &mut [u8], split_message: bool) -> Poll<KcpResult<usize>> {
+        let mut recv = self.lock_recv();
+        let result = recv.poll_recv(&self.session, cx, buf, split_message);
+        recv.timeout.poll(cx, result)
+    }
+
+    /// Receives data into `buf`, see `poll_recv`
     pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        self.lock_recv().timeout.restart();
         future::poll_fn(|cx| self.poll_recv(cx, buf)).await
     }
+
+    /// Receives into the unfilled part of `buf`, which doesn't have to be initialized.
+    ///
+    /// If it is initialized, like a `ReadBuf` of `AsyncReadExt::read`, KCP copies into it directly, the same as
+    /// `poll_recv`. Otherwise data is received into a buffer of the stream and copied from there, which is only
+    /// zeroed when it grows, so the uninitialized part of `buf` is never zeroed.
+    pub fn poll_recv_buf(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<KcpResult<usize>> {
+        self.poll_recv_buf_split(cx, buf, false)
+    }
+
+    fn poll_recv_buf_split(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+        split_message: bool,
+    ) -> Poll<KcpResult<usize>> {
+        if buf.initialized().len() - buf.filled().len() >= buf.remaining() {
+            // Doesn't write anything, all of it is initialized
+            let dst = buf.initialize_unfilled();
+            let n = ready!(self.poll_recv_split(cx, dst, split_message))?;
+            buf.advance(n);
+            return Ok(n).into();
+        }
+
+        let mut recv = self.lock_recv();
+        let result = recv.poll_recv_buffered(&self.session, cx, buf.remaining(), split_message);
+        ready!(recv.timeout.poll(cx, result))?;
+        let n = (recv.cap - recv.pos).min(buf.remaining());
+        buf.put_slice(&recv.buffer[recv.pos..recv.pos + n]);
+        recv.pos += n;
+        Ok(n).into()
+    }
+
+    pub async fn recv_buf(&mut self, buf: &mut ReadBuf<'_>) -> KcpResult<usize> {
+        self.lock_recv().timeout.restart();
+        future::poll_fn(|cx| self.poll_recv_buf(cx, buf)).await
+    }
+
+    /// Sends all of `buf` as one message, which peer receives whole by `recv_msg`, or `recv` with a large enough
+    /// buffer.
+    ///
+    /// Waits while the send window is full, then queues the whole message at once, never a part of it. Fails with
+    /// `MessageTooLarge` carrying `max_message_size` before anything is sent if `buf` is larger, and with
+    /// `ConfigInvalid` in stream mode, where messages have no boundaries. An empty message is not sent, peer would
+    /// take it for the end.
+    pub async fn send_msg(&mut self, buf: &[u8]) -> KcpResult<()> {
+        {
+            let kcp = self.session.lock_socket();
+            if kcp.is_stream() {
+                return Err(KcpError::ConfigInvalid(
+                    "messages need KcpConfig::stream disabled".to_owned(),
+                ));
+            }
+            if buf.len() > kcp.max_send_len() {
+                return Err(KcpError::MessageTooLarge(kcp.max_send_len()));
+            }
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        self.lock_write_timeout().restart();
+        future::poll_fn(|cx| {
+            let mut write_timeout = self.lock_write_timeout();
+            let result = self.session.poll_send_whole(cx, buf);
+            write_timeout.poll(cx, result)
+        })
+        .await
+    }
+
+    /// Receives one whole message sent by peer into `buf`, returns its length.
+    ///
+    /// `buf` is replaced by the message, and grown if it's larger than the capacity of `buf`, so nothing is kept back
+    /// like by `recv` with `BufferTooSmall`. Reusing `buf` for the next message saves the allocation. Returns 0 with
+    /// `buf` empty after peer closed or shut down for writing, and all messages before were received. Fails with
+    /// `ConfigInvalid` in stream mode, where messages have no boundaries.
+    ///
+    /// Fails with `PartialMessage` if `AsyncRead` of this handle has read a part of a message, the rest of it is
+    /// never returned as a whole message. Read it by `recv` or `AsyncRead` first. The content of `buf` is unspecified
+    /// if the future is dropped before it completes.
+    pub async fn recv_msg(&mut self, buf: &mut Vec<u8>) -> KcpResult<usize> {
+        if self.session.lock_socket().is_stream() {
+            return Err(KcpError::ConfigInvalid(
+                "messages need KcpConfig::stream disabled".to_owned(),
+            ));
+        }
+        {
+            let mut recv = self.lock_recv();
+            if recv.pos < recv.cap {
+                return Err(KcpError::PartialMessage(recv.cap - recv.pos));
+            }
+            recv.timeout.restart();
+        }
+
+        // Only zeroed once, and where it grows for a larger message
+        buf.resize(buf.capacity(), 0);
+        let result = future::poll_fn(|cx| loop {
+            match ready!(self.poll_recv(cx, buf)) {
+                Err(KcpError::BufferTooSmall(size)) => buf.resize(size, 0),
+                result => return result.into(),
+            }
+        })
+        .await;
+        match result {
+            Ok(n) => buf.truncate(n),
+            Err(..) => buf.clear(),
+        }
+        result
+    }
+}
+
+/// Errors that a later attempt of connecting may not have
+fn is_retryable(err: &KcpError) -> bool {
+    matches!(
+        err,
+        KcpError::Timeout | KcpError::ConnectionClosed | KcpError::ConnectionRefused | KcpError::IoError(..)
+    )
 }
 
 impl AsyncRead for KcpStream {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        match ready!(self.poll_recv(cx, buf.initialize_unfilled())) {
-            Ok(n) => {
-                buf.advance(n);
-                Ok(()).into()
-            }
-            Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match ready!(self.poll_recv_buf_split(cx, buf, true)) {
+            Ok(..) => Ok(()).into(),
+            Err(err) => Err(err.into()).into(),
         }
     }
 }
 
 impl AsyncWrite for KcpStream {
-    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        match ready!(self.poll_send(cx, buf)) {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match ready!(self.poll_send_split(cx, &[IoSlice::new(buf)], true)) {
             Ok(n) => Ok(n).into(),
-            Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+            Err(err) => Err(err.into()).into(),
         }
     }
 
-    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        // Mutex doesn't have poll_lock, spinning on it.
-        let socket = self.session.kcp_socket();
-        let mut kcp = match socket.try_lock() {
-            Ok(guard) => guard,
-            Err(..) => {
-                cx.waker().wake_by_ref();
-                return Poll::Pending;
-            }
-        };
-
-        match kcp.flush() {
-            Ok(..) => Ok(()).into(),
-            Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match ready!(self.poll_send_split(cx, bufs, true)) {
+            Ok(n) => Ok(n).into(),
+            Err(err) => Err(err.into()).into(),
         }
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Ok(()).into()
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.flush_kcp().map_err(io::Error::from).into()
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.shutdown_write().map_err(io::Error::from).into()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{self, IoSlice},
+        mem::MaybeUninit,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        task::{Context, Poll},
+        time::Duration,
+    };
+
+    use bytes::{Buf, Bytes};
+    use futures::{future, task::noop_waker_ref, SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+        net::UdpSocket,
+        sync::broadcast,
+        time::{self, Instant},
+    };
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    use super::KcpStream;
+    use crate::{
+        config::{FlushStrategy, KcpConfig, KcpNoDelayConfig, PmtuConfig, RetryConfig},
+        congestion::{CongestionController, CongestionMode, CongestionStats, ControllerFactory},
+        error::KcpError,
+        memory::MemoryTransport,
+        skcp::{KCP_CMD_ACK, KCP_CMD_PUSH, KCP_HEADER_LEN},
+        utils::now_millis,
+        KcpEvent, KcpListener, PacingConfig, RecvQueueLen,
+    };
+
+    #[tokio::test]
+    async fn dead_link() {
+        let _ = env_logger::try_init();
+
+        // Never replies
+        let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = blackhole.local_addr().unwrap();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            dead_link: Some(3),
+            ..Default::default()
+        };
+
+        let mut stream = KcpStream::connect(&config, addr).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+
+        // RTO starts from 200ms, increases 1.5x in nodelay mode: 200ms + 300ms
+        let start = Instant::now();
+        let mut buf = [0u8; 1024];
+        let err = time::timeout(Duration::from_secs(3), stream.recv(&mut buf))
+            .await
+            .expect("dead link not detected")
+            .unwrap_err();
+        match err {
+            KcpError::Timeout => {}
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+
+        // Following calls fail too
+        assert!(stream.send(b"HELLO WORLD").await.is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn dead_link_netem() {
+        use crate::NetEmConfig;
+
+        let _ = env_logger::try_init();
+
+        let server_config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        // Peer is gone, nothing goes through
+        let client_config = KcpConfig {
+            dead_link: Some(5),
+            test_netem: Some(NetEmConfig {
+                loss_rate: 1.0,
+                ..Default::default()
+            }),
+            ..server_config.clone()
+        };
+
+        let listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&client_config, server_addr).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+
+        let mut buf = [0u8; 1024];
+        let err = time::timeout(Duration::from_secs(10), stream.recv(&mut buf))
+            .await
+            .expect("dead link not detected")
+            .unwrap_err();
+        match err {
+            KcpError::Timeout => {}
+            err => panic!("unexpected error: {:?}", err),
+        }
+        assert!(stream.retransmissions() >= 4);
+        assert!(stream.send(b"HELLO WORLD").await.is_err());
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn connect_with_data() {
+        use crate::{FlushStrategy, NetEmConfig};
+
+        let _ = env_logger::try_init();
+
+        let server_config = KcpConfig {
+            flush_strategy: FlushStrategy::OnWrite,
+            ..Default::default()
+        };
+        // Round trip of 200ms
+        let client_config = KcpConfig {
+            test_netem: Some(NetEmConfig {
+                extra_latency: Duration::from_millis(100),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 1024];
+            let n = server.recv(&mut buf).await.unwrap();
+            assert_eq!(b"HELLO", &buf[..n]);
+            server.send(b"WORLD").await.unwrap();
+            // Keep the session until client received
+            let _ = server.recv(&mut buf).await;
+        });
+
+        // Relays datagrams between client and server, counting the ones from client until the response passes
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let relay_addr = relay.local_addr().unwrap();
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        upstream.connect(server_addr).await.unwrap();
+        let (first_tx, first_rx) = tokio::sync::oneshot::channel();
+        let (response_tx, response_rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let mut first_tx = Some(first_tx);
+            let mut response_tx = Some(response_tx);
+            let mut client_addr = None;
+            let mut from_client = 0;
+            let mut buf = [0u8; 2048];
+            let mut upstream_buf = [0u8; 2048];
+            loop {
+                tokio::select! {
+                    Ok((n, addr)) = relay.recv_from(&mut buf) => {
+                        client_addr = Some(addr);
+                        from_client += 1;
+                        if let Some(tx) = first_tx.take() {
+                            let _ = tx.send(buf[..n].to_vec());
+                        }
+                        let _ = upstream.send(&buf[..n]).await;
+                    }
+                    Ok(n) = upstream.recv(&mut upstream_buf) => {
+                        let buf = &upstream_buf[..n];
+                        if buf.windows(5).any(|w| w == b"WORLD") {
+                            if let Some(tx) = response_tx.take() {
+                                let _ = tx.send(from_client);
+                            }
+                        }
+                        if let Some(addr) = client_addr {
+                            let _ = relay.send_to(buf, addr).await;
+                        }
+                    }
+                }
+            }
+        });
+
+        let start = Instant::now();
+        let mut stream = KcpStream::connect_with_data(&client_config, relay_addr, b"HELLO")
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"WORLD", &buf[..n]);
+        // Not earlier than the round trip of the link
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        // One round trip: the first datagram carries the request, and server responds to it alone
+        let first = first_rx.await.unwrap();
+        assert!(
+            first.windows(5).any(|w| w == b"HELLO"),
+            "request not in the first datagram"
+        );
+        assert_eq!(
+            response_rx.await.unwrap(),
+            1,
+            "datagrams from client before the response"
+        );
+
+        // A message is carried by one segment
+        let message_config = KcpConfig {
+            stream: false,
+            ..Default::default()
+        };
+        match KcpStream::connect_with_data(&message_config, server_addr, &[0u8; 2048]).await {
+            Err(KcpError::Kcp(kcp::Error::UserBufTooBig)) => {}
+            result => panic!("unexpected result: {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[tokio::test]
+    async fn recv_buffered_rest() {
+        let _ = env_logger::try_init();
+
+        let mut listener = KcpListener::bind(KcpConfig::default(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+        let data: Vec<u8> = (0..1300).map(|i| i as u8).collect();
+        stream.send(&data).await.unwrap();
+
+        // The segment doesn't fit, it's buffered and read partially
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4096];
+        let n = server.recv(&mut buf[..500]).await.unwrap();
+        assert_eq!(&buf[..n], &data[..500]);
+
+        // The rest is shorter than the buffer
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], &data[500..]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_write() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let request = (0..256 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut client_events = stream.events();
+        let expected = request.clone();
+        let server = tokio::spawn(async move {
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            server.read_to_end(&mut received).await.unwrap();
+            assert!(received == expected, "request corrupted");
+
+            // Still writable after peer shut down
+            server.write_all(b"RESPONSE").await.unwrap();
+            server.shutdown().await.unwrap();
+            let mut events = server.events();
+            loop {
+                if events.recv().await.unwrap() == KcpEvent::Closed {
+                    break;
+                }
+            }
+            // Read to the end before
+            assert_eq!(server.recv(&mut [0u8; 16]).await.unwrap(), 0);
+        });
+
+        let mut sent = 0;
+        while sent < request.len() {
+            sent += stream.send(&request[sent..]).await.unwrap();
+        }
+        // The end of the request is still queued
+        assert!(!stream.all_acked());
+        stream.shutdown_write().unwrap();
+        match stream.send(b"MORE").await {
+            Err(KcpError::WriteShutdown) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        assert_eq!(
+            stream.write_all(b"MORE").await.unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        let mut response = Vec::new();
+        time::timeout(Duration::from_secs(10), stream.read_to_end(&mut response))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response, b"RESPONSE");
+
+        // Both directions are shut down
+        time::timeout(Duration::from_secs(5), async {
+            loop {
+                if client_events.recv().await.unwrap() == KcpEvent::Closed {
+                    break;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn into_remaining() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.send(b"WORLD").await.unwrap();
+        server.send(b" AND GOODBYE").await.unwrap();
+        // Peer closes right after its last message, which has arrived
+        server.finish().await.unwrap();
+
+        // Partially read into the buffer of the stream
+        let mut buf = [0u8; 3];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"WOR");
+        assert_eq!(stream.into_remaining(), b"LD AND GOODBYE");
+    }
+
+    #[tokio::test]
+    async fn poll_manually() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        let mut buf = [0u8; 1024];
+
+        match stream.poll_send(&mut cx, b"HELLO") {
+            Poll::Ready(Ok(5)) => {}
+            poll => panic!("unexpected poll: {:?}", poll),
+        }
+        assert!(stream.poll_recv(&mut cx, &mut buf).is_pending());
+        // Session isn't left locked
+        assert_eq!(stream.mtu(), config.mtu);
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert_eq!(server.recv(&mut buf).await.unwrap(), 5);
+        server.send(b"WORLD").await.unwrap();
+        let n = loop {
+            match stream.poll_recv(&mut cx, &mut buf) {
+                Poll::Ready(result) => break result.unwrap(),
+                Poll::Pending => time::sleep(Duration::from_millis(5)).await,
+            }
+        };
+        assert_eq!(&buf[..n], b"WORLD");
+
+        // Fills the window before the session task can process any acknowledgement
+        let chunk = [0x42u8; 1024];
+        let mut sent = 0;
+        while let Poll::Ready(result) = stream.poll_send(&mut cx, &chunk) {
+            sent += result.unwrap();
+        }
+        assert!(sent > 0);
+        assert!(!stream.all_acked());
+        loop {
+            match stream.poll_send(&mut cx, &chunk) {
+                Poll::Ready(result) => {
+                    sent += result.unwrap();
+                    break;
+                }
+                Poll::Pending => time::sleep(Duration::from_millis(5)).await,
+            }
+        }
+
+        let mut received = 0;
+        while received < sent {
+            received += server.recv(&mut buf).await.unwrap();
+        }
+        assert_eq!(received, sent);
+
+        // Sends and receives of the same handle are polled concurrently through shared references
+        let mut response = [0u8; 16];
+        let (sent, received, _) = tokio::join!(
+            future::poll_fn(|cx| stream.poll_send(cx, b"PING")),
+            future::poll_fn(|cx| stream.poll_recv(cx, &mut response)),
+            async {
+                let n = server.recv(&mut buf).await.unwrap();
+                assert_eq!(&buf[..n], b"PING");
+                server.send(b"PONG").await.unwrap();
+            }
+        );
+        assert_eq!(sent.unwrap(), 4);
+        assert_eq!(&response[..received.unwrap()], b"PONG");
+    }
+
+    #[cfg(feature = "experimental-cc")]
+    #[tokio::test]
+    async fn set_cwnd() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            wnd_size: (128, 128),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 64 * 1024];
+        server.recv(&mut buf).await.unwrap();
+        stream.flush_acked().await.unwrap();
+        assert!(stream.congestion_stats().kcp_window);
+        assert_eq!(stream.bytes_in_flight(), 0);
+
+        stream.set_cwnd(16);
+        assert_eq!(stream.cwnd(), 16);
+        assert!(!stream.congestion_stats().kcp_window);
+        // Between 1 and the send window
+        stream.set_cwnd(0);
+        assert_eq!(stream.cwnd(), 1);
+        stream.set_cwnd(1000);
+        assert_eq!(stream.cwnd(), 128);
+
+        stream.set_cwnd(4);
+        let mss = stream.mtu() - KCP_HEADER_LEN;
+        let mut writer = stream.clone();
+        let sender = tokio::spawn(async move { writer.write_all(&[0x42u8; 64 * 1024]).await.unwrap() });
+        let mut max_in_flight = 0;
+        let mut received = 0;
+        while received < 64 * 1024 {
+            max_in_flight = max_in_flight.max(stream.bytes_in_flight());
+            received += server.recv(&mut buf).await.unwrap();
+        }
+        sender.await.unwrap();
+        assert!(
+            max_in_flight > 0 && max_in_flight <= 4 * mss,
+            "{} in flight",
+            max_in_flight
+        );
+
+        stream.flush_acked().await.unwrap();
+        assert_eq!(stream.bytes_in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn unreliable() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        server.recv(&mut buf).await.unwrap();
+        // Conv is allocated by then
+        stream.flush_acked().await.unwrap();
+
+        stream.send_unreliable(b"PING").unwrap();
+        let n = time::timeout(Duration::from_secs(5), server.recv_unreliable(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"PING", &buf[..n]);
+        server.send_unreliable(b"PONG").unwrap();
+        let n = time::timeout(Duration::from_secs(5), stream.recv_unreliable(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"PONG", &buf[..n]);
+
+        // Kept for a larger buffer
+        stream.send_unreliable(b"HELLO WORLD").unwrap();
+        let mut small = [0u8; 4];
+        match time::timeout(Duration::from_secs(5), server.recv_unreliable(&mut small))
+            .await
+            .unwrap()
+        {
+            Err(KcpError::BufferTooSmall(11)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+        let n = server.recv_unreliable(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO WORLD", &buf[..n]);
+
+        // Not carried by the reliable stream
+        assert_eq!(server.recv_queue_len().ready, 0);
+        let mss = stream.mtu() - KCP_HEADER_LEN;
+        match stream.send_unreliable(&vec![0u8; mss + 1]) {
+            Err(KcpError::Kcp(kcp::Error::UserBufTooBig)) => {}
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn unreliable_loss() {
+        use crate::NetEmConfig;
+
+        let _ = env_logger::try_init();
+
+        let client_config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        // Loses what server receives
+        let server_config = KcpConfig {
+            test_netem: Some(NetEmConfig {
+                loss_rate: 0.3,
+                seed: 11,
+                ..Default::default()
+            }),
+            ..client_config.clone()
+        };
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const COUNT: u32 = 100;
+        let mut stream = KcpStream::connect(&client_config, server_addr).await.unwrap();
+        stream.write_all(&COUNT.to_be_bytes()).await.unwrap();
+        time::timeout(Duration::from_secs(10), stream.flush_acked())
+            .await
+            .unwrap()
+            .unwrap();
+        for i in 0..COUNT {
+            stream.send_unreliable(&i.to_be_bytes()).unwrap();
+            stream.write_all(&i.to_be_bytes()).await.unwrap();
+        }
+
+        let (mut server, _) = time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        // All in order, although the datagrams between them were lost
+        for i in 0..COUNT {
+            let mut buf = [0u8; 4];
+            time::timeout(Duration::from_secs(10), server.read_exact(&mut buf))
+                .await
+                .expect("reliable stream stalled")
+                .unwrap();
+            assert_eq!(u32::from_be_bytes(buf), i);
+        }
+
+        let mut received = Vec::new();
+        let mut buf = [0u8; 4];
+        while let Ok(result) = time::timeout(Duration::from_millis(200), server.recv_unreliable(&mut buf)).await {
+            assert_eq!(result.unwrap(), 4);
+            received.push(u32::from_be_bytes(buf));
+        }
+        assert!(
+            !received.is_empty() && received.len() < COUNT as usize,
+            "{} received",
+            received.len()
+        );
+        assert!(received.iter().all(|&i| i < COUNT));
+    }
+
+    #[tokio::test]
+    async fn idle_wakeups() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        server.recv(&mut buf).await.unwrap();
+        server.send(b"WORLD").await.unwrap();
+        stream.recv(&mut buf).await.unwrap();
+
+        // Let the last ACKs go
+        time::sleep(Duration::from_millis(500)).await;
+
+        let updates = stream.session.update_count();
+        time::sleep(Duration::from_secs(3)).await;
+        let idle_updates = stream.session.update_count() - updates;
+
+        // Ticking every 40ms would be 75 updates
+        assert!(idle_updates <= 5, "{} updates while idle", idle_updates);
+
+        // Still works after idle
+        stream.send(b"HELLO").await.unwrap();
+        let n = time::timeout(Duration::from_secs(1), server.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+    }
+
+    #[tokio::test]
+    async fn connection_events() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut events = stream.events();
+        stream.send(b"HELLO").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).await.unwrap();
+        server.send(&buf[..n]).await.unwrap();
+        stream.recv(&mut buf).await.unwrap();
+
+        // Peer closed, then drained
+        drop(server);
+
+        let mut received = Vec::new();
+        loop {
+            let event = time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("session not closed")
+                .unwrap();
+            received.push(event);
+            if event == KcpEvent::Closed {
+                break;
+            }
+        }
+
+        assert_eq!(received[0], KcpEvent::Connected { conv: 1 });
+        assert!(received.contains(&KcpEvent::DataReceived { bytes: 5 }));
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn netem_retransmission() {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        use crate::NetEmConfig;
+
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            test_netem: Some(NetEmConfig {
+                loss_rate: 0.1,
+                dup_rate: 0.05,
+                reorder_rate: 0.1,
+                extra_latency: Duration::from_millis(5),
+                seed: 7,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 64 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut events = stream.events();
+        stream.write_all(&data).await.unwrap();
+
+        let (mut server, _) = time::timeout(Duration::from_secs(5), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        let mut received = vec![0u8; DATA_SIZE];
+        time::timeout(Duration::from_secs(10), server.read_exact(&mut received))
+            .await
+            .expect("data not delivered")
+            .unwrap();
+        assert!(received == data);
+
+        // Lost datagrams were sent again
+        let mut retransmitted = false;
+        loop {
+            match events.try_recv() {
+                Ok(KcpEvent::Retransmit { .. }) => retransmitted = true,
+                Ok(..) | Err(TryRecvError::Lagged(..)) => {}
+                Err(..) => break,
+            }
+        }
+        assert!(retransmitted);
+
+        // Recovery by fast resend, otherwise by the retransmission timeout of normal mode
+        async fn transfer(config: &KcpConfig, data: &[u8]) -> (Duration, u64) {
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let mut stream = KcpStream::connect(config, server_addr).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+
+            let start = Instant::now();
+            stream.write_all(data).await.unwrap();
+            let mut received = vec![0u8; data.len()];
+            time::timeout(Duration::from_secs(20), server.read_exact(&mut received))
+                .await
+                .expect("data not delivered")
+                .unwrap();
+            assert!(received == data);
+            (start.elapsed(), stream.fast_retransmissions())
+        }
+
+        let normal = KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nodelay: false,
+                interval: 10,
+                resend: 0,
+                nc: true,
+            },
+            ..config
+        };
+        let (rto_elapsed, fast) = transfer(&normal, &data).await;
+        assert_eq!(fast, 0);
+        let fast_resend = KcpConfig {
+            fast_resend: Some(2),
+            ..normal
+        };
+        let (fast_elapsed, fast) = transfer(&fast_resend, &data).await;
+        assert!(fast > 0);
+        assert!(
+            fast_elapsed < rto_elapsed,
+            "fast resend {:?}, rto {:?}",
+            fast_elapsed,
+            rto_elapsed
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn nocwnd_throughput() {
+        use crate::NetEmConfig;
+
+        let _ = env_logger::try_init();
+
+        // 1% loss, the congestion window of KCP collapses after each one
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nc: false,
+                ..KcpNoDelayConfig::fastest()
+            },
+            test_netem: Some(NetEmConfig {
+                loss_rate: 0.01,
+                extra_latency: Duration::from_millis(10),
+                seed: 11,
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        const DATA_SIZE: usize = 256 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        async fn transfer(config: &KcpConfig, data: &[u8], nc: bool) -> Duration {
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let mut stream = KcpStream::connect(config, server_addr).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+
+            // Re-tuned at runtime
+            stream.set_nodelay(KcpNoDelayConfig { nc, ..config.nodelay });
+            assert_eq!(stream.congestion_stats().kcp_window, !nc);
+
+            let start = Instant::now();
+            stream.write_all(data).await.unwrap();
+            let mut received = vec![0u8; data.len()];
+            time::timeout(Duration::from_secs(30), server.read_exact(&mut received))
+                .await
+                .expect("data not delivered")
+                .unwrap();
+            assert!(received == data);
+            start.elapsed()
+        }
+
+        let with_cwnd = transfer(&config, &data, false).await;
+        let without_cwnd = transfer(&config, &data, true).await;
+        assert!(
+            without_cwnd * 2 < with_cwnd,
+            "nocwnd {:?}, cwnd {:?}",
+            without_cwnd,
+            with_cwnd
+        );
+    }
+
+    #[tokio::test]
+    async fn finish_delivers_all() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 256 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut events = stream.events();
+        stream.write_all(&data).await.unwrap();
+        time::timeout(Duration::from_secs(10), stream.finish())
+            .await
+            .expect("finish timed out")
+            .unwrap();
+        // Acknowledged before closing, nothing is left to be sent
+        loop {
+            if events.recv().await.unwrap() == KcpEvent::Closed {
+                break;
+            }
+        }
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        time::timeout(Duration::from_secs(5), server.read_to_end(&mut received))
+            .await
+            .expect("FIN not received")
+            .unwrap();
+        assert!(received == data);
+    }
+
+    #[tokio::test]
+    async fn ack_only_datagrams() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 256 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let mut received = vec![0u8; DATA_SIZE];
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.write_all(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        server.read_exact(&mut received[..5]).await.unwrap();
+
+        // Only ACKs go back to the client, batched by flushes, then one for every input
+        let mut acks = Vec::new();
+        for flush_acks_input in [false, true] {
+            server.set_flush_acks_input(flush_acks_input);
+            let before = server.ack_only_datagrams();
+            stream.write_all(&data).await.unwrap();
+            stream.flush_acked().await.unwrap();
+            server.read_exact(&mut received).await.unwrap();
+            assert!(received == data);
+            acks.push(server.ack_only_datagrams() - before);
+        }
+        assert!(acks[0] > 0);
+        assert!(acks[1] > acks[0], "ACK-only datagrams: {:?}", acks);
+    }
+
+    #[tokio::test]
+    async fn flush_acked() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 256 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        time::timeout(Duration::from_secs(10), stream.flush_acked())
+            .await
+            .expect("flush_acked timed out")
+            .unwrap();
+
+        // Still open in both directions
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; DATA_SIZE];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+        server.send(b"RESPONSE").await.unwrap();
+        let mut buf = [0u8; 8];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"RESPONSE");
+
+        // Nothing is acknowledged by a silent peer
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = KcpStream::connect(&config, silent.local_addr().unwrap()).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+        match stream.flush_acked_timeout(Duration::from_millis(200)).await {
+            Err(KcpError::Timeout) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("acknowledged by a silent peer"),
+        }
+    }
+
+    #[tokio::test]
+    async fn all_acked() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 64 * 1024;
+        let data = (0..DATA_SIZE).map(|i| (i * 3) as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        assert!(stream.all_acked());
+        stream.write_all(&data).await.unwrap();
+        assert!(!stream.all_acked());
+
+        let start = Instant::now();
+        while !stream.all_acked() {
+            assert!(start.elapsed() < Duration::from_secs(10), "not acknowledged");
+            time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Acknowledged data is in the receive queue of peer, it is read without waiting for retransmissions
+        let (mut server, _) = listener.accept().await.unwrap();
+        let len = server.recv_queue_len();
+        assert!(len.ready > 0 && len.out_of_order == 0, "{:?}", len);
+        let mut received = vec![0u8; DATA_SIZE];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        // Nothing is acknowledged by a silent peer
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = KcpStream::connect(&config, silent.local_addr().unwrap()).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!stream.all_acked());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn bandwidth_estimate() {
+        const RATE: u64 = 1_000_000;
+
+        // RTO of normal mode is longer than the queue of the link, retransmissions would take its bandwidth. The
+        // window is enough for the bandwidth-delay product.
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                interval: 10,
+                nc: true,
+                ..KcpNoDelayConfig::normal()
+            },
+            wnd_size: (64, 64),
+            ..Default::default()
+        };
+        let (server, client) = MemoryTransport::pair();
+        let server_addr = server.local_addr();
+        client.set_rate(Some(RATE));
+        client.set_latency(Duration::from_millis(20));
+        server.set_latency(Duration::from_millis(20));
+        let mut listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, Arc::new(client), server_addr)
+            .await
+            .unwrap();
+        assert_eq!(stream.bandwidth_estimate(), None);
+
+        let data = vec![0x42u8; 2 * RATE as usize];
+        let mut writer = stream.clone();
+        let sender = tokio::spawn(async move { writer.write_all(&data).await.unwrap() });
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut received = 0;
+        while received < 2 * RATE as usize {
+            received += server.recv(&mut buf).await.unwrap();
+        }
+        sender.await.unwrap();
+        stream.flush_acked().await.unwrap();
+
+        // Headers carried by the link are not counted, timers of the link round to milliseconds
+        let estimate = stream.bandwidth_estimate().unwrap();
+        assert!(
+            estimate > RATE * 9 / 10 && estimate < RATE * 105 / 100,
+            "estimate {} of {}",
+            estimate,
+            RATE
+        );
+        // Nothing was sent the other way
+        assert_eq!(server.bandwidth_estimate(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dump_state() {
+        let config = KcpConfig::default();
+        let (server, client) = MemoryTransport::pair();
+        let (server_addr, client_addr) = (server.local_addr(), client.local_addr());
+        let mut listener = KcpListener::with_transport(config.clone(), Arc::new(server)).unwrap();
+        let client = Arc::new(client);
+        let mut stream = KcpStream::connect_with_transport(&config, client.clone(), server_addr)
+            .await
+            .unwrap();
+
+        // Segments stay in flight while the link is down
+        client.set_loss_rate(1.0);
+        let data = vec![b'x'; 4000];
+        stream.write_all(&data).await.unwrap();
+        time::sleep(Duration::from_secs(1)).await;
+
+        let dump = stream.dump_state();
+        assert_eq!(dump.peer_addr, server_addr);
+        assert!(dump.connected && !dump.closed && dump.error.is_none());
+        // Stream mode, fragmented into segments of `mss`
+        assert_eq!(dump.snd_buf + dump.snd_queue, 3, "{:?}", dump);
+        assert_eq!(dump.segments.len(), dump.snd_buf);
+        assert_eq!(dump.snd_una, dump.segments[0].sn);
+        assert_eq!(dump.snd_nxt, dump.segments.last().unwrap().sn + 1);
+        assert!(
+            dump.segments.iter().all(|segment| segment.xmit > 1),
+            "{:?}",
+            dump.segments
+        );
+        assert!(dump.rto_ms > 0);
+        assert!(dump.since_last_send_ms < dump.since_last_recv_ms);
+        assert_eq!(dump.config.mtu, config.mtu);
+        assert_eq!(dump.config.snd_wnd, config.wnd_size.0);
+
+        client.set_loss_rate(0.0);
+        stream.flush_acked().await.unwrap();
+        let dump = stream.dump_state();
+        assert_eq!((dump.snd_buf, dump.snd_queue), (0, 0));
+        assert!(dump.segments.is_empty());
+        assert_eq!(dump.snd_una, dump.snd_nxt);
+
+        // Received data is waiting on the server
+        let dumps = listener.dump_sessions().await;
+        assert_eq!(dumps.len(), 1);
+        assert_eq!((dumps[0].conv, dumps[0].peer_addr), (dump.conv, client_addr));
+        assert_eq!(dumps[0].rcv_queue, data.len());
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&dumps[0]).unwrap();
+            assert_eq!(json["peer_addr"], client_addr.to_string());
+            assert_eq!(json["config"]["mtu"], config.mtu);
+        }
+
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; data.len()];
+        accepted.read_exact(&mut buf).await.unwrap();
+        assert!(buf == data);
+        assert_eq!(listener.dump_sessions().await[0].rcv_queue, 0);
+    }
+
+    #[tokio::test]
+    async fn linger_delivers_after_drop() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            linger: Some(Duration::from_secs(5)),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 256 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        stream.send(b"LAST MESSAGE").await.unwrap();
+        drop(stream);
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        time::timeout(Duration::from_secs(10), server.read_to_end(&mut received))
+            .await
+            .expect("FIN not received")
+            .unwrap();
+        assert!(received.len() == DATA_SIZE + 12);
+        assert!(received[..DATA_SIZE] == data[..]);
+        assert_eq!(&received[DATA_SIZE..], b"LAST MESSAGE");
+    }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn linger_timeout() {
+        use crate::NetEmConfig;
+
+        let _ = env_logger::try_init();
+
+        let server_config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        // Nothing will be acknowledged, lingering ends long before the link is considered dead
+        let client_config = KcpConfig {
+            linger: Some(Duration::from_millis(300)),
+            test_netem: Some(NetEmConfig {
+                loss_rate: 1.0,
+                ..Default::default()
+            }),
+            ..server_config.clone()
+        };
+
+        let listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&client_config, server_addr).await.unwrap();
+        let mut events = stream.events();
+        stream.send(b"HELLO WORLD").await.unwrap();
+
+        let start = Instant::now();
+        drop(stream);
+        time::timeout(Duration::from_secs(3), async {
+            loop {
+                if events.recv().await.unwrap() == KcpEvent::Closed {
+                    break;
+                }
+            }
+        })
+        .await
+        .expect("session not closed after lingering");
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn recv_buffer_too_small() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 8192];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+
+        let message: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        client.send(&message).await.unwrap();
+        client.send(&message).await.unwrap();
+
+        // The message is kept, retried with the required size
+        match server.recv(&mut buf[..1000]).await {
+            Err(KcpError::BufferTooSmall(required)) => assert_eq!(required, 3000),
+            result => panic!("unexpected {:?}", result),
+        }
+        let n = server.recv(&mut buf[..3000]).await.unwrap();
+        assert_eq!(&buf[..n], &message[..]);
+
+        // Also into an uninitialized buffer
+        client.send(&message).await.unwrap();
+        let mut storage = [MaybeUninit::<u8>::uninit(); 4096];
+        let mut uninit = ReadBuf::uninit(&mut storage[..1000]);
+        match server.recv_buf(&mut uninit).await {
+            Err(KcpError::BufferTooSmall(required)) => assert_eq!(required, 3000),
+            result => panic!("unexpected {:?}", result),
+        }
+        assert_eq!(uninit.initialized().len(), 0);
+        let mut uninit = ReadBuf::uninit(&mut storage);
+        server.recv_buf(&mut uninit).await.unwrap();
+        assert_eq!(uninit.filled(), &message[..]);
+
+        // AsyncRead is a byte stream and reads messages partially
+        server.read_exact(&mut buf[..1000]).await.unwrap();
+        assert_eq!(&buf[..1000], &message[..1000]);
+        server.read_exact(&mut buf[..2000]).await.unwrap();
+        assert_eq!(&buf[..2000], &message[1000..]);
+    }
+
+    #[tokio::test]
+    async fn send_recv_msg() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mss = client.mtu() - KCP_HEADER_LEN;
+        let max = client.max_message_size();
+        assert_eq!(max, mss * 127);
+
+        // Nothing is sent for messages that are too large
+        match client.send_msg(&vec![0u8; max + 1]).await {
+            Err(KcpError::MessageTooLarge(n)) => assert_eq!(n, max),
+            result => panic!("unexpected {:?}", result),
+        }
+
+        let sizes = [1, mss - 1, mss, mss + 1, 2 * mss, max - 1, max];
+        let messages = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| (0..len).map(|j| (i + j) as u8).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        // Empty ones are not sent
+        client.send_msg(&[]).await.unwrap();
+        for message in &messages {
+            client.send_msg(message).await.unwrap();
+        }
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        // Grown from nothing, then reused for smaller ones
+        let mut buf = Vec::new();
+        for message in &messages {
+            let n = time::timeout(Duration::from_secs(5), server.recv_msg(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(n, message.len());
+            assert_eq!(&buf, message);
+        }
+
+        client.shutdown_write().unwrap();
+        let n = time::timeout(Duration::from_secs(5), server.recv_msg(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(n, 0);
+        assert!(buf.is_empty());
+
+        // No boundaries in stream mode
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let mut client = KcpStream::connect(&config, listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(
+            client.send_msg(b"HELLO").await,
+            Err(KcpError::ConfigInvalid(..))
+        ));
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        assert!(matches!(
+            server.recv_msg(&mut buf).await,
+            Err(KcpError::ConfigInvalid(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn recv_msg_after_read() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        for message in [&b"HELLO WORLD"[..], b"FIRST", b"SECOND"] {
+            client.send_msg(message).await.unwrap();
+        }
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut head = [0u8; 5];
+        server.read_exact(&mut head).await.unwrap();
+        assert_eq!(&head, b"HELLO");
+
+        // The rest isn't a whole message, it's kept
+        let mut buf = Vec::with_capacity(64);
+        let capacity = buf.capacity();
+        match server.recv_msg(&mut buf).await {
+            Err(KcpError::PartialMessage(6)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+        let mut rest = [0u8; 6];
+        server.read_exact(&mut rest).await.unwrap();
+        assert_eq!(&rest, b" WORLD");
+
+        for message in [&b"FIRST"[..], b"SECOND"] {
+            let n = time::timeout(Duration::from_secs(5), server.recv_msg(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(n, message.len());
+            assert_eq!(&buf, message);
+            // Fits, not reallocated
+            assert_eq!(buf.capacity(), capacity);
+        }
+    }
+
+    #[tokio::test]
+    async fn clone_concurrent_send() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const MESSAGES: usize = 200;
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        // Heartbeats and data from different tasks, with sizes spanning several segments
+        let senders = [(b'H', 16), (b'D', 4000)].map(|(byte, size)| {
+            let mut stream = stream.clone();
+            tokio::spawn(async move {
+                let message = vec![byte; size];
+                for _ in 0..MESSAGES {
+                    assert_eq!(stream.send(&message).await.unwrap(), size);
+                }
+            })
+        });
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 8192];
+        let mut counts = [0; 2];
+        for _ in 0..MESSAGES * 2 {
+            let n = time::timeout(Duration::from_secs(10), server.recv(&mut buf))
+                .await
+                .expect("message lost")
+                .unwrap();
+            // Messages are never interleaved
+            match n {
+                16 => assert!(buf[..n].iter().all(|b| *b == b'H')),
+                4000 => assert!(buf[..n].iter().all(|b| *b == b'D')),
+                n => panic!("unexpected message of {} bytes", n),
+            }
+            counts[(n == 4000) as usize] += 1;
+        }
+        assert_eq!(counts, [MESSAGES, MESSAGES]);
+        for sender in senders {
+            sender.await.unwrap();
+        }
+
+        // Closed after the last clone is dropped
+        drop(stream);
+        let n = time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+            .await
+            .expect("FIN not received")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn clone_concurrent_recv() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const MESSAGES: u32 = 500;
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"START").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        // Both wait before anything arrives, each message wakes them up
+        let receivers = (0..2)
+            .map(|_| {
+                let mut stream = stream.clone();
+                tokio::spawn(async move {
+                    let mut received = Vec::new();
+                    let mut buf = [0u8; 4];
+                    loop {
+                        let n = stream.recv(&mut buf).await.unwrap();
+                        assert_eq!(n, 4);
+                        let i = u32::from_be_bytes(buf);
+                        if i == MESSAGES {
+                            return received;
+                        }
+                        received.push(i);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(stream);
+
+        time::sleep(Duration::from_millis(100)).await;
+        for i in 0..MESSAGES {
+            server.send(&i.to_be_bytes()).await.unwrap();
+        }
+        // An end marker for each of them
+        for _ in 0..2 {
+            server.send(&MESSAGES.to_be_bytes()).await.unwrap();
+        }
+
+        let mut received = Vec::new();
+        for receiver in receivers {
+            received.extend(
+                time::timeout(Duration::from_secs(10), receiver)
+                    .await
+                    .expect("wakeup lost")
+                    .unwrap(),
+            );
+        }
+        received.sort_unstable();
+        assert_eq!(received, (0..MESSAGES).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn set_mtu_live() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 64 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; DATA_SIZE];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        match stream.set_mtu(20) {
+            Err(err @ KcpError::ConfigInvalid(..)) => {
+                assert_eq!(io::Error::from(err).kind(), io::ErrorKind::InvalidInput)
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+        assert_eq!(stream.mtu(), config.mtu);
+
+        // Smaller segments from now on, in both directions
+        stream.set_mtu(576).unwrap();
+        server.set_mtu(576).unwrap();
+        assert_eq!(stream.mtu(), 576);
+
+        stream.write_all(&data).await.unwrap();
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        server.write_all(&data).await.unwrap();
+        stream.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+    }
+
+    #[tokio::test]
+    async fn buffers_sized_for_mtu() {
+        let _ = env_logger::try_init();
+
+        // Default buffers are sized for jumbo frames, datagrams of both sides are received whole
+        let config = KcpConfig {
+            mtu: 9000,
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        const DATA_SIZE: usize = 64 * 1024;
+        let data = (0..DATA_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; DATA_SIZE];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        server.write_all(&data).await.unwrap();
+        stream.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+
+        // Larger than the buffers
+        match stream.set_mtu(16000) {
+            Err(KcpError::ConfigInvalid(..)) => {}
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_with_conv() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        match KcpStream::connect_with_conv(&config, 0, server_addr).await {
+            Err(KcpError::ConfigInvalid(..)) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        const CONV: u32 = 0x1234_5678;
+
+        // Larger than a segment, which has to wait for an allocated conv otherwise
+        let data = vec![b'A'; 4096];
+        let mut stream = KcpStream::connect_with_conv(&config, CONV, server_addr).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert!(received == data);
+        assert_eq!(listener.peers().await[0].0, CONV);
+
+        server.write_all(b"WORLD").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"WORLD");
+    }
+
+    #[tokio::test]
+    async fn framed_length_delimited() {
+        let _ = env_logger::try_init();
+
+        for stream in [true, false] {
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                stream,
+                ..Default::default()
+            };
+            framed_echo(config).await;
+        }
+    }
+
+    async fn framed_echo(config: KcpConfig) {
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+            while let Some(Ok(frame)) = framed.next().await {
+                if framed.send(frame.freeze()).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut framed = Framed::new(stream, LengthDelimitedCodec::new());
+
+        // Frames shorter, equal and longer than a segment, and longer than one send
+        let sizes = [0, 1, 7, 1375, 1376, 1377, 4096, 65_535, 200_000, 3];
+        for (i, size) in sizes.iter().enumerate() {
+            let frame = (0..*size).map(|b| (b + i) as u8).collect::<Vec<u8>>();
+            framed.feed(Bytes::from(frame)).await.unwrap();
+        }
+        SinkExt::<Bytes>::flush(&mut framed).await.unwrap();
+
+        for (i, size) in sizes.iter().enumerate() {
+            let frame = time::timeout(Duration::from_secs(5), framed.next())
+                .await
+                .expect("frame not echoed")
+                .unwrap()
+                .unwrap();
+            assert_eq!(frame.len(), *size);
+            assert!(frame.iter().enumerate().all(|(b, v)| *v == (b + i) as u8));
+        }
+    }
+
+    #[tokio::test]
+    async fn flush_sends_immediately() {
+        let _ = env_logger::try_init();
+
+        // Update timer of the session never fires by itself
+        time::pause();
+
+        // Read without yielding to the session task
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_nonblocking(true).unwrap();
+
+        let config = KcpConfig::default();
+        let mut stream = KcpStream::connect_with_conv(&config, 42, peer.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        // First update of the session task, then the clock of KCP is left behind
+        tokio::task::yield_now().await;
+        std::thread::sleep(Duration::from_millis(300));
+
+        stream.write_all(b"HELLO").await.unwrap();
+        let mut buf = [0u8; 1500];
+        assert_eq!(peer.recv(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        stream.flush().await.unwrap();
+        let n = peer.recv(&mut buf).expect("nothing sent by flush");
+        assert_eq!(buf[4], KCP_CMD_PUSH);
+        assert_eq!(&buf[KCP_HEADER_LEN..n], b"HELLO");
+
+        // Stamped with the time of flush
+        let age = now_millis().wrapping_sub((&buf[8..]).get_u32_le());
+        assert!(age < 100, "segment stamped {} ms ago", age);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flush_strategies() {
+        let _ = env_logger::try_init();
+
+        for strategy in [
+            FlushStrategy::Interval,
+            FlushStrategy::OnWrite,
+            FlushStrategy::OnAck,
+            FlushStrategy::Aggressive,
+        ] {
+            let (write_flushed, ack_flushed) = flushed_before_update(strategy).await;
+            assert_eq!(write_flushed, strategy.flushes_write(), "{:?} write", strategy);
+            assert_eq!(ack_flushed, strategy.flushes_ack(), "{:?} ACK", strategy);
+        }
+    }
+
+    /// Whether a write is sent before it returns, and whether the ACK of a segment from peer is sent before the
+    /// next update of the session, which never comes while the clock is paused and the test doesn't wait
+    async fn flushed_before_update(strategy: FlushStrategy) -> (bool, bool) {
+        let peer = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        peer.set_nonblocking(true).unwrap();
+
+        let config = KcpConfig {
+            flush_strategy: strategy,
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect_with_conv(&config, 42, peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        let local_addr = stream.get_ref().unwrap().local_addr().unwrap();
+        tokio::task::yield_now().await;
+
+        // Read without yielding to the session task
+        stream.send(b"HELLO").await.unwrap();
+        let mut buf = [0u8; 1500];
+        let write_flushed = peer.recv(&mut buf).is_ok();
+        // Otherwise flushed by the session task
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+        while peer.recv(&mut buf).is_ok() {}
+
+        let mut segment = Vec::new();
+        segment.extend_from_slice(&42u32.to_le_bytes());
+        segment.extend_from_slice(&[KCP_CMD_PUSH, 0]);
+        segment.extend_from_slice(&256u16.to_le_bytes());
+        segment.extend_from_slice(&now_millis().to_le_bytes());
+        segment.extend_from_slice(&0u32.to_le_bytes()); // sn
+        segment.extend_from_slice(&0u32.to_le_bytes()); // una
+        segment.extend_from_slice(&5u32.to_le_bytes());
+        segment.extend_from_slice(b"WORLD");
+        peer.send_to(&segment, local_addr).unwrap();
+
+        // Never waiting, the clock would be advanced to the next update
+        let mut ack_flushed = false;
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+            while let Ok(n) = peer.recv(&mut buf) {
+                ack_flushed |= n >= KCP_HEADER_LEN && buf[4] == KCP_CMD_ACK;
+            }
+        }
+        assert_eq!(stream.recv_queue_len().ready, 5, "segment not input");
+        (write_flushed, ack_flushed)
+    }
+
+    #[tokio::test]
+    async fn read_timeout() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        server.set_read_timeout(Some(Duration::from_millis(200))).unwrap();
+        assert_eq!(server.read_timeout(), Some(Duration::from_millis(200)));
+        let mut buf = [0u8; 16];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+
+        let start = Instant::now();
+        match server.recv(&mut buf).await {
+            Err(KcpError::Timeout) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("received nothing sent"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let err = server.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Session is still usable
+        stream.send(b"WORLD").await.unwrap();
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"WORLD", &buf[..n]);
+
+        // Waits forever after cleared
+        assert!(matches!(
+            server.set_read_timeout(Some(Duration::ZERO)),
+            Err(KcpError::ConfigInvalid(..))
+        ));
+        server.set_read_timeout(None).unwrap();
+        assert!(time::timeout(Duration::from_millis(400), server.recv(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn write_timeout() {
+        let _ = env_logger::try_init();
+
+        // Never acknowledges, the send window fills up
+        let blackhole = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let config = KcpConfig {
+            wnd_size: (4, 4),
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect_with_conv(&config, 42, blackhole.local_addr().unwrap())
+            .await
+            .unwrap();
+        stream.set_write_timeout(Some(Duration::from_millis(200))).unwrap();
+
+        let data = [7u8; 1000];
+        let start = Instant::now();
+        loop {
+            match stream.send(&data).await {
+                Ok(..) => assert!(start.elapsed() < Duration::from_secs(3), "send window never full"),
+                Err(KcpError::Timeout) => break,
+                Err(err) => panic!("unexpected error {}", err),
+            }
+        }
+        let err = stream.write(&data).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Clones start with the same timeout
+        let mut clone = stream.clone();
+        assert_eq!(clone.write_timeout(), Some(Duration::from_millis(200)));
+        assert!(matches!(clone.send(&data).await, Err(KcpError::Timeout)));
+
+        stream.set_write_timeout(None).unwrap();
+        assert!(time::timeout(Duration::from_millis(400), stream.send(&data))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn write_coalesce() {
+        let _ = env_logger::try_init();
+
+        async fn sent_segments(write_coalesce: Option<Duration>) -> u32 {
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                write_coalesce,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+            client.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            server.read_exact(&mut buf).await.unwrap();
+
+            let sent = client.session.sent_segments();
+            for i in 0..20u8 {
+                client.write_all(&[i; 10]).await.unwrap();
+                time::sleep(Duration::from_millis(1)).await;
+            }
+
+            let mut buf = [0u8; 200];
+            time::timeout(Duration::from_secs(3), server.read_exact(&mut buf))
+                .await
+                .expect("data not received")
+                .unwrap();
+            for (i, chunk) in buf.chunks(10).enumerate() {
+                assert!(chunk.iter().all(|b| *b == i as u8));
+            }
+
+            client.session.sent_segments() - sent
+        }
+
+        let immediate = sent_segments(None).await;
+        let coalesced = sent_segments(Some(Duration::from_millis(50))).await;
+        assert!(
+            coalesced * 2 < immediate,
+            "coalesced {} segments, immediate {}",
+            coalesced,
+            immediate
+        );
+    }
+
+    #[tokio::test]
+    async fn pacing_rate_limit() {
+        let _ = env_logger::try_init();
+
+        let client_config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        // 16KB burst, then 256KB/s
+        let server_config = KcpConfig {
+            pacing: Some(PacingConfig {
+                max_burst: 16 * 1024,
+                rate: 256 * 1024,
+                packet_rate: None,
+                adaptive: false,
+            }),
+            ..client_config.clone()
+        };
+
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&client_config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 1024];
+        server.recv(&mut buf).await.unwrap();
+        assert!(stream.pacing_stats().is_none());
+
+        const DATA_SIZE: usize = 64 * 1024;
+        let start = Instant::now();
+        server.write_all(&[0u8; DATA_SIZE]).await.unwrap();
+        let mut received = 0;
+        while received < DATA_SIZE {
+            received += stream.recv(&mut buf).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        let stats = server.pacing_stats().unwrap();
+        assert_eq!(stats.rate, 256 * 1024);
+        assert!(stats.consumed >= DATA_SIZE as u64);
+        assert!(stats.consumed_packets >= (DATA_SIZE / 1400) as u64);
+    }
+
+    /// Relays datagrams between one client and `server_addr` with `delay` each way.
+    ///
+    /// Datagrams from client pass a policer, which drops them when more than `burst` arrive faster than `rate` per second.
+    async fn spawn_policed_relay(server_addr: SocketAddr, delay: Duration, burst: f64, rate: f64) -> SocketAddr {
+        use std::sync::Arc;
+
+        use tokio::sync::mpsc;
+
+        fn delayed_sender(socket: Arc<UdpSocket>) -> mpsc::UnboundedSender<(Instant, Vec<u8>, SocketAddr)> {
+            let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>, SocketAddr)>();
+            tokio::spawn(async move {
+                while let Some((deadline, buf, addr)) = rx.recv().await {
+                    time::sleep_until(deadline).await;
+                    let _ = socket.send_to(&buf, addr).await;
+                }
+            });
+            tx
+        }
+
+        let client_side = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_side = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let relay_addr = client_side.local_addr().unwrap();
+        let to_server = delayed_sender(server_side.clone());
+        let to_client = delayed_sender(client_side.clone());
+
+        tokio::spawn(async move {
+            let mut client_addr = None;
+            let mut tokens = burst;
+            let mut last_refill = Instant::now();
+            let mut client_buf = [0u8; 2048];
+            let mut server_buf = [0u8; 2048];
+            loop {
+                tokio::select! {
+                    Ok((n, addr)) = client_side.recv_from(&mut client_buf) => {
+                        client_addr = Some(addr);
+                        let now = Instant::now();
+                        tokens = (tokens + (now - last_refill).as_secs_f64() * rate).min(burst);
+                        last_refill = now;
+                        if tokens >= 1.0 {
+                            tokens -= 1.0;
+                            let _ = to_server.send((now + delay, client_buf[..n].to_vec(), server_addr));
+                        }
+                    }
+                    Ok(n) = server_side.recv(&mut server_buf) => {
+                        if let Some(addr) = client_addr {
+                            let _ = to_client.send((Instant::now() + delay, server_buf[..n].to_vec(), addr));
+                        }
+                    }
+                }
+            }
+        });
+
+        relay_addr
+    }
+
+    #[tokio::test]
+    async fn pacing_policer() {
+        let _ = env_logger::try_init();
+
+        async fn transfer_time(pacing: Option<PacingConfig>) -> Duration {
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                wnd_size: (32, 256),
+                pacing,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+            // 40ms RTT, 1000 datagrams per second with bursts of 16, a window of 32 datagrams is 800 per second
+            let relay_addr = spawn_policed_relay(server_addr, Duration::from_millis(20), 16.0, 1000.0).await;
+
+            const DATA_SIZE: usize = 512 * 1024;
+            let receiver = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; DATA_SIZE];
+                stream.read_exact(&mut buf).await.unwrap();
+            });
+
+            let mut stream = KcpStream::connect(&config, relay_addr).await.unwrap();
+            let start = Instant::now();
+            stream.write_all(&[0u8; DATA_SIZE]).await.unwrap();
+            time::timeout(Duration::from_secs(30), receiver).await.unwrap().unwrap();
+            start.elapsed()
+        }
+
+        let bursty = transfer_time(None).await;
+        let paced = transfer_time(Some(PacingConfig {
+            max_burst: 4 * 1400,
+            rate: 10 * 1024 * 1024,
+            packet_rate: None,
+            adaptive: true,
+        }))
+        .await;
+        assert!(paced < bursty, "paced {:?}, bursty {:?}", paced, bursty);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[tokio::test]
+    async fn send_bytes() {
+        use bytes::Bytes;
+
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Larger than the send window, sent in parts
+        let data = (0..256 * 1024).map(|i| i as u8).collect::<Vec<_>>();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let sender = {
+            let data = Bytes::from(data.clone());
+            tokio::spawn(async move {
+                let n = stream.send_bytes(data).await.unwrap();
+                (stream, n)
+            })
+        };
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        time::timeout(Duration::from_secs(10), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == data);
+
+        let (_stream, n) = sender.await.unwrap();
+        assert_eq!(n, data.len());
+    }
+
+    #[tokio::test]
+    async fn send_vectored_message() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let header = b"HEADER\r\n";
+        let body = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let message = [&header[..], &body[..]].concat();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 8192];
+        server.recv(&mut buf).await.unwrap();
+
+        // Larger than MSS, sent as one message with an empty slice in between
+        for _ in 0..2 {
+            let bufs = [IoSlice::new(header), IoSlice::new(&[]), IoSlice::new(&body)];
+            let n = stream.send_vectored(&bufs).await.unwrap();
+            assert_eq!(n, message.len());
+        }
+
+        for _ in 0..2 {
+            let n = time::timeout(Duration::from_secs(1), server.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(buf[..n] == message[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_vectored_shared() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 8192];
+        server.recv(&mut buf).await.unwrap();
+
+        let stream = &stream;
+        let body = (0..2000).map(|i| i as u8).collect::<Vec<_>>();
+        let first = [IoSlice::new(b"FIRST\r\n"), IoSlice::new(&body)];
+        let second = [IoSlice::new(b"SECOND\r\n"), IoSlice::new(&body)];
+        // Both through a shared reference
+        let (a, b) = tokio::join!(stream.send_vectored(&first), stream.send_vectored(&second));
+        assert_eq!(a.unwrap(), 7 + body.len());
+        assert_eq!(b.unwrap(), 8 + body.len());
+
+        let mut messages = Vec::new();
+        for _ in 0..2 {
+            let n = time::timeout(Duration::from_secs(1), server.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            messages.push(buf[..n].to_vec());
+        }
+        // Each one is received whole, as the concatenation of its slices
+        messages.sort();
+        assert!(messages[0] == [&b"FIRST\r\n"[..], &body[..]].concat());
+        assert!(messages[1] == [&b"SECOND\r\n"[..], &body[..]].concat());
+    }
+
+    #[tokio::test]
+    async fn send_vectored_stream() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        assert!(AsyncWrite::is_write_vectored(&stream));
+
+        // Nothing to send
+        assert_eq!(stream.send_vectored(&[]).await.unwrap(), 0);
+        let empty = [IoSlice::new(&[]), IoSlice::new(&[])];
+        assert_eq!(stream.send_vectored(&empty).await.unwrap(), 0);
+
+        let header = b"HEADER\r\n";
+        let body = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let message = [&header[..], &body[..], &header[..]].concat();
+
+        let bufs = [
+            IoSlice::new(header),
+            IoSlice::new(&[]),
+            IoSlice::new(&body),
+            IoSlice::new(header),
+        ];
+        let mut sent = 0;
+        while sent < message.len() {
+            // Writes what's left of the slices after a partial send
+            let rest = [IoSlice::new(&message[sent..])];
+            let bufs = if sent == 0 { &bufs[..] } else { &rest[..] };
+            sent += stream.write_vectored(bufs).await.unwrap();
+        }
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = vec![0u8; message.len()];
+        time::timeout(Duration::from_secs(1), server.read_exact(&mut received))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(received == message);
+    }
+
+    #[tokio::test]
+    async fn recv_uninit_buf() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+
+        let mut storage = [MaybeUninit::<u8>::uninit(); 65536];
+        let mut buf = ReadBuf::uninit(&mut storage);
+        let n = server.recv_buf(&mut buf).await.unwrap();
+        assert_eq!(n, 11);
+        assert_eq!(buf.filled(), b"HELLO WORLD");
+        // Only bytes written by KCP are initialized
+        assert_eq!(buf.initialized().len(), 11);
+
+        // Appended after the filled part
+        stream.send(b"!").await.unwrap();
+        server.recv_buf(&mut buf).await.unwrap();
+        assert_eq!(buf.filled(), b"HELLO WORLD!");
+        assert_eq!(buf.initialized().len(), 12);
+
+        // Received into directly if it's initialized
+        let mut storage = [0u8; 4];
+        let mut buf = ReadBuf::new(&mut storage);
+        stream.send(b"HELLO").await.unwrap();
+        assert_eq!(server.recv_buf(&mut buf).await.unwrap(), 4);
+        assert_eq!(buf.filled(), b"HELL");
+        let mut storage = [MaybeUninit::<u8>::uninit(); 4];
+        let mut buf = ReadBuf::uninit(&mut storage);
+        assert_eq!(server.recv_buf(&mut buf).await.unwrap(), 1);
+        assert_eq!(buf.filled(), b"O");
+    }
+
+    #[tokio::test]
+    async fn auto_rebind() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            enable_migration: true,
+            auto_rebind: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, client_addr) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+
+        // Wait for the resumption token
+        time::sleep(Duration::from_millis(200)).await;
+
+        // Network is gone, sends of the socket keep failing
+        let mut events = client.events();
+        client.session.lock_socket().break_socket();
+
+        client.send(b"WORLD").await.unwrap();
+        let n = time::timeout(Duration::from_secs(3), server.recv(&mut buffer))
+            .await
+            .expect("client didn't rebind")
+            .unwrap();
+        assert_eq!(b"WORLD", &buffer[..n]);
+
+        assert_eq!(client.rebinds(), 1);
+        assert_ne!(server.peer_addr(), client_addr);
+        loop {
+            if let KcpEvent::Rebound { local_addr } = events.recv().await.unwrap() {
+                assert_eq!(local_addr.port(), server.peer_addr().port());
+                break;
+            }
+        }
+
+        // Requires migration on server
+        let config = KcpConfig {
+            enable_migration: false,
+            ..config
+        };
+        match KcpStream::connect(&config, server_addr).await {
+            Err(KcpError::ConfigInvalid(..)) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("invalid config accepted"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn bind_device() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            bind_device: Some("lo".to_owned()),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+
+        let config = KcpConfig {
+            bind_device: Some("kcp-missing0".to_owned()),
+            ..config
+        };
+        assert!(KcpListener::bind(config.clone(), "127.0.0.1:0").await.is_err());
+        assert!(KcpStream::connect(&config, server_addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ttl_tos() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ttl: Some(7),
+            #[cfg(target_os = "linux")]
+            tos: Some(0xb8),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let udp = stream.get_ref().unwrap();
+        assert_eq!(udp.ttl().unwrap(), 7);
+        #[cfg(target_os = "linux")]
+        assert_eq!(socket2::SockRef::from(&*udp).tos_v4().unwrap(), 0xb8);
+
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+
+        let config = KcpConfig {
+            ttl: Some(0),
+            ..Default::default()
+        };
+        match KcpStream::connect(&config, server_addr).await {
+            Err(KcpError::ConfigInvalid(..)) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("invalid config accepted"),
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn socket_access() {
+        use std::os::unix::io::{AsFd, AsRawFd};
+
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let udp = listener.get_ref().unwrap();
+        assert_eq!(udp.local_addr().unwrap(), server_addr);
+        assert_eq!(udp.as_fd().as_raw_fd(), udp.as_raw_fd());
+        let listener_fd = udp.as_raw_fd();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        assert_eq!(stream.get_ref().unwrap().peer_addr().unwrap(), server_addr);
+
+        // Options set from outside don't disturb sessions
+        socket2::SockRef::from(udp).set_recv_buffer_size(1 << 20).unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+
+        // Sessions of the listener share its socket
+        assert_eq!(server.get_ref().unwrap().as_raw_fd(), listener_fd);
+    }
+
+    #[tokio::test]
+    async fn connect_verified() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut stream = KcpStream::connect_verified(&config, server_addr, Duration::from_secs(1))
+            .await
+            .unwrap();
+        // Server has the session before any data is sent
+        let (mut server, _) = time::timeout(Duration::from_millis(100), listener.accept())
+            .await
+            .expect("session not created by the probe")
+            .unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(b"HELLO", &buf[..n]);
+
+        // Server that never responds
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let start = Instant::now();
+        match KcpStream::connect_verified(&config, silent.local_addr().unwrap(), Duration::from_millis(300)).await {
+            Err(KcpError::Timeout) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("connected to a silent server"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(300));
+
+        // Nothing is listening
+        let dead_addr = silent.local_addr().unwrap();
+        drop(silent);
+        match KcpStream::connect_verified(&config, dead_addr, Duration::from_secs(1)).await {
+            Err(KcpError::ConnectionRefused) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("connected to a dead address"),
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_retry() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+        let retry = RetryConfig {
+            max_attempts: 20,
+            initial_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_millis(100),
+            jitter: 0.5,
+            attempt_timeout: Duration::from_millis(100),
+        };
+
+        // A port that nobody listens on yet
+        let server_addr = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let server_config = config.clone();
+        let delay = Duration::from_millis(300);
+        tokio::spawn(async move {
+            time::sleep(delay).await;
+            let mut listener = KcpListener::bind(server_config, server_addr).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = server.recv(&mut buffer).await.unwrap();
+            server.send(&buffer[..n]).await.unwrap();
+            server.flush().await.unwrap();
+            time::sleep(Duration::from_secs(1)).await;
+        });
+
+        let start = Instant::now();
+        let mut client = time::timeout(
+            Duration::from_secs(5),
+            KcpStream::connect_retry(&config, server_addr, retry),
+        )
+        .await
+        .expect("connect didn't finish")
+        .unwrap();
+        assert!(start.elapsed() >= delay);
+
+        client.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(3), client.recv(&mut buffer))
+            .await
+            .expect("echo timed out")
+            .unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+
+        // Gives up with the last error
+        let dead_addr = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let retry = RetryConfig {
+            max_attempts: 3,
+            ..retry
+        };
+        let start = Instant::now();
+        match KcpStream::connect_retry(&config, dead_addr, retry).await {
+            Err(KcpError::ConnectionRefused) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("connected to nobody"),
+        }
+        assert!(start.elapsed() >= Duration::from_millis(25 + 50));
+    }
+
+    #[tokio::test]
+    async fn connection_refused() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        // A port that nobody listens on, ICMP port unreachable is reported for it
+        let dead_addr = UdpSocket::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, dead_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let err = time::timeout(Duration::from_secs(1), client.recv(&mut buffer))
+            .await
+            .expect("refusal not reported")
+            .unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::ConnectionRefused);
+        let err = client.send(b"WORLD").await.unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), io::ErrorKind::ConnectionRefused);
+
+        // Handshake fails without waiting for all SYN retries
+        let config = KcpConfig {
+            handshake: true,
+            ..config
+        };
+        let start = Instant::now();
+        match KcpStream::connect(&config, dead_addr).await {
+            Err(KcpError::ConnectionRefused) => {}
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(..) => panic!("connected to nobody"),
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Datagrams from other sources don't reach the connected socket
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let client_addr = server.peer_addr();
+        let stranger = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stranger
+            .send_to(b"garbage that is long enough to be a segment", client_addr)
+            .await
+            .unwrap();
+
+        server.send(b"HELLO").await.unwrap();
+        let n = client.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+    }
+
+    #[tokio::test]
+    async fn pmtu_probing() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            mtu: 1400,
+            pmtu: Some(PmtuConfig {
+                min_mtu: 576,
+                max_mtu: 1500,
+                probe_interval: Duration::from_secs(60),
+                probe_timeout: Duration::from_millis(100),
+            }),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut events = client.events();
+        client.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 4096];
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(b"HELLO", &buffer[..n]);
+
+        async fn mtu_changed(events: &mut broadcast::Receiver<KcpEvent>) -> usize {
+            loop {
+                if let KcpEvent::MtuChanged { mtu } = events.recv().await.unwrap() {
+                    return mtu;
+                }
+            }
+        }
+
+        // Loopback supports the largest size
+        let mtu = time::timeout(Duration::from_secs(3), mtu_changed(&mut events))
+            .await
+            .expect("path MTU not found");
+        assert!(mtu > 1400 && mtu <= 1500);
+        assert_eq!(client.mtu(), mtu);
+
+        // Route changes to a smaller MTU, data of the old size is lost
+        client.session.lock_socket().set_path_mtu(1000);
+        client.send(&[1u8; 2000]).await.unwrap();
+        let start = Instant::now();
+        let fallback = time::timeout(Duration::from_secs(3), mtu_changed(&mut events))
+            .await
+            .expect("MTU didn't fall back");
+        assert_eq!(fallback, 576);
+        let mtu = time::timeout(Duration::from_secs(3), mtu_changed(&mut events))
+            .await
+            .expect("path MTU not found again");
+        assert!(mtu > 1000 - 16 && mtu <= 1000);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn rx_minrto() {
+        let _ = env_logger::try_init();
+
+        async fn echo_rto(config: KcpConfig) -> (Duration, Duration) {
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+            let mut buf = [0u8; 16];
+            client.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            for _ in 0..10 {
+                let n = server.recv(&mut buf).await.unwrap();
+                server.send(&buf[..n]).await.unwrap();
+                let n = client.recv(&mut buf).await.unwrap();
+                client.send(&buf[..n]).await.unwrap();
+            }
+            server.recv(&mut buf).await.unwrap();
+
+            (client.congestion_stats().rto, server.congestion_stats().rto)
+        }
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            ..Default::default()
+        };
+
+        // Floor of nodelay mode
+        let (client, server) = echo_rto(config.clone()).await;
+        assert!(client >= Duration::from_millis(30) && server >= Duration::from_millis(30));
+
+        // RTO of loopback is about the update interval
+        let (client, server) = echo_rto(KcpConfig {
+            rx_minrto: Some(5),
+            ..config.clone()
+        })
+        .await;
+        for rto in [client, server] {
+            assert!(
+                rto >= Duration::from_millis(10) && rto < Duration::from_millis(30),
+                "{:?}",
+                rto
+            );
+        }
+
+        let invalid = KcpConfig {
+            rx_minrto: Some(0),
+            ..config
+        };
+        assert!(matches!(invalid.validate(), Err(KcpError::ConfigInvalid(..))));
+    }
+
+    #[tokio::test]
+    async fn congestion_modes() {
+        let _ = env_logger::try_init();
+
+        /// Fixed window, counts what it observes
+        struct FixedWindow(Arc<AtomicUsize>);
+
+        impl CongestionController for FixedWindow {
+            fn on_ack(&mut self, _rtt: Duration) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+
+            fn on_loss(&mut self, _segments: usize) {}
+
+            fn cwnd(&self) -> u16 {
+                4
+            }
+
+            fn pacing_rate(&self) -> Option<u64> {
+                Some(1_000_000)
+            }
+        }
+
+        async fn echo_stats(config: KcpConfig) -> (CongestionStats, CongestionStats) {
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+            client.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 8000];
+            let n = server.recv(&mut buffer).await.unwrap();
+            assert_eq!(b"HELLO", &buffer[..n]);
+
+            client.send(&[1u8; 8000]).await.unwrap();
+            server.read_exact(&mut buffer).await.unwrap();
+            server.send(b"OK").await.unwrap();
+            let n = client.recv(&mut buffer).await.unwrap();
+            assert_eq!(b"OK", &buffer[..n]);
+
+            (client.congestion_stats(), server.congestion_stats())
+        }
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            wnd_size: (64, 128),
+            ..Default::default()
+        };
+
+        // KCP's window, unless nc disables it
+        let normal = KcpConfig {
+            nodelay: KcpNoDelayConfig::normal(),
+            ..config.clone()
+        };
+        let (client, server) = echo_stats(normal).await;
+        assert!(client.kcp_window && server.kcp_window);
+        let (client, server) = echo_stats(config.clone()).await;
+        assert!(!client.kcp_window && !server.kcp_window);
+
+        let off = KcpConfig {
+            nodelay: KcpNoDelayConfig::normal(),
+            congestion: CongestionMode::Off,
+            ..config.clone()
+        };
+        let (client, server) = echo_stats(off).await;
+        assert!(!client.kcp_window && !server.kcp_window);
+        assert_eq!((client.cwnd, client.pacing_rate), (64, None));
+
+        let acks = Arc::new(AtomicUsize::new(0));
+        let factory_acks = acks.clone();
+        let custom = KcpConfig {
+            congestion: CongestionMode::Custom(ControllerFactory::new(move || {
+                Box::new(FixedWindow(factory_acks.clone()))
+            })),
+            ..config
+        };
+        let (client, server) = echo_stats(custom).await;
+        assert!(!client.kcp_window && !server.kcp_window);
+        assert_eq!((client.cwnd, client.pacing_rate), (4, Some(1_000_000)));
+        assert_eq!(server.cwnd, 4);
+        assert!(acks.load(Ordering::Relaxed) >= 8000 / 1400);
+    }
+
+    #[tokio::test]
+    async fn recv_queue_len() {
+        use bytes::BufMut;
+
+        let _ = env_logger::try_init();
+
+        fn push_segment(sn: u32, frg: u8, data: &[u8]) -> Vec<u8> {
+            let mut segment = Vec::new();
+            segment.put_u32_le(10);
+            segment.put_u8(81);
+            segment.put_u8(frg);
+            segment.put_u16_le(128);
+            segment.put_u32_le(0);
+            segment.put_u32_le(sn);
+            segment.put_u32_le(0);
+            segment.put_u32_le(data.len() as u32);
+            segment.put_slice(data);
+            segment
+        }
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = KcpStream::connect_with_conv(&config, 10, peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let mut buf = [0u8; 1024];
+        let (_, client_addr) = peer.recv_from(&mut buf).await.unwrap();
+
+        async fn queue_after(client: &KcpStream, peer: &UdpSocket, addr: SocketAddr, segment: Vec<u8>) -> RecvQueueLen {
+            peer.send_to(&segment, addr).await.unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+            client.recv_queue_len()
+        }
+
+        // Second message arrives first
+        let len = queue_after(&client, &peer, client_addr, push_segment(1, 0, b"abc")).await;
+        assert_eq!((len.ready, len.out_of_order), (0, 1));
+        let len = queue_after(&client, &peer, client_addr, push_segment(0, 0, b"xy")).await;
+        assert_eq!((len.ready, len.out_of_order), (2, 0));
+
+        // Incomplete message isn't ready
+        let len = queue_after(&client, &peer, client_addr, push_segment(2, 1, b"12")).await;
+        assert_eq!((len.ready, len.out_of_order), (2, 0));
+        let len = queue_after(&client, &peer, client_addr, push_segment(3, 0, b"34")).await;
+        assert_eq!((len.ready, len.out_of_order), (3, 0));
+
+        let n = client.recv(&mut buf).await.unwrap();
+        assert_eq!(b"xy", &buf[..n]);
+        assert_eq!(client.recv_queue_len().ready, 2);
+    }
+
+    #[cfg(feature = "debug-internals")]
+    #[tokio::test]
+    async fn debug_state() {
+        use bytes::BufMut;
+
+        let _ = env_logger::try_init();
+
+        fn push_segment(sn: u32, una: u32, data: &[u8]) -> Vec<u8> {
+            let mut segment = Vec::new();
+            segment.put_u32_le(10);
+            segment.put_u8(81);
+            segment.put_u8(0);
+            segment.put_u16_le(128);
+            segment.put_u32_le(0);
+            segment.put_u32_le(sn);
+            segment.put_u32_le(una);
+            segment.put_u32_le(data.len() as u32);
+            segment.put_slice(data);
+            segment
+        }
+
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            stream: false,
+            ..Default::default()
+        };
+
+        // Peer never acknowledges by itself
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = KcpStream::connect_with_conv(&config, 10, peer.local_addr().unwrap())
+            .await
+            .unwrap();
+        client.send(b"HELLO").await.unwrap();
+        client.send(b"WORLD!").await.unwrap();
+        let mut buffer = [0u8; 1024];
+        let (_, client_addr) = peer.recv_from(&mut buffer).await.unwrap();
+
+        time::sleep(Duration::from_millis(300)).await;
+        let state = client.debug_state();
+        assert_eq!(state.conv, 10);
+        assert_eq!((state.snd_una, state.snd_nxt, state.wait_snd), (0, 2, 2));
+        let segments: Vec<_> = state.snd_buf.iter().map(|s| (s.sn, s.len)).collect();
+        assert_eq!(segments, [(0, 5), (1, 6)]);
+        assert!(state.snd_buf.iter().all(|s| s.xmit >= 2));
+
+        // Second message of peer arrives first
+        peer.send_to(&push_segment(1, 0, b"abc"), client_addr).await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let state = client.debug_state();
+        assert_eq!(state.rcv_nxt, 0);
+        let segments: Vec<_> = state.rcv_buf.iter().map(|s| (s.sn, s.len)).collect();
+        assert_eq!(segments, [(1, 3)]);
+        // Acknowledged by the flush
+        assert!(state.ack_list.is_empty());
+
+        // First message of peer acknowledges both segments
+        peer.send_to(&push_segment(0, 2, b"xy"), client_addr).await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let state = client.debug_state();
+        assert_eq!((state.snd_una, state.snd_nxt, state.wait_snd), (2, 2, 0));
+        assert!(state.snd_buf.is_empty());
+        assert_eq!(state.rcv_nxt, 2);
+        assert!(state.rcv_buf.is_empty());
     }
 }
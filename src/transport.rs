@@ -0,0 +1,154 @@
+//! Transport of datagrams
+//!
+//! `KcpTransport` is what the datagram paths of listeners and `KcpClient` require from a socket, sending to and
+//! receiving from any peer, so that datagrams could be carried by something else than UDP, like WebSocket where
+//! UDP is blocked, or by `MemoryTransport` in tests. It is implemented by `UdpSocket`, which listeners, clients and
+//! streams bind by default, others are given to `KcpListener::with_transport`, `KcpClient::with_transport` and
+//! `KcpStream::connect_with_transport`. Socket options, connected client sockets, rebinding and path MTU probing stay
+//! specific to UDP.
+
+use std::{
+    io,
+    mem::MaybeUninit,
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::{buf::UninitSlice, BufMut};
+use futures::future;
+use tokio::{io::ReadBuf, net::UdpSocket};
+
+use crate::utils::udp_dropped_datagrams;
+
+/// Sends and receives datagrams of any peer, like an unconnected `UdpSocket`.
+///
+/// Datagrams keep their boundaries, and may be lost, duplicated or reordered like on UDP. KCP recovers from them.
+pub trait KcpTransport: Send + Sync + 'static {
+    /// Sends `buf` as one datagram to `target`, returns the number of bytes sent
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>>;
+
+    /// Sends `buf` without waiting, fails with `WouldBlock` if it can't be sent now.
+    ///
+    /// Called by the output of sessions while their socket is locked.
+    fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize>;
+
+    /// Receives one datagram into the unfilled part of `buf`, returns the address of its sender.
+    ///
+    /// The part of the datagram that doesn't fit in `buf` is discarded.
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>>;
+
+    /// Address of this end of the transport
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Datagrams dropped since the transport was created because they arrived faster than they were received, `None`
+    /// if they are not counted. Sampled for `KcpEvent::ReceiveOverload`.
+    fn dropped_datagrams(&self) -> Option<u64> {
+        None
+    }
+}
+
+impl KcpTransport for UdpSocket {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_send_to(self, cx, buf, target)
+    }
+
+    fn try_send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        UdpSocket::try_send_to(self, buf, target)
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<SocketAddr>> {
+        UdpSocket::poll_recv_from(self, cx, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    /// Drops counted by the receive buffer of the socket on Linux, like `SO_RXQ_OVFL`
+    fn dropped_datagrams(&self) -> Option<u64> {
+        udp_dropped_datagrams(self)
+    }
+}
+
+/// Socket of a listener, a client or a session
+#[derive(Clone)]
+pub enum Endpoint {
+    Udp(Arc<UdpSocket>),
+    /// Transport given by `with_transport`, which has none of the features of UDP
+    Custom(Arc<dyn KcpTransport>),
+}
+
+impl Endpoint {
+    pub fn transport(&self) -> &dyn KcpTransport {
+        match *self {
+            Endpoint::Udp(ref udp) => &**udp,
+            Endpoint::Custom(ref transport) => &**transport,
+        }
+    }
+
+    /// The UDP socket, `None` for a custom transport
+    pub fn udp(&self) -> Option<&Arc<UdpSocket>> {
+        match *self {
+            Endpoint::Udp(ref udp) => Some(udp),
+            Endpoint::Custom(..) => None,
+        }
+    }
+}
+
+impl From<Arc<UdpSocket>> for Endpoint {
+    fn from(udp: Arc<UdpSocket>) -> Endpoint {
+        Endpoint::Udp(udp)
+    }
+}
+
+/// Sends `buf` as one datagram to `target`
+pub async fn send_to<T: KcpTransport + ?Sized>(transport: &T, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+    future::poll_fn(|cx| transport.poll_send_to(cx, buf, target)).await
+}
+
+/// Receives one datagram into the remaining capacity of `buf`, returns its length and the address of its sender
+pub async fn recv_buf_from<T: KcpTransport + ?Sized, B: BufMut>(
+    transport: &T,
+    buf: &mut B,
+) -> io::Result<(usize, SocketAddr)> {
+    let dst = buf.chunk_mut();
+    // Safety: `UninitSlice` is a transparent wrapper of `[MaybeUninit<u8>]`
+    let dst = unsafe { &mut *(dst as *mut UninitSlice as *mut [MaybeUninit<u8>]) };
+    let mut read_buf = ReadBuf::uninit(dst);
+    let addr = future::poll_fn(|cx| transport.poll_recv_from(cx, &mut read_buf)).await?;
+    let n = read_buf.filled().len();
+
+    // Safety: `ReadBuf` guarantees that its filled part was initialized
+    unsafe {
+        buf.advance_mut(n);
+    }
+    Ok((n, addr))
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use tokio::net::UdpSocket;
+
+    use super::{recv_buf_from, send_to, KcpTransport};
+
+    #[tokio::test]
+    async fn udp_transport() {
+        let a: Arc<dyn KcpTransport> = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let b = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let b_addr = KcpTransport::local_addr(&b).unwrap();
+
+        assert_eq!(send_to(&*a, b"HELLO", b_addr).await.unwrap(), 5);
+        assert_eq!(a.try_send_to(b"WORLD", b_addr).unwrap(), 5);
+
+        let mut buf = BytesMut::with_capacity(1024);
+        let (n, addr) = recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!((n, addr), (5, a.local_addr().unwrap()));
+        let (n, _) = recv_buf_from(&b, &mut buf).await.unwrap();
+        assert_eq!(n, 5);
+        assert_eq!(&buf[..], b"HELLOWORLD");
+    }
+}
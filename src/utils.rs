@@ -1,8 +1,338 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::OnceLock,
+    task::Waker,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+use tokio::{
+    net::{ToSocketAddrs, UdpSocket},
+    time::Instant,
+};
+
+/// Milliseconds elapsed on the monotonic clock, used as the clock of KCP
+///
+/// Wraps around every ~49.7 days, which is handled by KCP.
 #[inline]
 pub fn now_millis() -> u32 {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("time went afterwards");
-    (since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_millis() as u64 / 1_000_000) as u32
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+    let epoch = *EPOCH.get_or_init(Instant::now);
+    epoch.elapsed().as_millis() as u32
+}
+
+/// Check if `err` is reported because the datagram is larger than the path allows (`EMSGSIZE`)
+pub fn is_message_size_error(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        err.raw_os_error() == Some(libc::EMSGSIZE)
+    }
+
+    #[cfg(windows)]
+    {
+        // WSAEMSGSIZE
+        err.raw_os_error() == Some(10040)
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+/// Sets DF (don't fragment) on datagrams sent by `udp`, so that datagrams larger than the path MTU are dropped
+/// instead of being fragmented. Fails with `Unsupported` on platforms that can't do it.
+pub fn set_dont_fragment(udp: &UdpSocket) -> io::Result<()> {
+    let ipv6 = udp.local_addr()?.is_ipv6();
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        if ipv6 {
+            setsockopt_int(udp, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_DO)
+        } else {
+            setsockopt_int(udp, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_DO)
+        }
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    {
+        if ipv6 {
+            setsockopt_int(udp, libc::IPPROTO_IPV6, libc::IPV6_DONTFRAG, 1)
+        } else {
+            setsockopt_int(udp, libc::IPPROTO_IP, libc::IP_DONTFRAG, 1)
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    )))]
+    {
+        let _ = (udp, ipv6);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "don't fragment is not supported on this platform",
+        ))
+    }
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd"
+))]
+fn setsockopt_int(udp: &UdpSocket, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // SAFETY: `value` outlives the call and its size is passed along with it
+    let ret = unsafe {
+        libc::setsockopt(
+            udp.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Datagrams dropped by the receive buffer of `udp`, `SK_MEMINFO_DROPS` of `SO_MEMINFO`, which counts the drops of
+/// `SO_RXQ_OVFL` without enabling it on every datagram. `None` on other platforms.
+pub fn udp_dropped_datagrams(udp: &UdpSocket) -> Option<u64> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // Not exported by `libc` for every target
+        #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+        const SO_MEMINFO: libc::c_int = 0x39;
+        #[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+        const SO_MEMINFO: libc::c_int = 55;
+
+        let mut meminfo = [0u32; libc::SK_MEMINFO_DROPS as usize + 1];
+        let mut len = std::mem::size_of_val(&meminfo) as libc::socklen_t;
+        // SAFETY: `meminfo` outlives the call and its size is passed along with it
+        let ret = unsafe {
+            libc::getsockopt(
+                udp.as_raw_fd(),
+                libc::SOL_SOCKET,
+                SO_MEMINFO,
+                meminfo.as_mut_ptr() as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret == -1 || (len as usize) < std::mem::size_of_val(&meminfo) {
+            return None;
+        }
+        Some(meminfo[libc::SK_MEMINFO_DROPS as usize] as u64)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = udp;
+        None
+    }
+}
+
+/// Binds `udp` to the network interface `device` (`SO_BINDTODEVICE`). Fails with `Unsupported` on platforms
+/// that can't do it.
+pub fn set_bind_device(udp: &UdpSocket, device: &str) -> io::Result<()> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        socket2::SockRef::from(udp).bind_device(Some(device.as_bytes()))
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = (udp, device);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "binding to a device is not supported on this platform",
+        ))
+    }
+}
+
+/// Sets TTL of datagrams sent by `udp`, or their hop limit if it is an IPv6 socket
+pub fn set_ttl(udp: &UdpSocket, ttl: u32) -> io::Result<()> {
+    if !udp.local_addr()?.is_ipv6() {
+        return udp.set_ttl(ttl);
+    }
+
+    #[cfg(unix)]
+    {
+        socket2::SockRef::from(udp).set_unicast_hops_v6(ttl)
+    }
+
+    #[cfg(not(unix))]
+    {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "hop limit of IPv6 is not supported on this platform",
+        ))
+    }
+}
+
+/// Sets ToS (`IP_TOS`), or traffic class of an IPv6 socket (`IPV6_TCLASS`), of datagrams sent by `udp`.
+/// Fails with `Unsupported` on platforms that can't do it.
+pub fn set_tos(udp: &UdpSocket, tos: u32) -> io::Result<()> {
+    let ipv6 = udp.local_addr()?.is_ipv6();
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    ))]
+    {
+        if ipv6 {
+            setsockopt_int(udp, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos as libc::c_int)
+        } else {
+            setsockopt_int(udp, libc::IPPROTO_IP, libc::IP_TOS, tos as libc::c_int)
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    )))]
+    {
+        let _ = (udp, ipv6, tos);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "ToS is not supported on this platform",
+        ))
+    }
+}
+
+/// Options of UDP sockets created for sessions, see `KcpConfig::socket_options`
+#[derive(Debug, Clone, Default)]
+pub struct SocketOptions {
+    pub bind_device: Option<String>,
+    pub ttl: Option<u32>,
+    pub tos: Option<u32>,
+}
+
+impl SocketOptions {
+    /// Applies to `udp`, before it sends anything
+    pub fn apply(&self, udp: &UdpSocket) -> io::Result<()> {
+        if let Some(ref device) = self.bind_device {
+            set_bind_device(udp, device)?;
+        }
+        if let Some(ttl) = self.ttl {
+            set_ttl(udp, ttl)?;
+        }
+        if let Some(tos) = self.tos {
+            set_tos(udp, tos)?;
+        }
+        Ok(())
+    }
+}
+
+/// Binds a UDP socket on `addr` with `options`
+pub async fn bind_on<A: ToSocketAddrs>(addr: A, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let udp = UdpSocket::bind(addr).await?;
+    options.apply(&udp)?;
+    Ok(udp)
+}
+
+/// Binds a UDP socket of the same address family as `addr`
+pub async fn bind_for(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    match addr.ip() {
+        IpAddr::V4(..) => bind_on("0.0.0.0:0", options).await,
+        IpAddr::V6(..) => bind_on("[::]:0", options).await,
+    }
+}
+
+/// Binds a UDP socket for `addr` and connects it, so the OS reports ICMP unreachable errors on following sends
+/// and receives, and drops datagrams from other sources
+pub async fn connect_to(addr: SocketAddr, options: &SocketOptions) -> io::Result<UdpSocket> {
+    let udp = bind_for(addr, options).await?;
+    udp.connect(addr).await?;
+    Ok(udp)
+}
+
+/// Generates a random `u64`, not cryptographically secure
+pub fn random_u64() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.finish()
+}
+
+/// SplitMix64, small and good enough to decide impairments of emulated networks
+#[cfg(any(test, feature = "testing"))]
+pub struct Rng(u64);
+
+#[cfg(any(test, feature = "testing"))]
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `[0, 1)`
+    pub fn next_f64(&mut self) -> f64 {
+        // 53 bits fit in the mantissa of f64
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// `true` with probability `p`
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.next_f64() < p
+    }
+}
+
+/// Tasks waiting for the same condition, all of them are woken up when it may have changed.
+///
+/// Tasks that lose the race register again, so no wakeup is lost when a stream has several handles.
+#[derive(Default)]
+pub struct WakerList {
+    wakers: Vec<Waker>,
+}
+
+impl WakerList {
+    /// Registers `waker`, a task polled again before being woken up is only registered once
+    pub fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.wakers.is_empty()
+    }
+
+    pub fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
 }